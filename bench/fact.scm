@@ -0,0 +1,8 @@
+; factorial.scm-style loops: a named let in a do loop
+(load "factorial.scm")
+(define (repeat n)
+  (do ((i 0 (+ i 1))
+       (acc 0 (+ acc (fact2 20) (fact1 20))))
+      ((= i n) acc)))
+(display (repeat 20000))
+(newline)
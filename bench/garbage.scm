@@ -0,0 +1,9 @@
+; short-lived allocation with a long-lived list
+(define live (let loop ((i 0) (l '())) (if (= i 400) l (loop (+ i 1) (cons i l)))))
+(define (churn n)
+  (let loop ((i 0) (acc 0))
+    (if (= i n)
+        acc
+        (loop (+ i 1) (+ acc (length (list i i i)))))))
+(display (churn 300000))
+(newline)
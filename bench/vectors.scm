@@ -0,0 +1,8 @@
+; slots of a long-lived vector are overwritten with new objects
+(define table (make-vector 200 '()))
+(define (fill n)
+  (do ((i 0 (+ i 1))) ((= i n))
+    (vector-set! table (modulo i 200) (list i))))
+(fill 200000)
+(display (vector-ref table 0))
+(newline)
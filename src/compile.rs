@@ -0,0 +1,653 @@
+use std::cell::OnceCell;
+use std::rc::Rc;
+
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::data::{Object, Environment};
+use crate::token::{Token, Pos, written_name};
+use crate::symbol::Symbol;
+use crate::eval::{eval_quote, ensure_proper_list};
+use crate::vm::Chunk;
+
+/**
+ * Code: an expression compiled for the evaluator.
+ * Special forms are recognized once, and local variables are resolved to lexical addresses,
+ * i.e. how many frames out the variable is and which slot of the frame it has.
+ */
+pub struct Code {
+    pub op: Op,
+    pub pos: Option<Pos>,
+    pub chunk: OnceCell<Rc<Chunk>>,   // instructions for the VM, made when it first runs the code
+}
+
+pub enum Op {
+    Const(Object),
+    // a local variable: depth, index and name (for error messages)
+    Local(usize, usize, Symbol),
+    Global(Symbol),
+    // set! to the local variable at the address, or to a global variable
+    Set(Option<(usize, usize)>, Symbol, Rc<Code>),
+    // a definition in a slot of the current frame, or at toplevel
+    Define(Option<usize>, Symbol, Option<Rc<Code>>),
+    Lambda(Rc<Lambda>),
+    If(Rc<Code>, Rc<Code>, Option<Rc<Code>>),
+    Seq(Rc<[Rc<Code>]>),
+    App(Rc<App>),
+    Let(Rc<Let>),
+    Letrec(Rc<Let>),
+    Cond(Rc<[Clause]>),
+    And(Rc<[Rc<Code>]>),
+    Or(Rc<[Rc<Code>]>),
+    Do(Rc<DoLoop>),
+    Guard(Rc<Guard>),
+    Quasi(Rc<Quasi>),
+    // a syntax error, which is reported when the form is evaluated
+    Error(String),
+}
+
+/**
+ * Lambda: a compiled procedure body.
+ * Its frame has a slot for each argument, followed by slots for internal definitions.
+ */
+pub struct Lambda {
+    pub require: usize,
+    pub is_variadic: bool,
+    pub size: usize,
+    pub body: Rc<Code>,
+}
+
+pub struct App {
+    pub call: Token,
+    pub exps: Vec<Rc<Code>>,   // operator and operands
+    pub is_flat: bool,         // all of "exps" are constants or variables
+}
+
+/**
+ * Let: let, named let, let* (nested lets with one binding each) or letrec.
+ * The variables are the arguments of "body".
+ */
+pub struct Let {
+    pub call: Token,
+    pub name: Option<Symbol>,   // named let
+    pub ids: Vec<Symbol>,
+    pub inits: Vec<Rc<Code>>,
+    pub body: Rc<Lambda>,
+}
+
+// a clause of cond or guard. "else" has no test.
+pub struct Clause {
+    pub test: Option<Rc<Code>>,
+    pub body: Rc<Code>,
+}
+
+/**
+ * DoLoop: a compiled do form. Each iteration has a frame of the variables.
+ */
+pub struct DoLoop {
+    pub call: Token,
+    pub inits: Vec<Rc<Code>>,
+    pub steps: Vec<Rc<Code>>,
+    pub test: Rc<Code>,
+    pub exps: Option<Rc<Code>>,
+    pub cmds: Option<Rc<Code>>,
+}
+
+/**
+ * Guard: a compiled guard form. The clauses are in a frame of the condition variable.
+ */
+pub struct Guard {
+    pub call: Token,
+    pub body: Rc<Lambda>,
+    pub clauses: Rc<[Clause]>,
+}
+
+pub struct Quasi {
+    pub template: Template,
+    pub exps: Vec<Rc<Code>>,   // unquoted expressions, in the order they are filled in
+}
+
+// a quasiquote template, whose pairs and vectors are made anew each time
+pub enum Template {
+    Datum(Object),
+    Unquote,
+    UnquoteSplicing,
+    Pair(Box<Template>, Box<Template>),
+    Vector(Vec<Template>),
+}
+
+/**
+ * Scope: the variables of a frame at compile time, and the scope outside it.
+ * Variables which are not in any scope are global.
+ */
+struct Scope<'a> {
+    vars: Vec<Symbol>,
+    parent: Option<&'a Scope<'a>>,
+}
+
+impl Scope<'_> {
+    fn lookup(&self, id: &Symbol) -> Option<(usize, usize)> {
+        let mut scope = Some(self);
+        let mut depth = 0;
+        while let Some(s) = scope {
+            if let Some(index) = s.vars.iter().rposition(|var| var == id) {
+                return Some((depth, index));
+            }
+            depth += 1;
+            scope = s.parent;
+        }
+        None
+    }
+}
+
+// compile a toplevel form, whose definitions are global
+pub fn compile(token: &Token, env: &Environment) -> Rc<Code> {
+    Compiler{env}.toplevel(token)
+}
+
+struct Compiler<'a> {
+    env: &'a Environment,   // the global environment, which tells special forms from variables
+}
+
+impl Compiler<'_> {
+    fn toplevel(&self, token: &Token) -> Rc<Code> {
+        if let Token::Pair{car, cdr, ..} = token {
+            if let Token::Id(id, _) = &**car {
+                // definitions in (begin ...) belong to where begin is
+                if id == "begin" && self.env.lookup(id).is_none() && cdr.is_list() && !cdr.is_empty() {
+                    let codes = cdr.into_iter().map(|form| self.toplevel(form)).collect();
+                    return seq(codes, token);
+                }
+            }
+        }
+        self.form(token, None)
+    }
+
+    // an expression or a definition
+    fn form(&self, token: &Token, scope: Option<&Scope>) -> Rc<Code> {
+        match token {
+            Token::Pair{cdr, ..} if is_define(token) => {
+                self.define(token, cdr, scope).unwrap_or_else(|err| error(err, token))
+            }
+            _ => self.exp(token, scope),
+        }
+    }
+
+    fn define(&self, token: &Token, cdr: &Token, scope: Option<&Scope>) -> Result<Rc<Code>> {
+        ensure_proper_list(cdr)?;
+        cdr.elem().with_context(|| format!("syntax error: {}", token))?;
+        let id = defined_id(token).with_context(|| format!("syntax error: {}", token))?;
+        // internal definitions have slots made before the body is compiled
+        let index = scope.map(|s| s.vars.iter().rposition(|var| var == id).unwrap());
+        let exp = match cdr.elem() {
+            Some(Token::Pair{cdr: args, ..}) => {
+                let body = cdr.next().unwrap();
+                Some(code(Op::Lambda(self.lambda(args, body, token, scope)?), token))
+            }
+            _ => cdr.next().unwrap().elem().map(|exp| self.exp(exp, scope)),
+        };
+        Ok(code(Op::Define(index, id.clone(), exp), token))
+    }
+
+    fn exp(&self, token: &Token, scope: Option<&Scope>) -> Rc<Code> {
+        let op = match token {
+            Token::Id(id, _) => match scope.and_then(|s| s.lookup(id)) {
+                Some((depth, index)) => Op::Local(depth, index, id.clone()),
+                None => Op::Global(id.clone()),
+            }
+            Token::Pair{car, cdr, ..} => {
+                let res = match &**car {
+                    Token::Id(id, _) if self.is_syntax(id, scope) => self.syntax(id, token, cdr, scope),
+                    Token::Id(..) | Token::Pair{..} => self.app(token, scope),
+                    _ => Err(anyhow!("invalid application: {}", token)),
+                };
+                return res.unwrap_or_else(|err| error(err, token));
+            }
+            Token::Label(..) | Token::LabelRef(..) => {
+                Op::Error(format!("syntax error: datum label outside of a quoted datum: {}", token))
+            }
+            _ => match eval_quote(token) {
+                Ok(obj) => Op::Const(obj),
+                Err(err) => Op::Error(err.to_string()),
+            }
+        };
+        code(op, token)
+    }
+
+    // a keyword of a special form, unless it is bound as a variable
+    fn is_syntax(&self, id: &Symbol, scope: Option<&Scope>) -> bool {
+        scope.and_then(|s| s.lookup(id)).is_none() && self.env.lookup(id).is_none()
+    }
+
+    fn app(&self, token: &Token, scope: Option<&Scope>) -> Result<Rc<Code>> {
+        ensure_proper_list(token)?;
+        let exps: Vec<Rc<Code>> = token.into_iter().map(|exp| self.exp(exp, scope)).collect();
+        let is_flat = exps.iter().all(|exp| matches!(exp.op, Op::Const(_) | Op::Local(..) | Op::Global(_)));
+        Ok(code(Op::App(Rc::new(App{call: token.clone(), exps, is_flat})), token))
+    }
+
+    fn syntax(&self, id: &Symbol, token: &Token, cdr: &Token, scope: Option<&Scope>) -> Result<Rc<Code>> {
+        let op = match id.as_str() {
+            "lambda" => {
+                ensure_proper_list(cdr)?;
+                let arg = cdr.elem().with_context(|| format!("syntax error: malformed lambda: {}", token))?;
+                let body = cdr.next().unwrap();
+                Op::Lambda(self.lambda(arg, body, token, scope)?)
+            }
+            "quote" => {
+                ensure_proper_list(cdr)?;
+                match (cdr.elem(), cdr.nth(1)) {
+                    (Some(datum), None) => Op::Const(eval_quote(datum)?),
+                    _ => bail!("syntax error: malformed quote: {}", token),
+                }
+            }
+            "quasiquote" => {
+                ensure_proper_list(cdr)?;
+                match (cdr.elem(), cdr.nth(1)) {
+                    (Some(template), None) => {
+                        let mut exps = Vec::new();
+                        let template = self.template(template, 1, scope, &mut exps)?;
+                        Op::Quasi(Rc::new(Quasi{template, exps}))
+                    }
+                    _ => bail!("syntax error: malformed quasiquote: {}", token),
+                }
+            }
+            "unquote" | "unquote-splicing" => bail!("syntax error: {} outside of quasiquote: {}", id, token),
+            "set!" => {
+                ensure_proper_list(token)?;
+                let id = token
+                    .nth(1)
+                    .with_context(|| format!("syntax error: malformed set!: {}", token))?;
+                let exp = token
+                    .nth(2)
+                    .with_context(|| format!("syntax error: malformed set!: {}", token))?;
+                match id {
+                    Token::Id(id, _) => {
+                        let addr = scope.and_then(|s| s.lookup(id));
+                        Op::Set(addr, id.clone(), self.exp(exp, scope))
+                    }
+                    _ => bail!("syntax error: identifier required, but got {}", id),
+                }
+            }
+            "let" => {
+                ensure_proper_list(cdr)?;
+                match cdr.nth(0).with_context(|| format!("syntax error: malformed let: {}", token))? {
+                    Token::Id(name, _) => {
+                        let bindings = cdr.nth(1).with_context(|| format!("syntax error: malformed let: {}", token))?;
+                        let body = cdr.next().unwrap().next().with_context(|| format!("syntax error: malformed let: {}", token))?;
+                        let ids = check_bindings(bindings, token)?;
+                        let inits = self.inits(bindings, scope);
+                        // the procedure sees itself by the name
+                        let name_scope = Scope{vars: vec![name.clone()], parent: scope};
+                        let body = self.body_lambda(ids.clone(), false, body, token, Some(&name_scope));
+                        Op::Let(Rc::new(Let{call: token.clone(), name: Some(name.clone()), ids, inits, body}))
+                    }
+                    bindings => {
+                        let ids = check_bindings(bindings, token)?;
+                        let inits = self.inits(bindings, scope);
+                        let body = self.body_lambda(ids.clone(), false, cdr.next().unwrap(), token, scope);
+                        Op::Let(Rc::new(Let{call: token.clone(), name: None, ids, inits, body}))
+                    }
+                }
+            }
+            "let*" => {
+                ensure_proper_list(cdr)?;
+                let bindings = cdr.nth(0).with_context(|| format!("syntax error: malformed let: {}", token))?;
+                check_bindings(bindings, token)?;
+                let bindings: Vec<&Token> = bindings.into_iter().collect();
+                return Ok(self.let_star(token, &bindings, cdr.next().unwrap(), scope));
+            }
+            "letrec" => {
+                ensure_proper_list(cdr)?;
+                let bindings = cdr.nth(0).with_context(|| format!("syntax error: malformed let: {}", token))?;
+                let ids = check_bindings(bindings, token)?;
+                // inits are compiled in the frame they are bound in
+                let body = cdr.next().unwrap();
+                let mut vars = ids.clone();
+                vars.extend(internal_defines(body));
+                let frame = Scope{vars, parent: scope};
+                let inits = self.inits(bindings, Some(&frame));
+                let body = Rc::new(Lambda{
+                    require: ids.len(),
+                    is_variadic: false,
+                    size: frame.vars.len(),
+                    body: self.body(body, &frame).unwrap_or_else(|err| error(err, token)),
+                });
+                Op::Letrec(Rc::new(Let{call: token.clone(), name: None, ids, inits, body}))
+            }
+            "if" => {
+                // (if exp1 exp2 exp3)
+                let exp1 = cdr.elem()
+                    .with_context(|| format!("error: proper list required for function application or macro use: {}", token))?;
+                let exp2 = cdr.next()
+                    .with_context(|| format!("error: proper list required for function application or macro use: {}", token))?
+                    .elem()
+                    .with_context(|| format!("syntax error: malformed if: {}", token))?;
+                let exp3 = cdr.next()
+                    .unwrap()
+                    .next()
+                    .with_context(|| format!("error: proper list required for function application or macro use: {}", token))?;
+                let els = match exp3 {
+                    Token::Pair{car: exp3_car, cdr: exp3_cdr, ..} => {
+                        if !exp3_cdr.is_empty() {
+                            bail!("syntax error: malformed if: {}", token);
+                        }
+                        Some(self.exp(exp3_car, scope))
+                    }
+                    Token::Empty(_) => None,
+                    _ => bail!("error: proper list required for function application or macro use: {}", token),
+                };
+                Op::If(self.exp(exp1, scope), self.exp(exp2, scope), els)
+            }
+            "cond" => {
+                ensure_proper_list(cdr)?;
+                if cdr.is_empty() {
+                    bail!("syntax error: at least one clause is required for cond: {}", token);
+                }
+                Op::Cond(self.clauses(cdr, "cond", token, scope))
+            }
+            "and" | "or" => {
+                ensure_proper_list(cdr)?;
+                if cdr.is_empty() {
+                    Op::Const(Object::new_boolean(id == "and", true))
+                } else {
+                    let tests = cdr.into_iter().map(|test| self.exp(test, scope)).collect();
+                    if id == "and" { Op::And(tests) } else { Op::Or(tests) }
+                }
+            }
+            "begin" => {
+                ensure_proper_list(cdr)?;
+                if cdr.is_empty() {
+                    Op::Const(Object::new_undefined())
+                } else {
+                    return Ok(seq(cdr.into_iter().map(|exp| self.exp(exp, scope)).collect(), token));
+                }
+            }
+            "guard" => {
+                // (guard (var clause ...) body ...)
+                ensure_proper_list(cdr)?;
+                let (var, clauses) = match cdr.elem() {
+                    Some(Token::Pair{car, cdr: clauses, ..}) if clauses.is_list() => match &**car {
+                        Token::Id(var, _) => (var.clone(), clauses),
+                        _ => bail!("syntax error: malformed guard: {}", token),
+                    }
+                    _ => bail!("syntax error: malformed guard: {}", token),
+                };
+                let body = self.body_lambda(Vec::new(), false, cdr.next().unwrap(), token, scope);
+                let var_scope = Scope{vars: vec![var], parent: scope};
+                let clauses = self.clauses(clauses, "guard", token, Some(&var_scope));
+                Op::Guard(Rc::new(Guard{call: token.clone(), body, clauses}))
+            }
+            "do" => {
+                // (do ((var init step) ...) (test exps) cmds)
+                ensure_proper_list(cdr)?;
+                let var_init_steps = cdr.elem().with_context(|| format!("syntax error: malformed do: {}", token))?;
+                if !var_init_steps.is_list() {
+                    bail!("syntax error: malformed do: {}", token);
+                }
+                let mut vars = Vec::new();
+                let mut inits = Vec::new();
+                let mut steps = Vec::new();
+                for var_init_step in var_init_steps {
+                    let var = var_init_step.nth(0).with_context(|| format!("syntax error: malformed do: {}", token))?;
+                    let init = var_init_step.nth(1).with_context(|| format!("syntax error: malformed do: {}", token))?;
+                    // a variable without step keeps its value
+                    let step = var_init_step.nth(2).unwrap_or(var);
+                    if let Token::Id(id, _) = var {
+                        vars.push(id.clone());
+                    } else {
+                        bail!("syntax error: malformed do: {}", token);
+                    }
+                    inits.push(self.exp(init, scope));
+                    steps.push(step);
+                    if var_init_step.nth(3).is_some() {
+                        bail!("syntax error: malformed do: {}", token);
+                    }
+                }
+
+                let test_exp = cdr
+                    .next()
+                    .with_context(|| format!("syntax error: malformed do: {}", token))?
+                    .elem()
+                    .with_context(|| format!("syntax error: malformed do: {}", token))?;
+                if !test_exp.is_list() {
+                    bail!("syntax error: malformed do: {}", token);
+                }
+                let test = test_exp.elem().with_context(|| format!("syntax error: malformed do: {}", token))?;
+
+                let cmds = cdr
+                    .next()
+                    .unwrap()
+                    .next()
+                    .with_context(|| format!("syntax error: malformed do: {}", token))?;
+
+                let frame = Scope{vars, parent: scope};
+                let exps = test_exp.next().unwrap();
+                let lp = DoLoop{
+                    call: token.clone(),
+                    inits,
+                    steps: steps.into_iter().map(|step| self.exp(step, Some(&frame))).collect(),
+                    test: self.exp(test, Some(&frame)),
+                    exps: (!exps.is_empty()).then(|| seq(exps.into_iter().map(|exp| self.exp(exp, Some(&frame))).collect(), exps)),
+                    cmds: (!cmds.is_empty()).then(|| seq(cmds.into_iter().map(|cmd| self.exp(cmd, Some(&frame))).collect(), cmds)),
+                };
+                Op::Do(Rc::new(lp))
+            }
+            _ if !token.is_list() => bail!("unbound variable: {}", written_name(id)),
+            // not a special form, e.g. a global procedure defined later
+            _ => return self.app(token, scope),
+        };
+        Ok(code(op, token))
+    }
+
+    fn lambda(&self, mut arg: &Token, body: &Token, token: &Token, scope: Option<&Scope>) -> Result<Rc<Lambda>> {
+        let mut args = Vec::new();
+        while let Some(id) = arg.elem() {
+            match id {
+                Token::Id(id, _) => args.push(id.clone()),
+                _ => bail!("syntax error: identifier required, but got {}", id),
+            }
+            arg = arg.cdr().unwrap();
+        }
+        match arg {
+            Token::Id(id, _) => {
+                args.push(id.clone());
+                Ok(self.body_lambda(args, true, body, token, scope))
+            }
+            Token::Empty(_) => Ok(self.body_lambda(args, false, body, token, scope)),
+            _ => Err(anyhow!("syntax error: identifier required, but got {}", arg))
+        }
+    }
+
+    // a body in a new frame of "args" (the last one is the rest argument if variadic)
+    fn body_lambda(&self, mut args: Vec<Symbol>, is_variadic: bool, body: &Token, token: &Token, scope: Option<&Scope>) -> Rc<Lambda> {
+        // argument "token" is the form of the body (for error messages)
+        let require = if is_variadic { args.len() - 1 } else { args.len() };
+        args.extend(internal_defines(body));
+        let frame = Scope{vars: args, parent: scope};
+        let body = self.body(body, &frame).unwrap_or_else(|err| error(err, token));
+        Rc::new(Lambda{require, is_variadic, size: frame.vars.len(), body})
+    }
+
+    fn body(&self, token: &Token, scope: &Scope) -> Result<Rc<Code>> {
+        ensure_proper_list(token)?;
+        token.elem().context("syntax error: at least one expression is necessary")?;
+        if is_define(token.into_iter().last().unwrap()) {
+            bail!("syntax error: at least one expression is necessary");
+        }
+        Ok(seq(token.into_iter().map(|form| self.form(form, Some(scope))).collect(), token))
+    }
+
+    // let* is let in let for each binding
+    fn let_star(&self, token: &Token, bindings: &[&Token], body: &Token, scope: Option<&Scope>) -> Rc<Code> {
+        let lt = match bindings.split_first() {
+            None => Let{
+                call: token.clone(),
+                name: None,
+                ids: Vec::new(),
+                inits: Vec::new(),
+                body: self.body_lambda(Vec::new(), false, body, token, scope),
+            },
+            Some((binding, rest)) => {
+                let id = match binding.nth(0) {
+                    Some(Token::Id(id, _)) => id.clone(),
+                    _ => unreachable!(),
+                };
+                let init = self.exp(binding.nth(1).unwrap(), scope);
+                let body = if rest.is_empty() {
+                    self.body_lambda(vec![id.clone()], false, body, token, scope)
+                } else {
+                    let frame = Scope{vars: vec![id.clone()], parent: scope};
+                    let body = self.let_star(token, rest, body, Some(&frame));
+                    Rc::new(Lambda{require: 1, is_variadic: false, size: 1, body})
+                };
+                Let{call: token.clone(), name: None, ids: vec![id], inits: vec![init], body}
+            }
+        };
+        code(Op::Let(Rc::new(lt)), token)
+    }
+
+    fn inits(&self, bindings: &Token, scope: Option<&Scope>) -> Vec<Rc<Code>> {
+        bindings.into_iter().map(|binding| self.exp(binding.nth(1).unwrap(), scope)).collect()
+    }
+
+    // clauses of cond or guard. A bad clause is reported when it is reached.
+    fn clauses(&self, clauses: &Token, name: &str, token: &Token, scope: Option<&Scope>) -> Rc<[Clause]> {
+        let mut compiled = Vec::new();
+        for clause in clauses {
+            match clause {
+                Token::Pair{car: test, cdr: exps, ..} if !exps.is_empty() && exps.is_list() => {
+                    let body = seq(exps.into_iter().map(|exp| self.exp(exp, scope)).collect(), exps);
+                    match &**test {
+                        Token::Id(s, _) if s == "else" => {
+                            compiled.push(Clause{test: None, body});
+                            break;
+                        }
+                        _ => compiled.push(Clause{test: Some(self.exp(test, scope)), body}),
+                    }
+                }
+                _ => {
+                    let err = anyhow!("syntax error: bad clause in {}: {}", name, token);
+                    compiled.push(Clause{test: None, body: error(err, token)});
+                    break;
+                }
+            }
+        }
+        compiled.into()
+    }
+
+    // (quasiquote x), (unquote x) or (unquote-splicing x) in a quasiquote template -> (name, x)
+    fn template_form<'a>(&self, token: &'a Token) -> Option<(&'a str, &'a Token)> {
+        match (token.car(), token.nth(1), token.cdr().and_then(Token::next)) {
+            (Some(Token::Id(id, _)), Some(arg), Some(Token::Empty(_)))
+                if ["quasiquote", "unquote", "unquote-splicing"].contains(&id.as_str()) && self.env.lookup(id).is_none()
+                => Some((id, arg)),
+            _ => None,
+        }
+    }
+
+    fn is_splicing(&self, token: &Token, depth: usize) -> bool {
+        depth == 1 && matches!(self.template_form(token), Some(("unquote-splicing", _)))
+    }
+
+    // the template at level "depth". Expressions unquoted at level 1 are put in "exps".
+    fn template(&self, token: &Token, depth: usize, scope: Option<&Scope>, exps: &mut Vec<Rc<Code>>) -> Result<Template> {
+        match self.template_form(token) {
+            Some(("unquote", arg)) if depth == 1 => {
+                exps.push(self.exp(arg, scope));
+                Ok(Template::Unquote)
+            }
+            Some(("unquote-splicing", _)) if depth == 1 => bail!("syntax error: unquote-splicing out of list or vector: {}", token),
+            Some((name, arg)) => {
+                let depth = if name == "quasiquote" { depth + 1 } else { depth - 1 };
+                let arg = self.template(arg, depth, scope, exps)?;
+                let list = Template::Pair(Box::new(arg), Box::new(Template::Datum(Object::new_empty())));
+                let name = Template::Datum(Object::new_symbol(Symbol::intern(name)));
+                Ok(Template::Pair(Box::new(name), Box::new(list)))
+            }
+            None => match token {
+                Token::Pair{car, cdr, ..} => {
+                    let car = self.template_elem(car, depth, scope, exps)?;
+                    let cdr = self.template(cdr, depth, scope, exps)?;
+                    Ok(Template::Pair(Box::new(car), Box::new(cdr)))
+                }
+                Token::Vector(elems, _) => {
+                    let elems = elems.iter().map(|elem| self.template_elem(elem, depth, scope, exps));
+                    Ok(Template::Vector(elems.collect::<Result<_>>()?))
+                }
+                _ => Ok(Template::Datum(eval_quote(token)?)),
+            }
+        }
+    }
+
+    // an element of a list or a vector, which may be spliced
+    fn template_elem(&self, token: &Token, depth: usize, scope: Option<&Scope>, exps: &mut Vec<Rc<Code>>) -> Result<Template> {
+        match self.template_form(token) {
+            Some((_, arg)) if self.is_splicing(token, depth) => {
+                exps.push(self.exp(arg, scope));
+                Ok(Template::UnquoteSplicing)
+            }
+            _ => self.template(token, depth, scope, exps),
+        }
+    }
+}
+
+fn code(op: Op, token: &Token) -> Rc<Code> {
+    Rc::new(Code{op, pos: token.pos().cloned(), chunk: OnceCell::new()})
+}
+
+fn error(err: anyhow::Error, token: &Token) -> Rc<Code> {
+    code(Op::Error(err.to_string()), token)
+}
+
+// a sequence of one or more codes, the last of which is in tail position
+fn seq(mut codes: Vec<Rc<Code>>, token: &Token) -> Rc<Code> {
+    if codes.len() == 1 {
+        codes.pop().unwrap()
+    } else {
+        code(Op::Seq(codes.into()), token)
+    }
+}
+
+fn is_define(token: &Token) -> bool {
+    matches!(token.elem(), Some(Token::Id(id, _)) if id == "define")
+}
+
+// the variable defined by (define id ...) or (define (id . args) ...)
+fn defined_id(token: &Token) -> Option<&Symbol> {
+    match token.nth(1)? {
+        Token::Id(id, _) => Some(id),
+        Token::Pair{car, ..} => match &**car {
+            Token::Id(id, _) => Some(id),
+            _ => None,
+        }
+        _ => None,
+    }
+}
+
+// the variables defined in a body, which get slots of its frame
+fn internal_defines(body: &Token) -> Vec<Symbol> {
+    body.into_iter()
+        .filter(|form| is_define(form))
+        .filter_map(|form| defined_id(form).cloned())
+        .collect()
+}
+
+// check bindings of let, let* and letrec, and return the variables
+fn check_bindings(bindings: &Token, token: &Token) -> Result<Vec<Symbol>> {
+    // argument "token" is for error messages
+    if !matches!(bindings, Token::Pair{..} | Token::Empty(_)) {
+        bail!("syntax error: malformed let: {}", token);
+    }
+    ensure_proper_list(bindings)?;
+    let mut ids = Vec::new();
+    for binding in bindings {
+        match binding.nth(0).with_context(|| format!("syntax error: malformed let: {}", token))? {
+            Token::Id(id, _) => ids.push(id.clone()),
+            t => bail!("syntax error: identifier required, but got {}", t),
+        };
+        if binding.nth(1).is_none() || binding.nth(2).is_some() {
+            bail!("syntax error: malformed let: {}", token);
+        }
+    }
+    Ok(ids)
+}
@@ -2,21 +2,43 @@ pub mod object;
 pub mod env;
 mod r#ref;
 pub mod memory;
+pub mod cont;
+mod number;
+pub mod port;
+pub mod hashtable;
 
-use self::r#ref::{ObjRef, EnvRef};
+pub(crate) use self::r#ref::{ObjRef, EnvRef, ContRef};
+use self::cont::{ContBody, Frame};
 use self::object::*;
-use self::memory::Marker;
+use self::memory::{Marker, Generation};
 use self::memory::Memory;
+use self::port::Port;
+use self::hashtable::{HashTable, Comparator};
 use crate::data::env::EnvBody;
-use crate::token::Token;
+use crate::token::written_name;
+use crate::symbol::Symbol;
+use crate::expand::Macro;
+use crate::compile::Lambda;
 
-use std::cell::Cell;
-use std::collections::{VecDeque, HashMap};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::iter::{Iterator, IntoIterator};
+use std::rc::Rc;
+use std::sync::Arc;
 
 use anyhow::{Result, anyhow, bail};
 
+thread_local! {
+    // interned symbol -> the object of the symbol, which is forgotten when GC finds the object dead
+    static SYMBOL_OBJECTS: RefCell<HashMap<Symbol, ObjRef>> = RefCell::new(HashMap::new());
+}
+
+// called by GC after marking, so that symbols which are not used any more can be collected
+pub(crate) fn forget_dead_symbols() {
+    SYMBOL_OBJECTS.with(|objs| objs.borrow_mut().retain(|_, re| matches!(re.borrow().mark, Marker::Black)));
+}
+
 // define Object and Environment
 // These structs follows Interior mutability pattern (擬き)
 
@@ -25,7 +47,6 @@ use anyhow::{Result, anyhow, bail};
  * The entity of object is object::ObjBody. 
  * The interpreter accesses an object through this object.
  */
-
 pub struct Object{
     re: ObjRef,
 }
@@ -37,6 +58,19 @@ impl Object {
             is_mutable,
             kind: Kind::Number(Number::Int(i)),
             mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    pub fn new_number(num: Number, is_mutable: bool) -> Object {
+        let body = ObjBody {
+            is_mutable,
+            kind: Kind::Number(num),
+            mark: Marker::Black,
+            generation: Generation::Young,
             rc: Cell::new(1),
         };
         let re = Memory::push_obj(body);
@@ -48,6 +82,7 @@ impl Object {
             is_mutable,
             kind: Kind::Number(Number::Float(f)),
             mark: Marker::Black,
+            generation: Generation::Young,
             rc: Cell::new(1),
         };
         let re = Memory::push_obj(body);
@@ -59,6 +94,19 @@ impl Object {
             is_mutable,
             kind: Kind::Boolean(b),
             mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    pub fn new_char(c: char) -> Object {
+        let body = ObjBody {
+            is_mutable: false,
+            kind: Kind::Char(c),
+            mark: Marker::Black,
+            generation: Generation::Young,
             rc: Cell::new(1),
         };
         let re = Memory::push_obj(body);
@@ -70,21 +118,32 @@ impl Object {
             is_mutable,
             kind: Kind::String(s),
             mark: Marker::Black,
+            generation: Generation::Young,
             rc: Cell::new(1),
         };
         let re = Memory::push_obj(body);
         Object{re}
     }
 
-    pub fn new_symbol(s: String, is_mutable: bool) -> Object {
+    // an interned symbol has one object while it is alive
+    pub fn new_symbol(symbol: Symbol) -> Object {
+        if let Some(re) = SYMBOL_OBJECTS.with(|objs| objs.borrow().get(&symbol).copied()) {
+            re.borrow().inc_rc();
+            return Object{re};
+        }
         let body = ObjBody {
-            is_mutable,
-            kind: Kind::Symbol(s),
+            is_mutable: false,
+            kind: Kind::Symbol(symbol.clone()),
             mark: Marker::Black,
+            generation: Generation::Young,
             rc: Cell::new(1),
         };
         let re = Memory::push_obj(body);
-        Object{re}
+        let obj = Object{re};
+        if symbol.is_interned() {
+            SYMBOL_OBJECTS.with(|objs| objs.borrow_mut().insert(symbol, obj.re));
+        }
+        obj
     }
 
     pub fn new_empty() -> Object {
@@ -99,33 +158,200 @@ impl Object {
                 cdr: cdr.re,
             }),
             mark: Marker::Black,
+            generation: Generation::Young,
             rc: Cell::new(1),
         };
         let re = Memory::push_obj(body);
         Object{re}
     }
 
-    pub fn new_procedure(env: Environment, args: Vec<String>, is_variadic: bool, require: usize, body: Token) -> Object {
+    pub fn new_vector(elems: Vec<Object>, is_mutable: bool) -> Object {
+        let body = ObjBody {
+            is_mutable,
+            kind: Kind::Vector(Vector{
+                elems: elems.iter().map(|obj| obj.re).collect(),
+            }),
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    // (values obj ...), which is obj itself if there is only one
+    pub fn new_values(mut objs: Vec<Object>) -> Object {
+        if objs.len() == 1 {
+            return objs.pop().unwrap();
+        }
+        let body = ObjBody {
+            is_mutable: false,
+            kind: Kind::Values(Vector{
+                elems: objs.iter().map(|obj| obj.re).collect(),
+            }),
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    pub fn new_bytevector(bytes: Vec<u8>, is_mutable: bool) -> Object {
+        let body = ObjBody {
+            is_mutable,
+            kind: Kind::Bytevector(bytes),
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    pub fn new_procedure(env: Environment, lambda: Rc<Lambda>) -> Object {
         let body = ObjBody {
             is_mutable: false,
             kind: Kind::Procedure(Procedure::Proc(Proc{
                 env: env.re, 
-                args, is_variadic, require, body,
+                name: None,
+                lambda,
             })),
             mark: Marker::Black,
+            generation: Generation::Young,
             rc: Cell::new(1),
         };
         let re = Memory::push_obj(body);
         Object{re}
     }
 
-    fn new_subroutine(is_variadic: bool, require: usize, fun: fn(VecDeque<Object>) -> Result<Object> ) -> Object {
+    fn new_subroutine(is_variadic: bool, require: usize, fun: SubrFn) -> Object {
         let body = ObjBody {
             is_mutable: false,
             kind: Kind::Procedure(Procedure::Subr(Subr{
                 is_variadic, require, fun,
             })),
             mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    fn new_control(is_variadic: bool, require: usize, fun: CtrlFn) -> Object {
+        let body = ObjBody {
+            is_mutable: false,
+            kind: Kind::Procedure(Procedure::Ctrl(Ctrl{
+                is_variadic, require, fun,
+            })),
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    pub fn new_continuation(k: &Cont, winders: &Object) -> Object {
+        let body = ObjBody {
+            is_mutable: false,
+            kind: Kind::Procedure(Procedure::Cont(Continuation{
+                k: k.re,
+                winders: winders.re,
+            })),
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    pub fn new_syntax(mac: Rc<Macro>) -> Object {
+        let body = ObjBody {
+            is_mutable: false,
+            kind: Kind::Syntax(mac),
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    pub fn new_error(message: String, irritants: Object) -> Object {
+        let body = ObjBody {
+            is_mutable: false,
+            kind: Kind::Error(ErrorObject{
+                message,
+                irritants: irritants.re,
+            }),
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    pub fn new_record_type(name: Symbol, fields: Vec<Symbol>) -> Object {
+        let body = ObjBody {
+            is_mutable: false,
+            kind: Kind::RecordType(Rc::new(RecordType{name, fields})),
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    pub fn new_record(rtd: Rc<RecordType>, fields: Vec<Object>) -> Object {
+        let body = ObjBody {
+            is_mutable: true,
+            kind: Kind::Record(Record{
+                rtd,
+                fields: fields.iter().map(|obj| obj.re).collect(),
+            }),
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    pub fn new_hash_table(comparator: Comparator) -> Object {
+        let body = ObjBody {
+            is_mutable: true,
+            kind: Kind::HashTable(HashTable::new(comparator)),
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    pub fn new_port(port: Port) -> Object {
+        let body = ObjBody {
+            is_mutable: false,
+            kind: Kind::Port(port),
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_obj(body);
+        Object{re}
+    }
+
+    pub fn new_eof() -> Object {
+        let body = ObjBody {
+            is_mutable: false,
+            kind: Kind::Eof,
+            mark: Marker::Black,
+            generation: Generation::Young,
             rc: Cell::new(1),
         };
         let re = Memory::push_obj(body);
@@ -137,6 +363,7 @@ impl Object {
             is_mutable: false,
             kind: Kind::Undefined,
             mark: Marker::Black,
+            generation: Generation::Young,
             rc: Cell::new(1),
         };
         let re = Memory::push_obj(body);
@@ -145,11 +372,20 @@ impl Object {
 
     /***** Constructor to here *****/
 
+    // raw reference to be kept in a frame, which is traced by GC instead of being counted
+    #[inline]
+    pub(crate) fn raw(&self) -> ObjRef {
+        self.re
+    }
+
+    #[inline]
+    pub(crate) fn from_raw(re: ObjRef) -> Object {
+        re.borrow().inc_rc();
+        Object{re}
+    }
+
     pub fn is_falsy(&self) -> bool {
-        match self.re.borrow().kind {
-            Kind::Boolean(b) if !b => true,
-            _ => false,
-        }
+        matches!(self.re.borrow().kind, Kind::Boolean(false))
     }
 
     #[inline]
@@ -177,6 +413,7 @@ impl Object {
         if !self.re.borrow().is_mutable {
             bail!("got immutable object {}", self)
         }
+        Memory::write_obj(&self.re);
         unsafe {
             match &mut self.re.borrow_mut().kind {
                 Kind::Pair(pair) => pair.car = car.re,
@@ -186,10 +423,140 @@ impl Object {
         Ok(())
     }
 
+    // replace elements of a vector from "at" with "objs"
+    pub fn vector_splice(&self, at: usize, objs: Vec<Object>) -> Result<()> {
+        if !self.re.borrow().is_mutable {
+            bail!("got immutable object {}", self)
+        }
+        Memory::write_obj(&self.re);
+        unsafe {
+            match &mut self.re.borrow_mut().kind {
+                Kind::Vector(vector) => {
+                    if at + objs.len() > vector.elems.len() {
+                        bail!("index out of range: {}", at + objs.len());
+                    }
+                    for (i, obj) in objs.iter().enumerate() {
+                        vector.elems[at + i] = obj.re;
+                    }
+                }
+                _ => return Err(anyhow!("vector required, but got {}", self))
+            };
+        }
+        Ok(())
+    }
+
+    pub fn set_record_field(&self, k: usize, obj: Object) -> Result<()> {
+        Memory::write_obj(&self.re);
+        unsafe {
+            match &mut self.re.borrow_mut().kind {
+                Kind::Record(record) => record.fields[k] = obj.re,
+                _ => return Err(anyhow!("record required, but got {}", self))
+            };
+        }
+        Ok(())
+    }
+
+    // associate "key" with "value" in a hash table
+    pub fn hash_table_set(&self, key: &Object, value: &Object) -> Result<()> {
+        Memory::write_obj(&self.re);
+        unsafe {
+            match &mut self.re.borrow_mut().kind {
+                Kind::HashTable(table) => table.insert(key, value),
+                _ => Err(anyhow!("hash table required, but got {}", self)),
+            }
+        }
+    }
+
+    pub fn hash_table_delete(&self, key: &Object) -> Result<()> {
+        unsafe {
+            match &mut self.re.borrow_mut().kind {
+                Kind::HashTable(table) => table.remove(key),
+                _ => Err(anyhow!("hash table required, but got {}", self)),
+            }
+        }
+    }
+
+    // replace bytes of a bytevector from "at" with "bytes"
+    pub fn bytevector_splice(&self, at: usize, bytes: &[u8]) -> Result<()> {
+        if !self.re.borrow().is_mutable {
+            bail!("got immutable object {}", self)
+        }
+        unsafe {
+            match &mut self.re.borrow_mut().kind {
+                Kind::Bytevector(bv) => {
+                    if at + bytes.len() > bv.len() {
+                        bail!("index out of range: {}", at + bytes.len());
+                    }
+                    bv[at..at + bytes.len()].copy_from_slice(bytes);
+                }
+                _ => return Err(anyhow!("bytevector required, but got {}", self))
+            };
+        }
+        Ok(())
+    }
+
+    // replace characters of a string from "at" with "chars"
+    pub fn string_splice(&self, at: usize, chars: &[char]) -> Result<()> {
+        if !self.re.borrow().is_mutable {
+            bail!("got immutable object {}", self)
+        }
+        unsafe {
+            match &mut self.re.borrow_mut().kind {
+                Kind::String(s) => {
+                    let mut old: Vec<char> = s.chars().collect();
+                    if at + chars.len() > old.len() {
+                        bail!("index out of range: {}", at + chars.len());
+                    }
+                    old[at..at + chars.len()].copy_from_slice(chars);
+                    *s = old.into_iter().collect();
+                }
+                _ => return Err(anyhow!("string required, but got {}", self))
+            };
+        }
+        Ok(())
+    }
+
+    // replace the references to "from" in the pairs and vectors reachable from this object with "to",
+    // e.g. to make the cycle of a datum with labels
+    pub(crate) fn replace_ref(&self, from: &Object, to: &Object) {
+        let mut visited = HashSet::new();
+        let mut stack = vec![self.re];
+        while let Some(re) = stack.pop() {
+            if !visited.insert(re) {
+                continue;
+            }
+            Memory::write_obj(&re);
+            let refs: Vec<&mut ObjRef> = match unsafe {&mut re.borrow_mut().kind} {
+                Kind::Pair(pair) => vec![&mut pair.car, &mut pair.cdr],
+                Kind::Vector(vector) => vector.elems.iter_mut().collect(),
+                _ => continue,
+            };
+            for r in refs {
+                if *r == from.re {
+                    *r = to.re;
+                } else {
+                    stack.push(*r);
+                }
+            }
+        }
+    }
+
+    // give a name to an anonymous procedure (used in call stacks)
+    pub fn set_proc_name(&self, name: &str) {
+        unsafe {
+            if let Kind::Procedure(Procedure::Proc(proc)) = &mut self.re.borrow_mut().kind {
+                if proc.name.is_none() {
+                    proc.name = Some(Arc::from(written_name(name)));
+                }
+            }
+        }
+    }
+
     pub fn set_cdr(&self, cdr: Object) -> Result<()> {
         if !self.re.borrow().is_mutable {
             bail!("got immutable object {}", self)
         }
+        Memory::write_obj(&self.re);
         unsafe {
             match &mut self.re.borrow_mut().kind {
                 Kind::Pair(pair) => pair.cdr = cdr.re,
@@ -215,7 +582,21 @@ impl Drop for Object {
 
 impl fmt::Display for Object {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.re.to_string())
+        write!(f, "{}", self.re)
+    }
+}
+
+impl Object {
+    pub fn display_string(&self) -> String {
+        self.re.display_string()
+    }
+
+    pub fn write_shared_string(&self) -> String {
+        self.re.write_shared_string()
+    }
+
+    pub fn write_simple_string(&self) -> String {
+        self.re.write_simple_string()
     }
 }
 
@@ -250,37 +631,53 @@ impl Iterator for SchemeListIter {
  * The entity is env::EnvBody. 
  * The interpreter accesses an environment through this object.
  */
-
 pub struct Environment{
     re: EnvRef,
 }
 
 impl Environment {
-    pub fn new_global(subrs: Vec<(String, bool, usize, fn(VecDeque<Object>) -> Result<Object>)>) -> Self {
+    pub fn new_global(
+        subrs: Vec<(String, bool, usize, SubrFn)>,
+        ctrls: Vec<(String, bool, usize, CtrlFn)>,
+        prims: Vec<(Symbol, bool, usize, SubrFn)>,
+    ) -> Self {
         // lib[i].0: function name in scheme
         // lib[i].1: is variadic function? 
         // lib[i].2: number of required argument
         // lib[i].3: function
-        let mut vars: HashMap<String, ObjRef> = HashMap::new();
-        for (name, is_variadic, require, fun) in subrs {
-            let subr = Object::new_subroutine(is_variadic, require, fun);
-            vars.insert(name, subr.re);
-        }
+        // the environment is made first, so that GC during the setup finds the procedures from it
         let body = EnvBody {
-            vars,
+            vars: HashMap::new(),
+            slots: Vec::new(),
             parent: None,
             mark: Marker::Black,
+            generation: Generation::Young,
             rc: Cell::new(1),
         };
-        let re = Memory::push_env(body);
-        Environment{re}
+        let env = Environment{re: Memory::push_env(body)};
+        for (name, is_variadic, require, fun) in subrs {
+            env.insert(Symbol::intern(&name), Object::new_subroutine(is_variadic, require, fun));
+        }
+        for (name, is_variadic, require, fun) in ctrls {
+            env.insert(Symbol::intern(&name), Object::new_control(is_variadic, require, fun));
+        }
+        for (id, is_variadic, require, fun) in prims {
+            env.insert(id, Object::new_subroutine(is_variadic, require, fun));
+        }
+        env
     }
 
-    pub fn new(parent: Environment) -> Self {
+    // a frame of "size" local variables, the first of which are bound to "vals"
+    pub fn new_frame(parent: &Environment, vals: &[Object], size: usize) -> Self {
+        let mut slots = Vec::with_capacity(size);
+        slots.extend(vals.iter().map(|obj| Some(obj.re)));
+        slots.resize(size, None);
         let body = EnvBody {
             vars: HashMap::new(),
+            slots,
             parent: Some(parent.re),
             mark: Marker::Black,
+            generation: Generation::Young,
             rc: Cell::new(1),
         };
         let re = Memory::push_env(body);
@@ -296,16 +693,48 @@ impl Environment {
         }
     }
 
-    pub fn lookup(&self, id: &String) -> Option<Object> {
+    #[inline]
+    pub(crate) fn raw(&self) -> EnvRef {
+        self.re
+    }
+
+    #[inline]
+    pub(crate) fn from_raw(re: EnvRef) -> Environment {
+        re.borrow().inc_rc();
+        Environment{re}
+    }
+
+    pub fn lookup(&self, id: &Symbol) -> Option<Object> {
         self.re.lookup(id)
     }
 
-    pub fn contains_at(&self, id: &String) -> Option<Environment> {
+    pub fn contains_at(&self, id: &Symbol) -> Option<Environment> {
         self.re.contains_at(id)
     }
 
-    pub fn insert(&self, id: String, obj: Object) {
+    // the local variable "index" of the frame "depth" frames out, if it has been assigned
+    pub fn get(&self, depth: usize, index: usize) -> Option<Object> {
+        let mut re = self.re;
+        for _ in 0..depth {
+            re = re.borrow().parent.unwrap();
+        }
+        re.borrow().slots[index].map(Object::from_raw)
+    }
+
+    pub fn set(&self, depth: usize, index: usize, obj: Object) {
+        let mut re = self.re;
+        for _ in 0..depth {
+            re = re.borrow().parent.unwrap();
+        }
+        unsafe {
+            Memory::write_env(&re);
+            re.borrow_mut().slots[index] = Some(obj.re);
+        }
+    }
+
+    pub fn insert(&self, id: Symbol, obj: Object) {
         unsafe {
+            Memory::write_env(&self.re);
             self.re.borrow_mut().vars.insert(id, obj.re);
         }
     }
@@ -324,3 +753,77 @@ impl Drop for Environment {
         self.re.borrow().dec_rc();
     }
 }
+
+/**
+ * Cont: struct for continuation, i.e. the frames which wait for a value.
+ * The entity is cont::ContBody. 
+ * The interpreter accesses a continuation through this object.
+ */
+pub struct Cont{
+    re: ContRef,
+}
+
+impl Cont {
+    pub fn base() -> Self {
+        let body = ContBody {
+            frame: Frame::Base,
+            parent: None,
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_cont(body);
+        Cont{re}
+    }
+
+    pub(crate) fn push(&self, frame: Frame) -> Self {
+        let body = ContBody {
+            frame,
+            parent: Some(self.re),
+            mark: Marker::Black,
+            generation: Generation::Young,
+            rc: Cell::new(1),
+        };
+        let re = Memory::push_cont(body);
+        Cont{re}
+    }
+
+    #[inline]
+    pub(crate) fn frame(&self) -> &Frame {
+        &self.re.borrow().frame
+    }
+
+    pub fn parent(&self) -> Option<Cont> {
+        if let Some(parent) = self.re.borrow().parent {
+            parent.borrow().inc_rc();
+            Some(Cont{re: parent})
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub(crate) fn raw(&self) -> ContRef {
+        self.re
+    }
+
+    #[inline]
+    pub(crate) fn from_raw(re: ContRef) -> Cont {
+        re.borrow().inc_rc();
+        Cont{re}
+    }
+}
+
+impl Clone for Cont {
+    fn clone(&self) -> Self {
+        let re = self.re;
+        re.borrow().inc_rc();
+        Cont{re}
+    }
+}
+
+impl Drop for Cont {
+    fn drop(&mut self) {
+        self.re.borrow().dec_rc();
+    }
+}
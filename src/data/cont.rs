@@ -0,0 +1,185 @@
+use super::r#ref::{ObjRef, EnvRef, ContRef};
+use super::memory::{Marker, Generation};
+use super::object::NativeFn;
+use crate::token::Token;
+use crate::compile::{Code, App, Quasi, Let, Clause, DoLoop, Guard};
+use crate::vm::Chunk;
+use crate::symbol::Symbol;
+use crate::trace::CallFrame;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+pub(crate) struct ContBody {
+    pub frame: Frame,
+    pub parent: Option<ContRef>,
+    pub mark: Marker,
+    pub generation: Generation,
+    pub rc: Cell<u32>,
+}
+
+/**
+ * Frame: what is left to do with the value of an expression.
+ * A continuation is a chain of frames, the innermost first.
+ * Frames are never modified after they are pushed,
+ * so a captured continuation can be resumed any number of times.
+ */
+pub(crate) enum Frame {
+    // the end of an evaluation started by eval()
+    Base,
+    // a procedure call which has not returned yet (used in call stacks)
+    Call(CallFrame),
+    // (if test then els)
+    If{then: Rc<Code>, els: Option<Rc<Code>>, env: EnvRef},
+    // the rest of a sequence from "next"
+    Seq{codes: Rc<[Rc<Code>]>, next: usize, env: EnvRef},
+    // operator and operands of an application, evaluated from left to right
+    Args{app: Rc<App>, done: Vec<ObjRef>, env: EnvRef},
+    // expressions unquoted in a quasiquote template, evaluated from left to right
+    Quasi{quasi: Rc<Quasi>, done: Vec<ObjRef>, env: EnvRef},
+    // a definition in a slot of "env", or in the global environment
+    Define{index: Option<usize>, id: Symbol, env: EnvRef},
+    Set{addr: Option<(usize, usize)>, id: Symbol, env: EnvRef},
+    // inits of let and named let
+    Let{lt: Rc<Let>, done: Vec<ObjRef>, env: EnvRef},
+    // inits of letrec from "next", evaluated in the frame they are bound in
+    Letrec{lt: Rc<Let>, next: usize, env: EnvRef},
+    // test of a cond clause
+    Cond{clauses: Rc<[Clause]>, next: usize, env: EnvRef},
+    And{tests: Rc<[Rc<Code>]>, next: usize, env: EnvRef},
+    Or{tests: Rc<[Rc<Code>]>, next: usize, env: EnvRef},
+    // stages of do. "env" is where do is evaluated for inits,
+    // and the frame of the current iteration for the others.
+    DoInit{lp: Rc<DoLoop>, done: Vec<ObjRef>, env: EnvRef},
+    DoTest{lp: Rc<DoLoop>, env: EnvRef},
+    DoCmds{lp: Rc<DoLoop>, env: EnvRef},
+    DoStep{lp: Rc<DoLoop>, done: Vec<ObjRef>, env: EnvRef},
+    // dynamic-wind: before, thunk and after thunks are called in this order
+    WindBefore{call: Token, before: ObjRef, thunk: ObjRef, after: ObjRef},
+    WindBody{call: Token, after: ObjRef, winders: ObjRef},
+    WindAfter{value: ObjRef},
+    // calls after/before thunks one by one, then passes "value" to "target".
+    // Each step is a thunk and the winders it is called with.
+    Reroot{call: Token, steps: Vec<(ObjRef, ObjRef)>, target: ContRef, winders: ObjRef, value: ObjRef},
+    // a handler installed by with-exception-handler
+    Handler{handler: ObjRef},
+    // (guard (var clauses ...) body ...), which handles what is raised in body.
+    // "winders" are the dynamic-wind entries active at guard.
+    Guard{guard: Rc<Guard>, env: EnvRef, winders: ObjRef},
+    // a handler is being called; handlers outside "outer" are in effect
+    Raised{outer: ContRef},
+    // the handler of raise (not raise-continuable) must not return
+    NonContinuable{call: Token, obj: ObjRef},
+    // clauses of guard
+    GuardBody{g: Rc<Guarded>},
+    GuardClause{g: Rc<Guarded>, next: usize, env: EnvRef},
+    // raise "obj" again by raise-continuable where it was raised
+    Reraise{call: Token, obj: ObjRef},
+    // a control procedure such as map waiting for the result of a call
+    Native{call: Token, next: NativeFn, state: Vec<ObjRef>},
+    // the VM waiting for the result of a call: the chunk goes on from "pc" with "stack"
+    Vm{chunk: Rc<Chunk>, pc: usize, env: EnvRef, stack: Vec<ObjRef>},
+}
+
+/**
+ * Guarded: a condition caught by guard, with where it was raised.
+ */
+pub struct Guarded {
+    pub guard: Rc<Guard>,
+    pub(crate) env: EnvRef,
+    pub(crate) condition: ObjRef,
+    pub raise_call: Token,
+    pub(crate) raise_k: ContRef,
+    pub(crate) raise_winders: ObjRef,
+}
+
+impl ContBody {
+    #[inline]
+    fn rc(&self) -> u32 {
+        self.rc.get()
+    }
+
+    #[inline]
+    pub fn inc_rc(&self) {
+        let rc = self.rc();
+        self.rc.set(rc + 1);
+    }
+
+    #[inline]
+    pub fn dec_rc(&self) {
+        let rc = self.rc();
+        if rc == 0 {
+            panic!("A ContBody which is already dead has been borrowed.");
+        }
+        self.rc.set(rc - 1);
+    }
+}
+
+impl Frame {
+    // visit objects, environments and continuations which this frame refers to
+    pub(crate) fn refs(&self, obj: &mut dyn FnMut(&ObjRef), env: &mut dyn FnMut(&EnvRef), cont: &mut dyn FnMut(&ContRef)) {
+        match self {
+            Frame::Base | Frame::Call(_) => {}
+            Frame::If{env: e, ..}
+            | Frame::Seq{env: e, ..}
+            | Frame::Define{env: e, ..}
+            | Frame::Set{env: e, ..}
+            | Frame::Letrec{env: e, ..}
+            | Frame::Cond{env: e, ..}
+            | Frame::And{env: e, ..}
+            | Frame::Or{env: e, ..}
+            | Frame::DoTest{env: e, ..}
+            | Frame::DoCmds{env: e, ..} => env(e),
+            Frame::Args{done, env: e, ..}
+            | Frame::Quasi{done, env: e, ..}
+            | Frame::Let{done, env: e, ..}
+            | Frame::DoInit{done, env: e, ..}
+            | Frame::DoStep{done, env: e, ..}
+            | Frame::Vm{stack: done, env: e, ..} => {
+                done.iter().for_each(&mut *obj);
+                env(e);
+            }
+            Frame::WindBefore{before, thunk, after, ..} => {
+                obj(before);
+                obj(thunk);
+                obj(after);
+            }
+            Frame::WindBody{after, winders, ..} => {
+                obj(after);
+                obj(winders);
+            }
+            Frame::WindAfter{value} => obj(value),
+            Frame::Reroot{steps, target, winders, value, ..} => {
+                for (thunk, winders) in steps {
+                    obj(thunk);
+                    obj(winders);
+                }
+                cont(target);
+                obj(winders);
+                obj(value);
+            }
+            Frame::Handler{handler} => obj(handler),
+            Frame::Guard{env: e, winders, ..} => {
+                env(e);
+                obj(winders);
+            }
+            Frame::Raised{outer} => cont(outer),
+            Frame::NonContinuable{obj: o, ..} | Frame::Reraise{obj: o, ..} => obj(o),
+            Frame::GuardBody{g} => g.refs(obj, env, cont),
+            Frame::Native{state, ..} => state.iter().for_each(&mut *obj),
+            Frame::GuardClause{g, env: e, ..} => {
+                g.refs(obj, env, cont);
+                env(e);
+            }
+        }
+    }
+}
+
+impl Guarded {
+    fn refs(&self, obj: &mut dyn FnMut(&ObjRef), env: &mut dyn FnMut(&EnvRef), cont: &mut dyn FnMut(&ContRef)) {
+        env(&self.env);
+        obj(&self.condition);
+        cont(&self.raise_k);
+        obj(&self.raise_winders);
+    }
+}
@@ -1,17 +1,25 @@
 use super::r#ref::{ObjRef, EnvRef};
-use super::memory::Marker;
+use super::memory::{Marker, Generation};
+use crate::symbol::Symbol;
 
 use std::cell::Cell;
 use std::collections::HashMap;
 
 pub(crate) struct EnvBody {
-    pub vars: HashMap<String, ObjRef>,
+    pub vars: HashMap<Symbol, ObjRef>,     // global variables
+    pub slots: Vec<Option<ObjRef>>,        // local variables, which are None until assigned
     pub parent: Option<EnvRef>,
     pub mark: Marker,
+    pub generation: Generation,
     pub rc: Cell<u32>,
 }
 
 impl EnvBody {
+    // the objects bound in this environment
+    pub fn values(&self) -> impl Iterator<Item = &ObjRef> {
+        self.vars.values().chain(self.slots.iter().flatten())
+    }
+
     #[inline]
     fn rc(&self) -> u32 {
         self.rc.get()
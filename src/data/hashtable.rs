@@ -0,0 +1,228 @@
+use super::object::{Kind, Number};
+use super::r#ref::ObjRef;
+use super::Object;
+
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use anyhow::{Result, anyhow};
+
+// how many objects of a key equal-hashing looks at, so that it ends on circular structures
+const EQUAL_HASH_LIMIT: usize = 64;
+
+// how the keys of a hash table are compared
+#[derive(Clone, Copy, PartialEq)]
+pub enum Comparator {
+    Eq,
+    Eqv,
+    Equal,
+    String,     // string=?, whose keys must be strings
+}
+
+/**
+ * HashTable: associations from keys to values.
+ * The entries are kept in a vector with the hashes of their keys,
+ * and the index finds the entries of a hash.
+ */
+pub struct HashTable {
+    pub comparator: Comparator,
+    pub(crate) entries: Vec<(u64, ObjRef, ObjRef)>,     // hash, key, value
+    index: HashMap<u64, Vec<usize>>,
+}
+
+impl HashTable {
+    pub fn new(comparator: Comparator) -> Self {
+        HashTable{comparator, entries: Vec::new(), index: HashMap::new()}
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &Object) -> Result<Option<Object>> {
+        let hash = self.hash(&key.re)?;
+        Ok(self.find(hash, &key.re).map(|k| {
+            let re = self.entries[k].2;
+            re.borrow().inc_rc();
+            Object{re}
+        }))
+    }
+
+    pub fn keys(&self) -> Vec<Object> {
+        self.entries.iter().map(|(_, re, _)| {
+            re.borrow().inc_rc();
+            Object{re: *re}
+        }).collect()
+    }
+
+    pub fn to_vec(&self) -> Vec<(Object, Object)> {
+        self.entries.iter().map(|(_, key, value)| {
+            key.borrow().inc_rc();
+            value.borrow().inc_rc();
+            (Object{re: *key}, Object{re: *value})
+        }).collect()
+    }
+
+    pub(crate) fn insert(&mut self, key: &Object, value: &Object) -> Result<()> {
+        let hash = self.hash(&key.re)?;
+        match self.find(hash, &key.re) {
+            Some(k) => self.entries[k].2 = value.re,
+            None => {
+                self.index.entry(hash).or_default().push(self.entries.len());
+                self.entries.push((hash, key.re, value.re));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, key: &Object) -> Result<()> {
+        let hash = self.hash(&key.re)?;
+        let Some(k) = self.find(hash, &key.re) else {
+            return Ok(())
+        };
+        self.unindex(hash, k);
+        // the last entry is moved to the place of the removed one
+        let last = self.entries.len() - 1;
+        if k != last {
+            let moved = self.entries[last].0;
+            self.unindex(moved, last);
+            self.index.entry(moved).or_default().push(k);
+        }
+        self.entries.swap_remove(k);
+        Ok(())
+    }
+
+    fn find(&self, hash: u64, key: &ObjRef) -> Option<usize> {
+        self.index.get(&hash)?.iter().copied().find(|&k| self.is_same(&self.entries[k].1, key))
+    }
+
+    fn unindex(&mut self, hash: u64, k: usize) {
+        let ks = self.index.get_mut(&hash).unwrap();
+        ks.retain(|&i| i != k);
+        if ks.is_empty() {
+            self.index.remove(&hash);
+        }
+    }
+
+    fn is_same(&self, lhs: &ObjRef, rhs: &ObjRef) -> bool {
+        match self.comparator {
+            Comparator::Eq | Comparator::Eqv => lhs.scm_eq(rhs),
+            Comparator::Equal | Comparator::String => lhs.scm_equal(rhs),
+        }
+    }
+
+    fn hash(&self, key: &ObjRef) -> Result<u64> {
+        let mut state = DefaultHasher::new();
+        match self.comparator {
+            Comparator::Eq | Comparator::Eqv => eqv_hash(key, &mut state),
+            Comparator::Equal => {
+                let mut limit = EQUAL_HASH_LIMIT;
+                equal_hash(key, &mut state, &mut limit);
+            }
+            Comparator::String => match &key.borrow().kind {
+                Kind::String(s) => s.hash(&mut state),
+                _ => return Err(anyhow!("string required, but got {}", key)),
+            }
+        }
+        Ok(state.finish())
+    }
+}
+
+// objects which eqv? compares by value are hashed by value, and the others by address
+fn eqv_hash(re: &ObjRef, state: &mut DefaultHasher) {
+    let kind = &re.borrow().kind;
+    std::mem::discriminant(kind).hash(state);
+    match kind {
+        Kind::Number(Number::Int(i)) => i.hash(state),
+        // as eqv? compares them, so that 0.0 and -0.0 differ and NaN finds itself
+        Kind::Number(Number::Float(f)) => f.to_bits().hash(state),
+        Kind::Number(num) => num.to_string().hash(state),
+        Kind::Boolean(b) => b.hash(state),
+        Kind::Char(c) => c.hash(state),
+        Kind::Symbol(s) => s.hash(state),
+        Kind::Empty | Kind::Eof => {}
+        _ => re.hash(state),
+    }
+}
+
+// hashes the first objects of a key in depth-first order, which are the same for keys equal? to each other
+fn equal_hash(re: &ObjRef, state: &mut DefaultHasher, limit: &mut usize) {
+    if *limit == 0 {
+        return
+    }
+    *limit -= 1;
+    let kind = &re.borrow().kind;
+    match kind {
+        Kind::String(s) => s.hash(state),
+        Kind::Bytevector(bytes) => bytes.hash(state),
+        Kind::Pair(pair) => {
+            std::mem::discriminant(kind).hash(state);
+            equal_hash(&pair.car, state, limit);
+            equal_hash(&pair.cdr, state, limit);
+        }
+        Kind::Vector(vector) => {
+            vector.elems.len().hash(state);
+            vector.elems.iter().for_each(|re| equal_hash(re, state, limit));
+        }
+        _ => eqv_hash(re, state),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::memory::Memory;
+    use crate::symbol::Symbol;
+
+    fn hash(comparator: Comparator, key: &Object) -> u64 {
+        HashTable::new(comparator).hash(&key.re).unwrap()
+    }
+
+    // one test, since all objects are in the one global memory
+    #[test]
+    fn hash_table_test() {
+        Memory::init(1000, 1000, 2.0);
+
+        // numbers are hashed as eqv? compares them
+        let float = |f| Object::new_float(f, true);
+        assert_eq!(hash(Comparator::Eqv, &float(1.5)), hash(Comparator::Eqv, &float(1.5)));
+        assert_eq!(hash(Comparator::Eqv, &float(f64::NAN)), hash(Comparator::Eqv, &float(f64::NAN)));
+        assert_ne!(hash(Comparator::Eqv, &float(0.0)), hash(Comparator::Eqv, &float(-0.0)));
+        let mut table = HashTable::new(Comparator::Eqv);
+        let (nan, zero, value) = (float(f64::NAN), float(0.0), Object::new_int(1, true));
+        table.insert(&nan, &value).unwrap();
+        table.insert(&zero, &value).unwrap();
+        assert!(table.get(&float(f64::NAN)).unwrap().is_some());
+        assert!(table.get(&float(0.0)).unwrap().is_some());
+        assert!(table.get(&float(-0.0)).unwrap().is_none());
+        assert!(table.get(&Object::new_int(0, true)).unwrap().is_none());
+        table.remove(&float(f64::NAN)).unwrap();
+        assert!(table.get(&nan).unwrap().is_none());
+        assert_eq!(table.len(), 1);
+
+        // keys equal? to each other, and circular ones
+        let list = |n| (0..n).rev().fold(Object::new_empty(), |cdr, i| Object::new_pair(Object::new_int(i, true), cdr, true));
+        assert_eq!(hash(Comparator::Equal, &list(3)), hash(Comparator::Equal, &list(3)));
+        let int = |i| Object::new_int(i, true);
+        let circular = || {
+            let last = Object::new_pair(int(2), Object::new_empty(), true);
+            let key = Object::new_pair(int(0), Object::new_pair(int(1), last.clone(), true), true);
+            last.set_cdr(key.clone()).unwrap();
+            key
+        };
+        let mut table = HashTable::new(Comparator::Equal);
+        let (key, list_key, other) = (circular(), list(3), Object::new_symbol(Symbol::intern("other")));
+        table.insert(&key, &value).unwrap();
+        table.insert(&list_key, &other).unwrap();
+        assert_eq!(hash(Comparator::Equal, &key), hash(Comparator::Equal, &circular()));
+        assert!(table.get(&circular()).unwrap().is_some());
+        assert_eq!(table.get(&list(3)).unwrap().unwrap().to_string(), "other");
+        assert!(table.get(&list(4)).unwrap().is_none());
+        assert_eq!(table.len(), 2);
+    }
+}
@@ -1,15 +1,44 @@
 use super::object::{ObjBody, Kind, Procedure};
 use super::env::EnvBody;
-use super::r#ref::{ObjRef, EnvRef};
+use super::cont::{ContBody, Frame};
+use super::r#ref::{ObjRef, EnvRef, ContRef};
 
 use std::cell::Cell;
+use std::collections::HashMap;
+
+// GC is generational. New bodies are allocated in the nursery (young space),
+// which is collected by a minor GC when the heap is full. Bodies which survive it are moved to the old space,
+// which is collected only by a major GC, when the old space grows beyond old_limit.
+// Old bodies can refer to young ones only after they are written, so writes to old bodies go through
+// a write barrier (Memory::write_obj, Memory::write_env), which records them as roots of minor GCs.
+
+// The heap holds at least this many objects, which leaves room for the procedures of the global environment
+// and the other objects made at startup.
+const MIN_HEAP_SIZE: usize = 512;
+
+// Bodies are boxed so that ObjRef/EnvRef stay valid when the vectors reallocate.
+// Boxes of dead bodies are kept and reused, since freeing them was most of the cost of GC;
+// after a major GC, those which the heap can not hold any more are released.
+#[allow(clippy::vec_box)]
+struct Space<T> {
+    old: Vec<Box<T>>,
+    young: Vec<Box<T>>,
+    free: Vec<Box<T>>,  // boxes of dead bodies, reused by alloc
+}
 
 pub(crate) struct Memory {
-    obj_mem: Vec<Box<ObjBody>>,
-    env_mem: Vec<Box<EnvBody>>,
-    obj_size: usize,
-    env_size: usize,
-    max_size: usize,
+    obj_mem: Space<ObjBody>,
+    env_mem: Space<EnvBody>,
+    cont_mem: Space<ContBody>,
+    remembered_objs: Vec<ObjRef>,   // old bodies written since the last GC
+    remembered_envs: Vec<EnvRef>,
+    old_limit: usize,   // a major GC runs when the old space of objects or environments grows beyond this
+    cont_limit: usize,  // frames are not limited by heap_size; a major GC runs when they reach this
+    heap_size: usize,   // the number of objects (and of environments) which fit in the heap now
+    initial_size: usize,
+    max_size: usize,    // the heap does not grow beyond this
+    growth: f64,        // the factor by which the heap grows or shrinks
+    exhausted: bool,    // the heap is full and can not grow
     initialized: bool,
 }
 pub(crate) enum Marker {
@@ -18,177 +47,532 @@ pub(crate) enum Marker {
     White,  // maybe dead
 }
 
+pub(crate) enum Generation {
+    Young,
+    Old,
+    Remembered,     // old, and written since the last GC
+}
+
 pub(crate) static mut MEMORY: Memory = Memory{
-    obj_mem: Vec::new(),
-    env_mem: Vec::new(),
-    obj_size: 0,
-    env_size: 0,
+    obj_mem: Space::new(),
+    env_mem: Space::new(),
+    cont_mem: Space::new(),
+    remembered_objs: Vec::new(),
+    remembered_envs: Vec::new(),
+    old_limit: 0,
+    cont_limit: 0,
+    heap_size: 0,
+    initial_size: 0,
     max_size: 0,
+    growth: 2.0,
+    exhausted: false,
     initialized: false,
 };
 
+#[inline]
+unsafe fn memory() -> &'static mut Memory {
+    &mut *std::ptr::addr_of_mut!(MEMORY)
+}
+
+// what GC needs of ObjBody, EnvBody and ContBody
+trait Collectable {
+    fn header(&mut self) -> (u32, &mut Marker, &mut Generation);
+    // drop what a dead body owns, so that its box can be reused
+    fn clear(&mut self);
+}
+
+impl Collectable for ObjBody {
+    fn header(&mut self) -> (u32, &mut Marker, &mut Generation) {
+        (self.rc.get(), &mut self.mark, &mut self.generation)
+    }
+
+    fn clear(&mut self) {
+        self.kind = Kind::Empty;
+    }
+}
+
+impl Collectable for EnvBody {
+    fn header(&mut self) -> (u32, &mut Marker, &mut Generation) {
+        (self.rc.get(), &mut self.mark, &mut self.generation)
+    }
+
+    fn clear(&mut self) {
+        self.vars = HashMap::new();
+        self.slots = Vec::new();
+        self.parent = None;
+    }
+}
+
+impl Collectable for ContBody {
+    fn header(&mut self) -> (u32, &mut Marker, &mut Generation) {
+        (self.rc.get(), &mut self.mark, &mut self.generation)
+    }
+
+    fn clear(&mut self) {
+        self.frame = Frame::Base;
+        self.parent = None;
+    }
+}
+
+impl<T: Collectable> Space<T> {
+    const fn new() -> Self {
+        Space{old: Vec::new(), young: Vec::new(), free: Vec::new()}
+    }
+
+    // put a new body in the nursery, reusing a dead box if any
+    fn alloc(&mut self, body: T) -> &mut T {
+        let boxed = match self.free.pop() {
+            Some(mut boxed) => {
+                *boxed = body;
+                boxed
+            }
+            None => Box::new(body),
+        };
+        self.young.push(boxed);
+        self.young.last_mut().unwrap()
+    }
+
+    fn len(&self) -> usize {
+        self.old.len() + self.young.len()
+    }
+
+    // the bodies which a GC collects: the nursery, and the old space too in a major GC
+    fn collected(&mut self, major: bool) -> impl Iterator<Item = &mut Box<T>> {
+        let old = if major { &mut self.old[..] } else { &mut [] };
+        old.iter_mut().chain(self.young.iter_mut())
+    }
+
+    // bodies in the Rust stack are roots
+    fn reset_marks(&mut self, major: bool) {
+        for body in self.collected(major) {
+            let (rc, mark, _) = body.header();
+            *mark = if rc > 0 { Marker::Gray } else { Marker::White };
+        }
+    }
+
+    // drop dead bodies and move the living ones in the nursery to the old space
+    fn sweep(&mut self, major: bool) {
+        if major {
+            let mut i = 0;
+            while i < self.old.len() {
+                if let Marker::Black = self.old[i].header().1 {
+                    i += 1;
+                } else {
+                    let body = self.old.swap_remove(i);
+                    self.free(body);
+                }
+            }
+        }
+        let mut young = std::mem::take(&mut self.young);
+        for mut body in young.drain(..) {
+            let (_, mark, generation) = body.header();
+            if let Marker::Black = mark {
+                *generation = Generation::Old;
+                self.old.push(body);
+            } else {
+                self.free(body);
+            }
+        }
+        self.young = young;
+    }
+
+    fn free(&mut self, mut body: Box<T>) {
+        body.clear();
+        self.free.push(body);
+    }
+
+    // release the boxes beyond those which can be allocated before the space holds "size" bodies
+    fn trim(&mut self, size: usize) {
+        self.free.truncate(size.saturating_sub(self.len()));
+        self.free.shrink_to_fit();
+    }
+}
+
 impl Memory {
-    pub fn init(max: usize) {
+    // "size" is the initial size of the heap, which grows by "growth" times up to "max".
+    // Both are raised to MIN_HEAP_SIZE.
+    pub fn init(size: usize, max: usize, growth: f64) {
+        let size = std::cmp::max(size, MIN_HEAP_SIZE);
         unsafe {
-            MEMORY.max_size = max;
-            MEMORY.obj_mem.push(Box::new(ObjBody{
+            memory().heap_size = size;
+            memory().initial_size = size;
+            memory().max_size = std::cmp::max(max, size);
+            memory().growth = growth;
+            memory().old_limit = size / 2;
+            memory().cont_limit = size;
+            memory().obj_mem.old.push(Box::new(ObjBody{
                 is_mutable: false,
                 kind: Kind::Empty,
                 mark: Marker::Black,
+                generation: Generation::Old,
                 rc: Cell::new(1),
             }));
-            MEMORY.initialized = true;
+            memory().initialized = true;
         }
     }
 
     fn ensure_initialized() {
         unsafe {
-            if !MEMORY.initialized {
-                panic!("Memory has not yet initialized. Call crate::data::memory::Memory::init(usize). ");
+            if !memory().initialized {
+                panic!("Memory has not yet initialized. Call crate::data::memory::Memory::init(usize, usize, f64). ");
             }
         }
     }
 
     pub(crate) fn push_obj(obj: ObjBody) -> ObjRef {
         Self::ensure_initialized();
-        let mut obj = Box::new(obj);
-        let re = ObjRef::new(obj.as_mut());
         unsafe {
-            if MEMORY.obj_size >= MEMORY.max_size-1 {
-                Self::gc();
+            if memory().obj_mem.len() >= memory().heap_size-1 && !memory().exhausted {
+                Self::collect();
             }
-            MEMORY.obj_size += 1;
-            MEMORY.obj_mem.push(obj);
+            ObjRef::new(memory().obj_mem.alloc(obj))
         }
-        re
     }
 
     pub(crate) fn push_env(env: EnvBody) -> EnvRef {
         Self::ensure_initialized();
-        let mut env = Box::new(env);
-        let re = EnvRef::new(env.as_mut());
         unsafe {
-            if MEMORY.env_size == MEMORY.max_size-1 {
-                Self::gc();
+            if memory().env_mem.len() >= memory().heap_size-1 && !memory().exhausted {
+                Self::collect();
             }
-            MEMORY.env_size += 1;
-            MEMORY.env_mem.push(env);
+            EnvRef::new(memory().env_mem.alloc(env))
+        }
+    }
+
+    pub(crate) fn push_cont(cont: ContBody) -> ContRef {
+        Self::ensure_initialized();
+        unsafe {
+            if memory().cont_mem.len() >= memory().cont_limit && !memory().exhausted {
+                Self::collect();
+            }
+            ContRef::new(memory().cont_mem.alloc(cont))
         }
-        re
     }
 
     pub(crate) fn get_empty() -> ObjRef {
         Self::ensure_initialized();
         unsafe {
-            let mut empty = &mut MEMORY.obj_mem[0];
+            let empty = &mut memory().obj_mem.old[0];
             empty.inc_rc();
-            ObjRef::new(&mut empty)
+            ObjRef::new(empty)
+        }
+    }
+
+    // write barrier: called before an object is made to refer to another
+    #[inline]
+    pub(crate) fn write_obj(re: &ObjRef) {
+        if let Generation::Old = re.borrow().generation {
+            unsafe {
+                re.borrow_mut().generation = Generation::Remembered;
+                memory().remembered_objs.push(*re);
+            }
+        }
+    }
+
+    // write barrier: called before a variable of an environment is set
+    #[inline]
+    pub(crate) fn write_env(re: &EnvRef) {
+        if let Generation::Old = re.borrow().generation {
+            unsafe {
+                re.borrow_mut().generation = Generation::Remembered;
+                memory().remembered_envs.push(*re);
+            }
+        }
+    }
+
+    // a minor GC, and a major one if the old space has no room for the next nursery
+    fn collect() {
+        Self::run(false);
+        unsafe {
+            let mem = memory();
+            if mem.obj_mem.old.len() > mem.old_limit
+                || mem.env_mem.old.len() > mem.old_limit
+                || mem.cont_mem.old.len() >= mem.cont_limit {
+                Self::run(true);
+                mem.exhausted = Self::resize();
+            }
         }
     }
 
+    // a major GC
     pub fn gc() {
+        Self::run(true);
+        Self::resize();
+    }
+
+    // Whether the heap has been exhausted since the last call.
+    // Allocation never fails; instead the evaluator checks this and reports an error.
+    pub(crate) fn take_exhausted() -> bool {
+        unsafe {std::mem::take(&mut memory().exhausted)}
+    }
+
+    pub(crate) fn max_size() -> usize {
+        unsafe {memory().max_size}
+    }
+
+    // Grow the heap when it is more than half full after a major GC, and shrink it when less than a quarter.
+    // If it is still full, returns true and leaves a reserve so that the error can be handled.
+    fn resize() -> bool {
+        unsafe {
+            let mem = memory();
+            let live = std::cmp::max(mem.obj_mem.len(), mem.env_mem.len());
+            let mut size = mem.heap_size;
+            if live * 2 > size {
+                size = std::cmp::max((size as f64 * mem.growth) as usize, size + 1);
+            } else if live * 4 < size {
+                size = std::cmp::max((size as f64 / mem.growth) as usize, mem.initial_size);
+            }
+            size = std::cmp::min(size, mem.max_size);
+            let full = live + 1 >= size;
+            if full {
+                size = live + std::cmp::max(mem.max_size / 8, 16);
+            }
+            mem.heap_size = size;
+            // deep recursion keeps many frames alive; leave room so that GC does not run on every push
+            mem.cont_limit = std::cmp::max(size, mem.cont_mem.len() * 2);
+            // the heap shrinks when the free boxes are more than it can hold
+            mem.obj_mem.trim(size);
+            mem.env_mem.trim(size);
+            mem.cont_mem.trim(mem.cont_limit);
+            // leave half of the free space, and at least a quarter of the heap, to the nursery
+            mem.old_limit = std::cmp::min((live + size) / 2, size / 4 * 3);
+            full
+        }
+    }
+
+    fn run(major: bool) {
         Memory::ensure_initialized();
-        Self::mark();
-        Self::sweep();
+        Self::mark(major);
+        super::forget_dead_symbols();
+        // forget remembered bodies before sweep, since some of them may be dead
         unsafe {
-            MEMORY.obj_size = MEMORY.obj_mem.len();
-            MEMORY.env_size = MEMORY.env_mem.len();
-            if MEMORY.obj_size >= MEMORY.max_size {
-                panic!("Memory overflow")
+            for re in memory().remembered_objs.drain(..) {
+                re.borrow_mut().generation = Generation::Old;
+            }
+            for re in memory().remembered_envs.drain(..) {
+                re.borrow_mut().generation = Generation::Old;
             }
         }
+        Self::sweep(major);
     }
-    
-    fn mark() {
+
+    // Old bodies are black outside GC, so marking stops at them in a minor GC.
+    fn mark(major: bool) {
+        let mut work = Worklist::default();
         unsafe {
-            // mark obj, env in stack
-            for obj in &mut MEMORY.obj_mem {
-                if obj.rc.get() > 0 {
-                    obj.mark = Marker::Gray;
-                } else {
-                    obj.mark = Marker::White;
+            memory().obj_mem.reset_marks(major);
+            memory().env_mem.reset_marks(major);
+            memory().cont_mem.reset_marks(major);
+
+            // old bodies which have been written may refer to young ones
+            if !major {
+                for re in &memory().remembered_objs {
+                    work.trace_obj(re.borrow());
                 }
-            }
-            for env in &mut MEMORY.env_mem {
-                if env.rc.get() > 0 {
-                    env.mark = Marker::Gray;
-                } else {
-                    env.mark = Marker::White;
+                for re in &memory().remembered_envs {
+                    re.borrow().values().for_each(|obj| work.obj(obj));
                 }
             }
-    
-            // mark obj, env which can be reached from obj, env in stack
-            for obj in &mut MEMORY.obj_mem {
+
+            // bodies in stack
+            for obj in memory().obj_mem.collected(major) {
                 if let Marker::Gray = obj.mark {
-                    obj.mark = Marker::Black;
-                    match &obj.kind {
-                        Kind::Pair(pair) => {
-                            Self::mark_obj(&pair.car);
-                            Self::mark_obj(&pair.cdr);
-                        }
-                        Kind::Procedure(proc) => {
-                            if let Procedure::Proc(proc) = proc {
-                                Self::mark_env(&proc.env);
-                            }
-                        }
-                        _ => {}
-                    }
+                    work.obj(&ObjRef::new(obj));
                 }
             }
-    
-            for env in &mut MEMORY.env_mem {
+            for env in memory().env_mem.collected(major) {
                 if let Marker::Gray = env.mark {
-                    env.mark = Marker::Black;
-                    for obj in env.vars.values() {
-                        Self::mark_obj(obj);
-                    }
-                    if let Some(parent) = &env.parent {
-                        Self::mark_env(parent);
-                    }
+                    work.env(&EnvRef::new(env));
+                }
+            }
+            for cont in memory().cont_mem.collected(major) {
+                if let Marker::Gray = cont.mark {
+                    work.cont(&ContRef::new(cont));
                 }
             }
         }
+        work.drain();
+    }
+
+    fn sweep(major: bool) {
+        unsafe {
+            memory().obj_mem.sweep(major);
+            memory().env_mem.sweep(major);
+            memory().cont_mem.sweep(major);
+        }
+    }
+}
+
+// The bodies which have been marked, but whose references have not.
+// Marking takes them from here instead of recursing, since lists, vectors and environments can be very deep.
+#[derive(Default)]
+struct Worklist {
+    objs: Vec<ObjRef>,
+    envs: Vec<EnvRef>,
+    conts: Vec<ContRef>,
+}
+
+impl Worklist {
+    fn obj(&mut self, obj: &ObjRef) {
+        Self::push(&mut self.objs, obj, |re| unsafe {&mut re.borrow_mut().mark});
+    }
+
+    fn env(&mut self, env: &EnvRef) {
+        Self::push(&mut self.envs, env, |re| unsafe {&mut re.borrow_mut().mark});
     }
-    
-    fn mark_obj(obj: &ObjRef) {
-        if let Marker::Black = &obj.borrow().mark {
+
+    fn cont(&mut self, cont: &ContRef) {
+        Self::push(&mut self.conts, cont, |re| unsafe {&mut re.borrow_mut().mark});
+    }
+
+    // mark "re" black, and keep it to trace unless it was black already
+    fn push<R: Copy>(list: &mut Vec<R>, re: &R, mark: fn(&R) -> &mut Marker) {
+        let mark = mark(re);
+        if let Marker::Black = mark {
             return
         }
-    
-        unsafe {obj.borrow_mut().mark = Marker::Black};
-        match &obj.borrow().kind {
+        *mark = Marker::Black;
+        list.push(*re);
+    }
+
+    // mark everything which can be reached from the bodies kept
+    fn drain(&mut self) {
+        loop {
+            if let Some(obj) = self.objs.pop() {
+                self.trace_obj(obj.borrow());
+            } else if let Some(env) = self.envs.pop() {
+                let env = env.borrow();
+                env.values().for_each(|obj| self.obj(obj));
+                if let Some(parent) = &env.parent {
+                    self.env(parent);
+                }
+            } else if let Some(cont) = self.conts.pop() {
+                let cont = cont.borrow();
+                let Worklist{objs, envs, conts} = self;
+                cont.frame.refs(
+                    &mut |obj| Self::push(objs, obj, |re| unsafe {&mut re.borrow_mut().mark}),
+                    &mut |env| Self::push(envs, env, |re| unsafe {&mut re.borrow_mut().mark}),
+                    &mut |cont| Self::push(conts, cont, |re| unsafe {&mut re.borrow_mut().mark}),
+                );
+                if let Some(parent) = &cont.parent {
+                    self.cont(parent);
+                }
+            } else {
+                break
+            }
+        }
+    }
+
+    // keep what "obj" refers to
+    fn trace_obj(&mut self, obj: &ObjBody) {
+        match &obj.kind {
             Kind::Pair(pair) => {
-                Self::mark_obj(&pair.car);
-                Self::mark_obj(&pair.cdr);
+                self.obj(&pair.car);
+                self.obj(&pair.cdr);
+            }
+            Kind::Vector(vector) | Kind::Values(vector) => {
+                vector.elems.iter().for_each(|obj| self.obj(obj));
+            }
+            Kind::Record(record) => {
+                record.fields.iter().for_each(|obj| self.obj(obj));
             }
-            Kind::Procedure(proc) => {
-                if let Procedure::Proc(proc) = proc {
-                    Self::mark_env(&proc.env);
+            Kind::HashTable(table) => {
+                for (_, key, value) in &table.entries {
+                    self.obj(key);
+                    self.obj(value);
                 }
             }
+            Kind::Procedure(Procedure::Proc(proc)) => {
+                self.env(&proc.env);
+            }
+            Kind::Procedure(Procedure::Cont(cont)) => {
+                self.cont(&cont.k);
+                self.obj(&cont.winders);
+            }
+            Kind::Error(err) => {
+                self.obj(&err.irritants);
+            }
             _ => {}
         }
     }
-    
-    fn mark_env(env: &EnvRef) {
-        if let Marker::Black = &env.borrow().mark {
-            return;
-        }
-    
-        unsafe {env.borrow_mut().mark = Marker::Black};
-        for obj in env.borrow().vars.values() {
-            Self::mark_obj(obj);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Body {
+        rc: u32,
+        mark: Marker,
+        generation: Generation,
+        data: Option<String>,
+    }
+
+    impl Collectable for Body {
+        fn header(&mut self) -> (u32, &mut Marker, &mut Generation) {
+            (self.rc, &mut self.mark, &mut self.generation)
         }
 
-        if let Some(parent) = &env.borrow().parent {
-            Self::mark_env(parent);
+        fn clear(&mut self) {
+            self.data = None;
         }
     }
-    
-    fn sweep() {
-        unsafe {
-            MEMORY.obj_mem.retain(|obj| matches!(obj.mark, Marker::Black));
-            MEMORY.env_mem.retain(|env| matches!(env.mark, Marker::Black));
+
+    fn body(rc: u32, data: &str) -> Body {
+        Body{rc, mark: Marker::Black, generation: Generation::Young, data: Some(data.to_string())}
+    }
+
+    // a GC of "space", where the bodies held from the Rust stack are the only living ones
+    fn gc(space: &mut Space<Body>, major: bool) {
+        space.reset_marks(major);
+        for body in space.collected(major) {
+            if let Marker::Gray = body.mark {
+                body.mark = Marker::Black;
+            }
         }
+        space.sweep(major);
+    }
+
+    #[test]
+    fn free_list() {
+        let mut space = Space::new();
+        space.alloc(body(1, "a"));
+        space.alloc(body(0, "b"));
+        space.alloc(body(1, "c"));
+
+        // the survivors of a minor GC are old, and the box of the dead one is kept cleared
+        gc(&mut space, false);
+        assert_eq!((space.old.len(), space.young.len(), space.free.len()), (2, 0, 1));
+        assert!(space.old.iter().all(|body| matches!(body.generation, Generation::Old)));
+        assert!(space.free[0].data.is_none());
+
+        // a new body reuses the box
+        let reused: *const Body = &*space.free[0];
+        let new: *const Body = space.alloc(body(0, "d"));
+        assert_eq!(new, reused);
+        assert!(space.free.is_empty());
+        assert_eq!(space.young[0].data.as_deref(), Some("d"));
+
+        // a minor GC leaves the old space alone, and a major one collects it too
+        space.old[0].rc = 0;
+        gc(&mut space, false);
+        assert_eq!((space.len(), space.free.len()), (2, 1));
+        gc(&mut space, true);
+        assert_eq!((space.len(), space.free.len()), (1, 2));
+        assert_eq!(space.old[0].data.as_deref(), Some("c"));
+        space.alloc(body(1, "e"));
+        space.alloc(body(1, "f"));
+        space.alloc(body(1, "g"));
+        assert_eq!((space.len(), space.free.len()), (4, 0));
+
+        // boxes which do not fit in a smaller heap are released
+        space.old.iter_mut().chain(space.young.iter_mut()).for_each(|body| body.rc = 0);
+        gc(&mut space, true);
+        assert_eq!((space.len(), space.free.len()), (0, 4));
+        space.trim(6);
+        assert_eq!(space.free.len(), 4);
+        space.alloc(body(1, "h"));
+        space.trim(3);
+        assert_eq!((space.len(), space.free.len()), (1, 2));
     }
 }
@@ -0,0 +1,479 @@
+use super::object::Number;
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use anyhow::{Result, bail};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_integer::Integer;
+use num_traits::{ToPrimitive, Signed, One, Zero};
+
+// Exact numbers are kept in their simplest form:
+// an integer in the range of i64 is Int, and a rational with the denominator 1 is an integer.
+impl Number {
+    pub fn from_bigint(i: BigInt) -> Number {
+        match i.to_i64() {
+            Some(i) => Number::Int(i),
+            None => Number::BigInt(i),
+        }
+    }
+
+    pub fn from_ratio(r: BigRational) -> Number {
+        if r.is_integer() {
+            Number::from_bigint(r.to_integer())
+        } else {
+            Number::Rational(r)
+        }
+    }
+
+    pub fn is_exact(&self) -> bool {
+        !matches!(self, Number::Float(_))
+    }
+
+    // eqv?: inexact numbers are the same if they have the same bits, so that 0.0 and -0.0 differ and NaN is itself
+    pub fn is_eqv(&self, other: &Number) -> bool {
+        match (self, other) {
+            (Number::Float(lhs), Number::Float(rhs)) => lhs.to_bits() == rhs.to_bits(),
+            (Number::Float(_), _) | (_, Number::Float(_)) => false,
+            _ => self == other,
+        }
+    }
+
+    pub fn is_exact_integer(&self) -> bool {
+        matches!(self, Number::Int(_) | Number::BigInt(_))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        match self {
+            Number::Int(i) => *i == 0,
+            Number::Float(f) => *f == 0.0,
+            _ => false,
+        }
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        match self {
+            Number::Int(i) => *i as f64,
+            Number::BigInt(i) => i.to_f64().unwrap_or(f64::NAN),
+            Number::Rational(r) => r.to_f64().unwrap_or(f64::NAN),
+            Number::Float(f) => *f,
+        }
+    }
+
+    fn to_bigint(&self) -> Option<BigInt> {
+        match self {
+            Number::Int(i) => Some(BigInt::from(*i)),
+            Number::BigInt(i) => Some(i.clone()),
+            _ => None,
+        }
+    }
+
+    fn to_ratio(&self) -> Option<BigRational> {
+        match self {
+            Number::Rational(r) => Some(r.clone()),
+            Number::Float(_) => None,
+            _ => self.to_bigint().map(BigRational::from_integer),
+        }
+    }
+
+    // an operation on exact numbers, done on integers if possible
+    fn exact_op(&self, rhs: &Number, int: fn(BigInt, BigInt) -> BigInt, ratio: fn(BigRational, BigRational) -> BigRational) -> Number {
+        match (self.to_bigint(), rhs.to_bigint()) {
+            (Some(lhs), Some(rhs)) => Number::from_bigint(int(lhs, rhs)),
+            _ => Number::from_ratio(ratio(self.to_ratio().unwrap(), rhs.to_ratio().unwrap())),
+        }
+    }
+
+    pub fn add(&self, rhs: &Number) -> Number {
+        match (self, rhs) {
+            (Number::Int(lhs), Number::Int(rhs)) if lhs.checked_add(*rhs).is_some() => Number::Int(lhs + rhs),
+            (Number::Float(_), _) | (_, Number::Float(_)) => Number::Float(self.to_f64() + rhs.to_f64()),
+            _ => self.exact_op(rhs, |a, b| a + b, |a, b| a + b),
+        }
+    }
+
+    pub fn sub(&self, rhs: &Number) -> Number {
+        match (self, rhs) {
+            (Number::Int(lhs), Number::Int(rhs)) if lhs.checked_sub(*rhs).is_some() => Number::Int(lhs - rhs),
+            (Number::Float(_), _) | (_, Number::Float(_)) => Number::Float(self.to_f64() - rhs.to_f64()),
+            _ => self.exact_op(rhs, |a, b| a - b, |a, b| a - b),
+        }
+    }
+
+    pub fn mul(&self, rhs: &Number) -> Number {
+        match (self, rhs) {
+            (Number::Int(lhs), Number::Int(rhs)) if lhs.checked_mul(*rhs).is_some() => Number::Int(lhs * rhs),
+            (Number::Float(_), _) | (_, Number::Float(_)) => Number::Float(self.to_f64() * rhs.to_f64()),
+            _ => self.exact_op(rhs, |a, b| a * b, |a, b| a * b),
+        }
+    }
+
+    // a quotient of exact numbers is exact: (/ 1 3) is 1/3
+    pub fn div(&self, rhs: &Number) -> Result<Number> {
+        match (self, rhs) {
+            (Number::Float(_), _) | (_, Number::Float(_)) => Ok(Number::Float(self.to_f64() / rhs.to_f64())),
+            _ if rhs.is_zero() => bail!("zero division error"),
+            _ => Ok(Number::from_ratio(self.to_ratio().unwrap() / rhs.to_ratio().unwrap())),
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        match self {
+            Number::Float(f) => f.is_finite() && f.fract() == 0.0,
+            Number::Rational(_) => false,
+            _ => true,
+        }
+    }
+
+    pub fn is_negative(&self) -> bool {
+        match self {
+            Number::Int(i) => *i < 0,
+            Number::BigInt(i) => i.is_negative(),
+            Number::Rational(r) => r.is_negative(),
+            Number::Float(f) => *f < 0.0,
+        }
+    }
+
+    // the value of an integer (exact or inexact) as BigInt
+    pub fn integer_value(&self) -> Option<BigInt> {
+        match self {
+            Number::Float(f) if self.is_integer() => BigRational::from_float(*f).map(|r| r.to_integer()),
+            _ => self.to_bigint(),
+        }
+    }
+
+    pub fn abs(&self) -> Number {
+        match self {
+            Number::Float(f) => Number::Float(f.abs()),
+            _ if self.is_negative() => Number::Int(0).sub(self),
+            _ => self.clone(),
+        }
+    }
+
+    fn round_by(&self, float: fn(f64) -> f64, ratio: fn(&BigRational) -> BigRational) -> Number {
+        match self {
+            Number::Float(f) => Number::Float(float(*f)),
+            Number::Rational(r) => Number::from_ratio(ratio(r)),
+            _ => self.clone(),
+        }
+    }
+
+    pub fn floor(&self) -> Number {
+        self.round_by(f64::floor, BigRational::floor)
+    }
+
+    pub fn ceiling(&self) -> Number {
+        self.round_by(f64::ceil, BigRational::ceil)
+    }
+
+    pub fn truncate(&self) -> Number {
+        self.round_by(f64::trunc, BigRational::trunc)
+    }
+
+    // to the nearest integer, and to the even one if it is just between two integers
+    pub fn round(&self) -> Number {
+        self.round_by(f64::round_ties_even, |r| {
+            let floor = r.floor();
+            let half = BigRational::new(1.into(), 2.into());
+            match (r - &floor).cmp(&half) {
+                Ordering::Less => floor,
+                Ordering::Greater => floor + BigRational::one(),
+                Ordering::Equal if floor.to_integer().is_even() => floor,
+                Ordering::Equal => floor + BigRational::one(),
+            }
+        })
+    }
+
+    // the exact root of an exact number if it has one
+    pub fn sqrt(&self) -> Result<Number> {
+        if self.is_negative() {
+            bail!("complex numbers are not supported: (sqrt {})", self);
+        }
+        if let Some(r) = self.to_ratio() {
+            let (numer, denom) = (r.numer().sqrt(), r.denom().sqrt());
+            if &(&numer * &numer) == r.numer() && &(&denom * &denom) == r.denom() {
+                return Ok(Number::from_ratio(BigRational::new(numer, denom)));
+            }
+        }
+        Ok(Number::Float(self.to_f64().sqrt()))
+    }
+
+    pub fn exact(&self) -> Result<Number> {
+        match self {
+            Number::Float(f) => match BigRational::from_float(*f) {
+                Some(r) => Ok(Number::from_ratio(r)),
+                None => bail!("exact number cannot be made from {}", self),
+            }
+            _ => Ok(self.clone()),
+        }
+    }
+
+    pub fn inexact(&self) -> Number {
+        Number::Float(self.to_f64())
+    }
+
+    pub fn numerator(&self) -> Result<Number> {
+        match self {
+            Number::Rational(r) => Ok(Number::from_bigint(r.numer().clone())),
+            Number::Float(_) => Ok(self.exact()?.numerator()?.inexact()),
+            _ => Ok(self.clone()),
+        }
+    }
+
+    pub fn denominator(&self) -> Result<Number> {
+        match self {
+            Number::Rational(r) => Ok(Number::from_bigint(r.denom().clone())),
+            Number::Float(_) => Ok(self.exact()?.denominator()?.inexact()),
+            _ => Ok(Number::Int(1)),
+        }
+    }
+}
+
+// reading numbers: [#x #b #o #d] [#e #i] (integer | integer/integer | decimal | +inf.0 | -inf.0 | +nan.0)
+impl Number {
+    pub fn parse(text: &str, radix: u32) -> Option<Number> {
+        let (mut radix_prefix, mut exact) = (None, None);
+        let mut rest = text;
+        while let Some(prefix) = rest.strip_prefix('#') {
+            match prefix.bytes().next()?.to_ascii_lowercase() {
+                b'x' if radix_prefix.is_none() => radix_prefix = Some(16),
+                b'd' if radix_prefix.is_none() => radix_prefix = Some(10),
+                b'o' if radix_prefix.is_none() => radix_prefix = Some(8),
+                b'b' if radix_prefix.is_none() => radix_prefix = Some(2),
+                b'e' if exact.is_none() => exact = Some(true),
+                b'i' if exact.is_none() => exact = Some(false),
+                _ => return None,
+            }
+            rest = &prefix[1..];
+        }
+        let num = Number::parse_real(rest, radix_prefix.unwrap_or(radix), exact == Some(true))?;
+        match exact {
+            Some(true) => num.exact().ok(),
+            Some(false) => Some(num.inexact()),
+            None => Some(num),
+        }
+    }
+
+    fn parse_real(text: &str, radix: u32, exact: bool) -> Option<Number> {
+        match text.to_ascii_lowercase().as_str() {
+            "+inf.0" => return Some(Number::Float(f64::INFINITY)),
+            "-inf.0" => return Some(Number::Float(f64::NEG_INFINITY)),
+            "+nan.0" | "-nan.0" => return Some(Number::Float(f64::NAN)),
+            _ => (),
+        }
+        let (negative, body) = match text.as_bytes().first()? {
+            b'+' => (false, &text[1..]),
+            b'-' => (true, &text[1..]),
+            _ => (false, text),
+        };
+        let uinteger = |s: &str| match !s.is_empty() && s.chars().all(|c| c.is_digit(radix)) {
+            true => BigInt::parse_bytes(s.as_bytes(), radix),
+            false => None,
+        };
+        let num = if let Some((numer, denom)) = body.split_once('/') {
+            let (numer, denom) = (uinteger(numer)?, uinteger(denom)?);
+            if denom.is_zero() {
+                return None;
+            }
+            Number::from_ratio(BigRational::new(numer, denom))
+        } else if let Some(i) = uinteger(body) {
+            Number::from_bigint(i)
+        } else if radix == 10 {
+            Number::parse_decimal(body, exact)?
+        } else {
+            return None;
+        };
+        match (negative, num) {
+            (true, Number::Float(f)) => Some(Number::Float(-f)),
+            (true, num) => Some(Number::Int(0).sub(&num)),
+            (false, num) => Some(num),
+        }
+    }
+
+    // digits with a point and/or an exponent: 1.5, .5, 1., 1e10, 1.5e-3
+    fn parse_decimal(text: &str, exact: bool) -> Option<Number> {
+        let (mantissa, exponent) = match text.find(['e', 'E']) {
+            Some(i) => (&text[..i], Some(&text[i + 1..])),
+            None => (text, None),
+        };
+        let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if int.len() + frac.len() == 0 || !is_digits(int) || !is_digits(frac) {
+            return None;
+        }
+        let exponent: i32 = match exponent {
+            Some(e) => {
+                let digits = e.strip_prefix(['+', '-']).unwrap_or(e);
+                if digits.is_empty() || !is_digits(digits) {
+                    return None;
+                }
+                e.parse().ok()?
+            }
+            None => 0,
+        };
+        if !exact {
+            return text.parse().ok().map(Number::Float);
+        }
+        // #e1.5 is exactly 3/2
+        let digits: BigInt = format!("{}{}", int, frac).parse().ok()?;
+        let scale = exponent - frac.len() as i32;
+        let power = BigInt::from(10).pow(scale.unsigned_abs());
+        let r = match scale >= 0 {
+            true => BigRational::from_integer(digits * power),
+            false => BigRational::new(digits, power),
+        };
+        Some(Number::from_ratio(r))
+    }
+}
+
+impl PartialEq for Number {
+    fn eq(&self, other: &Number) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+// An exact number and a float are compared exactly, since a finite float is a rational.
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Number) -> Option<Ordering> {
+        match (self, other) {
+            (Number::Int(lhs), Number::Int(rhs)) => lhs.partial_cmp(rhs),
+            (Number::Float(lhs), Number::Float(rhs)) => lhs.partial_cmp(rhs),
+            (Number::Float(lhs), _) => compare_float(*lhs, other),
+            (_, Number::Float(rhs)) => compare_float(*rhs, self).map(Ordering::reverse),
+            _ => self.to_ratio().unwrap().partial_cmp(&other.to_ratio().unwrap()),
+        }
+    }
+}
+
+// how "f" is ordered against the exact number "exact"
+fn compare_float(f: f64, exact: &Number) -> Option<Ordering> {
+    // integers up to 2^53 are floats without rounding
+    const EXACT_LIMIT: i64 = 1 << 53;
+    match exact {
+        _ if f.is_nan() => None,
+        _ if f.is_infinite() => Some(if f > 0.0 { Ordering::Greater } else { Ordering::Less }),
+        Number::Int(i) if (-EXACT_LIMIT..=EXACT_LIMIT).contains(i) => f.partial_cmp(&(*i as f64)),
+        _ => BigRational::from_float(f).unwrap().partial_cmp(&exact.to_ratio().unwrap()),
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Int(i) => write!(f, "{}", i),
+            Number::BigInt(i) => write!(f, "{}", i),
+            Number::Rational(r) => write!(f, "{}", r),
+            Number::Float(fl) if fl.is_nan() => write!(f, "+nan.0"),
+            Number::Float(fl) if fl.is_infinite() => write!(f, "{}inf.0", if fl.is_sign_positive() { "+" } else { "-" }),
+            // an inexact integer keeps its point: 1.0
+            Number::Float(fl) if fl.fract() == 0.0 && fl.abs() < 1e16 => write!(f, "{:.1}", fl),
+            Number::Float(fl) if fl.abs() >= 1e16 => write!(f, "{:e}", fl),
+            Number::Float(fl) => write!(f, "{}", fl),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(text: &str) -> Number {
+        Number::parse(text, 10).unwrap()
+    }
+
+    #[test]
+    fn bignum_promotion() {
+        let max = Number::Int(i64::MAX);
+        let big = max.add(&Number::Int(1));
+        assert!(matches!(big, Number::BigInt(_)));
+        assert_eq!(big.to_string(), "9223372036854775808");
+        assert!(matches!(big.sub(&Number::Int(1)), Number::Int(i64::MAX)));
+        assert!(matches!(Number::Int(i64::MIN).sub(&Number::Int(1)), Number::BigInt(_)));
+        let square = Number::Int(1 << 40).mul(&Number::Int(1 << 40));
+        assert_eq!(square.to_string(), "1208925819614629174706176");
+        assert!(matches!(square.div(&Number::Int(1 << 40)).unwrap(), Number::Int(1099511627776)));
+        assert!(matches!(num("123456789012345678901234567890"), Number::BigInt(_)));
+        assert!(matches!(num("-9223372036854775808"), Number::Int(i64::MIN)));
+    }
+
+    #[test]
+    fn rational_normalisation() {
+        let third = Number::Int(1).div(&Number::Int(3)).unwrap();
+        assert!(matches!(third, Number::Rational(_)));
+        assert_eq!(third.to_string(), "1/3");
+        assert_eq!(Number::Int(6).div(&Number::Int(-4)).unwrap().to_string(), "-3/2");
+        assert_eq!(num("2/4").to_string(), "1/2");
+        assert!(matches!(num("4/2"), Number::Int(2)));
+        assert!(matches!(third.add(&num("2/3")), Number::Int(1)));
+        assert!(matches!(third.mul(&Number::Int(3)), Number::Int(1)));
+        assert_eq!(num("1/2").sub(&num("1/3")).to_string(), "1/6");
+        assert_eq!(num("1/2").numerator().unwrap().to_string(), "1");
+        assert_eq!(num("1/2").denominator().unwrap().to_string(), "2");
+        assert!(num("1/2") < num("2/3"));
+        assert!(Number::Int(1).div(&Number::Int(0)).is_err());
+    }
+
+    #[test]
+    fn exactness() {
+        let half = num("1/2");
+        assert!(matches!(half.add(&Number::Float(0.5)), Number::Float(f) if f == 1.0));
+        assert!(matches!(Number::Int(1).mul(&Number::Float(2.0)), Number::Float(f) if f == 2.0));
+        assert!(matches!(Number::Float(1.0).div(&Number::Int(0)).unwrap(), Number::Float(f) if f == f64::INFINITY));
+        assert_eq!(Number::Float(0.25).exact().unwrap().to_string(), "1/4");
+        assert!(Number::Float(f64::NAN).exact().is_err());
+        assert_eq!(half.inexact().to_string(), "0.5");
+        assert_eq!(num("#e1.5").to_string(), "3/2");
+        assert_eq!(num("#i3/4").to_string(), "0.75");
+        assert_eq!(Number::Float(3.0).to_string(), "3.0");
+        // equal by =, but not by eqv?
+        assert!(Number::Int(1) == Number::Float(1.0));
+        assert!(!Number::Int(1).is_eqv(&Number::Float(1.0)));
+        assert!(!Number::Float(0.0).is_eqv(&Number::Float(-0.0)));
+        assert!(Number::Float(f64::NAN).is_eqv(&Number::Float(f64::NAN)));
+    }
+
+    #[test]
+    fn rounding() {
+        let show = |n: Number| n.to_string();
+        assert_eq!(show(num("-7/2").floor()), "-4");
+        assert_eq!(show(num("-7/2").ceiling()), "-3");
+        assert_eq!(show(num("-7/2").truncate()), "-3");
+        assert_eq!(show(num("5/2").round()), "2");
+        assert_eq!(show(num("7/2").round()), "4");
+        assert_eq!(show(num("-2.5").round()), "-2.0");
+        assert_eq!(show(num("2.7").floor()), "2.0");
+        assert_eq!(show(num("-1/2").abs()), "1/2");
+        assert_eq!(show(Number::Int(i64::MIN).abs()), "9223372036854775808");
+        assert_eq!(show(num("9/4").sqrt().unwrap()), "3/2");
+        assert_eq!(show(num("2").sqrt().unwrap()), "1.4142135623730951");
+        assert!(num("-4").sqrt().is_err());
+        assert_eq!(show(num("0.5").numerator().unwrap()), "1.0");
+        assert_eq!(show(num("0.5").denominator().unwrap()), "2.0");
+        assert!(num("2.0").is_integer() && !num("1/2").is_integer() && !num("+inf.0").is_integer());
+        assert_eq!(num("3.0").integer_value().unwrap().to_string(), "3");
+    }
+
+    #[test]
+    fn mixed_comparison() {
+        // 2^53 + 1 is not a float, so it equals neither 2^53 nor 2^53 + 2 as floats
+        let (below, above) = (Number::Float(9007199254740992.0), Number::Float(9007199254740994.0));
+        let odd = num("9007199254740993");
+        assert!(odd != below && odd > below && odd < above);
+        assert!(below < odd && above > odd);
+        assert!(num("9007199254740992") == below);
+        assert!(Number::Int(i64::MAX) != Number::Float(i64::MAX as f64));
+        assert!(Number::Int(i64::MAX) < Number::Float(i64::MAX as f64));
+        // bignums beyond the range of f64 are finite
+        let big = Number::Int(10).mul(&num("1e300").exact().unwrap()).mul(&num("1e100").exact().unwrap());
+        assert!(matches!(big, Number::BigInt(_)));
+        assert!(big != Number::Float(f64::INFINITY) && big < Number::Float(f64::INFINITY));
+        assert!(big.sub(&big).sub(&big) > Number::Float(f64::NEG_INFINITY));
+        assert!(big > Number::Float(f64::MAX));
+        assert!(num("1/3") != Number::Float(1.0 / 3.0));
+        assert!(num("1/2") == Number::Float(0.5));
+        assert_eq!(big.partial_cmp(&Number::Float(f64::NAN)), None);
+        assert_eq!(Number::Float(f64::NAN).partial_cmp(&Number::Int(1)), None);
+    }
+}
@@ -1,33 +1,56 @@
-use super::r#ref::{ObjRef, EnvRef};
-use super::memory::Marker;
-use super::{Object, Environment};
-use crate::token::Token;
+use super::r#ref::{ObjRef, EnvRef, ContRef};
+use super::memory::{Marker, Generation};
+use super::{Object, Environment, Cont};
+use super::port::Port;
+use super::hashtable::HashTable;
+use crate::symbol::Symbol;
+use crate::expand::Macro;
+use crate::compile::Lambda;
 
 use std::collections::VecDeque;
 use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::Arc;
 
 use anyhow::{Result};
+use num_bigint::BigInt;
+use num_rational::BigRational;
 
 pub(crate) struct ObjBody {
     pub is_mutable: bool,
     pub kind: Kind,
     pub mark: Marker,
+    pub generation: Generation,
     pub rc: Cell<u32>,
 }
 
 pub enum Kind {
     Number(Number),
     Boolean(bool),
+    Char(char),
     String(String),
-    Symbol(String),
+    Symbol(Symbol),
     Empty,
     Pair(Pair),
+    Vector(Vector),
+    Bytevector(Vec<u8>),
+    Values(Vector),     // multiple values, other than one
     Procedure(Procedure),
+    Syntax(Rc<Macro>),
+    Error(ErrorObject),
+    Port(Port),
+    RecordType(Rc<RecordType>),
+    Record(Record),
+    HashTable(HashTable),
+    Eof,
     Undefined,
 }
 
+#[derive(Clone)]
 pub enum Number {
     Int(i64),
+    BigInt(BigInt),         // an integer out of the range of i64
+    Rational(BigRational),  // a non-integer exact number
     Float(f64),
 }
 
@@ -36,23 +59,85 @@ pub struct Pair {
     pub(crate) cdr: ObjRef,
 }
 
+pub struct Vector {
+    pub(crate) elems: Vec<ObjRef>,
+}
+
 pub enum Procedure {
     Proc(Proc),
     Subr(Subr),
+    Ctrl(Ctrl),
+    Cont(Continuation),
 }
 
 pub struct Proc {
     pub(crate) env: EnvRef,
-    pub args: Vec<String>,
+    pub name: Option<Arc<str>>,
+    pub lambda: Rc<Lambda>,
+}
+
+pub type SubrFn = fn(VecDeque<Object>) -> Result<Object>;
+
+pub struct Subr {
     pub is_variadic: bool,
     pub require: usize,
-    pub body: Token,
+    pub fun: SubrFn,
 }
 
-pub struct Subr {
+/**
+ * Control: what a control procedure asks the evaluator to do,
+ * since it cannot be done by returning a value.
+ */
+pub enum Control {
+    CallCC(Object),                         // call the procedure with the current continuation
+    DynamicWind(Object, Object, Object),    // before, thunk, after
+    WithHandler(Object, Object),            // handler, thunk
+    Raise(Object, bool),                    // the object raised, and whether the handler may return
+    Apply(Object, VecDeque<Object>),        // call the procedure in place of the control procedure (a tail call)
+    Call(Object, VecDeque<Object>, NativeFn, Vec<Object>),   // call the procedure, then pass the result and the state to NativeFn
+    Return(Object),                         // return the value as it is
+}
+
+// the rest of a control procedure after a call: (result, state) -> what to do next
+pub type NativeFn = fn(Object, Vec<Object>) -> Result<Control>;
+
+pub type CtrlFn = fn(VecDeque<Object>) -> Result<Control>;
+
+pub struct Ctrl {
     pub is_variadic: bool,
     pub require: usize,
-    pub fun: fn(VecDeque<Object>) -> Result<Object>,
+    pub fun: CtrlFn,
+}
+
+/**
+ * Continuation: a continuation captured by call/cc,
+ * with the dynamic-wind entries which were active then.
+ */
+pub struct Continuation {
+    pub(crate) k: ContRef,
+    pub(crate) winders: ObjRef,
+}
+
+/**
+ * ErrorObject: a condition made by error, or by a failure in the interpreter.
+ */
+pub struct ErrorObject {
+    pub message: String,
+    pub(crate) irritants: ObjRef,
+}
+
+/**
+ * RecordType: a type defined by define-record-type, with the names of its fields.
+ */
+pub struct RecordType {
+    pub name: Symbol,
+    pub fields: Vec<Symbol>,
+}
+
+// an instance of a record type, with a value for each field
+pub struct Record {
+    pub rtd: Rc<RecordType>,
+    pub(crate) fields: Vec<ObjRef>,
 }
 
 impl ObjBody {
@@ -91,6 +176,42 @@ impl Pair {
     }
 }
 
+impl ErrorObject {
+    pub fn irritants(&self) -> Object {
+        let re = self.irritants;
+        re.borrow().inc_rc();
+        Object{re}
+    }
+}
+
+impl Vector {
+    pub fn len(&self) -> usize {
+        self.elems.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elems.is_empty()
+    }
+
+    pub fn get(&self, k: usize) -> Option<Object> {
+        let re = *self.elems.get(k)?;
+        re.borrow().inc_rc();
+        Some(Object{re})
+    }
+
+    pub fn to_vec(&self) -> Vec<Object> {
+        (0..self.len()).map(|k| self.get(k).unwrap()).collect()
+    }
+}
+
+impl Record {
+    pub fn get(&self, k: usize) -> Object {
+        let re = self.fields[k];
+        re.borrow().inc_rc();
+        Object{re}
+    }
+}
+
 impl Proc {
     pub fn env(&self) -> Environment {
         let env = self.env;
@@ -98,3 +219,17 @@ impl Proc {
         Environment{re: env}
     }
 }
+
+impl Continuation {
+    pub fn k(&self) -> Cont {
+        let re = self.k;
+        re.borrow().inc_rc();
+        Cont{re}
+    }
+
+    pub fn winders(&self) -> Object {
+        let re = self.winders;
+        re.borrow().inc_rc();
+        Object{re}
+    }
+}
@@ -0,0 +1,211 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Write};
+
+use anyhow::{Result, bail};
+
+use super::memory::Memory;
+
+/**
+ * Port: a source or a sink of characters.
+ * The file of a port is closed when the port is closed or collected by GC.
+ */
+pub struct Port {
+    pub name: String,
+    pub is_input: bool,
+    state: RefCell<PortState>,
+}
+
+// reads a line into the buffer, and returns the number of bytes read (0 at the end)
+type LineReader = Box<dyn FnMut(&mut String) -> io::Result<usize>>;
+
+enum PortState {
+    Input{source: LineReader, buf: VecDeque<char>},
+    Output(Output),
+    Closed,
+}
+
+enum Output {
+    Stdout,
+    Stderr,
+    String(String),
+    File(File),
+}
+
+// Ports which are not closed keep their files open until GC collects them,
+// so GC runs once when there are too many open files.
+fn open_file(path: &str, open: fn(&str) -> io::Result<File>) -> Result<File> {
+    const EMFILE: i32 = 24;
+    let res = match open(path) {
+        Err(e) if e.raw_os_error() == Some(EMFILE) => {
+            Memory::gc();
+            open(path)
+        }
+        res => res,
+    };
+    res.map_err(|e| anyhow::anyhow!("cannot open file {}: {}", path, e))
+}
+
+impl Port {
+    fn input(name: String, source: LineReader) -> Port {
+        Port{name, is_input: true, state: RefCell::new(PortState::Input{source, buf: VecDeque::new()})}
+    }
+
+    fn output(name: String, output: Output) -> Port {
+        Port{name, is_input: false, state: RefCell::new(PortState::Output(output))}
+    }
+
+    pub fn stdin() -> Port {
+        // stdin is shared with the REPL, so it is read without another buffer
+        Port::input("stdin".to_string(), Box::new(|line| io::stdin().read_line(line)))
+    }
+
+    pub fn stdout() -> Port {
+        Port::output("stdout".to_string(), Output::Stdout)
+    }
+
+    pub fn stderr() -> Port {
+        Port::output("stderr".to_string(), Output::Stderr)
+    }
+
+    pub fn input_string(s: String) -> Port {
+        let mut reader = Cursor::new(s.into_bytes());
+        Port::input("string".to_string(), Box::new(move |line| reader.read_line(line)))
+    }
+
+    pub fn output_string() -> Port {
+        Port::output("string".to_string(), Output::String(String::new()))
+    }
+
+    pub fn input_file(path: &str) -> Result<Port> {
+        let file = open_file(path, |p| File::open(p))?;
+        let mut reader = BufReader::new(file);
+        Ok(Port::input(path.to_string(), Box::new(move |line| reader.read_line(line))))
+    }
+
+    pub fn output_file(path: &str) -> Result<Port> {
+        let file = open_file(path, |p| File::create(p))?;
+        Ok(Port::output(path.to_string(), Output::File(file)))
+    }
+
+    pub fn is_open(&self) -> bool {
+        !matches!(*self.state.borrow(), PortState::Closed)
+    }
+
+    pub fn close(&self) {
+        // the file is closed by dropping it
+        *self.state.borrow_mut() = PortState::Closed;
+    }
+
+    // make "buf" have at least "n" characters unless the source has ended
+    fn fill(&self, n: usize) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let (source, buf) = match &mut *state {
+            PortState::Input{source, buf} => (source, buf),
+            PortState::Output(_) => bail!("input port required, but got output port {}", self.name),
+            PortState::Closed => bail!("port {} is closed", self.name),
+        };
+        while buf.len() < n {
+            let mut line = String::new();
+            match source(&mut line) {
+                Ok(0) => break,
+                Ok(_) => buf.extend(line.chars()),
+                Err(e) => bail!("cannot read from port {}: {}", self.name, e),
+            }
+        }
+        Ok(())
+    }
+
+    // None at the end of the input
+    pub fn read_char(&self) -> Result<Option<char>> {
+        self.fill(1)?;
+        match &mut *self.state.borrow_mut() {
+            PortState::Input{buf, ..} => Ok(buf.pop_front()),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn peek_char(&self) -> Result<Option<char>> {
+        self.fill(1)?;
+        match &*self.state.borrow() {
+            PortState::Input{buf, ..} => Ok(buf.front().copied()),
+            _ => unreachable!(),
+        }
+    }
+
+    // a line without the line ending, or None at the end of the input
+    pub fn read_line(&self) -> Result<Option<String>> {
+        let mut line = String::new();
+        loop {
+            match self.read_char()? {
+                Some('\n') => return Ok(Some(line)),
+                Some(c) => line.push(c),
+                None if line.is_empty() => return Ok(None),
+                None => return Ok(Some(line)),
+            }
+        }
+    }
+
+    // the rest of the line with the line ending, or None at the end of the input
+    pub fn read_raw_line(&self) -> Result<Option<String>> {
+        let mut line = String::new();
+        while let Some(c) = self.read_char()? {
+            line.push(c);
+            if c == '\n' {
+                break;
+            }
+        }
+        Ok(Some(line).filter(|line| !line.is_empty()))
+    }
+
+    // put back characters which have been read but not used
+    pub fn unread(&self, s: &str) {
+        if let PortState::Input{buf, ..} = &mut *self.state.borrow_mut() {
+            s.chars().rev().for_each(|c| buf.push_front(c));
+        }
+    }
+
+    // at most "k" characters, or None at the end of the input
+    pub fn read_string(&self, k: usize) -> Result<Option<String>> {
+        self.fill(k)?;
+        match &mut *self.state.borrow_mut() {
+            PortState::Input{buf, ..} if buf.is_empty() && k > 0 => Ok(None),
+            PortState::Input{buf, ..} => Ok(Some(buf.drain(..k.min(buf.len())).collect())),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_str(&self, s: &str) -> Result<()> {
+        let res = match &mut *self.state.borrow_mut() {
+            PortState::Output(Output::Stdout) => io::stdout().write_all(s.as_bytes()),
+            PortState::Output(Output::Stderr) => io::stderr().write_all(s.as_bytes()),
+            PortState::Output(Output::String(buf)) => {
+                buf.push_str(s);
+                Ok(())
+            }
+            PortState::Output(Output::File(file)) => file.write_all(s.as_bytes()),
+            PortState::Input{..} => bail!("output port required, but got input port {}", self.name),
+            PortState::Closed => bail!("port {} is closed", self.name),
+        };
+        res.map_err(|e| anyhow::anyhow!("cannot write to port {}: {}", self.name, e))
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        let res = match &mut *self.state.borrow_mut() {
+            PortState::Output(Output::Stdout) => io::stdout().flush(),
+            PortState::Output(Output::Stderr) => io::stderr().flush(),
+            PortState::Output(Output::File(file)) => file.flush(),
+            _ => Ok(()),
+        };
+        res.map_err(|e| anyhow::anyhow!("cannot write to port {}: {}", self.name, e))
+    }
+
+    // the string written to a string port so far
+    pub fn get_output_string(&self) -> Option<String> {
+        match &*self.state.borrow() {
+            PortState::Output(Output::String(s)) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
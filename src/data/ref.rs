@@ -1,7 +1,10 @@
 use super::{Object, Environment};
-use super::{object::*, env::EnvBody};
+use super::{object::*, env::EnvBody, cont::ContBody};
+use crate::token::{write_char, write_string, write_symbol};
+use crate::symbol::Symbol;
 
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::ptr::NonNull;
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
@@ -20,6 +23,7 @@ impl ObjRef {
     }
 
     #[inline]
+    #[allow(clippy::mut_from_ref)]
     pub(crate) unsafe fn borrow_mut(&self) -> &mut ObjBody {
         &mut *self.0.as_ptr()
     }
@@ -85,94 +89,287 @@ impl ObjRef {
 
     pub(crate) fn scm_eq(&self, other: &ObjRef) -> bool {
         match (&self.borrow().kind, &other.borrow().kind) {
-            (Kind::Number(lhs), Kind::Number(rhs)) => match (lhs, rhs) {
-                (Number::Int(lhs), Number::Int(rhs)) => lhs == rhs,
-                (Number::Float(lhs), Number::Float(rhs)) => lhs == rhs,
-                (_, _) => false,
-            }
+            (Kind::Number(lhs), Kind::Number(rhs)) => lhs.is_eqv(rhs),
             (Kind::Boolean(lhs), Kind::Boolean(rhs)) => lhs == rhs,
+            (Kind::Char(lhs), Kind::Char(rhs)) => lhs == rhs,
             (Kind::Symbol(lhs), Kind::Symbol(rhs)) => lhs == rhs,
             (Kind::Empty, Kind::Empty) => true,
+            (Kind::Eof, Kind::Eof) => true,
             (_, _) => self == other, 
         }
     }
 
     pub(crate) fn scm_equal(&self, other: &ObjRef) -> bool {
-        match (&self.borrow().kind, &other.borrow().kind) {
-            (Kind::Number(lhs), Kind::Number(rhs)) => match (lhs, rhs) {
-                (Number::Int(lhs), Number::Int(rhs)) => lhs == rhs,
-                (Number::Float(lhs), Number::Float(rhs)) => lhs == rhs,
-                (_, _) => false,
+        self.equal_assuming(other, &mut HashSet::new())
+    }
+
+    // "assumed" has the pairs and vectors being compared, which are taken as equal when they are met again,
+    // so that circular structures are compared to the end
+    fn equal_assuming(&self, other: &ObjRef, assumed: &mut HashSet<(ObjRef, ObjRef)>) -> bool {
+        let (mut lhs, mut rhs) = (*self, *other);
+        // the cdrs of lists are followed in the loop
+        loop {
+            let (car, cdr) = match (&lhs.borrow().kind, &rhs.borrow().kind) {
+                (Kind::Pair(l), Kind::Pair(r)) => ((l.car, r.car), (l.cdr, r.cdr)),
+                (Kind::Vector(l), Kind::Vector(r)) => return l.elems.len() == r.elems.len()
+                    && (!assumed.insert((lhs, rhs))
+                        || l.elems.iter().zip(&r.elems).all(|(l, r)| l.equal_assuming(r, assumed))),
+                (Kind::Number(l), Kind::Number(r)) => return l.is_eqv(r),
+                (Kind::Boolean(l), Kind::Boolean(r)) => return l == r,
+                (Kind::Char(l), Kind::Char(r)) => return l == r,
+                (Kind::Symbol(l), Kind::Symbol(r)) => return l == r,
+                (Kind::String(l), Kind::String(r)) => return l == r,
+                (Kind::Empty, Kind::Empty) => return true,
+                (Kind::Bytevector(l), Kind::Bytevector(r)) => return l == r,
+                (Kind::Eof, Kind::Eof) => return true,
+                (_, _) => return lhs == rhs,
+            };
+            if !assumed.insert((lhs, rhs)) {
+                return true
             }
-            (Kind::Boolean(lhs), Kind::Boolean(rhs)) => lhs == rhs,
-            (Kind::Symbol(lhs), Kind::Symbol(rhs)) => lhs == rhs,
-            (Kind::String(lhs), Kind::String(rhs)) => lhs == rhs,
-            (Kind::Empty, Kind::Empty) => true,
-            (Kind::Pair(lhs), Kind::Pair(rhs))
-                => ObjRef::scm_equal(&lhs.car, &rhs.car) && ObjRef::scm_equal(&lhs.cdr, &rhs.cdr),
-            (_, _) => self == other,
+            if !car.0.equal_assuming(&car.1, assumed) {
+                return false
+            }
+            (lhs, rhs) = cdr;
         }
     }
 }
 
+// which objects are written with datum labels (#0=, #0#)
+enum Labels {
+    Cycles,                 // objects which contain themselves (write, display)
+    Shared(HashSet<ObjRef>),    // objects which appear more than once (write-shared)
+    Never,                  // none; circular objects are not printed to the end (write-simple)
+}
+
 struct ObjRefDisplayState {
     obj_tag: HashMap<ObjRef, usize>,
-    exists: HashSet<ObjRef>, 
+    path: HashSet<ObjRef>,      // pairs, vectors and records being printed, which contain the current one
+    write: bool,                // whether strings and chars are written as literals
+    labels: Labels,
+}
+
+impl fmt::Display for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut state = ObjRefDisplayState{obj_tag: HashMap::new(), path: HashSet::new(), write: true, labels: Labels::Cycles};
+        write!(f, "{}", self._to_string(&mut state))
+    }
 }
 
 impl ObjRef {
-    pub fn to_string(&self) -> String {
-        
-        let mut state = ObjRefDisplayState{obj_tag: HashMap::new(), exists: HashSet::new()};
+    // the representation for display, where strings and chars are printed as they are
+    pub(crate) fn display_string(&self) -> String {
+        let mut state = ObjRefDisplayState{obj_tag: HashMap::new(), path: HashSet::new(), write: false, labels: Labels::Cycles};
         self._to_string(&mut state)
     }
 
+    // the representation for write-shared, where shared structure is labeled too
+    pub(crate) fn write_shared_string(&self) -> String {
+        let mut shared = HashSet::new();
+        self.find_shared(&mut HashSet::new(), &mut shared);
+        let mut state = ObjRefDisplayState{obj_tag: HashMap::new(), path: HashSet::new(), write: true, labels: Labels::Shared(shared)};
+        self._to_string(&mut state)
+    }
+
+    // the representation for write-simple, without labels
+    pub(crate) fn write_simple_string(&self) -> String {
+        let mut state = ObjRefDisplayState{obj_tag: HashMap::new(), path: HashSet::new(), write: true, labels: Labels::Never};
+        self._to_string(&mut state)
+    }
+
+    // pairs, vectors and records which can be reached more than once
+    fn find_shared(&self, seen: &mut HashSet<ObjRef>, shared: &mut HashSet<ObjRef>) {
+        let mut re = *self;
+        // the cdrs of lists are followed in the loop
+        loop {
+            let cdr = match &re.borrow().kind {
+                Kind::Pair(_) | Kind::Vector(_) | Kind::Record(_) if !seen.insert(re) => {
+                    shared.insert(re);
+                    return
+                }
+                Kind::Pair(pair) => {
+                    pair.car.find_shared(seen, shared);
+                    pair.cdr
+                }
+                Kind::Vector(vector) | Kind::Values(vector) => {
+                    vector.elems.iter().for_each(|re| re.find_shared(seen, shared));
+                    return
+                }
+                Kind::Record(record) => return record.fields.iter().for_each(|re| re.find_shared(seen, shared)),
+                Kind::Error(err) => err.irritants,
+                _ => return,
+            };
+            re = cdr;
+        }
+    }
+
     fn _to_string(&self, state: &mut ObjRefDisplayState) -> String {
+        let mut out = String::new();
+        self.write_to(&mut out, state);
+        out
+    }
+
+    fn write_to(&self, out: &mut String, state: &mut ObjRefDisplayState) {
         match &self.borrow().kind {
-            Kind::Number(num) => match num {
-                Number::Int(i) => i.to_string(),
-                Number::Float(f) => f.to_string(),
+            Kind::Number(num) => out.push_str(&num.to_string()),
+            Kind::Boolean(b) if *b => out.push_str("#t"),
+            Kind::Boolean(_) => out.push_str("#f"),
+            Kind::Char(c) if state.write => out.push_str(&write_char(*c)),
+            Kind::Char(c) => out.push(*c),
+            Kind::String(s) if state.write => out.push_str(&write_string(s)),
+            Kind::String(s) => out.push_str(s),
+            Kind::Symbol(s) if state.write => out.push_str(&write_symbol(s)),
+            Kind::Symbol(s) => out.push_str(s),
+            Kind::Empty => out.push_str("()"),
+            Kind::Bytevector(bytes) => {
+                let bytes: Vec<String> = bytes.iter().map(|b| b.to_string()).collect();
+                out.push_str(&format!("#u8({})", bytes.join(" ")));
             }
-            Kind::Boolean(b) if *b => "#t".to_string(),
-            Kind::Boolean(_) => "#f".to_string(),
-            Kind::String(s) => format!(r#""{}""#, s),
-            Kind::Symbol(s) => s.clone(),
-            Kind::Empty => "()".to_string(),
-            Kind::Procedure(proc) => match proc {
-                Procedure::Proc(_) => "#<procedure>".to_string(),
-                Procedure::Subr(_) => "#<subroutine>".to_string(),
+            Kind::Procedure(proc) => out.push_str(match proc {
+                Procedure::Proc(_) => "#<procedure>",
+                Procedure::Subr(_) | Procedure::Ctrl(_) => "#<subroutine>",
+                Procedure::Cont(_) => "#<continuation>",
+            }),
+            Kind::Syntax(_) => out.push_str("#<syntax>"),
+            Kind::Error(err) => {
+                out.push_str("#<error ");
+                match state.write {
+                    true => out.push_str(&write_string(&err.message)),
+                    false => out.push_str(&err.message),
+                }
+                // the irritants without their parentheses
+                let start = out.len();
+                err.irritants.write_to(out, state);
+                if out.len() > start + 2 && out[start..].starts_with('(') && out.ends_with(')') {
+                    out.pop();
+                    out.replace_range(start..start + 1, " ");
+                } else {
+                    out.truncate(start);
+                }
+                out.push('>');
             }
-            Kind::Undefined => "#<undef>".to_string(),
+            Kind::Port(port) if port.is_input => out.push_str(&format!("#<input-port {}>", port.name)),
+            Kind::Port(port) => out.push_str(&format!("#<output-port {}>", port.name)),
+            Kind::RecordType(rtd) => out.push_str(&format!("#<record-type {}>", rtd.name)),
+            Kind::HashTable(table) => out.push_str(&format!("#<hash-table {}>", table.len())),
+            Kind::Eof => out.push_str("#<eof>"),
+            Kind::Undefined => out.push_str("#<undef>"),
+            Kind::Values(values) => {
+                for (i, re) in values.elems.iter().enumerate() {
+                    if i > 0 {
+                        out.push(' ');
+                    }
+                    re.write_to(out, state);
+                }
+            }
+            Kind::Pair(_) | Kind::Vector(_) | Kind::Record(_) => {
+                if let Some(tag) = self.reference(state) {
+                    return out.push_str(&format!("#{}#", tag))
+                }
+                match &state.labels {
+                    Labels::Cycles => {
+                        // an object which contains itself is labeled: #0=(1 . #0#)
+                        let start = out.len();
+                        state.path.insert(*self);
+                        self.write_compound(out, state);
+                        state.path.remove(self);
+                        if let Some(tag) = state.obj_tag.get(self) {
+                            out.insert_str(start, &format!("#{}=", tag));
+                        }
+                    }
+                    Labels::Shared(shared) if shared.contains(self) => {
+                        // labeled where it first appears: (#0=(a) #0#)
+                        let tag = state.obj_tag.len();
+                        state.obj_tag.insert(*self, tag);
+                        out.push_str(&format!("#{}=", tag));
+                        self.write_compound(out, state);
+                    }
+                    Labels::Shared(_) | Labels::Never => self.write_compound(out, state),
+                }
+            }
+        }
+    }
+
+    // the tag which a pair, vector or record is written as, if it has been labeled already or contains itself
+    fn reference(&self, state: &mut ObjRefDisplayState) -> Option<usize> {
+        if let Some(tag) = state.obj_tag.get(self) {
+            return Some(*tag)
+        }
+        if matches!(state.labels, Labels::Cycles) && state.path.contains(self) {
+            let tag = state.obj_tag.len();
+            state.obj_tag.insert(*self, tag);
+            return Some(tag)
+        }
+        None
+    }
+
+    // whether the pair "re" must be written after a dot instead of as the rest of a list
+    fn is_labeled(re: &ObjRef, state: &ObjRefDisplayState) -> bool {
+        match &state.labels {
+            Labels::Cycles => state.obj_tag.contains_key(re) || state.path.contains(re),
+            Labels::Shared(shared) => shared.contains(re),
+            Labels::Never => false,
+        }
+    }
+
+    fn write_compound(&self, out: &mut String, state: &mut ObjRefDisplayState) {
+        match &self.borrow().kind {
             Kind::Pair(pair) => {
-                if let Some(tag) = state.obj_tag.get(self) {
-                    format!("#{}#", tag)
-                } else if let Some(_) = state.exists.take(self) {
-                    let tag = state.obj_tag.len();
-                    state.obj_tag.insert(*self, tag);
-                    format!("#{}#", tag)
-                } else {
-                    state.exists.insert(*self);
-                    let car = pair.car._to_string(state);
-                    let cdr = pair.cdr._to_string(state);
-                    if let Some(tag) = state.obj_tag.get(self) {
-                        if cdr == "()" {
-                            format!("#{}=({})", tag, car)
-                        } else if cdr.starts_with('(') {
-                            format!("#{}=({} {}", tag, car, cdr.split_at(1).1)
-                        } else {
-                            format!("#{}=({} . {})", tag, car, cdr)
+                out.push('(');
+                pair.car.write_to(out, state);
+                // the cdrs are written in the loop; "tails" has them with where they start in "out"
+                let mut tails = Vec::new();
+                let mut rest = pair.cdr;
+                loop {
+                    let cdr = match &rest.borrow().kind {
+                        Kind::Empty => break,
+                        Kind::Pair(pair) if !Self::is_labeled(&rest, state) => {
+                            tails.push((rest, out.len()));
+                            if let Labels::Cycles = state.labels {
+                                state.path.insert(rest);
+                            }
+                            out.push(' ');
+                            pair.car.write_to(out, state);
+                            pair.cdr
                         }
-                    } else {
-                        if cdr == "()" {
-                            format!("({})", car)
-                        } else if cdr.starts_with('(') {
-                            format!("({} {}", car, cdr.split_at(1).1)
-                        } else {
-                            format!("({} . {})", car, cdr)
+                        _ => {
+                            out.push_str(" . ");
+                            rest.write_to(out, state);
+                            break
                         }
+                    };
+                    rest = cdr;
+                }
+                out.push(')');
+                // a tail which turned out to contain itself is written after a dot with its label: (1 . #0=(2 . #0#))
+                for (tail, start) in tails.into_iter().rev() {
+                    state.path.remove(&tail);
+                    if let Some(tag) = state.obj_tag.get(&tail) {
+                        out.replace_range(start..start + 1, &format!(" . #{}=(", tag));
+                        out.push(')');
                     }
                 }
             }
+            Kind::Vector(vector) => {
+                out.push_str("#(");
+                for (i, re) in vector.elems.iter().enumerate() {
+                    if i > 0 {
+                        out.push(' ');
+                    }
+                    re.write_to(out, state);
+                }
+                out.push(')');
+            }
+            // #<record point x: 1 y: 2>
+            Kind::Record(record) => {
+                out.push_str(&format!("#<record {}", record.rtd.name));
+                for (field, re) in record.rtd.fields.iter().zip(&record.fields) {
+                    out.push_str(&format!(" {}: ", field));
+                    re.write_to(out, state);
+                }
+                out.push('>');
+            }
+            _ => unreachable!(),
         }
     }
 }
@@ -187,7 +384,7 @@ impl EnvRef {
         )
     }
 
-    pub(crate) fn lookup(&self, id: &String) -> Option<Object> {
+    pub(crate) fn lookup(&self, id: &Symbol) -> Option<Object> {
         if let Some(re) = self.borrow().vars.get(id) {
             re.borrow().inc_rc();
             Some(Object{re: *re})
@@ -198,8 +395,8 @@ impl EnvRef {
         }
     }
 
-    pub fn contains_at(&self, id: &String) -> Option<Environment> {
-        if let Some(_) = self.borrow().vars.get(id) {
+    pub fn contains_at(&self, id: &Symbol) -> Option<Environment> {
+        if self.borrow().vars.contains_key(id) {
             self.borrow().inc_rc();
             Some(Environment{re: *self})
         } else if let Some(parent) = self.borrow().parent {
@@ -215,7 +412,30 @@ impl EnvRef {
     }
 
     #[inline]
+    #[allow(clippy::mut_from_ref)]
     pub(crate) unsafe fn borrow_mut(&self) -> &mut EnvBody {
         &mut *self.0.as_ptr()
     }
 }
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct ContRef(NonNull<ContBody>);
+
+impl ContRef {
+    pub(crate) fn new(cont: &mut ContBody) -> Self {
+        ContRef(
+            unsafe {NonNull::new_unchecked(cont as *mut _)}
+        )
+    }
+
+    #[inline]
+    pub(crate) fn borrow(&self) -> &ContBody {
+        unsafe {self.0.as_ref()}
+    }
+
+    #[inline]
+    #[allow(clippy::mut_from_ref)]
+    pub(crate) unsafe fn borrow_mut(&self) -> &mut ContBody {
+        &mut *self.0.as_ptr()
+    }
+}
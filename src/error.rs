@@ -0,0 +1,108 @@
+use crate::token::Pos;
+use crate::trace::CallFrame;
+
+use std::fmt;
+
+use anyhow::Error;
+
+// the number of frames shown at each end of a long call stack
+const TRACE_LIMIT: usize = 10;
+
+/**
+ * SchemeError: an error annotated with where it occurred,
+ * i.e. the position in the source and the call stack of scheme procedures.
+ * It is displayed as the inner error, so messages do not change.
+ */
+pub struct SchemeError {
+    pub pos: Option<Pos>,
+    pub trace: Option<Vec<CallFrame>>,
+    err: Error,
+}
+
+impl fmt::Display for SchemeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.err)
+    }
+}
+
+impl fmt::Debug for SchemeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.pos {
+            Some(pos) => write!(f, "{}: {:?}", pos, self.err),
+            None => write!(f, "{:?}", self.err),
+        }
+    }
+}
+
+impl std::error::Error for SchemeError {}
+
+fn annotate(mut err: Error, f: impl FnOnce(&mut SchemeError)) -> Error {
+    if let Some(scm_err) = err.downcast_mut::<SchemeError>() {
+        f(scm_err);
+        err
+    } else {
+        let mut scm_err = SchemeError{pos: None, trace: None, err};
+        f(&mut scm_err);
+        Error::new(scm_err)
+    }
+}
+
+// attach "pos" to "err" unless it already knows where it occurred (the innermost position wins)
+pub fn locate(err: Error, pos: Option<&Pos>) -> Error {
+    match pos {
+        Some(pos) => annotate(err, |e| if e.pos.is_none() {
+            e.pos = Some(pos.clone());
+        }),
+        None => err,
+    }
+}
+
+// attach the call stack made by "trace" to "err" unless it already has one
+pub fn with_trace(err: Error, trace: impl FnOnce() -> Vec<CallFrame>) -> Error {
+    annotate(err, |e| if e.trace.is_none() {
+        e.trace = Some(trace());
+    })
+}
+
+pub fn report(err: &Error) {
+    println!("[ERROR] {}", err);
+    let scm_err = match err.downcast_ref::<SchemeError>() {
+        Some(scm_err) => scm_err,
+        None => return,
+    };
+
+    if let Some(pos) = &scm_err.pos {
+        println!(" --> {}", pos);
+        if let Some(line) = pos.src.line(pos.line) {
+            // keep tabs so that the caret is put under the right character
+            let indent: String = line
+                .chars()
+                .take(pos.col - 1)
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let margin = " ".repeat(pos.line.to_string().len());
+            println!("{} |", margin);
+            println!("{} | {}", pos.line, line);
+            println!("{} | {}^", margin, indent);
+        }
+    }
+
+    if let Some(trace) = &scm_err.trace {
+        if trace.is_empty() {
+            return;
+        }
+        println!("call stack (most recent call first):");
+        for (i, frame) in trace.iter().enumerate() {
+            if trace.len() > TRACE_LIMIT * 2 && i == TRACE_LIMIT {
+                println!("    ... {} frames omitted ...", trace.len() - TRACE_LIMIT * 2);
+            }
+            if trace.len() <= TRACE_LIMIT * 2 || i < TRACE_LIMIT || i >= trace.len() - TRACE_LIMIT {
+                if let Some(tail) = &frame.tail {
+                    println!("    at {}", tail);
+                    println!("      [{} tail call{} collapsed]", frame.tail_calls, if frame.tail_calls == 1 {""} else {"s"});
+                }
+                println!("    at {}", frame.call);
+            }
+        }
+    }
+}
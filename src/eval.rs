@@ -1,69 +1,49 @@
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
 
-use anyhow::{Context, Result, anyhow, bail};
+use anyhow::{Result, anyhow, bail};
 
-use crate::data::{*, object::*};
+use crate::data::{*, object::*, cont::{Frame, Guarded}, memory::Memory};
 use crate::token::*;
+use crate::symbol::Symbol;
 use crate::parse::Parser;
+use crate::error;
+use crate::expand;
+use crate::compile::{self, Code, Op, App, Let, Clause, DoLoop, Quasi, Template};
+use crate::vm::{self, Chunk, Inst};
+use crate::function::port;
+use crate::trace::{self, Call, CallFrame};
 
 pub fn eval(token: Token, env: Environment) -> Result<Object> {
+    let token = expand::expand(&token, &env).map_err(|e| error::locate(e, token.pos()))?;
     // Exp, Define, (load String)
-    match &token {
-        Token::Pair{car, cdr} => match &**car {
-            Token::Id(id) if id == "define" => {
-                eval_define(&token, env)
+    let res = match &token {
+        Token::Pair{car, cdr, ..} => match &**car {
+            Token::Id(id, _) if id == "load" => {
+                eval_load(&token, cdr, env)
             }
-            Token::Id(id) if id == "load" => {
-                eval_load(&token, &**cdr, env)
-            }
-            _ => eval_exp(token.clone(), env)
+            _ => Machine::run(compile::compile(&token, &env), env)
         }
-        _ => eval_exp(token.clone(), env)
-    }
-}
-
-pub fn eval_define(token: &Token, env: Environment) -> Result<Object> {
-    let def_token = token;
-    let token = token.next().unwrap();
-    ensure_proper_list(token)?;
-
-    let ids = token.elem().with_context(|| format!("syntax error: {}", def_token))?;
-    match ids {
-        Token::Id(id) => {
-            let obj = token
-                .next()
-                .unwrap()
-                .elem()
-                .map(|t| eval_exp(t.clone(), env.clone()))
-                .unwrap_or_else(|| Ok(Object::new_undefined()))?;
-            env.insert(id.clone(), obj);
-            Ok(Object::new_symbol(id.clone(), false))
-        }
-        Token::Pair{car: id, cdr: args} => {
-            if let Token::Id(id) = &**id {
-                let body = token.next().unwrap();
-                let obj = eval_lambda(args, body, env.clone())?;
-                env.insert(id.clone(), obj);
-                Ok(Object::new_symbol(id.clone(), false))
-            } else {
-                Err(anyhow!("syntax error: {}", def_token))
-            }
-        }
-        _ => Err(anyhow!("syntax error: {}", def_token))
+        _ => Machine::run(compile::compile(&token, &env), env)
+    };
+    if res.is_err() {
+        port::reset_current_ports();
     }
+    res.map_err(|e| error::locate(e, token.pos()))
 }
 
 pub fn eval_load(token: &Token, path: &Token, env: Environment) -> Result<Object> {
     // argument "token" is used for error messages
     let (mut file, path_display) = match path {
-        Token::Pair{car, cdr} => {
-            if let Token::Empty = &**cdr {
-                if let Token::String(path) = &**car {
-                    let path = Path::new(&*path);
-                    match File::open(&path) {
+        Token::Pair{car, cdr, ..} => {
+            if let Token::Empty(_) = &**cdr {
+                if let Token::String(path, _) = &**car {
+                    let path = Path::new(path);
+                    match File::open(path) {
                         Ok(file) => (file, path.display()),
                         Err(reason) => bail!("could't open {}: {}", path.display(), reason)
                     }
@@ -78,572 +58,1019 @@ pub fn eval_load(token: &Token, path: &Token, env: Environment) -> Result<Object
     };
 
     let mut buf = String::new();
-    let parser;
-    let tokens;
     if let Err(reason) = file.read_to_string(&mut buf) {
         bail!("could't read {}: {}", path_display, reason)
     }
-    parser = Parser::new(buf);
-    tokens = parser.build_tokens();
+    let parser = Parser::with_name(buf, &path_display.to_string());
+    let tokens = parser.build_tokens();
     for token in tokens {
         match eval(token?, env.clone()) {
             Ok(_) => {},
-            Err(reason) => println!("{}", reason),
+            Err(reason) => error::report(&reason),
         }
     }
     Ok(Object::new_boolean(true, true))
 }
 
-fn eval_exp(mut token: Token, mut env: Environment) -> Result<Object> {
-    'exp: loop {
-        match &token {
-            &Token::Int(i) => break 'exp Ok(Object::new_int(i, false)),
-            &Token::Float(f) => break 'exp Ok(Object::new_float(f, false)),
-            &Token::Boolean(b) => break 'exp Ok(Object::new_boolean(b, false)),
-            Token::String(s) => break 'exp Ok(Object::new_string(s.clone(), false)),
-            Token::Empty => break 'exp Ok(Object::new_empty()),
-            Token::Symbol(s) => break 'exp eval_quote(&*s),
-            Token::Id(id) => if let Some(var) = env.lookup(id) {
-                break 'exp Ok(var);
-            } else {
-                break 'exp Err(anyhow!("unbound variable: {}", id));
-            },
-            Token::Pair{car, cdr} => match &**car {
-                Token::Id(id) => {
-                    if env.lookup(id).is_some() {
-                        match eval_app(&token, car, cdr, env)? {
-                            AppResult::Proc((t, e)) => {
-                                token = t;
-                                env = e;
-                                continue 'exp;
-                            }
-                            AppResult::Subr(res) => break 'exp Ok(res)
-                        }
-                    } else {
-                        match id.as_str() {
-                            "lambda" => {
-                                ensure_proper_list(cdr)?;
-                                let arg = cdr.elem().with_context(|| format!("syntax error: malformed lambda: {}", &token))?;
-                                let body = cdr.next().unwrap();
-                                break 'exp eval_lambda(arg, body, env)
-                            },
-                            "quote" => {
-                                break 'exp eval_quote(cdr)
-                            },
-                            "set!" => {
-                                ensure_proper_list(&token)?;
-                                let id = token
-                                    .nth(1)
-                                    .with_context(|| format!("syntax error: malformed set!: {}", &token))?;
-                                if let Token::Id(id) = id {
-                                    let exp = eval_exp(
-                                        token
-                                        .nth(2)
-                                        .with_context(|| format!("syntax error: malformed set!: {}", &token))?
-                                        .clone(),
-                                        env.clone()
-                                    )?;
-                                    if let Some(env) = env.contains_at(id) {
-                                        env.insert(id.clone(), exp);
-                                        break 'exp Ok(Object::new_undefined())
-                                    } else {
-                                        break 'exp Err(anyhow!("symbol not defined: {}", id))
-                                    }
-                                } else {
-                                    break 'exp Err(anyhow!(""))
-                                }
-                            }
-                            "let" => {
-                                ensure_proper_list(cdr)?;
-                                match cdr.nth(0).with_context(|| format!("syntax error: malformed let: {}", &token))? {
-                                    Token::Id(id) => {
-                                        let name_env = Environment::new(env.clone());
-                                        let new_env = Environment::new(name_env.clone());
-                                        let name = id.clone();
-                                        let bindings = cdr.nth(1).with_context(|| format!("syntax error: malformed let: {}", &token))?;
-                                        let body = cdr.next().unwrap().next().with_context(|| format!("syntax error: malformed let: {}", &token))?;
-                                        ensure_proper_list(bindings)?;
-
-                                        let mut args = Vec::new();
-                                        let mut inits = VecDeque::new();
-                                        for binding in bindings {
-                                            match binding.nth(0).with_context(|| format!("syntax error: malformed let: {}", &token))? {
-                                                Token::Id(id) => args.push(id.clone()),
-                                                t => bail!("syntax error: identifier required, but got {}", t),
-                                            };
-                                            inits.push_back(eval_exp(binding.nth(1).with_context(|| format!("syntax error: malformed let: {}", &token))?.clone(), env.clone())?);
-                                            if binding.nth(2).is_some() {
-                                                bail!("syntax error: malformed let: {}", &token)
-                                            }
-                                        }
-                                        let proc = Object::new_procedure(name_env.clone(), args.clone(), false, args.len(), body.clone());
-                                        name_env.insert(name, proc);
-                                        for arg in args {
-                                            new_env.insert(arg, inits.pop_front().unwrap());
-                                        }
-
-                                        let res = eval_body(body, new_env)?;
-                                        token = res.0;
-                                        env = res.1;
-                                        continue 'exp;
-                                    }
-                                    Token::Pair{..} => {
-                                        let bindings = cdr.nth(0).unwrap();
-                                        let body = cdr.next().with_context(|| format!("syntax error: malformed let: {}", &token))?;
-                                        ensure_proper_list(bindings)?;
-                                        let new_env = Environment::new(env.clone());
-                                        for binding in bindings {
-                                            let id = match binding.nth(0).with_context(|| format!("syntax error: malformed let: {}", &token))? {
-                                                Token::Id(id) => id.clone(),
-                                                t => bail!("syntax error: identifier required, but got {}", t),
-                                            };
-                                            new_env.insert(id, eval_exp(binding.nth(1).with_context(|| format!("syntax error: malformed let: {}", &token))?.clone(), env.clone())?);
-                                        }
-
-                                        let res = eval_body(body, new_env)?;
-                                        token = res.0;
-                                        env = res.1;
-                                        continue 'exp;
-                                    }
-                                    _ => break 'exp Err(anyhow!("syntax error: malformed let: {}", &token))
-                                }
-                            }
-                            "let*" => {
-                                ensure_proper_list(cdr)?;
-                                match cdr.nth(0).with_context(|| format!("syntax error: malformed let: {}", &token))? {
-                                    Token::Pair{..} => {
-                                        let bindings = cdr.nth(0).unwrap();
-                                        let body = cdr.next().with_context(|| format!("syntax error: malformed let: {}", &token))?;
-                                        ensure_proper_list(bindings)?;
-                                        let mut cur_env = env.clone();
-                                        for binding in bindings {
-                                            let id = match binding.nth(0).with_context(|| format!("syntax error: malformed let: {}", &token))? {
-                                                Token::Id(id) => id.clone(),
-                                                t => bail!("syntax error: identifier required, but got {}", t),
-                                            };
-                                            let init = eval_exp(binding.nth(1).with_context(|| format!("syntax error: malformed let: {}", token))?.clone(), env.clone())?;
-                                            let new_env = Environment::new(cur_env);
-                                            new_env.insert(id, init);
-                                            cur_env = new_env;
-                                        }
-
-                                        let res = eval_body(body, cur_env)?;
-                                        token = res.0;
-                                        env = res.1;
-                                        continue 'exp;
-                                    }
-                                    _ => break 'exp Err(anyhow!("syntax error: malformed let: {}", &token))
-                                }
-                            }
-                            "letrec" => {
-                                ensure_proper_list(cdr)?;
-                                match cdr.nth(0).with_context(|| format!("syntax error: malformed let: {}", &token))? {
-                                    Token::Pair{..} => {
-                                        let bindings = cdr.nth(0).unwrap();
-                                        let body = cdr.next().with_context(|| format!("syntax error: malformed let: {}", &token))?;
-                                        ensure_proper_list(bindings)?;
-                                        let new_env = Environment::new(env);
-                                        for binding in bindings {
-                                            let id = match binding.nth(0).with_context(|| format!("syntax error: malformed let: {}", &token))? {
-                                                Token::Id(id) => id.clone(),
-                                                t => bail!("syntax error: identifier required, but got {}", t),
-                                            };
-                                            let init = eval_exp(binding.nth(1).with_context(|| format!("syntax error: malformed let: {}", &token))?.clone(), new_env.clone())?;
-                                            new_env.insert(id, init);
-                                        }
-
-                                        let res = eval_body(body, new_env)?;
-                                        token = res.0;
-                                        env = res.1;
-                                        continue 'exp;
-                                    }
-                                    _ => break 'exp Err(anyhow!("syntax error: malformed let: {}", &token))
-                                }
-                            }
-                            "if" => {
-                                // (if exp1 exp2 exp3)
-                                let exp1 = cdr.elem()
-                                    .with_context(|| format!("error: proper list required for function application or macro use: {}", &token))?;
-                                let exp2 = cdr.next()
-                                    .with_context(|| format!("error: proper list required for function application or macro use: {}", &token))?
-                                    .elem()
-                                    .with_context(|| format!("syntax error: malformed if: {}", &token))?;
-                                let exp3 = cdr.next()
-                                    .unwrap()
-                                    .next()
-                                    .with_context(|| format!("error: proper list required for function application or macro use: {}", &token))?;
-                                let cond = eval_exp(exp1.clone(), env.clone())?;
-                                match exp3 {
-                                    Token::Pair{car: exp3_car, cdr: exp3_cdr} => {
-                                        if !exp3_cdr.is_empty() {
-                                            break 'exp Err(anyhow!("syntax error: malformed if: {}", &token));
-                                        } else if !cond.is_falsy() {
-                                            // return eval_exp(token, env)
-                                            token = exp2.clone();
-                                            continue 'exp;
-                                        } else {
-                                            token = exp3_car.as_ref().clone();
-                                            continue 'exp;
-                                        }
-                                    }
-                                    Token::Empty => {
-                                        if !cond.is_falsy() {
-                                            token = exp2.clone();
-                                            continue 'exp;
-                                        } else {
-                                            break 'exp Ok(Object::new_undefined());
-                                        }
-                                    }
-                                    _ => {
-                                        break 'exp Err(anyhow!("error: proper list required for function application or macro use: {}", &token));
-                                    }
-                                }
-                            }
-                            "cond" => {
-                                ensure_proper_list(cdr)?;
-                                if cdr.is_empty() {
-                                    bail!("syntax error: at least one clause is required for cond: {}", &token);
-                                }
-
-                                let mut res = Object::new_undefined();
-                                for clause in &**cdr {
-                                    if let Token::Pair{car: test, cdr: exps} = clause {
-                                        if exps.is_empty() {
-                                            bail!("syntax error: bad clause in cond: {}", &token);
-                                        }
-                                        match &**test {
-                                            Token::Id(s) if s == "else" => {
-                                                let mut exps = &**exps;
-                                                loop {
-                                                    match exps {
-                                                        Token::Empty => break,
-                                                        Token::Pair{car: exp, cdr: next_exps} => {
-                                                            if let Token::Empty = &**next_exps {
-                                                                token = exp.as_ref().clone();
-                                                                continue 'exp;
-                                                            }
-                                                            res = eval_exp(exp.as_ref().clone(), env.clone())?;
-                                                            exps = next_exps;
-                                                        }
-                                                        _ => bail!("syntax error: bad clause in cond: {}", &token)
-                                                    }
-                                                }
-                                                break;
-                                            }
-                                            _ => {
-                                                let mut exps = &**exps;
-                                                if !eval_exp(test.as_ref().clone(), env.clone())?.is_falsy() {
-                                                    loop {
-                                                        match exps {
-                                                            Token::Empty => break,
-                                                            Token::Pair{car: exp, cdr: next_exps} => {
-                                                                if let Token::Empty = &**next_exps {
-                                                                    token = exp.as_ref().clone();
-                                                                    continue 'exp;
-                                                                }
-                                                                res = eval_exp(exp.as_ref().clone(), env.clone())?;
-                                                                exps = next_exps;
-                                                            }
-                                                            _ => bail!("syntax error: bad clause in cond: {}", &token)
-                                                        }
-                                                    }
-                                                    break;
-                                                } else {
-                                                    continue;
-                                                }
-                                            }
-                                        }
-                                    } else {
-                                        bail!("syntax error: bad clause in cond: {}", &token);
-                                    }
-                                }
-                                break 'exp Ok(res);
-                            }
-                            "and" => {
-                                ensure_proper_list(cdr)?;
-                                let mut res = Object::new_boolean(true, true);
-                                let mut tests = cdr.as_ref();
-                                loop {
-                                    match tests {
-                                        Token::Empty => break,
-                                        Token::Pair{car: test, cdr: next_tests} => {
-                                            if let Token::Empty = **next_tests {
-                                                token = test.as_ref().clone();
-                                                continue 'exp;
-                                            }
-                                            res = eval_exp(test.as_ref().clone(), env.clone())?;
-                                            if res.is_falsy() {
-                                                break;
-                                            } else {
-                                                tests = &**next_tests
-                                            }
-                                        }
-                                        _ => unreachable!()
-                                    }
-                                };
-                                break 'exp Ok(res);
-                            }
-                            "or" => {
-                                ensure_proper_list(cdr)?;
-                                let mut res = Object::new_boolean(false, true);
-                                let mut tests = cdr.as_ref();
-                                loop {
-                                    match tests {
-                                        Token::Empty => break,
-                                        Token::Pair{car: test, cdr: next_tests} => {
-                                            if let Token::Empty = **next_tests {
-                                                token = test.as_ref().clone();
-                                                continue 'exp;
-                                            }
-                                            res = eval_exp(test.as_ref().clone(), env.clone())?;
-                                            if !res.is_falsy() {
-                                            break;
-                                            } else {
-                                                tests = &**next_tests
-                                            }
-                                        }
-                                        _ => unreachable!()
-                                    }
-                                };
-                                break 'exp Ok(res);
-                            }
-                            "begin" => {
-                                ensure_proper_list(cdr)?;
-                                let res = Object::new_undefined();
-                                let mut exps = &**cdr;
-                                loop {
-                                    match exps {
-                                        Token::Empty => break,
-                                        Token::Pair{car: exp, cdr: next_exps} => {    
-                                            if let Token::Empty = &**next_exps {
-                                                token = exp.as_ref().clone();
-                                                continue 'exp;
-                                            } else {
-                                                eval_exp(exp.as_ref().clone(), env.clone())?;
-                                                exps = &**next_exps;
-                                            }
-                                        }
-                                        _ => unreachable!()
-                                    }
-                                }
-                                break 'exp Ok(res);
-                            }
-                            "do" => {
-                                // (do (val_init_steps) (test exps) body)
-                                ensure_proper_list(cdr)?;
-                                let do_env = Environment::new(env.clone());
-
-                                let val_init_steps = cdr.elem().with_context(|| format!("syntax error: malformed do: {}", &token))?;
-                                if !val_init_steps.is_list() {
-                                    bail!("syntax error: malformed do: {}", &token);
-                                }
-                                let mut vals: Vec<String> = Vec::new();
-                                let mut steps: Vec<&Token> = Vec::new();
-                                for val_init_step in val_init_steps {
-                                    let val = val_init_step.nth(0).with_context(|| format!("syntax error: malformed do: {}", &token))?;
-                                    let init = val_init_step.nth(1).with_context(|| format!("syntax error: malformed do: {}", &token))?;
-                                    let step = val_init_step.nth(2).with_context(|| format!("syntax error: malformed do: {}", &token))?;
-                                    if let Token::Id(id) = val {
-                                        vals.push(id.clone());
-                                        do_env.insert(id.clone(), eval_exp(init.clone(), env.clone())?);
-                                    } else {
-                                        bail!("syntax error: malformed do: {}", &token);
-                                    }
-                                    steps.push(step);
-                                    if let Some(_) = val_init_step.nth(3) {
-                                        bail!("syntax error: malformed do: {}", &token);
-                                    }
-                                }
-
-                                let test_exp = cdr
-                                    .next()
-                                    .with_context(|| format!("syntax error: malformed do: {}", &token))?
-                                    .elem()
-                                    .with_context(|| format!("syntax error: malformed do: {}", &token))?;
-                                if !test_exp.is_list() {
-                                    bail!("syntax error: malformed do: {}", &token);
-                                }
-                                let test = test_exp.elem().with_context(|| format!("syntax error: malformed do: {}", &token))?;
-
-                                let cmds = cdr
-                                    .next()
-                                    .unwrap()
-                                    .next()
-                                    .with_context(|| format!("syntax error: malformed do: {}", &token))?;
-                                
-                                while eval_exp(test.clone(), do_env.clone())?.is_falsy() {
-                                    eval_body(cmds, do_env.clone())?;
-                                    for i in 0..vals.len() {
-                                        do_env.insert(
-                                            vals.get(i).unwrap().clone(),
-                                            eval_exp((*steps.get(i).unwrap()).clone(), do_env.clone())?
-                                        );
-                                    }
-                                }
-
-                                let mut res = Object::new_undefined();
-                                let mut exps = test_exp.next().unwrap();
-                                loop {
-                                    match exps {
-                                        Token::Empty => break,
-                                        Token::Pair{car: exp, cdr: next_exps} => {
-                                            if let Token::Empty = &**next_exps {
-                                                token = exp.as_ref().clone();
-                                                continue 'exp;
-                                            } else {
-                                                res = eval_exp(exp.as_ref().clone(), do_env.clone())?;
-                                                exps = &**next_exps;
-                                            }
-                                        }
-                                        _ => unreachable!()
-                                    }
-                                }
-                                break 'exp Ok(res);
-                            }
-                            _ => break 'exp Err(anyhow!("unbound variable: {}", id)),
-                        }
+/**
+ * Machine: evaluates compiled code with an explicit continuation.
+ * The continuation is a chain of frames in Memory instead of the Rust stack,
+ * so that call/cc can capture it and resume it any number of times.
+ */
+struct Machine {
+    k: Cont,            // frames waiting for the value of the current expression
+    winders: Object,    // active dynamic-wind entries: list of (before . after), the innermost first
+    pos: Option<Pos>,   // position of the current step (for error messages)
+    global: Environment,
+    vm: bool,           // code is run on the VM
+}
+
+enum State {
+    Eval(Rc<Code>, Environment),
+    Return(Object),
+}
+
+/**
+ * Vm: registers of the VM running a chunk.
+ * "pc" is the instruction being run, and "stack" has the operands of the instructions after it.
+ */
+struct Vm {
+    chunk: Rc<Chunk>,
+    pc: usize,
+    env: Environment,
+    stack: Vec<Object>,
+}
+
+impl Machine {
+    fn run(code: Rc<Code>, env: Environment) -> Result<Object> {
+        let mut m = Machine{k: Cont::base(), winders: Object::new_empty(), pos: code.pos.clone(), global: env.clone(), vm: vm::is_enabled()};
+        let mut state = Ok(State::Eval(code, env));
+        loop {
+            // the heap has been exhausted by the last step
+            if Memory::take_exhausted() {
+                state = Err(out_of_memory());
+            }
+            state = match state {
+                Ok(State::Eval(code, env)) => m.eval(code, env),
+                Ok(State::Return(obj)) => {
+                    // continuations captured by an earlier evaluation also end at its base,
+                    // and their value becomes the value of this evaluation
+                    if let Frame::Base = m.k.frame() {
+                        return Ok(obj);
                     }
-                }, 
-                Token::Pair{..} => {
-                    match eval_app(&token, car, cdr, env)? {
-                        AppResult::Proc((t, e)) => {
-                            token = t;
-                            env = e;
-                            continue 'exp;
-                        }
-                        AppResult::Subr(res) => {
-                            break 'exp Ok(res);
+                    m.resume(obj)
+                }
+                // errors are raised as error objects, which handlers can catch
+                Err(err) if find_handler(&m.k).is_some() => {
+                    let obj = Object::new_error(err.to_string(), Object::new_empty());
+                    let call = Token::Id(Symbol::intern("raise"), m.pos.clone());
+                    m.raise(obj, false, &call)
+                }
+                Err(err) => {
+                    let k = m.k.clone();
+                    return Err(error::locate(error::with_trace(err, || trace::backtrace(&k)), m.pos.as_ref()));
+                }
+            }
+        }
+    }
+
+    #[inline]
+    fn push(&mut self, frame: Frame) {
+        self.k = self.k.push(frame);
+    }
+
+    fn eval(&mut self, code: Rc<Code>, env: Environment) -> Result<State> {
+        self.pos = code.pos.clone();
+        if self.vm {
+            return self.exec(Vm{chunk: vm::chunk(&code), pc: 0, env, stack: Vec::new()});
+        }
+        if let Some(obj) = self.eval_atom(&code, &env) {
+            return Ok(State::Return(obj?));
+        }
+        match &code.op {
+            Op::Set(addr, id, exp) => {
+                self.push(Frame::Set{addr: *addr, id: id.clone(), env: env.raw()});
+                Ok(State::Eval(exp.clone(), env))
+            }
+            Op::Define(index, id, exp) => match exp {
+                Some(exp) => {
+                    self.push(Frame::Define{index: *index, id: id.clone(), env: env.raw()});
+                    Ok(State::Eval(exp.clone(), env))
+                }
+                None => self.define(*index, id, Object::new_undefined(), &env),
+            }
+            Op::Lambda(lambda) => Ok(State::Return(Object::new_procedure(env, lambda.clone()))),
+            Op::If(test, then, els) => match self.eval_atom(test, &env) {
+                Some(val) => {
+                    let val = val.inspect_err(|_| self.pos = test.pos.clone())?;
+                    self.branch(val, then, els, env)
+                }
+                None => {
+                    self.push(Frame::If{then: then.clone(), els: els.clone(), env: env.raw()});
+                    Ok(State::Eval(test.clone(), env))
+                }
+            }
+            Op::Seq(codes) => self.seq(codes.clone(), 0, env),
+            Op::App(app) => self.eval_args(app.clone(), Vec::with_capacity(app.exps.len()), env),
+            Op::Let(lt) => self.eval_let(lt.clone(), Vec::new(), env),
+            Op::Letrec(lt) => {
+                let env = Environment::new_frame(&env, &[], lt.body.size);
+                self.eval_letrec(lt.clone(), 0, env)
+            }
+            Op::Cond(clauses) => self.eval_cond(clauses.clone(), 0, env),
+            Op::And(tests) => self.eval_and(tests.clone(), 0, env),
+            Op::Or(tests) => self.eval_or(tests.clone(), 0, env),
+            Op::Do(lp) => self.eval_do_init(lp.clone(), Vec::new(), env),
+            Op::Guard(guard) => {
+                let body = Environment::new_frame(&env, &[], guard.body.size);
+                self.push(Frame::Guard{guard: guard.clone(), env: env.raw(), winders: self.winders.raw()});
+                Ok(State::Eval(guard.body.body.clone(), body))
+            }
+            Op::Quasi(quasi) => self.eval_quasi(quasi.clone(), Vec::new(), env),
+            Op::Error(message) => Err(anyhow!("{}", message)),
+            Op::Const(_) | Op::Local(..) | Op::Global(_) => unreachable!(),
+        }
+    }
+
+    // the value of code which needs no frame, i.e. a constant, a variable,
+    // or a call of a subroutine with constants and variables
+    fn eval_atom(&mut self, code: &Code, env: &Environment) -> Option<Result<Object>> {
+        Some(match &code.op {
+            Op::Const(obj) => Ok(obj.clone()),
+            &Op::Local(depth, index, ref id) => {
+                env.get(depth, index).ok_or_else(|| anyhow!("unbound variable: {}", written_name(id)))
+            }
+            Op::Global(id) => self.global.lookup(id).ok_or_else(|| anyhow!("unbound variable: {}", written_name(id))),
+            Op::App(app) if app.is_flat => {
+                // nothing is evaluated unless the operator is a subroutine
+                let proc = match self.eval_atom(&app.exps[0], env)? {
+                    Ok(proc) if matches!(proc.kind(), Kind::Procedure(Procedure::Subr(_))) => proc,
+                    _ => return None,
+                };
+                let mut args = VecDeque::with_capacity(app.exps.len() - 1);
+                for exp in &app.exps[1..] {
+                    match self.eval_atom(exp, env).unwrap() {
+                        Ok(obj) => args.push_back(obj),
+                        Err(err) => {
+                            self.pos = exp.pos.clone();
+                            return Some(Err(err));
                         }
                     }
                 }
-                _ => {
-                    break 'exp Err(anyhow!("invalid application: {}", &token))
+                match proc.kind() {
+                    Kind::Procedure(Procedure::Subr(subr)) => {
+                        check_arity(subr.is_variadic, subr.require, args.len())
+                            .and_then(|_| (subr.fun)(args))
+                            .inspect_err(|_| self.pos = app.call.pos().cloned())
+                    }
+                    _ => unreachable!(),
                 }
             }
+            _ => return None,
+        })
+    }
+
+    fn branch(&mut self, val: Object, then: &Rc<Code>, els: &Option<Rc<Code>>, env: Environment) -> Result<State> {
+        if !val.is_falsy() {
+            Ok(State::Eval(then.clone(), env))
+        } else if let Some(els) = els {
+            Ok(State::Eval(els.clone(), env))
+        } else {
+            Ok(State::Return(Object::new_undefined()))
         }
     }
-}
 
-fn eval_quote(token: &Token) -> Result<Object> {
-    // "token" must be elements of Token::Symbol
-    match token {
-        &Token::Int(i) => Ok(Object::new_int(i, false)),
-        &Token::Float(f) => Ok(Object::new_float(f, false)),
-        &Token::Boolean(b) => Ok(Object::new_boolean(b, false)),
-        Token::String(s) => Ok(Object::new_string(s.clone(), false)),
-        &Token::Empty => Ok(Object::new_empty()),
-        Token::Symbol(_) => Ok(Object::new_symbol(format!("{}", token), false)),
-        Token::Id(id) => Ok(Object::new_symbol(format!("{}", id), false)),
-        Token::Pair{car, cdr} => Ok(Object::new_pair(
-            eval_quote(&**car)?, 
-            eval_quote(&**cdr)?, 
-            false
-        )),
+    fn define(&mut self, index: Option<usize>, id: &Symbol, val: Object, env: &Environment) -> Result<State> {
+        match index {
+            Some(index) => env.set(0, index, val),
+            None => env.insert(id.clone(), val),
+        }
+        Ok(State::Return(Object::new_symbol(written_symbol(id))))
     }
-}
 
-fn eval_app(token: &Token, proc: &Token, args: &Token, env: Environment) -> Result<AppResult> {
-    // argument "token" is for error messages
-    ensure_proper_list(args)?;
-    let proc = eval_exp(proc.clone(), env.clone())?;
-    let mut args: VecDeque<Object> = args
-        .into_iter()
-        .map(|t| -> Result<Object> {eval_exp(t.clone(), env.clone())})
-        .collect::<Result<VecDeque<Object>>>()?;
-    
-    match proc.kind() {
-        Kind::Procedure(proc) => {
-            match proc {
-                Procedure::Proc(proc) => {
-                    let new_env = Environment::new(proc.env());
-                    if !proc.is_variadic && proc.require != args.len() {
-                        bail!("wrong number of arguments (required {}, got {})", proc.require, args.len());  
-                    } 
-                    if proc.require > args.len() {
-                        bail!("wrong number of arguments (required {}, got {})", proc.require, args.len());
+    // evaluate "codes" from "next"; the last one is in tail position
+    fn seq(&mut self, codes: Rc<[Rc<Code>]>, next: usize, env: Environment) -> Result<State> {
+        let code = codes[next].clone();
+        if next + 1 < codes.len() {
+            self.push(Frame::Seq{codes, next: next + 1, env: env.raw()});
+        }
+        Ok(State::Eval(code, env))
+    }
+
+    // the expressions unquoted in the template are evaluated after "done", then the template is filled with them
+    fn eval_quasi(&mut self, quasi: Rc<Quasi>, mut done: Vec<Object>, env: Environment) -> Result<State> {
+        while let Some(exp) = quasi.exps.get(done.len()) {
+            match self.eval_atom(exp, &env) {
+                Some(Ok(obj)) => done.push(obj),
+                Some(Err(err)) => {
+                    self.pos = exp.pos.clone();
+                    return Err(err);
+                }
+                None => {
+                    let exp = exp.clone();
+                    self.push(Frame::Quasi{quasi, done: done.iter().map(Object::raw).collect(), env: env.raw()});
+                    return Ok(State::Eval(exp, env));
+                }
+            }
+        }
+        let obj = fill_template(&quasi.template, &mut done.into_iter())?;
+        Ok(State::Return(obj))
+    }
+
+    // operator and operands after "done" are evaluated, then applied
+    fn eval_args(&mut self, app: Rc<App>, mut done: Vec<Object>, env: Environment) -> Result<State> {
+        while let Some(exp) = app.exps.get(done.len()) {
+            match self.eval_atom(exp, &env) {
+                Some(Ok(obj)) => done.push(obj),
+                Some(Err(err)) => {
+                    // an unbound operator is reported at the application
+                    if !done.is_empty() {
+                        self.pos = exp.pos.clone();
                     }
+                    return Err(err);
+                }
+                None => {
+                    let exp = exp.clone();
+                    self.push(Frame::Args{app, done: done.iter().map(Object::raw).collect(), env: env.raw()});
+                    return Ok(State::Eval(exp, env));
+                }
+            }
+        }
+        let mut args = VecDeque::from(done);
+        let proc = args.pop_front().unwrap();
+        self.apply(proc, args, &app.call)
+    }
+
+    fn apply(&mut self, proc: Object, mut args: VecDeque<Object>, call: &Token) -> Result<State> {
+        // argument "call" is for error messages and call stacks
+        self.pos = call.pos().cloned();
+        match proc.kind() {
+            Kind::Procedure(Procedure::Proc(proc)) => {
+                let new_env = bind_args(proc, args)?;
+                self.enter(proc.name.clone(), call);
+                Ok(State::Eval(proc.lambda.body.clone(), new_env))
+            }
+            Kind::Procedure(Procedure::Subr(subr)) => {
+                check_arity(subr.is_variadic, subr.require, args.len())?;
+                Ok(State::Return((subr.fun)(args)?))
+            }
+            Kind::Procedure(Procedure::Ctrl(ctrl)) => {
+                check_arity(ctrl.is_variadic, ctrl.require, args.len())?;
+                let ctrl = (ctrl.fun)(args)?;
+                self.control(ctrl, call)
+            }
+            Kind::Procedure(Procedure::Cont(cont)) => {
+                if args.len() > 1 {
+                    bail!("wrong number of arguments (required 0 or 1, got {})", args.len());
+                }
+                let value = args.pop_front().unwrap_or_else(Object::new_undefined);
+                self.throw(cont.k(), cont.winders(), value, call)
+            }
+            _ => Err(anyhow!("invalid application: {}", call))
+        }
+    }
+
+    // do what a control procedure asks
+    fn control(&mut self, ctrl: Control, call: &Token) -> Result<State> {
+        match ctrl {
+            Control::CallCC(proc) => {
+                let k = Object::new_continuation(&self.k, &self.winders);
+                self.apply(proc, VecDeque::from([k]), call)
+            }
+            Control::DynamicWind(before, thunk, after) => {
+                self.push(Frame::WindBefore{
+                    call: call.clone(),
+                    before: before.raw(),
+                    thunk: thunk.raw(),
+                    after: after.raw(),
+                });
+                self.apply(before, VecDeque::new(), call)
+            }
+            Control::WithHandler(handler, thunk) => {
+                self.push(Frame::Handler{handler: handler.raw()});
+                self.apply(thunk, VecDeque::new(), call)
+            }
+            Control::Raise(obj, continuable) => self.raise(obj, continuable, call),
+            Control::Apply(proc, args) => self.apply(proc, args, call),
+            Control::Call(proc, args, next, state) => {
+                self.push(Frame::Native{call: call.clone(), next, state: state.iter().map(Object::raw).collect()});
+                self.apply(proc, args, call)
+            }
+            Control::Return(obj) => Ok(State::Return(obj)),
+        }
+    }
+
+    // record a call of a scheme procedure in the continuation
+    fn enter(&mut self, name: Option<Arc<str>>, call: &Token) {
+        let call = Call{name, site: call.pos().cloned()};
+        if let Frame::Call(frame) = self.k.frame() {
+            // a tail call: the caller has nothing left to do but return
+            let frame = CallFrame{call: frame.call.clone(), tail: Some(call), tail_calls: frame.tail_calls + 1};
+            self.k = self.k.parent().unwrap().push(Frame::Call(frame));
+        } else {
+            self.push(Frame::Call(CallFrame{call, tail: None, tail_calls: 0}));
+        }
+    }
+
+    // pass "value" to continuation "target",
+    // calling after thunks of the entries left and before thunks of the entries entered
+    fn throw(&mut self, target: Cont, winders: Object, value: Object, call: &Token) -> Result<State> {
+        let from = tails(&self.winders);
+        let to = tails(&winders);
+        let mut common = 0;
+        while common < from.len() && common < to.len()
+            && from[from.len() - 1 - common].scm_eq(&to[to.len() - 1 - common]) {
+            common += 1;
+        }
+
+        // each thunk is called outside of its own entry
+        let mut steps = Vec::new();
+        for tail in &from[..from.len() - common] {
+            let (entry, outside) = uncons(tail);
+            steps.push((uncons(&entry).1, outside));
+        }
+        for tail in to[..to.len() - common].iter().rev() {
+            let (entry, outside) = uncons(tail);
+            steps.push((uncons(&entry).0, outside));
+        }
 
-                    for i in 0..proc.require {
-                        new_env.insert(proc.args.get(i).unwrap().clone(), args.pop_front().unwrap());
+        if steps.is_empty() {
+            self.winders = winders;
+            self.k = target;
+            return Ok(State::Return(value));
+        }
+        self.push(Frame::Reroot{
+            call: call.clone(),
+            steps: steps.iter().map(|(thunk, winders)| (thunk.raw(), winders.raw())).collect(),
+            target: target.raw(),
+            winders: winders.raw(),
+            value: value.raw(),
+        });
+        Ok(State::Return(Object::new_undefined()))
+    }
+
+    // pass "obj" to the innermost handler, which is called with the handlers outside it
+    fn raise(&mut self, obj: Object, continuable: bool, call: &Token) -> Result<State> {
+        let handler = match find_handler(&self.k) {
+            Some(handler) => handler,
+            None => {
+                self.pos = call.pos().cloned();
+                return Err(uncaught(&obj));
+            }
+        };
+        let outer = handler.parent().unwrap();
+        self.push(Frame::Raised{outer: outer.raw()});
+        if !continuable {
+            self.push(Frame::NonContinuable{call: call.clone(), obj: obj.raw()});
+        }
+        match handler.frame() {
+            Frame::Handler{handler} => self.apply(Object::from_raw(*handler), VecDeque::from([obj]), call),
+            Frame::Guard{guard, env, winders} => {
+                // leave the body of guard, remembering where "obj" was raised
+                let g = Guarded{
+                    guard: guard.clone(),
+                    env: *env,
+                    condition: obj.raw(),
+                    raise_call: call.clone(),
+                    raise_k: self.k.raw(),
+                    raise_winders: self.winders.raw(),
+                };
+                let target = outer.push(Frame::GuardBody{g: Rc::new(g)});
+                self.throw(target, Object::from_raw(*winders), obj, call)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    // pass "val" to the innermost frame
+    fn resume(&mut self, val: Object) -> Result<State> {
+        // "k" keeps the frame and what it refers to alive while they are used
+        let k = self.k.clone();
+        self.k = k.parent().unwrap();
+        match k.frame() {
+            Frame::Base => unreachable!(),
+            Frame::Call(_) => Ok(State::Return(val)),
+            Frame::If{then, els, env} => self.branch(val, then, els, Environment::from_raw(*env)),
+            Frame::Seq{codes, next, env} => self.seq(codes.clone(), *next, Environment::from_raw(*env)),
+            Frame::Args{app, done, env} => {
+                let mut done: Vec<Object> = done.iter().map(|re| Object::from_raw(*re)).collect();
+                done.push(val);
+                self.eval_args(app.clone(), done, Environment::from_raw(*env))
+            }
+            Frame::Quasi{quasi, done, env} => {
+                let mut done: Vec<Object> = done.iter().map(|re| Object::from_raw(*re)).collect();
+                done.push(val);
+                self.eval_quasi(quasi.clone(), done, Environment::from_raw(*env))
+            }
+            Frame::Define{index, id, env} => {
+                val.set_proc_name(id);
+                self.define(*index, id, val, &Environment::from_raw(*env))
+            }
+            Frame::Set{addr: Some((depth, index)), env, ..} => {
+                Environment::from_raw(*env).set(*depth, *index, val);
+                Ok(State::Return(Object::new_undefined()))
+            }
+            Frame::Set{addr: None, id, ..} => {
+                if self.global.lookup(id).is_some() {
+                    self.global.insert(id.clone(), val);
+                    Ok(State::Return(Object::new_undefined()))
+                } else {
+                    Err(anyhow!("symbol not defined: {}", written_name(id)))
+                }
+            }
+            Frame::Let{lt, done, env} => {
+                let mut done: Vec<Object> = done.iter().map(|re| Object::from_raw(*re)).collect();
+                done.push(val);
+                self.eval_let(lt.clone(), done, Environment::from_raw(*env))
+            }
+            Frame::Letrec{lt, next, env} => {
+                let env = Environment::from_raw(*env);
+                val.set_proc_name(&lt.ids[*next]);
+                env.set(0, *next, val);
+                self.eval_letrec(lt.clone(), next + 1, env)
+            }
+            Frame::Cond{clauses, next, env} => {
+                let env = Environment::from_raw(*env);
+                if !val.is_falsy() {
+                    Ok(State::Eval(clauses[*next].body.clone(), env))
+                } else {
+                    self.eval_cond(clauses.clone(), next + 1, env)
+                }
+            }
+            Frame::And{tests, next, env} => {
+                if val.is_falsy() {
+                    Ok(State::Return(val))
+                } else {
+                    self.eval_and(tests.clone(), *next, Environment::from_raw(*env))
+                }
+            }
+            Frame::Or{tests, next, env} => {
+                if !val.is_falsy() {
+                    Ok(State::Return(val))
+                } else {
+                    self.eval_or(tests.clone(), *next, Environment::from_raw(*env))
+                }
+            }
+            Frame::DoInit{lp, done, env} => {
+                let mut done: Vec<Object> = done.iter().map(|re| Object::from_raw(*re)).collect();
+                done.push(val);
+                self.eval_do_init(lp.clone(), done, Environment::from_raw(*env))
+            }
+            Frame::DoTest{lp, env} => {
+                let env = Environment::from_raw(*env);
+                if !val.is_falsy() {
+                    match &lp.exps {
+                        Some(exps) => Ok(State::Eval(exps.clone(), env)),
+                        None => Ok(State::Return(Object::new_undefined())),
                     }
-                    if proc.is_variadic {
-                        let mut variadic = Object::new_empty();
-                        for _ in 0..args.len() {
-                            variadic = Object::new_pair(
-                                args.pop_back().unwrap(),
-                                variadic,
-                                true,
-                            );
+                } else {
+                    match &lp.cmds {
+                        Some(cmds) => {
+                            self.push(Frame::DoCmds{lp: lp.clone(), env: env.raw()});
+                            Ok(State::Eval(cmds.clone(), env))
                         }
-                        new_env.insert(proc.args.get(proc.require).unwrap().clone(), variadic);
+                        None => self.eval_do_step(lp.clone(), Vec::new(), env),
                     }
+                }
+            }
+            Frame::DoCmds{lp, env} => {
+                self.eval_do_step(lp.clone(), Vec::new(), Environment::from_raw(*env))
+            }
+            Frame::DoStep{lp, done, env} => {
+                let mut done: Vec<Object> = done.iter().map(|re| Object::from_raw(*re)).collect();
+                done.push(val);
+                self.eval_do_step(lp.clone(), done, Environment::from_raw(*env))
+            }
+            Frame::WindBefore{call, before, thunk, after} => {
+                let thunk = Object::from_raw(*thunk);
+                let entry = Object::new_pair(Object::from_raw(*before), Object::from_raw(*after), false);
+                let outside = self.winders.clone();
+                self.winders = Object::new_pair(entry, outside.clone(), false);
+                self.push(Frame::WindBody{call: call.clone(), after: *after, winders: outside.raw()});
+                self.apply(thunk, VecDeque::new(), call)
+            }
+            Frame::WindBody{call, after, winders} => {
+                self.winders = Object::from_raw(*winders);
+                self.push(Frame::WindAfter{value: val.raw()});
+                self.apply(Object::from_raw(*after), VecDeque::new(), call)
+            }
+            Frame::WindAfter{value} => Ok(State::Return(Object::from_raw(*value))),
+            Frame::Reroot{call, steps, target, winders, value} => match steps.split_first() {
+                Some(((thunk, thunk_winders), rest)) => {
+                    let thunk = Object::from_raw(*thunk);
+                    self.winders = Object::from_raw(*thunk_winders);
+                    self.push(Frame::Reroot{
+                        call: call.clone(),
+                        steps: rest.to_vec(),
+                        target: *target,
+                        winders: *winders,
+                        value: *value,
+                    });
+                    self.apply(thunk, VecDeque::new(), call)
+                }
+                None => {
+                    self.winders = Object::from_raw(*winders);
+                    self.k = Cont::from_raw(*target);
+                    Ok(State::Return(Object::from_raw(*value)))
+                }
+            }
+            Frame::Handler{..} | Frame::Guard{..} | Frame::Raised{..} => Ok(State::Return(val)),
+            Frame::NonContinuable{call, obj} => {
+                self.pos = call.pos().cloned();
+                Err(anyhow!("exception handler returned from non-continuable raise: {}", Object::from_raw(*obj)))
+            }
+            Frame::GuardBody{g} => {
+                let env = Environment::new_frame(&Environment::from_raw(g.env), &[val], 1);
+                self.eval_guard(g.clone(), 0, env)
+            }
+            Frame::GuardClause{g, next, env} => {
+                let env = Environment::from_raw(*env);
+                if !val.is_falsy() {
+                    Ok(State::Eval(g.guard.clauses[*next].body.clone(), env))
+                } else {
+                    self.eval_guard(g.clone(), next + 1, env)
+                }
+            }
+            Frame::Reraise{call, obj} => self.raise(Object::from_raw(*obj), true, call),
+            Frame::Native{call, next, state} => {
+                let state = state.iter().map(|re| Object::from_raw(*re)).collect();
+                let ctrl = next(val, state)?;
+                self.control(ctrl, call)
+            }
+            Frame::Vm{chunk, pc, env, stack} => {
+                let mut stack: Vec<Object> = stack.iter().map(|re| Object::from_raw(*re)).collect();
+                stack.push(val);
+                self.exec(Vm{chunk: chunk.clone(), pc: *pc, env: Environment::from_raw(*env), stack})
+            }
+        }
+    }
 
-                    Ok(AppResult::Proc(eval_body(&proc.body, new_env)?))
+    // run instructions until the VM has to leave the chunks to the machine,
+    // e.g. to call a control procedure, or to return to a frame which is not of the VM
+    fn exec(&mut self, mut vm: Vm) -> Result<State> {
+        let res = self.run_vm(&mut vm);
+        if res.is_err() {
+            self.pos = vm.chunk.pos[vm.pc].clone();
+        }
+        res
+    }
+
+    fn run_vm(&mut self, vm: &mut Vm) -> Result<State> {
+        // "chunk" keeps the instruction alive while the VM moves to another chunk
+        let mut chunk = vm.chunk.clone();
+        loop {
+            if !Rc::ptr_eq(&chunk, &vm.chunk) {
+                chunk = vm.chunk.clone();
+            }
+            match &chunk.insts[vm.pc] {
+                Inst::Const(obj) => vm.stack.push(obj.clone()),
+                &Inst::Local(depth, index, ref id) => {
+                    let obj = vm.env.get(depth, index).ok_or_else(|| anyhow!("unbound variable: {}", written_name(id)))?;
+                    vm.stack.push(obj);
+                }
+                Inst::Global(id) => {
+                    let obj = self.global.lookup(id).ok_or_else(|| anyhow!("unbound variable: {}", written_name(id)))?;
+                    vm.stack.push(obj);
+                }
+                &Inst::SetLocal(depth, index) => {
+                    let val = vm.stack.pop().unwrap();
+                    vm.env.set(depth, index, val);
+                    vm.stack.push(Object::new_undefined());
+                }
+                Inst::SetGlobal(id) => {
+                    let val = vm.stack.pop().unwrap();
+                    if self.global.lookup(id).is_none() {
+                        bail!("symbol not defined: {}", written_name(id));
+                    }
+                    self.global.insert(id.clone(), val);
+                    vm.stack.push(Object::new_undefined());
+                }
+                Inst::Define(index, id) => {
+                    let val = vm.stack.pop().unwrap();
+                    val.set_proc_name(id);
+                    match index {
+                        Some(index) => vm.env.set(0, *index, val),
+                        None => vm.env.insert(id.clone(), val),
+                    }
+                    vm.stack.push(Object::new_symbol(written_symbol(id)));
+                }
+                &Inst::Bind(index, ref id) => {
+                    let val = vm.stack.pop().unwrap();
+                    val.set_proc_name(id);
+                    vm.env.set(0, index, val);
+                }
+                Inst::Closure(lambda) => vm.stack.push(Object::new_procedure(vm.env.clone(), lambda.clone())),
+                Inst::NamedLet(lt) => {
+                    let name_env = Environment::new_frame(&vm.env, &[], 1);
+                    let proc = Object::new_procedure(name_env.clone(), lt.body.clone());
+                    proc.set_proc_name(lt.name.as_ref().unwrap());
+                    name_env.set(0, 0, proc.clone());
+                    vm.stack.push(proc);
+                }
+                Inst::Pop => {
+                    vm.stack.pop();
+                }
+                &Inst::Jump(to) => {
+                    vm.pc = to;
+                    continue;
+                }
+                &Inst::JumpIfFalse(to) => {
+                    if vm.stack.pop().unwrap().is_falsy() {
+                        vm.pc = to;
+                        continue;
+                    }
+                }
+                &Inst::And(to) | &Inst::Or(to) => {
+                    let is_and = matches!(chunk.insts[vm.pc], Inst::And(_));
+                    if vm.stack.last().unwrap().is_falsy() == is_and {
+                        vm.pc = to;
+                        continue;
+                    }
+                    vm.stack.pop();
                 }
-                Procedure::Subr(subr) => {
-                    if !subr.is_variadic && subr.require != args.len() {
-                        bail!("wrong number of arguments (required {}, got {})", subr.require, args.len());
+                &Inst::Enter(n, size) => {
+                    let vals = vm.stack.split_off(vm.stack.len() - n);
+                    vm.env = Environment::new_frame(&vm.env, &vals, size);
+                }
+                Inst::Leave => vm.env = vm.env.parent().unwrap(),
+                &Inst::Rebind(n) => {
+                    if Memory::take_exhausted() {
+                        return Err(out_of_memory());
                     }
-                    if subr.require > args.len() {
-                        bail!("wrong number of arguments (required {}, got {})", subr.require, args.len());
+                    let vals = vm.stack.split_off(vm.stack.len() - n);
+                    vm.env = Environment::new_frame(&vm.env.parent().unwrap(), &vals, n);
+                }
+                Inst::Quasi(quasi) => {
+                    let vals = vm.stack.split_off(vm.stack.len() - quasi.exps.len());
+                    let obj = fill_template(&quasi.template, &mut vals.into_iter())?;
+                    vm.stack.push(obj);
+                }
+                Inst::Guard(guard) => {
+                    self.save(vm);
+                    let body = Environment::new_frame(&vm.env, &[], guard.body.size);
+                    self.push(Frame::Guard{guard: guard.clone(), env: vm.env.raw(), winders: self.winders.raw()});
+                    return Ok(State::Eval(guard.body.body.clone(), body));
+                }
+                Inst::Call(argc, call) | Inst::TailCall(argc, call) => {
+                    if Memory::take_exhausted() {
+                        return Err(out_of_memory());
                     }
+                    let tail = matches!(chunk.insts[vm.pc], Inst::TailCall(..));
+                    let args: VecDeque<Object> = vm.stack.drain(vm.stack.len() - argc..).collect();
+                    let proc = vm.stack.pop().unwrap();
+                    match proc.kind() {
+                        Kind::Procedure(Procedure::Subr(subr)) => {
+                            check_arity(subr.is_variadic, subr.require, args.len())?;
+                            let val = (subr.fun)(args)?;
+                            if !tail {
+                                vm.stack.push(val);
+                            } else if let Some(val) = self.ret(vm, val) {
+                                return Ok(State::Return(val));
+                            } else {
+                                continue;
+                            }
+                        }
+                        Kind::Procedure(Procedure::Proc(proc)) => {
+                            let new_env = bind_args(proc, args)?;
+                            if !tail {
+                                self.save(vm);
+                            }
+                            self.enter(proc.name.clone(), call);
+                            // the stack has been saved, or is dropped by the tail call
+                            vm.chunk = vm::chunk(&proc.lambda.body);
+                            vm.pc = 0;
+                            vm.env = new_env;
+                            vm.stack.clear();
+                            continue;
+                        }
+                        _ => {
+                            if !tail {
+                                self.save(vm);
+                            }
+                            return self.apply(proc, args, call);
+                        }
+                    }
+                }
+                Inst::Return => {
+                    let val = vm.stack.pop().unwrap();
+                    match self.ret(vm, val) {
+                        Some(val) => return Ok(State::Return(val)),
+                        None => continue,
+                    }
+                }
+                Inst::Error(message) => bail!("{}", message),
+            }
+            vm.pc += 1;
+        }
+    }
 
-                    Ok(AppResult::Subr((subr.fun)(args)?))
+    // the rest of the chunk after the current instruction waits for a value
+    fn save(&mut self, vm: &Vm) {
+        self.push(Frame::Vm{
+            chunk: vm.chunk.clone(),
+            pc: vm.pc + 1,
+            env: vm.env.raw(),
+            stack: vm.stack.iter().map(Object::raw).collect(),
+        });
+    }
+
+    // return "val" from a chunk. If a chunk waits for it, the VM goes on with that chunk;
+    // otherwise "val" is returned to the machine.
+    fn ret(&mut self, vm: &mut Vm, val: Object) -> Option<Object> {
+        loop {
+            let k = self.k.clone();
+            match k.frame() {
+                Frame::Call(_) => self.k = k.parent().unwrap(),
+                Frame::Vm{chunk, pc, env, stack} => {
+                    vm.stack.clear();
+                    vm.stack.extend(stack.iter().map(|re| Object::from_raw(*re)));
+                    vm.stack.push(val);
+                    vm.chunk = chunk.clone();
+                    vm.pc = *pc;
+                    vm.env = Environment::from_raw(*env);
+                    self.k = k.parent().unwrap();
+                    return None;
                 }
+                _ => return Some(val),
             }
         }
-        _ => Err(anyhow!("invalid application: {}", token))
     }
-}
 
-fn eval_lambda(mut arg: &Token, body: &Token, env: Environment) -> Result<Object> {
-    let mut args = Vec::new();
-    while let Some(id) = arg.elem() {
-        match id {
-            Token::Id(id) => args.push(id.clone()),
-            _ => bail!("syntax error: identifier required, but got {}", id),
+    // inits of let after "done" are evaluated.
+    // Then named let calls the procedure, and let evaluates the body.
+    fn eval_let(&mut self, lt: Rc<Let>, mut done: Vec<Object>, env: Environment) -> Result<State> {
+        while let Some(init) = lt.inits.get(done.len()) {
+            match self.eval_atom(init, &env) {
+                Some(Ok(obj)) => done.push(obj),
+                Some(Err(err)) => {
+                    self.pos = init.pos.clone();
+                    return Err(err);
+                }
+                None => {
+                    let init = init.clone();
+                    self.push(Frame::Let{lt, done: done.iter().map(Object::raw).collect(), env: env.raw()});
+                    return Ok(State::Eval(init, env));
+                }
+            }
+        }
+
+        match &lt.name {
+            Some(name) => {
+                let name_env = Environment::new_frame(&env, &[], 1);
+                let proc = Object::new_procedure(name_env.clone(), lt.body.clone());
+                proc.set_proc_name(name);
+                name_env.set(0, 0, proc.clone());
+                self.apply(proc, VecDeque::from(done), &lt.call)
+            }
+            None => {
+                let new_env = Environment::new_frame(&env, &done, lt.body.size);
+                Ok(State::Eval(lt.body.body.clone(), new_env))
+            }
+        }
+    }
+
+    // inits of letrec from "next" are evaluated in the frame they are bound in
+    fn eval_letrec(&mut self, lt: Rc<Let>, next: usize, env: Environment) -> Result<State> {
+        match lt.inits.get(next) {
+            Some(init) => {
+                let init = init.clone();
+                self.push(Frame::Letrec{lt, next, env: env.raw()});
+                Ok(State::Eval(init, env))
+            }
+            None => Ok(State::Eval(lt.body.body.clone(), env)),
+        }
+    }
+
+    fn eval_cond(&mut self, clauses: Rc<[Clause]>, next: usize, env: Environment) -> Result<State> {
+        match clauses.get(next) {
+            Some(Clause{test: None, body}) => Ok(State::Eval(body.clone(), env)),
+            Some(Clause{test: Some(test), ..}) => {
+                let test = test.clone();
+                self.push(Frame::Cond{clauses, next, env: env.raw()});
+                Ok(State::Eval(test, env))
+            }
+            None => Ok(State::Return(Object::new_undefined())),
         }
-        arg = arg.cdr().unwrap();
     }
-    let require = args.len();
-    match arg {
-        Token::Id(id) => {
-            args.push(id.clone());
-            Ok(Object::new_procedure(env, args, true, require, body.clone()))
+
+    // clauses of guard are like those of cond.
+    // If no clause is selected, the condition is raised again by raise-continuable
+    // in the dynamic environment where it was raised.
+    fn eval_guard(&mut self, g: Rc<Guarded>, next: usize, env: Environment) -> Result<State> {
+        match g.guard.clauses.get(next) {
+            Some(Clause{test: None, body}) => Ok(State::Eval(body.clone(), env)),
+            Some(Clause{test: Some(test), ..}) => {
+                let test = test.clone();
+                self.push(Frame::GuardClause{g, next, env: env.raw()});
+                Ok(State::Eval(test, env))
+            }
+            None => {
+                let target = Cont::from_raw(g.raise_k).push(Frame::Reraise{call: g.raise_call.clone(), obj: g.condition});
+                self.throw(target, Object::from_raw(g.raise_winders), Object::new_undefined(), &g.guard.call)
+            }
         }
-        Token::Empty => {
-            Ok(Object::new_procedure(env, args, false, require, body.clone()))
+    }
+
+    fn eval_and(&mut self, tests: Rc<[Rc<Code>]>, next: usize, env: Environment) -> Result<State> {
+        let test = tests[next].clone();
+        if next + 1 < tests.len() {
+            self.push(Frame::And{tests, next: next + 1, env: env.raw()});
+        }
+        Ok(State::Eval(test, env))
+    }
+
+    fn eval_or(&mut self, tests: Rc<[Rc<Code>]>, next: usize, env: Environment) -> Result<State> {
+        let test = tests[next].clone();
+        if next + 1 < tests.len() {
+            self.push(Frame::Or{tests, next: next + 1, env: env.raw()});
+        }
+        Ok(State::Eval(test, env))
+    }
+
+    fn eval_do_init(&mut self, lp: Rc<DoLoop>, done: Vec<Object>, env: Environment) -> Result<State> {
+        match lp.inits.get(done.len()) {
+            Some(init) => {
+                let init = init.clone();
+                self.push(Frame::DoInit{lp, done: done.iter().map(Object::raw).collect(), env: env.raw()});
+                Ok(State::Eval(init, env))
+            }
+            None => self.eval_do_test(lp, done, env),
+        }
+    }
+
+    // each iteration of do has its own frame in "env", where do is evaluated
+    fn eval_do_test(&mut self, lp: Rc<DoLoop>, vals: Vec<Object>, env: Environment) -> Result<State> {
+        let env = Environment::new_frame(&env, &vals, vals.len());
+        let test = lp.test.clone();
+        self.pos = lp.call.pos().cloned();
+        self.push(Frame::DoTest{lp, env: env.raw()});
+        Ok(State::Eval(test, env))
+    }
+
+    fn eval_do_step(&mut self, lp: Rc<DoLoop>, done: Vec<Object>, env: Environment) -> Result<State> {
+        match lp.steps.get(done.len()) {
+            Some(step) => {
+                let step = step.clone();
+                self.push(Frame::DoStep{lp, done: done.iter().map(Object::raw).collect(), env: env.raw()});
+                Ok(State::Eval(step, env))
+            }
+            None => self.eval_do_test(lp, done, env.parent().unwrap()),
         }
-        _ => Err(anyhow!("syntax error: identifier required, but got {}", arg))
     }
 }
 
-fn eval_body(mut token: &Token, env: Environment) -> Result<(Token, Environment)> {
-    ensure_proper_list(token)?;
-    // Define
-    let mut def = token.elem().context("syntax error: at least one expression is necessary")?;
+// the innermost handler in effect in "k", i.e. a Handler or Guard frame
+fn find_handler(k: &Cont) -> Option<Cont> {
+    let mut k = k.clone();
     loop {
-        if let Token::Pair{car, cdr: _} = def {
-            match &**car {
-                Token::Id(s) if s == "define" => {
-                    eval_define(def, env.clone())?;
-                    token = token.next().unwrap();
-                    def = token.elem().context("syntax error: at least one expression is necessary")?;
-                }
-                _ => break
+        k = match k.frame() {
+            Frame::Handler{..} | Frame::Guard{..} => return Some(k),
+            // handlers inside the one being called are not in effect
+            Frame::Raised{outer} => Cont::from_raw(*outer),
+            _ => k.parent()?,
+        };
+    }
+}
+
+// the error reported when nothing handles "obj"
+fn uncaught(obj: &Object) -> anyhow::Error {
+    match obj.kind() {
+        Kind::Error(err) => {
+            let mut message = err.message.clone();
+            for irritant in &err.irritants() {
+                message += &format!(" {}", irritant);
             }
-        } else {
-            break;
+            anyhow!(message)
         }
-    };
-    // Expression
-    loop {
-        let exp = token.elem().unwrap();
-        if let Token::Empty = token.next().unwrap() {
-            break Ok((exp.clone(), env));
+        _ => anyhow!("uncaught exception: {}", obj),
+    }
+}
+
+// a frame of "proc" where "args" are bound to its arguments
+fn bind_args(proc: &Proc, args: VecDeque<Object>) -> Result<Environment> {
+    let lambda = &proc.lambda;
+    check_arity(lambda.is_variadic, lambda.require, args.len())?;
+    let mut vals = Vec::from(args);
+    if lambda.is_variadic {
+        let mut variadic = Object::new_empty();
+        for _ in lambda.require..vals.len() {
+            variadic = Object::new_pair(
+                vals.pop().unwrap(),
+                variadic,
+                true,
+            );
+        }
+        vals.push(variadic);
+    }
+    Ok(Environment::new_frame(&proc.env(), &vals, lambda.size))
+}
+
+fn out_of_memory() -> anyhow::Error {
+    anyhow!("out of memory: the heap can not grow beyond {} objects", Memory::max_size())
+}
+
+fn check_arity(is_variadic: bool, require: usize, got: usize) -> Result<()> {
+    if (!is_variadic && require != got) || require > got {
+        bail!("wrong number of arguments (required {}, got {})", require, got);
+    }
+    Ok(())
+}
+
+// (a b c) -> [(a b c), (b c), (c)]
+fn tails(list: &Object) -> Vec<Object> {
+    let mut tails = Vec::new();
+    let mut list = list.clone();
+    while let Kind::Pair(_) = list.kind() {
+        let cdr = uncons(&list).1;
+        tails.push(list);
+        list = cdr;
+    }
+    tails
+}
+
+fn uncons(pair: &Object) -> (Object, Object) {
+    match pair.kind() {
+        Kind::Pair(pair) => (pair.car(), pair.cdr()),
+        _ => unreachable!(),
+    }
+}
+
+pub(crate) fn eval_quote(token: &Token) -> Result<Object> {
+    quote_datum(token, &mut HashMap::new())
+}
+
+// "labels" are the objects of datum labels (#n=) which have been read
+fn quote_datum(token: &Token, labels: &mut HashMap<usize, Object>) -> Result<Object> {
+    // "token" is a quoted datum
+    match token {
+        &Token::Int(i, _) => Ok(Object::new_int(i, false)),
+        Token::BigInt(i, _) => Ok(Object::new_number(Number::BigInt(i.clone()), false)),
+        Token::Rational(r, _) => Ok(Object::new_number(Number::Rational(r.clone()), false)),
+        &Token::Float(f, _) => Ok(Object::new_float(f, false)),
+        &Token::Boolean(b, _) => Ok(Object::new_boolean(b, false)),
+        &Token::Char(c, _) => Ok(Object::new_char(c)),
+        Token::String(s, _) => Ok(Object::new_string(s.clone(), false)),
+        &Token::Empty(_) => Ok(Object::new_empty()),
+        Token::Id(id, _) => Ok(Object::new_symbol(id.clone())),
+        Token::Pair{car, cdr, ..} => Ok(Object::new_pair(
+            quote_datum(car, labels)?, 
+            quote_datum(cdr, labels)?, 
+            false
+        )),
+        Token::Vector(elems, _) => Ok(Object::new_vector(
+            elems.iter().map(|t| quote_datum(t, labels)).collect::<Result<Vec<Object>>>()?,
+            false
+        )),
+        Token::Bytevector(bytes, _) => Ok(Object::new_bytevector(bytes.clone(), false)),
+        Token::Label(n, datum, _) => {
+            // references in the datum to itself point to a placeholder until the datum is made
+            let placeholder = Object::new_undefined();
+            labels.insert(*n, placeholder.clone());
+            let obj = quote_datum(datum, labels)?;
+            if obj.raw() == placeholder.raw() {
+                bail!("read error: datum label refers to itself: {}", token);
+            }
+            obj.replace_ref(&placeholder, &obj);
+            labels.insert(*n, obj.clone());
+            Ok(obj)
+        }
+        Token::LabelRef(n, _) => labels.get(n).cloned().ok_or_else(|| anyhow!("read error: undefined datum label: {}", token)),
+    }
+}
+
+// the template where the unquoted expressions are replaced with "vals"
+fn fill_template(template: &Template, vals: &mut impl Iterator<Item = Object>) -> Result<Object> {
+    // the elements of a list to be spliced
+    fn splice(list: Object) -> Result<Vec<Object>> {
+        match list.is_list() {
+            true => Ok(list.into_iter().collect()),
+            false => Err(anyhow!("proper list required for unquote-splicing, but got {}", list)),
+        }
+    }
+
+    match template {
+        Template::Datum(obj) => Ok(obj.clone()),
+        Template::Unquote | Template::UnquoteSplicing => Ok(vals.next().unwrap()),
+        Template::Pair(car, cdr) if matches!(**car, Template::UnquoteSplicing) => {
+            let elems = splice(vals.next().unwrap())?;
+            let rest = fill_template(cdr, vals)?;
+            Ok(elems.into_iter().rev().fold(rest, |rest, obj| Object::new_pair(obj, rest, true)))
+        }
+        Template::Pair(car, cdr) => {
+            let car = fill_template(car, vals)?;
+            let cdr = fill_template(cdr, vals)?;
+            Ok(Object::new_pair(car, cdr, true))
+        }
+        Template::Vector(tmpls) => {
+            let mut elems = Vec::new();
+            for tmpl in tmpls {
+                match tmpl {
+                    Template::UnquoteSplicing => elems.extend(splice(vals.next().unwrap())?),
+                    _ => elems.push(fill_template(tmpl, vals)?),
+                }
+            }
+            Ok(Object::new_vector(elems, true))
         }
-        eval_exp(exp.clone(), env.clone())?;
-        token = token.next().unwrap();
     }
 }
 
@@ -654,8 +1081,3 @@ pub fn ensure_proper_list(token: &Token) -> Result<()> {
         Ok(())
     }
 }
-
-enum AppResult {
-    Proc((Token, Environment)),
-    Subr(Object),
-}
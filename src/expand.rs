@@ -0,0 +1,951 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use anyhow::{Result, anyhow, bail};
+
+use crate::data::{*, object::*};
+use crate::token::*;
+use crate::symbol::Symbol;
+use crate::eval::ensure_proper_list;
+use crate::error;
+use crate::function::record;
+
+// The expander rewrites a token before it is evaluated, so that
+//  - every macro use is replaced by its expansion,
+//  - every local variable is renamed to a unique name (see written_name),
+//  - identifiers inserted by macros refer to the bindings where the macro was defined.
+// The result contains no macro uses and no aliases, and can be evaluated as it is.
+
+const SPECIAL_FORMS: [&str; 23] = [
+    "define", "load", "lambda", "quote", "set!", "let", "let*", "letrec", "if", "cond",
+    "and", "or", "begin", "do", "guard", "define-syntax", "let-syntax", "letrec-syntax", "syntax-rules",
+    "quasiquote", "unquote", "unquote-splicing", "define-record-type",
+];
+
+thread_local! {
+    // alias inserted by a macro -> the identifier in the template and where the macro was defined
+    static ALIASES: RefCell<HashMap<Symbol, (Symbol, Scope)>> = RefCell::new(HashMap::new());
+    static FRESH_ID: Cell<usize> = const { Cell::new(0) };
+}
+
+// renamed identifiers are uninterned, so that they are freed with the tokens
+fn fresh(id: &str) -> Symbol {
+    let n = FRESH_ID.with(|n| {
+        n.set(n.get() + 1);
+        n.get()
+    });
+    Symbol::uninterned(&format!("{}#{}", written_name(id), n))
+}
+
+/**
+ * Macro: a transformer made by syntax-rules, with the scope where it is defined.
+ */
+pub struct Macro {
+    ellipsis: Option<Symbol>,
+    literals: Vec<Symbol>,
+    rules: Vec<(Token, Token)>,     // (pattern, template)
+    scope: Scope,
+}
+
+#[derive(Clone)]
+enum Binding {
+    Var(Symbol),        // local variable, renamed
+    Macro(Rc<Macro>),
+    Free(Symbol),       // global variable or special form
+}
+
+/**
+ * Scope: local bindings of identifiers during expansion.
+ * The outermost scope is the global environment.
+ */
+#[derive(Clone, Default)]
+pub struct Scope(Option<Rc<ScopeFrame>>);
+
+pub struct ScopeFrame {
+    vars: RefCell<HashMap<Symbol, Binding>>,
+    parent: Scope,
+}
+
+impl Scope {
+    fn child(&self) -> Scope {
+        Scope(Some(Rc::new(ScopeFrame{vars: RefCell::new(HashMap::new()), parent: self.clone()})))
+    }
+
+    fn bind(&self, id: &Symbol, binding: Binding) {
+        if let Some(frame) = &self.0 {
+            frame.vars.borrow_mut().insert(id.clone(), binding);
+        }
+    }
+
+    fn get(&self, id: &Symbol) -> Option<Binding> {
+        let mut scope = self;
+        while let Some(frame) = &scope.0 {
+            if let Some(binding) = frame.vars.borrow().get(id) {
+                return Some(binding.clone());
+            }
+            scope = &frame.parent;
+        }
+        None
+    }
+}
+
+// what pattern variables matched
+#[derive(Clone)]
+enum Matched {
+    One(Token),
+    Many(Vec<Matched>),
+}
+
+// one use of a macro
+struct Instance<'a> {
+    mac: &'a Macro,
+    aliases: HashMap<Symbol, Symbol>,   // the same identifier in a template gets the same alias
+    pos: Option<Pos>,
+}
+
+pub fn expand(token: &Token, env: &Environment) -> Result<Token> {
+    Expander{env: env.clone()}.toplevel(token)
+}
+
+struct Expander {
+    env: Environment,   // global environment
+}
+
+impl Expander {
+    fn toplevel(&self, token: &Token) -> Result<Token> {
+        let scope = Scope::default();
+        let form = self.expand_head(token, &scope)?;
+        match self.keyword(&form, &scope) {
+            Some("define") => match define_name(&form) {
+                Some(id) => self.define(&form, written_symbol(id), &scope),
+                None => self.expand_each(&form, &scope),
+            }
+            Some("define-syntax") => {
+                let (name, mac) = self.define_syntax(&form, &scope)?;
+                let name = written_symbol(&name);
+                self.env.insert(name.clone(), Object::new_syntax(mac));
+                Ok(list(vec![Token::Id(Symbol::intern("quote"), None), Token::Id(name, None)], Token::Empty(None), form.pos().cloned()))
+            }
+            Some("begin") if form.is_list() => {
+                // definitions in begin are toplevel definitions
+                let mut forms = vec![Token::Id(Symbol::intern("begin"), form.car().unwrap().pos().cloned())];
+                for t in form.cdr().unwrap() {
+                    forms.push(self.toplevel(t)?);
+                }
+                Ok(list(forms, Token::Empty(None), form.pos().cloned()))
+            }
+            _ => self.expand(&form, &scope),
+        }
+    }
+
+    fn expand(&self, token: &Token, scope: &Scope) -> Result<Token> {
+        match token {
+            Token::Id(id, pos) => match self.resolve(id, scope) {
+                Binding::Var(name) | Binding::Free(name) => Ok(Token::Id(name, pos.clone())),
+                Binding::Macro(_) => Err(error::locate(anyhow!("syntax error: invalid use of syntax keyword: {}", token), pos.as_ref())),
+            },
+            Token::Vector(..) => Ok(strip(token)),
+            Token::Pair{car, ..} => {
+                if let Token::Id(id, _) = &**car {
+                    match self.resolve(id, scope) {
+                        Binding::Macro(mac) => {
+                            let expanded = self.expand_macro(&mac, token, scope)
+                                .map_err(|e| error::locate(e, token.pos()))?;
+                            return self.expand(&expanded, scope);
+                        }
+                        binding => if let Some(name) = self.special(&binding) {
+                            return self.expand_special(name, token, scope)
+                                .map_err(|e| error::locate(e, token.pos()));
+                        }
+                    }
+                }
+                self.expand_each(token, scope)
+            }
+            _ => Ok(token.clone()),
+        }
+    }
+
+    // expand each element of a list
+    fn expand_each(&self, token: &Token, scope: &Scope) -> Result<Token> {
+        match token {
+            Token::Pair{car, cdr, pos} => Ok(Token::Pair{
+                car: Arc::new(self.expand(car, scope)?),
+                cdr: Arc::new(self.expand_each(cdr, scope)?),
+                pos: pos.clone(),
+            }),
+            _ => Ok(token.clone()),
+        }
+    }
+
+    // expand macro uses at the head of "token" until it is not a macro use
+    fn expand_head(&self, token: &Token, scope: &Scope) -> Result<Token> {
+        let mut token = token.clone();
+        loop {
+            let mac = match &token {
+                Token::Pair{car, ..} => match &**car {
+                    Token::Id(id, _) => match self.resolve(id, scope) {
+                        Binding::Macro(mac) => mac,
+                        // definitions of a record type, which are spliced like those in begin
+                        binding if self.special(&binding) == Some("define-record-type") => {
+                            return record_type_definition(&token).map_err(|e| error::locate(e, token.pos()));
+                        }
+                        _ => return Ok(token),
+                    },
+                    _ => return Ok(token),
+                },
+                _ => return Ok(token),
+            };
+            token = self.expand_macro(&mac, &token, scope)
+                .map_err(|e| error::locate(e, token.pos()))?;
+        }
+    }
+
+    fn resolve(&self, id: &Symbol, scope: &Scope) -> Binding {
+        if let Some(binding) = scope.get(id) {
+            return binding;
+        }
+        if let Some((id, scope)) = ALIASES.with(|aliases| aliases.borrow().get(id).cloned()) {
+            return self.resolve(&id, &scope);
+        }
+        match self.env.lookup(id) {
+            Some(obj) => match obj.kind() {
+                Kind::Syntax(mac) => Binding::Macro(mac.clone()),
+                _ => Binding::Free(id.clone()),
+            }
+            None => Binding::Free(id.clone()),
+        }
+    }
+
+    // special forms can be shadowed by global variables, as in eval
+    fn special(&self, binding: &Binding) -> Option<&'static str> {
+        match binding {
+            Binding::Free(name) if self.env.lookup(name).is_none() => {
+                SPECIAL_FORMS.iter().find(|form| *name == ***form).copied()
+            }
+            _ => None,
+        }
+    }
+
+    fn keyword(&self, token: &Token, scope: &Scope) -> Option<&'static str> {
+        match token.car() {
+            Some(Token::Id(id, _)) => self.special(&self.resolve(id, scope)),
+            _ => None,
+        }
+    }
+
+    fn rename(&self, id: &Symbol, scope: &Scope) -> Symbol {
+        let name = fresh(id);
+        scope.bind(id, Binding::Var(name.clone()));
+        name
+    }
+
+    // Malformed special forms are left to eval, which reports them.
+    fn expand_special(&self, name: &str, token: &Token, scope: &Scope) -> Result<Token> {
+        let pos = token.pos().cloned();
+        let keyword = Token::Id(Symbol::intern(name), token.car().unwrap().pos().cloned());
+        let cdr = token.cdr().unwrap();
+        match name {
+            "quote" => Ok(cons(keyword, strip(cdr), pos)),
+            "quasiquote" => match (cdr.elem(), cdr.next()) {
+                (Some(template), Some(Token::Empty(_))) => {
+                    let template = self.expand_template(template, 1, scope)?;
+                    Ok(cons(keyword, cons(template, Token::Empty(None), cdr.pos().cloned()), pos))
+                }
+                _ => self.expand_each(token, scope),
+            }
+            "lambda" => {
+                let (formals, body) = match cdr {
+                    Token::Pair{car, cdr, ..} => (car, cdr),
+                    _ => return self.expand_each(token, scope),
+                };
+                let scope = scope.child();
+                match self.formals(formals, &scope) {
+                    Some(formals) => Ok(cons(keyword, cons(formals, self.body(body, &scope)?, cdr.pos().cloned()), pos)),
+                    None => self.expand_each(token, scope.0.as_ref().map(|f| &f.parent).unwrap()),
+                }
+            }
+            "let" | "let*" | "letrec" => {
+                let (name, bindings_token, body) = match (cdr.nth(0), cdr.nth(1)) {
+                    (Some(Token::Id(name, _)), Some(bindings)) if keyword_is(&keyword, "let") => {
+                        (Some(name), bindings, cdr.cdr().unwrap().cdr().unwrap())
+                    }
+                    (Some(bindings), _) => (None, bindings, cdr.cdr().unwrap()),
+                    _ => return self.expand_each(token, scope),
+                };
+                let bindings = match parse_bindings(bindings_token) {
+                    Some(bindings) => bindings,
+                    None => return self.expand_each(token, scope),
+                };
+
+                let mut inner = scope.clone();
+                let mut new_bindings = Vec::new();
+                let mut new_name = None;
+                match name {
+                    None if keyword_is(&keyword, "let*") => {
+                        // each init sees the variables before it
+                        for (binding, id, init) in bindings {
+                            let init = self.expand(init, &inner)?;
+                            inner = inner.child();
+                            let id = Token::Id(self.rename(id, &inner), None);
+                            new_bindings.push(list(vec![id, init], Token::Empty(None), binding.pos().cloned()));
+                        }
+                    }
+                    None if keyword_is(&keyword, "letrec") => {
+                        inner = inner.child();
+                        let ids: Vec<Symbol> = bindings.iter().map(|(_, id, _)| self.rename(id, &inner)).collect();
+                        for ((binding, _, init), id) in bindings.iter().zip(ids) {
+                            let init = self.expand(init, &inner)?;
+                            new_bindings.push(list(vec![Token::Id(id, None), init], Token::Empty(None), binding.pos().cloned()));
+                        }
+                    }
+                    _ => {
+                        let inits = bindings.iter().map(|(_, _, init)| self.expand(init, scope)).collect::<Result<Vec<Token>>>()?;
+                        if let Some(name) = name {
+                            inner = inner.child();
+                            new_name = Some(Token::Id(self.rename(name, &inner), cdr.car().unwrap().pos().cloned()));
+                        }
+                        inner = inner.child();
+                        for ((binding, id, _), init) in bindings.iter().zip(inits) {
+                            let id = Token::Id(self.rename(id, &inner), None);
+                            new_bindings.push(list(vec![id, init], Token::Empty(None), binding.pos().cloned()));
+                        }
+                    }
+                }
+
+                let body = self.body(body, &inner)?;
+                let rest = cons(list(new_bindings, Token::Empty(None), bindings_token.pos().cloned()), body, None);
+                match new_name {
+                    Some(name) => Ok(cons(keyword, cons(name, rest, cdr.pos().cloned()), pos)),
+                    None => Ok(cons(keyword, rest, pos)),
+                }
+            }
+            "do" => self.expand_do(keyword, token, scope),
+            "guard" => {
+                // (guard (var clause ...) body ...): var is bound in clauses
+                let (spec, body) = match cdr {
+                    Token::Pair{car, cdr, ..} => (car, cdr),
+                    _ => return self.expand_each(token, scope),
+                };
+                let (var, clauses) = match &**spec {
+                    Token::Pair{car, cdr, ..} if cdr.is_list() => match &**car {
+                        Token::Id(id, pos) => ((id, pos), cdr),
+                        _ => return self.expand_each(token, scope),
+                    }
+                    _ => return self.expand_each(token, scope),
+                };
+                let inner = scope.child();
+                let var = Token::Id(self.rename(var.0, &inner), var.1.clone());
+                let spec = cons(var, self.expand_each(clauses, &inner)?, spec.pos().cloned());
+                Ok(cons(keyword, cons(spec, self.body(body, scope)?, cdr.pos().cloned()), pos))
+            }
+            "define" => match define_name(token) {
+                Some(id) => {
+                    let name = match self.resolve(id, scope) {
+                        Binding::Var(name) | Binding::Free(name) => name,
+                        Binding::Macro(_) => written_symbol(id),
+                    };
+                    self.define(token, name, scope)
+                }
+                None => self.expand_each(token, scope),
+            }
+            "let-syntax" | "letrec-syntax" => {
+                // (let-syntax ((keyword (syntax-rules ...)) ...) body ...) -> (let () body ...)
+                let (bindings, body) = match cdr {
+                    Token::Pair{car, cdr, ..} if car.is_list() => (car, cdr),
+                    _ => bail!("syntax error: malformed {}: {}", name, token),
+                };
+                let inner = scope.child();
+                let mac_scope = if name == "letrec-syntax" { &inner } else { scope };
+                for binding in &**bindings {
+                    match (binding.nth(0), binding.nth(1), binding.nth(2)) {
+                        (Some(Token::Id(id, _)), Some(spec), None) => {
+                            let mac = self.syntax_rules(spec, mac_scope)?;
+                            inner.bind(id, Binding::Macro(mac));
+                        }
+                        _ => bail!("syntax error: malformed {}: {}", name, token),
+                    }
+                }
+                let body = self.body(body, &inner)?;
+                let keyword = Token::Id(Symbol::intern("let"), keyword.pos().cloned());
+                Ok(cons(keyword, cons(Token::Empty(None), body, None), pos))
+            }
+            "define-record-type" => self.expand(&record_type_definition(token)?, scope),
+            "define-syntax" => bail!("syntax error: define-syntax is not allowed here: {}", token),
+            "syntax-rules" => bail!("syntax error: syntax-rules is not allowed here: {}", token),
+            _ => self.expand_each(token, scope),
+        }
+    }
+
+    // a quasiquote template at nesting level "depth": only expressions unquoted at level 1 are expanded
+    fn expand_template(&self, token: &Token, depth: usize, scope: &Scope) -> Result<Token> {
+        let pos = token.pos().cloned();
+        let form = match (self.keyword(token, scope), token.nth(1), token.cdr().and_then(Token::next)) {
+            (Some(name @ ("quasiquote" | "unquote" | "unquote-splicing")), Some(arg), Some(Token::Empty(_))) => Some((name, arg)),
+            _ => None,
+        };
+        match form {
+            Some((name, arg)) => {
+                let keyword = Token::Id(Symbol::intern(name), token.car().unwrap().pos().cloned());
+                let arg = match name {
+                    "quasiquote" => self.expand_template(arg, depth + 1, scope)?,
+                    _ if depth == 1 => self.expand(arg, scope)?,
+                    _ => self.expand_template(arg, depth - 1, scope)?,
+                };
+                Ok(list(vec![keyword, arg], Token::Empty(None), pos))
+            }
+            None => match token {
+                Token::Pair{car, cdr, ..} => Ok(cons(
+                    self.expand_template(car, depth, scope)?,
+                    self.expand_template(cdr, depth, scope)?,
+                    pos,
+                )),
+                Token::Vector(elems, _) => Ok(Token::Vector(
+                    elems.iter().map(|t| self.expand_template(t, depth, scope)).collect::<Result<Vec<Token>>>()?,
+                    pos,
+                )),
+                _ => Ok(strip(token)),
+            }
+        }
+    }
+
+    // (do ((var init step) ...) (test exp ...) cmd ...)
+    fn expand_do(&self, keyword: Token, token: &Token, scope: &Scope) -> Result<Token> {
+        let cdr = token.cdr().unwrap();
+        let (specs, test, cmds) = match (cdr.nth(0), cdr.nth(1)) {
+            (Some(specs), Some(test)) if specs.is_list() && test.is_list() => (specs, test, cdr.cdr().unwrap().cdr().unwrap()),
+            _ => return self.expand_each(token, scope),
+        };
+        let inner = scope.child();
+        let mut vars = Vec::new();
+        for spec in specs {
+            match (spec.nth(0), spec.nth(1), spec.nth(3)) {
+                (Some(Token::Id(id, pos)), Some(init), None) if spec.is_list() => {
+                    vars.push((spec, Token::Id(id.clone(), pos.clone()), self.expand(init, scope)?));
+                }
+                _ => return self.expand_each(token, scope),
+            }
+        }
+        let mut new_specs = Vec::new();
+        for (_, var, _) in &vars {
+            if let Token::Id(id, _) = var {
+                self.rename(id, &inner);
+            }
+        }
+        for (spec, var, init) in vars {
+            let mut elems = vec![self.expand(&var, &inner)?, init];
+            if let Some(step) = spec.nth(2) {
+                elems.push(self.expand(step, &inner)?);
+            }
+            new_specs.push(list(elems, Token::Empty(None), spec.pos().cloned()));
+        }
+        Ok(cons(keyword, cons(
+            list(new_specs, Token::Empty(None), specs.pos().cloned()),
+            cons(self.expand_each(test, &inner)?, self.expand_each(cmds, &inner)?, None),
+            cdr.pos().cloned(),
+        ), token.pos().cloned()))
+    }
+
+    // rename formal arguments in "scope", None if malformed
+    fn formals(&self, formals: &Token, scope: &Scope) -> Option<Token> {
+        match formals {
+            Token::Id(id, pos) => Some(Token::Id(self.rename(id, scope), pos.clone())),
+            Token::Empty(_) => Some(formals.clone()),
+            Token::Pair{car, cdr, pos} => match &**car {
+                Token::Id(id, car_pos) => {
+                    let car = Token::Id(self.rename(id, scope), car_pos.clone());
+                    Some(cons(car, self.formals(cdr, scope)?, pos.clone()))
+                }
+                _ => None,
+            }
+            _ => None,
+        }
+    }
+
+    // (define id exp) or (define (id . formals) body ...), defining "name"
+    fn define(&self, token: &Token, name: Symbol, scope: &Scope) -> Result<Token> {
+        let keyword = Token::Id(Symbol::intern("define"), token.car().unwrap().pos().cloned());
+        let cdr = token.cdr().unwrap();
+        match cdr.car() {
+            Some(Token::Id(_, pos)) => {
+                let rest = self.expand_each(cdr.cdr().unwrap(), scope)?;
+                Ok(cons(keyword, cons(Token::Id(name, pos.clone()), rest, cdr.pos().cloned()), token.pos().cloned()))
+            }
+            Some(Token::Pair{car: id, cdr: formals, pos}) => {
+                let inner = scope.child();
+                let formals = match self.formals(formals, &inner) {
+                    Some(formals) => formals,
+                    None => return self.expand_each(token, scope),
+                };
+                let head = cons(Token::Id(name, id.pos().cloned()), formals, pos.clone());
+                let body = self.body(cdr.cdr().unwrap(), &inner)?;
+                Ok(cons(keyword, cons(head, body, cdr.pos().cloned()), token.pos().cloned()))
+            }
+            _ => self.expand_each(token, scope),
+        }
+    }
+
+    // A body has its own scope. Definitions in it are found first,
+    // so that they are visible from the whole body.
+    fn body(&self, body: &Token, scope: &Scope) -> Result<Token> {
+        if !body.is_list() {
+            return Ok(body.clone());
+        }
+        let scope = scope.child();
+        let mut forms: Vec<Token> = body.into_iter().cloned().collect();
+        forms.reverse();
+        let mut pending = Vec::new();   // (form, defined name)
+        while let Some(form) = forms.pop() {
+            let form = self.expand_head(&form, &scope)?;
+            match self.keyword(&form, &scope) {
+                Some("begin") if form.is_list() => {
+                    let mut inner: Vec<Token> = form.cdr().unwrap().into_iter().cloned().collect();
+                    inner.reverse();
+                    forms.extend(inner);
+                }
+                Some("define") if define_name(&form).is_some() => {
+                    let name = self.rename(define_name(&form).unwrap(), &scope);
+                    pending.push((form, Some(name)));
+                }
+                Some("define-syntax") => {
+                    let (name, mac) = self.define_syntax(&form, &scope)?;
+                    scope.bind(&name, Binding::Macro(mac));
+                }
+                _ => pending.push((form, None)),
+            }
+        }
+
+        let mut body = Vec::new();
+        for (form, name) in pending {
+            body.push(match name {
+                Some(name) => self.define(&form, name, &scope)?,
+                None => self.expand(&form, &scope)?,
+            });
+        }
+        Ok(list(body, Token::Empty(None), None))
+    }
+
+    // (define-syntax keyword (syntax-rules ...))
+    fn define_syntax(&self, token: &Token, scope: &Scope) -> Result<(Symbol, Rc<Macro>)> {
+        ensure_proper_list(token)?;
+        match (token.nth(1), token.nth(2), token.nth(3)) {
+            (Some(Token::Id(id, _)), Some(spec), None) => Ok((id.clone(), self.syntax_rules(spec, scope)?)),
+            _ => bail!("syntax error: malformed define-syntax: {}", token),
+        }
+    }
+
+    // (syntax-rules (literal ...) (pattern template) ...)
+    // (syntax-rules ellipsis (literal ...) (pattern template) ...)
+    fn syntax_rules(&self, spec: &Token, scope: &Scope) -> Result<Rc<Macro>> {
+        if self.keyword(spec, scope) != Some("syntax-rules") || !spec.is_list() {
+            bail!("syntax error: syntax-rules required, but got {}", spec);
+        }
+        let mut rest = spec.cdr().unwrap();
+        let mut ellipsis = Some(Symbol::intern("..."));
+        if let Some(Token::Id(id, _)) = rest.car() {
+            ellipsis = Some(id.clone());
+            rest = rest.cdr().unwrap();
+        }
+        let literals = rest.car().ok_or_else(|| anyhow!("syntax error: malformed syntax-rules: {}", spec))?;
+        if !literals.is_list() {
+            bail!("syntax error: malformed syntax-rules: {}", spec);
+        }
+        let literals = literals
+            .into_iter()
+            .map(|t| match t {
+                Token::Id(id, _) => Ok(id.clone()),
+                _ => Err(anyhow!("syntax error: identifier required, but got {}", t)),
+            })
+            .collect::<Result<Vec<Symbol>>>()?;
+        if literals.iter().any(|lit| Some(lit) == ellipsis.as_ref()) {
+            ellipsis = None;
+        }
+
+        let mut rules = Vec::new();
+        for rule in rest.cdr().unwrap() {
+            match (rule.nth(0), rule.nth(1), rule.nth(2)) {
+                (Some(pattern @ Token::Pair{..}), Some(template), None) => rules.push((pattern.clone(), template.clone())),
+                _ => bail!("syntax error: malformed syntax-rules: {}", spec),
+            }
+        }
+        Ok(Rc::new(Macro{ellipsis, literals, rules, scope: scope.clone()}))
+    }
+
+    fn expand_macro(&self, mac: &Macro, token: &Token, scope: &Scope) -> Result<Token> {
+        ensure_proper_list(token)?;
+        for (pattern, template) in &mac.rules {
+            let mut binds = HashMap::new();
+            // the keyword at the head of the pattern is ignored
+            if self.match_pattern(mac, pattern.cdr().unwrap(), token.cdr().unwrap(), scope, &mut binds) {
+                let mut inst = Instance{mac, aliases: HashMap::new(), pos: token.pos().cloned()};
+                return self.instantiate(template, &binds, &mut inst, mac.ellipsis.as_deref());
+            }
+        }
+        bail!("syntax error: no syntax rule matches {}", token)
+    }
+
+    fn match_pattern(&self, mac: &Macro, pat: &Token, form: &Token, scope: &Scope, binds: &mut HashMap<Symbol, Matched>) -> bool {
+        match pat {
+            Token::Id(id, _) if id == "_" => true,
+            Token::Id(id, _) if mac.literals.contains(id) => match form {
+                // literals match identifiers with the same binding
+                Token::Id(form, _) => same_binding(&self.resolve(form, scope), &self.resolve(id, &mac.scope)),
+                _ => false,
+            }
+            Token::Id(id, _) => {
+                binds.insert(id.clone(), Matched::One(form.clone()));
+                true
+            }
+            Token::Pair{car, cdr, ..} => match after_ellipsis(cdr, mac.ellipsis.as_deref()) {
+                Some(after) => {
+                    // "car ..." takes all elements but what the patterns after it need
+                    let min = count_pairs(after);
+                    let items: Vec<&Token> = form.into_iter().collect();
+                    if items.len() < min {
+                        return false;
+                    }
+                    let n = items.len() - min;
+                    let mut matches = Vec::new();
+                    for item in &items[..n] {
+                        let mut item_binds = HashMap::new();
+                        if !self.match_pattern(mac, car, item, scope, &mut item_binds) {
+                            return false;
+                        }
+                        matches.push(item_binds);
+                    }
+                    for var in pattern_vars(mac, car) {
+                        let many = matches
+                            .iter_mut()
+                            .map(|m| m.remove(&var).unwrap_or(Matched::Many(Vec::new())))
+                            .collect();
+                        binds.insert(var, Matched::Many(many));
+                    }
+                    let mut rest = form;
+                    for _ in 0..n {
+                        rest = rest.cdr().unwrap();
+                    }
+                    self.match_pattern(mac, after, rest, scope, binds)
+                }
+                None => match form {
+                    Token::Pair{car: form_car, cdr: form_cdr, ..} => {
+                        self.match_pattern(mac, car, form_car, scope, binds)
+                            && self.match_pattern(mac, cdr, form_cdr, scope, binds)
+                    }
+                    _ => false,
+                }
+            }
+            Token::Empty(_) => form.is_empty(),
+            // #(pattern ...) matches a vector as a list
+            Token::Vector(pats, _) => match form {
+                Token::Vector(elems, _) => {
+                    let pat = list(pats.clone(), Token::Empty(None), None);
+                    let form = list(elems.clone(), Token::Empty(None), None);
+                    self.match_pattern(mac, &pat, &form, scope, binds)
+                }
+                _ => false,
+            }
+            _ => same_datum(pat, form),
+        }
+    }
+
+    fn instantiate(&self, tmpl: &Token, binds: &HashMap<Symbol, Matched>, inst: &mut Instance, ellipsis: Option<&str>) -> Result<Token> {
+        match tmpl {
+            Token::Id(id, _) => match binds.get(id) {
+                Some(Matched::One(token)) => Ok(token.clone()),
+                Some(Matched::Many(_)) => bail!("syntax error: pattern variable {} is used without ellipsis", written_name(id)),
+                None => Ok(Token::Id(alias(id, inst), inst.pos.clone())),
+            }
+            Token::Pair{car, cdr, ..} => {
+                // (... template) escapes ellipses in template
+                if let (Token::Id(id, _), Some(e)) = (&**car, ellipsis) {
+                    if id == e {
+                        if let Token::Pair{car: escaped, cdr: empty, ..} = &**cdr {
+                            if empty.is_empty() {
+                                return self.instantiate(escaped, binds, inst, None);
+                            }
+                        }
+                    }
+                }
+                let mut depth = 0;
+                let mut rest = &**cdr;
+                while let Some(after) = after_ellipsis(rest, ellipsis) {
+                    depth += 1;
+                    rest = after;
+                }
+                let rest = self.instantiate(rest, binds, inst, ellipsis)?;
+                if depth == 0 {
+                    let car = self.instantiate(car, binds, inst, ellipsis)?;
+                    Ok(cons(car, rest, inst.pos.clone()))
+                } else {
+                    let items = self.instantiate_many(car, binds, depth, inst, ellipsis)?;
+                    Ok(list(items, rest, inst.pos.clone()))
+                }
+            }
+            Token::Vector(tmpls, _) => {
+                let tmpl = list(tmpls.clone(), Token::Empty(None), None);
+                let elems = self.instantiate(&tmpl, binds, inst, ellipsis)?;
+                Ok(Token::Vector(elems.into_iter().cloned().collect(), inst.pos.clone()))
+            }
+            _ => Ok(tmpl.clone()),
+        }
+    }
+
+    // "tmpl" followed by "depth" ellipses
+    fn instantiate_many(&self, tmpl: &Token, binds: &HashMap<Symbol, Matched>, depth: usize, inst: &mut Instance, ellipsis: Option<&str>) -> Result<Vec<Token>> {
+        let vars: Vec<&Symbol> = template_ids(tmpl)
+            .into_iter()
+            .filter_map(|id| binds.get_key_value(&id).map(|(k, _)| k))
+            .filter(|id| matches!(binds[*id], Matched::Many(_)))
+            .collect();
+        if vars.is_empty() {
+            bail!("syntax error: no pattern variable to repeat in {}", tmpl);
+        }
+        let len = match &binds[vars[0]] {
+            Matched::Many(many) => many.len(),
+            _ => unreachable!(),
+        };
+        if vars.iter().any(|id| matches!(&binds[*id], Matched::Many(many) if many.len() != len)) {
+            bail!("syntax error: pattern variables matched different numbers of forms in {}", tmpl);
+        }
+
+        let mut items = Vec::new();
+        for i in 0..len {
+            let mut item_binds = binds.clone();
+            for id in &vars {
+                if let Matched::Many(many) = &binds[*id] {
+                    item_binds.insert((*id).clone(), many[i].clone());
+                }
+            }
+            if depth > 1 {
+                items.extend(self.instantiate_many(tmpl, &item_binds, depth - 1, inst, ellipsis)?);
+            } else {
+                items.push(self.instantiate(tmpl, &item_binds, inst, ellipsis)?);
+            }
+        }
+        Ok(items)
+    }
+}
+
+// an identifier inserted by a macro refers to the binding where the macro was defined
+fn alias(id: &Symbol, inst: &mut Instance) -> Symbol {
+    let mac = inst.mac;
+    inst.aliases
+        .entry(id.clone())
+        .or_insert_with(|| {
+            let alias = fresh(id);
+            ALIASES.with(|aliases| aliases.borrow_mut().insert(alias.clone(), (id.clone(), mac.scope.clone())));
+            alias
+        })
+        .clone()
+}
+
+fn same_binding(lhs: &Binding, rhs: &Binding) -> bool {
+    match (lhs, rhs) {
+        (Binding::Var(lhs), Binding::Var(rhs)) | (Binding::Free(lhs), Binding::Free(rhs)) => lhs == rhs,
+        (Binding::Macro(lhs), Binding::Macro(rhs)) => Rc::ptr_eq(lhs, rhs),
+        _ => false,
+    }
+}
+
+fn same_datum(lhs: &Token, rhs: &Token) -> bool {
+    match (lhs, rhs) {
+        (Token::Int(lhs, _), Token::Int(rhs, _)) => lhs == rhs,
+        (Token::BigInt(lhs, _), Token::BigInt(rhs, _)) => lhs == rhs,
+        (Token::Rational(lhs, _), Token::Rational(rhs, _)) => lhs == rhs,
+        (Token::Float(lhs, _), Token::Float(rhs, _)) => lhs == rhs,
+        (Token::Boolean(lhs, _), Token::Boolean(rhs, _)) => lhs == rhs,
+        (Token::Char(lhs, _), Token::Char(rhs, _)) => lhs == rhs,
+        (Token::Bytevector(lhs, _), Token::Bytevector(rhs, _)) => lhs == rhs,
+        (Token::String(lhs, _), Token::String(rhs, _)) => lhs == rhs,
+        _ => false,
+    }
+}
+
+// (... . after) -> after
+fn after_ellipsis<'a>(token: &'a Token, ellipsis: Option<&str>) -> Option<&'a Token> {
+    match (token, ellipsis) {
+        (Token::Pair{car, cdr, ..}, Some(e)) if matches!(&**car, Token::Id(id, _) if id == e) => Some(cdr),
+        _ => None,
+    }
+}
+
+fn count_pairs(token: &Token) -> usize {
+    token.into_iter().count()
+}
+
+fn pattern_vars(mac: &Macro, pat: &Token) -> Vec<Symbol> {
+    template_ids(pat)
+        .into_iter()
+        .filter(|id| *id != *"_" && !mac.literals.contains(id) && Some(id) != mac.ellipsis.as_ref())
+        .collect()
+}
+
+fn template_ids(token: &Token) -> Vec<Symbol> {
+    match token {
+        Token::Id(id, _) => vec![id.clone()],
+        Token::Pair{car, cdr, ..} => {
+            let mut ids = template_ids(car);
+            ids.extend(template_ids(cdr));
+            ids
+        }
+        Token::Vector(elems, _) => elems.iter().flat_map(template_ids).collect(),
+        Token::Label(_, datum, _) => template_ids(datum),
+        _ => Vec::new(),
+    }
+}
+
+// quoted data have no aliases
+fn strip(token: &Token) -> Token {
+    match token {
+        Token::Id(id, pos) => Token::Id(written_symbol(id), pos.clone()),
+        Token::Pair{car, cdr, pos} => cons(strip(car), strip(cdr), pos.clone()),
+        Token::Vector(elems, pos) => Token::Vector(elems.iter().map(strip).collect(), pos.clone()),
+        Token::Label(n, datum, pos) => Token::Label(*n, Arc::new(strip(datum)), pos.clone()),
+        _ => token.clone(),
+    }
+}
+
+// an identifier which refers to the global binding of "name" wherever it is inserted
+fn global_id(name: &str, pos: Option<&Pos>) -> Token {
+    let alias = fresh(name);
+    ALIASES.with(|aliases| aliases.borrow_mut().insert(alias.clone(), (Symbol::intern(name), Scope::default())));
+    Token::Id(alias, pos.cloned())
+}
+
+// (define-record-type <point> (make-point x y) point? (x point-x set-point-x!) (y point-y)) ->
+// where make-record-type and the others are bound to uninterned symbols, which programs can not redefine:
+// (begin (define <point> (make-record-type 'point '(x y)))
+//        (define make-point (let ((type <point>)) (lambda (x y) (make-record type x y))))
+//        (define point? (let ((type <point>)) (lambda (obj) (record? obj type))))
+//        (define point-x (let ((type <point>)) (lambda (obj) (record-ref type obj 0))))
+//        (define set-point-x! (let ((type <point>)) (lambda (obj val) (record-set! type obj 0 val))))
+//        (define point-y (let ((type <point>)) (lambda (obj) (record-ref type obj 1)))))
+// Fields which the constructor does not take are unspecified.
+fn record_type_definition(token: &Token) -> Result<Token> {
+    let malformed = || anyhow!("syntax error: malformed define-record-type: {}", token);
+    ensure_proper_list(token)?;
+    let (name, ctor, pred) = match (token.nth(1), token.nth(2), token.nth(3)) {
+        (Some(name @ Token::Id(..)), Some(ctor), Some(pred @ Token::Id(..))) if ctor.is_list() => (name, ctor, pred),
+        _ => return Err(malformed()),
+    };
+    // (field accessor [modifier])
+    let mut fields: Vec<(&Symbol, &Token, Option<&Token>)> = Vec::new();
+    for spec in token.into_iter().skip(4) {
+        match (spec.nth(0), spec.nth(1), spec.nth(2), spec.nth(3)) {
+            (Some(Token::Id(field, _)), Some(accessor @ Token::Id(..)), modifier, None)
+                if spec.is_list() && matches!(modifier, None | Some(Token::Id(..)))
+                    && fields.iter().all(|(other, ..)| other != &field) => fields.push((field, accessor, modifier)),
+            _ => return Err(malformed()),
+        }
+    }
+    let field_index = |id: &Symbol| fields.iter().position(|(field, ..)| *field == id);
+
+    let pos = token.pos();
+    let id = |name: &str| global_id(name, pos);
+    let prim = |name: &str| Token::Id(record::primitive(name), pos.cloned());
+    let form = |items: Vec<Token>| list(items, Token::Empty(None), pos.cloned());
+    let quote = |datum: Token| form(vec![id("quote"), datum]);
+    let type_var = Token::Id(fresh("type"), None);
+    // a procedure which refers to the record type by "type_var"
+    let procedure = |args: Vec<Token>, body: Token| form(vec![
+        id("let"),
+        form(vec![form(vec![type_var.clone(), name.clone()])]),
+        form(vec![id("lambda"), form(args), body]),
+    ]);
+    let define = |id_token: &Token, exp: Token| form(vec![id("define"), id_token.clone(), exp]);
+
+    let type_name = match name {
+        Token::Id(id, _) => written_name(id).trim_start_matches('<').trim_end_matches('>').to_string(),
+        _ => unreachable!(),
+    };
+    let field_names = fields.iter().map(|(field, ..)| Token::Id(written_symbol(field), None)).collect();
+    let mut defs = vec![id("begin")];
+    defs.push(define(name, form(vec![
+        prim("make-record-type"),
+        quote(Token::Id(Symbol::intern(&type_name), None)),
+        quote(form(field_names)),
+    ])));
+
+    // constructor
+    let ctor_name = match ctor.elem() {
+        Some(ctor_name @ Token::Id(..)) => ctor_name,
+        _ => return Err(malformed()),
+    };
+    let mut args = Vec::new();
+    let mut vals: Vec<Token> = fields.iter().map(|_| form(vec![id("if"), Token::Boolean(false, None), Token::Boolean(false, None)])).collect();
+    for arg in ctor.cdr().unwrap() {
+        match arg {
+            Token::Id(field, _) => match field_index(field) {
+                Some(k) => {
+                    let var = Token::Id(fresh(field), None);
+                    args.push(var.clone());
+                    vals[k] = var;
+                }
+                None => bail!("syntax error: {} is not a field of {}: {}", arg, name, token),
+            }
+            _ => return Err(malformed()),
+        }
+    }
+    let mut make = vec![prim("make-record"), type_var.clone()];
+    make.extend(vals);
+    defs.push(define(ctor_name, procedure(args, form(make))));
+
+    // predicate, accessors and modifiers
+    let obj = Token::Id(fresh("obj"), None);
+    let val = Token::Id(fresh("val"), None);
+    defs.push(define(pred, procedure(vec![obj.clone()], form(vec![prim("record?"), obj.clone(), type_var.clone()]))));
+    for (k, (_, accessor, modifier)) in fields.iter().enumerate() {
+        let k = Token::Int(k as i64, None);
+        let body = form(vec![prim("record-ref"), type_var.clone(), obj.clone(), k.clone()]);
+        defs.push(define(accessor, procedure(vec![obj.clone()], body)));
+        if let Some(modifier) = modifier {
+            let body = form(vec![prim("record-set!"), type_var.clone(), obj.clone(), k, val.clone()]);
+            defs.push(define(modifier, procedure(vec![obj.clone(), val.clone()], body)));
+        }
+    }
+    Ok(form(defs))
+}
+
+fn define_name(token: &Token) -> Option<&Symbol> {
+    match token.nth(1) {
+        Some(Token::Id(id, _)) => Some(id),
+        Some(Token::Pair{car, ..}) => match &**car {
+            Token::Id(id, _) => Some(id),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn keyword_is(keyword: &Token, name: &str) -> bool {
+    matches!(keyword, Token::Id(id, _) if id == name)
+}
+
+// ((id init) ...) -> [(binding, id, init)], None if malformed
+fn parse_bindings(bindings: &Token) -> Option<Vec<(&Token, &Symbol, &Token)>> {
+    if !bindings.is_list() {
+        return None;
+    }
+    bindings
+        .into_iter()
+        .map(|binding| match (binding.nth(0), binding.nth(1), binding.nth(2)) {
+            (Some(Token::Id(id, _)), Some(init), None) if binding.is_list() => Some((binding, id, init)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn cons(car: Token, cdr: Token, pos: Option<Pos>) -> Token {
+    Token::Pair{car: Arc::new(car), cdr: Arc::new(cdr), pos}
+}
+
+// like the reader, inner pairs are where their elements are
+fn list(items: Vec<Token>, tail: Token, pos: Option<Pos>) -> Token {
+    let mut list = tail;
+    for (i, item) in items.into_iter().enumerate().rev() {
+        let pair_pos = if i == 0 { pos.clone() } else { item.pos().cloned() };
+        list = cons(item, list, pair_pos);
+    }
+    list
+}
@@ -4,17 +4,23 @@ pub mod list;
 pub mod symbol;
 pub mod cmp;
 pub mod string;
+pub mod char;
+pub mod vector;
+pub mod bytevector;
 pub mod procedure;
 pub mod display;
+pub mod control;
+pub mod exception;
+pub mod port;
+pub mod record;
+pub mod hashtable;
 
-use crate::data::Object;
+use crate::data::object::{SubrFn, CtrlFn};
+use crate::symbol::Symbol;
 
-use std::collections::VecDeque;
-
-use anyhow::Result;
-
-pub fn make_lib() -> Vec<(String, bool, usize, fn(VecDeque<Object>) -> Result<Object>)> {
-    let mut lib: Vec<(String, bool, usize, fn(VecDeque<Object>) -> Result<Object>)> = Vec::new();
+#[allow(clippy::vec_init_then_push)]
+pub fn make_lib() -> Vec<(String, bool, usize, SubrFn)> {
+    let mut lib: Vec<(String, bool, usize, SubrFn)> = Vec::new();
     // number
     lib.push(("number?".to_string(), false, 1, number::is_number));
     lib.push(("+".to_string(), true, 0, number::add));
@@ -26,6 +32,58 @@ pub fn make_lib() -> Vec<(String, bool, usize, fn(VecDeque<Object>) -> Result<Ob
     lib.push(("<=".to_string(), true, 2, number::le));
     lib.push((">".to_string(), true, 2, number::gt));
     lib.push((">=".to_string(), true, 2, number::ge));
+    lib.push(("exact-integer?".to_string(), false, 1, number::is_exact_integer));
+    lib.push(("exact".to_string(), false, 1, number::exact));
+    lib.push(("inexact".to_string(), false, 1, number::inexact));
+    lib.push(("inexact->exact".to_string(), false, 1, number::exact));
+    lib.push(("exact->inexact".to_string(), false, 1, number::inexact));
+    lib.push(("numerator".to_string(), false, 1, number::numerator));
+    lib.push(("denominator".to_string(), false, 1, number::denominator));
+    lib.push(("integer?".to_string(), false, 1, number::is_integer));
+    lib.push(("rational?".to_string(), false, 1, number::is_rational));
+    lib.push(("real?".to_string(), false, 1, number::is_real));
+    lib.push(("complex?".to_string(), false, 1, number::is_number));
+    lib.push(("exact?".to_string(), false, 1, number::is_exact));
+    lib.push(("inexact?".to_string(), false, 1, number::is_inexact));
+    lib.push(("nan?".to_string(), false, 1, number::is_nan));
+    lib.push(("infinite?".to_string(), false, 1, number::is_infinite));
+    lib.push(("finite?".to_string(), false, 1, number::is_finite));
+    lib.push(("zero?".to_string(), false, 1, number::is_zero));
+    lib.push(("positive?".to_string(), false, 1, number::is_positive));
+    lib.push(("negative?".to_string(), false, 1, number::is_negative));
+    lib.push(("odd?".to_string(), false, 1, number::is_odd));
+    lib.push(("even?".to_string(), false, 1, number::is_even));
+    lib.push(("quotient".to_string(), false, 2, number::truncate_quotient));
+    lib.push(("remainder".to_string(), false, 2, number::truncate_remainder));
+    lib.push(("modulo".to_string(), false, 2, number::floor_remainder));
+    lib.push(("floor/".to_string(), false, 2, number::floor_divide));
+    lib.push(("floor-quotient".to_string(), false, 2, number::floor_quotient));
+    lib.push(("floor-remainder".to_string(), false, 2, number::floor_remainder));
+    lib.push(("truncate/".to_string(), false, 2, number::truncate_divide));
+    lib.push(("truncate-quotient".to_string(), false, 2, number::truncate_quotient));
+    lib.push(("truncate-remainder".to_string(), false, 2, number::truncate_remainder));
+    lib.push(("gcd".to_string(), true, 0, number::gcd));
+    lib.push(("lcm".to_string(), true, 0, number::lcm));
+    lib.push(("abs".to_string(), false, 1, number::abs));
+    lib.push(("min".to_string(), true, 1, number::min));
+    lib.push(("max".to_string(), true, 1, number::max));
+    lib.push(("floor".to_string(), false, 1, number::floor));
+    lib.push(("ceiling".to_string(), false, 1, number::ceiling));
+    lib.push(("round".to_string(), false, 1, number::round));
+    lib.push(("truncate".to_string(), false, 1, number::truncate));
+    lib.push(("square".to_string(), false, 1, number::square));
+    lib.push(("sqrt".to_string(), false, 1, number::sqrt));
+    lib.push(("exact-integer-sqrt".to_string(), false, 1, number::exact_integer_sqrt));
+    lib.push(("expt".to_string(), false, 2, number::expt));
+    lib.push(("exp".to_string(), false, 1, number::exp));
+    lib.push(("log".to_string(), true, 1, number::log));
+    lib.push(("sin".to_string(), false, 1, number::sin));
+    lib.push(("cos".to_string(), false, 1, number::cos));
+    lib.push(("tan".to_string(), false, 1, number::tan));
+    lib.push(("asin".to_string(), false, 1, number::asin));
+    lib.push(("acos".to_string(), false, 1, number::acos));
+    lib.push(("atan".to_string(), true, 1, number::atan));
+    lib.push(("number->string".to_string(), true, 1, number::number_to_string));
     // boolean
     lib.push(("boolean?".to_string(), false, 1, bool::is_bool));
     lib.push(("not".to_string(), false, 1, bool::not));
@@ -45,22 +103,190 @@ pub fn make_lib() -> Vec<(String, bool, usize, fn(VecDeque<Object>) -> Result<Ob
     lib.push(("set-cdr!".to_string(), false, 2, list::set_cdr));
     // string
     lib.push(("string?".to_string(), false, 1, string::is_string));
-    lib.push(("string-append".to_string(), false, 2, string::string_append));
+    lib.push(("make-string".to_string(), true, 1, string::make_string));
+    lib.push(("string".to_string(), true, 0, string::string));
+    lib.push(("string-length".to_string(), false, 1, string::string_length));
+    lib.push(("string-ref".to_string(), false, 2, string::string_ref));
+    lib.push(("string-set!".to_string(), false, 3, string::string_set));
+    lib.push(("substring".to_string(), false, 3, string::substring));
+    lib.push(("string-append".to_string(), true, 0, string::string_append));
+    lib.push(("string-copy".to_string(), true, 1, string::string_copy));
+    lib.push(("string-copy!".to_string(), true, 3, string::string_copy_to));
+    lib.push(("string-fill!".to_string(), true, 2, string::string_fill));
+    lib.push(("string->list".to_string(), true, 1, string::string_to_list));
+    lib.push(("list->string".to_string(), false, 1, string::list_to_string));
+    lib.push(("string=?".to_string(), true, 2, string::string_eq));
+    lib.push(("string<?".to_string(), true, 2, string::string_lt));
+    lib.push(("string>?".to_string(), true, 2, string::string_gt));
+    lib.push(("string<=?".to_string(), true, 2, string::string_le));
+    lib.push(("string>=?".to_string(), true, 2, string::string_ge));
+    lib.push(("string-ci=?".to_string(), true, 2, string::string_ci_eq));
+    lib.push(("string-ci<?".to_string(), true, 2, string::string_ci_lt));
+    lib.push(("string-ci>?".to_string(), true, 2, string::string_ci_gt));
+    lib.push(("string-ci<=?".to_string(), true, 2, string::string_ci_le));
+    lib.push(("string-ci>=?".to_string(), true, 2, string::string_ci_ge));
+    lib.push(("string-upcase".to_string(), false, 1, string::string_upcase));
+    lib.push(("string-downcase".to_string(), false, 1, string::string_downcase));
+    lib.push(("string-foldcase".to_string(), false, 1, string::string_foldcase));
     lib.push(("symbol->string".to_string(), false, 1, string::symbol_to_string));
     lib.push(("string->symbol".to_string(), false, 1, string::string_to_symbol));
-    lib.push(("string->number".to_string(), false, 1, string::string_to_number));
-    lib.push(("number->string".to_string(), false, 1, string::number_to_string));
+    lib.push(("string->number".to_string(), true, 1, string::string_to_number));
+    // char
+    lib.push(("char?".to_string(), false, 1, char::is_char));
+    lib.push(("char->integer".to_string(), false, 1, char::char_to_integer));
+    lib.push(("integer->char".to_string(), false, 1, char::integer_to_char));
+    lib.push(("char=?".to_string(), true, 2, char::char_eq));
+    lib.push(("char<?".to_string(), true, 2, char::char_lt));
+    lib.push(("char>?".to_string(), true, 2, char::char_gt));
+    lib.push(("char<=?".to_string(), true, 2, char::char_le));
+    lib.push(("char>=?".to_string(), true, 2, char::char_ge));
+    lib.push(("char-ci=?".to_string(), true, 2, char::char_ci_eq));
+    lib.push(("char-ci<?".to_string(), true, 2, char::char_ci_lt));
+    lib.push(("char-ci>?".to_string(), true, 2, char::char_ci_gt));
+    lib.push(("char-ci<=?".to_string(), true, 2, char::char_ci_le));
+    lib.push(("char-ci>=?".to_string(), true, 2, char::char_ci_ge));
+    lib.push(("char-alphabetic?".to_string(), false, 1, char::is_alphabetic));
+    lib.push(("char-numeric?".to_string(), false, 1, char::is_numeric));
+    lib.push(("char-whitespace?".to_string(), false, 1, char::is_whitespace));
+    lib.push(("char-upper-case?".to_string(), false, 1, char::is_upper_case));
+    lib.push(("char-lower-case?".to_string(), false, 1, char::is_lower_case));
+    lib.push(("digit-value".to_string(), false, 1, char::digit_value));
+    lib.push(("char-upcase".to_string(), false, 1, char::char_upcase));
+    lib.push(("char-downcase".to_string(), false, 1, char::char_downcase));
+    lib.push(("char-foldcase".to_string(), false, 1, char::char_foldcase));
+    // vector
+    lib.push(("vector?".to_string(), false, 1, vector::is_vector));
+    lib.push(("make-vector".to_string(), true, 1, vector::make_vector));
+    lib.push(("vector".to_string(), true, 0, vector::vector));
+    lib.push(("vector-length".to_string(), false, 1, vector::vector_length));
+    lib.push(("vector-ref".to_string(), false, 2, vector::vector_ref));
+    lib.push(("vector-set!".to_string(), false, 3, vector::vector_set));
+    lib.push(("vector->list".to_string(), true, 1, vector::vector_to_list));
+    lib.push(("list->vector".to_string(), false, 1, vector::list_to_vector));
+    lib.push(("vector->string".to_string(), true, 1, vector::vector_to_string));
+    lib.push(("string->vector".to_string(), true, 1, vector::string_to_vector));
+    lib.push(("vector-copy".to_string(), true, 1, vector::vector_copy));
+    lib.push(("vector-copy!".to_string(), true, 3, vector::vector_copy_to));
+    lib.push(("vector-append".to_string(), true, 0, vector::vector_append));
+    lib.push(("vector-fill!".to_string(), true, 2, vector::vector_fill));
+    // bytevector
+    lib.push(("bytevector?".to_string(), false, 1, bytevector::is_bytevector));
+    lib.push(("make-bytevector".to_string(), true, 1, bytevector::make_bytevector));
+    lib.push(("bytevector".to_string(), true, 0, bytevector::bytevector));
+    lib.push(("bytevector-length".to_string(), false, 1, bytevector::bytevector_length));
+    lib.push(("bytevector-u8-ref".to_string(), false, 2, bytevector::bytevector_u8_ref));
+    lib.push(("bytevector-u8-set!".to_string(), false, 3, bytevector::bytevector_u8_set));
+    lib.push(("bytevector-copy".to_string(), true, 1, bytevector::bytevector_copy));
+    lib.push(("bytevector-copy!".to_string(), true, 3, bytevector::bytevector_copy_to));
+    lib.push(("bytevector-append".to_string(), true, 0, bytevector::bytevector_append));
+    lib.push(("utf8->string".to_string(), true, 1, bytevector::utf8_to_string));
+    lib.push(("string->utf8".to_string(), true, 1, bytevector::string_to_utf8));
     // symbol
     lib.push(("symbol?".to_string(), false, 1, symbol::is_symbol));
+    lib.push(("symbol=?".to_string(), true, 2, symbol::symbol_eq));
+    lib.push(("string->uninterned-symbol".to_string(), false, 1, symbol::string_to_uninterned_symbol));
+    lib.push(("gensym".to_string(), true, 0, symbol::gensym));
     // procedure
     lib.push(("procedure?".to_string(), false, 1, procedure::is_procedure));
+    lib.push(("disassemble".to_string(), true, 1, procedure::disassemble));
+    lib.push(("values".to_string(), true, 0, control::values));
     // comparison
     lib.push(("eq?".to_string(), false, 2, cmp::eq));
     lib.push(("neq?".to_string(), false, 2, cmp::neq));
+    lib.push(("eqv?".to_string(), false, 2, cmp::eqv));
     lib.push(("equal?".to_string(), false, 2, cmp::equal));
+    // hash table
+    lib.push(("make-hash-table".to_string(), true, 0, hashtable::make_hash_table));
+    lib.push(("hash-table?".to_string(), false, 1, hashtable::is_hash_table));
+    lib.push(("hash-table-ref/default".to_string(), false, 3, hashtable::hash_table_ref_default));
+    lib.push(("hash-table-set!".to_string(), false, 3, hashtable::hash_table_set));
+    lib.push(("hash-table-delete!".to_string(), false, 2, hashtable::hash_table_delete));
+    lib.push(("hash-table-contains?".to_string(), false, 2, hashtable::hash_table_contains));
+    lib.push(("hash-table-size".to_string(), false, 1, hashtable::hash_table_size));
+    lib.push(("hash-table-keys".to_string(), false, 1, hashtable::hash_table_keys));
+    lib.push(("hash-table->alist".to_string(), false, 1, hashtable::hash_table_to_alist));
 
     // additional
-    lib.push(("display".to_string(), false, 1, display::display));
+    lib.push(("display".to_string(), true, 1, display::display));
+    lib.push(("write".to_string(), true, 1, display::write));
+    lib.push(("write-shared".to_string(), true, 1, display::write_shared));
+    lib.push(("write-simple".to_string(), true, 1, display::write_simple));
+    // port
+    lib.push(("current-input-port".to_string(), false, 0, port::current_input_port));
+    lib.push(("current-output-port".to_string(), false, 0, port::current_output_port));
+    lib.push(("current-error-port".to_string(), false, 0, port::current_error_port));
+    lib.push(("port?".to_string(), false, 1, port::is_port));
+    lib.push(("input-port?".to_string(), false, 1, port::is_input_port));
+    lib.push(("output-port?".to_string(), false, 1, port::is_output_port));
+    lib.push(("textual-port?".to_string(), false, 1, port::is_port));
+    lib.push(("input-port-open?".to_string(), false, 1, port::is_input_port_open));
+    lib.push(("output-port-open?".to_string(), false, 1, port::is_output_port_open));
+    lib.push(("eof-object".to_string(), false, 0, port::eof_object));
+    lib.push(("eof-object?".to_string(), false, 1, port::is_eof_object));
+    lib.push(("open-input-file".to_string(), false, 1, port::open_input_file));
+    lib.push(("open-output-file".to_string(), false, 1, port::open_output_file));
+    lib.push(("open-input-string".to_string(), false, 1, port::open_input_string));
+    lib.push(("open-output-string".to_string(), false, 0, port::open_output_string));
+    lib.push(("get-output-string".to_string(), false, 1, port::get_output_string));
+    lib.push(("close-port".to_string(), false, 1, port::close_port));
+    lib.push(("close-input-port".to_string(), false, 1, port::close_input_port));
+    lib.push(("close-output-port".to_string(), false, 1, port::close_output_port));
+    lib.push(("read".to_string(), true, 0, port::read));
+    lib.push(("read-char".to_string(), true, 0, port::read_char));
+    lib.push(("peek-char".to_string(), true, 0, port::peek_char));
+    lib.push(("read-line".to_string(), true, 0, port::read_line));
+    lib.push(("read-string".to_string(), true, 1, port::read_string));
+    lib.push(("write-char".to_string(), true, 1, port::write_char));
+    lib.push(("write-string".to_string(), true, 1, port::write_string));
+    lib.push(("newline".to_string(), true, 0, port::newline));
+    lib.push(("flush-output-port".to_string(), true, 0, port::flush_output_port));
+    // exception
+    lib.push(("error-object?".to_string(), false, 1, exception::is_error_object));
+    lib.push(("error-object-message".to_string(), false, 1, exception::error_object_message));
+    lib.push(("error-object-irritants".to_string(), false, 1, exception::error_object_irritants));
+
+    lib
+}
+
+// procedures which need the evaluator, e.g. to capture the continuation
+#[allow(clippy::vec_init_then_push)]
+pub fn make_ctrl_lib() -> Vec<(String, bool, usize, CtrlFn)> {
+    let mut lib: Vec<(String, bool, usize, CtrlFn)> = Vec::new();
+    lib.push(("call-with-current-continuation".to_string(), false, 1, control::call_cc));
+    lib.push(("call/cc".to_string(), false, 1, control::call_cc));
+    lib.push(("dynamic-wind".to_string(), false, 3, control::dynamic_wind));
+    lib.push(("with-exception-handler".to_string(), false, 2, exception::with_exception_handler));
+    lib.push(("raise".to_string(), false, 1, exception::raise));
+    lib.push(("raise-continuable".to_string(), false, 1, exception::raise_continuable));
+    lib.push(("error".to_string(), true, 1, exception::error));
+    lib.push(("apply".to_string(), true, 2, control::apply));
+    lib.push(("call-with-values".to_string(), false, 2, control::call_with_values));
+    lib.push(("map".to_string(), true, 2, control::map));
+    lib.push(("for-each".to_string(), true, 2, control::for_each));
+    lib.push(("vector-map".to_string(), true, 2, control::vector_map));
+    lib.push(("vector-for-each".to_string(), true, 2, control::vector_for_each));
+    lib.push(("string-map".to_string(), true, 2, control::string_map));
+    lib.push(("string-for-each".to_string(), true, 2, control::string_for_each));
+    lib.push(("filter".to_string(), false, 2, list::filter));
+    lib.push(("fold".to_string(), true, 3, list::fold));
+    lib.push(("sort".to_string(), false, 2, list::sort));
+    lib.push(("string-index".to_string(), true, 2, string::string_index));
+    lib.push(("hash-table-ref".to_string(), true, 2, hashtable::hash_table_ref));
+    lib.push(("hash-table-update!".to_string(), true, 3, hashtable::hash_table_update));
+    lib.push(("hash-table-update!/default".to_string(), false, 4, hashtable::hash_table_update_default));
+    lib.push(("hash-table-walk".to_string(), false, 2, hashtable::hash_table_walk));
+    lib.push(("hash-table-count".to_string(), true, 1, hashtable::hash_table_count));
+    lib.push(("call-with-port".to_string(), false, 2, port::call_with_port));
+    lib.push(("call-with-input-file".to_string(), false, 2, port::call_with_input_file));
+    lib.push(("call-with-output-file".to_string(), false, 2, port::call_with_output_file));
+    lib.push(("call-with-output-string".to_string(), false, 1, port::call_with_output_string));
+    lib.push(("with-input-from-file".to_string(), false, 2, port::with_input_from_file));
+    lib.push(("with-output-to-file".to_string(), false, 2, port::with_output_to_file));
 
     lib
 }
+
+// procedures bound to uninterned symbols, which only the expansions of special forms refer to
+pub fn make_primitives() -> Vec<(Symbol, bool, usize, SubrFn)> {
+    record::PRIMITIVES.clone()
+}
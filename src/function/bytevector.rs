@@ -0,0 +1,110 @@
+use crate::data::{*, object::*};
+use super::vector::{index, range, filled};
+
+use std::collections::VecDeque;
+
+use anyhow::{Result, anyhow, bail};
+
+fn ensure_bytevector(obj: &Object) -> Result<Vec<u8>> {
+    match obj.kind() {
+        Kind::Bytevector(bytes) => Ok(bytes.clone()),
+        _ => Err(anyhow!("bytevector required, but got {}", obj)),
+    }
+}
+
+fn byte(obj: &Object) -> Result<u8> {
+    match obj.kind() {
+        Kind::Number(Number::Int(i)) if (0..256).contains(i) => Ok(*i as u8),
+        _ => Err(anyhow!("byte required, but got {}", obj)),
+    }
+}
+
+pub fn is_bytevector(mut args: VecDeque<Object>) -> Result<Object> {
+    match args.pop_front().unwrap().kind() {
+        Kind::Bytevector(_) => Ok(Object::new_boolean(true, true)),
+        _                   => Ok(Object::new_boolean(false, true)),
+    }
+}
+
+pub fn make_bytevector(mut args: VecDeque<Object>) -> Result<Object> {
+    let k = index(&args.pop_front().unwrap())?;
+    let fill = match args.pop_front() {
+        Some(obj) => byte(&obj)?,
+        None => 0,
+    };
+    if let Some(obj) = args.pop_front() {
+        bail!("too many arguments: {}", obj);
+    }
+    Ok(Object::new_bytevector(filled(fill, k)?, true))
+}
+
+pub fn bytevector(args: VecDeque<Object>) -> Result<Object> {
+    let bytes = args.iter().map(byte).collect::<Result<Vec<u8>>>()?;
+    Ok(Object::new_bytevector(bytes, true))
+}
+
+pub fn bytevector_length(mut args: VecDeque<Object>) -> Result<Object> {
+    let bytes = ensure_bytevector(&args.pop_front().unwrap())?;
+    Ok(Object::new_int(bytes.len() as i64, true))
+}
+
+pub fn bytevector_u8_ref(mut args: VecDeque<Object>) -> Result<Object> {
+    let bytes = ensure_bytevector(&args.pop_front().unwrap())?;
+    let k = index(&args.pop_front().unwrap())?;
+    match bytes.get(k) {
+        Some(b) => Ok(Object::new_int(*b as i64, true)),
+        None => Err(anyhow!("index out of range: {}", k)),
+    }
+}
+
+pub fn bytevector_u8_set(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let k = index(&args.pop_front().unwrap())?;
+    let b = byte(&args.pop_front().unwrap())?;
+    obj.bytevector_splice(k, &[b])?;
+    Ok(Object::new_undefined())
+}
+
+pub fn bytevector_copy(mut args: VecDeque<Object>) -> Result<Object> {
+    let bytes = ensure_bytevector(&args.pop_front().unwrap())?;
+    let (start, end) = range(args, bytes.len())?;
+    Ok(Object::new_bytevector(bytes[start..end].to_vec(), true))
+}
+
+// (bytevector-copy! to at from [start [end]])
+pub fn bytevector_copy_to(mut args: VecDeque<Object>) -> Result<Object> {
+    let to = args.pop_front().unwrap();
+    let at = index(&args.pop_front().unwrap())?;
+    let bytes = ensure_bytevector(&args.pop_front().unwrap())?;
+    let (start, end) = range(args, bytes.len())?;
+    to.bytevector_splice(at, &bytes[start..end])?;
+    Ok(Object::new_undefined())
+}
+
+pub fn bytevector_append(args: VecDeque<Object>) -> Result<Object> {
+    let mut bytes = Vec::new();
+    for obj in &args {
+        bytes.extend(ensure_bytevector(obj)?);
+    }
+    Ok(Object::new_bytevector(bytes, true))
+}
+
+pub fn utf8_to_string(mut args: VecDeque<Object>) -> Result<Object> {
+    let bytes = ensure_bytevector(&args.pop_front().unwrap())?;
+    let (start, end) = range(args, bytes.len())?;
+    match String::from_utf8(bytes[start..end].to_vec()) {
+        Ok(s) => Ok(Object::new_string(s, true)),
+        Err(_) => Err(anyhow!("invalid UTF-8 sequence")),
+    }
+}
+
+pub fn string_to_utf8(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let chars: Vec<char> = match obj.kind() {
+        Kind::String(s) => s.chars().collect(),
+        _ => bail!("string required, but got {}", obj),
+    };
+    let (start, end) = range(args, chars.len())?;
+    let s: String = chars[start..end].iter().collect();
+    Ok(Object::new_bytevector(s.into_bytes(), true))
+}
@@ -0,0 +1,150 @@
+use crate::data::{*, object::*};
+
+use std::collections::VecDeque;
+
+use anyhow::{Result, anyhow};
+
+fn ensure_char(obj: &Object) -> Result<char> {
+    match obj.kind() {
+        Kind::Char(c) => Ok(*c),
+        _ => Err(anyhow!("char required, but got {}", obj)),
+    }
+}
+
+// a case mapping which gives one character, or the character itself
+fn single(mut mapped: impl Iterator<Item = char>, c: char) -> char {
+    match (mapped.next(), mapped.next()) {
+        (Some(m), None) => m,
+        _ => c,
+    }
+}
+
+fn upcase(c: char) -> char {
+    single(c.to_uppercase(), c)
+}
+
+fn downcase(c: char) -> char {
+    single(c.to_lowercase(), c)
+}
+
+fn foldcase(c: char) -> char {
+    downcase(upcase(c))
+}
+
+fn compare(args: VecDeque<Object>, fold: fn(char) -> char, cmp: fn(char, char) -> bool) -> Result<Object> {
+    let chars = args.iter().map(ensure_char).collect::<Result<Vec<char>>>()?;
+    let res = chars.windows(2).all(|w| cmp(fold(w[0]), fold(w[1])));
+    Ok(Object::new_boolean(res, true))
+}
+
+pub fn is_char(mut args: VecDeque<Object>) -> Result<Object> {
+    match args.pop_front().unwrap().kind() {
+        Kind::Char(_) => Ok(Object::new_boolean(true, true)),
+        _             => Ok(Object::new_boolean(false, true)),
+    }
+}
+
+pub fn char_to_integer(mut args: VecDeque<Object>) -> Result<Object> {
+    let c = ensure_char(&args.pop_front().unwrap())?;
+    Ok(Object::new_int(c as i64, true))
+}
+
+pub fn integer_to_char(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    match obj.kind() {
+        Kind::Number(Number::Int(i)) => u32::try_from(*i)
+            .ok()
+            .and_then(char::from_u32)
+            .map(Object::new_char)
+            .ok_or_else(|| anyhow!("Unicode scalar value required, but got {}", obj)),
+        _ => Err(anyhow!("integer required, but got {}", obj)),
+    }
+}
+
+pub fn char_eq(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, |c| c, |a, b| a == b)
+}
+
+pub fn char_lt(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, |c| c, |a, b| a < b)
+}
+
+pub fn char_gt(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, |c| c, |a, b| a > b)
+}
+
+pub fn char_le(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, |c| c, |a, b| a <= b)
+}
+
+pub fn char_ge(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, |c| c, |a, b| a >= b)
+}
+
+pub fn char_ci_eq(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, foldcase, |a, b| a == b)
+}
+
+pub fn char_ci_lt(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, foldcase, |a, b| a < b)
+}
+
+pub fn char_ci_gt(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, foldcase, |a, b| a > b)
+}
+
+pub fn char_ci_le(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, foldcase, |a, b| a <= b)
+}
+
+pub fn char_ci_ge(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, foldcase, |a, b| a >= b)
+}
+
+pub fn is_alphabetic(mut args: VecDeque<Object>) -> Result<Object> {
+    let c = ensure_char(&args.pop_front().unwrap())?;
+    Ok(Object::new_boolean(c.is_alphabetic(), true))
+}
+
+pub fn is_numeric(mut args: VecDeque<Object>) -> Result<Object> {
+    let c = ensure_char(&args.pop_front().unwrap())?;
+    Ok(Object::new_boolean(c.is_numeric(), true))
+}
+
+pub fn is_whitespace(mut args: VecDeque<Object>) -> Result<Object> {
+    let c = ensure_char(&args.pop_front().unwrap())?;
+    Ok(Object::new_boolean(c.is_whitespace(), true))
+}
+
+pub fn is_upper_case(mut args: VecDeque<Object>) -> Result<Object> {
+    let c = ensure_char(&args.pop_front().unwrap())?;
+    Ok(Object::new_boolean(c.is_uppercase(), true))
+}
+
+pub fn is_lower_case(mut args: VecDeque<Object>) -> Result<Object> {
+    let c = ensure_char(&args.pop_front().unwrap())?;
+    Ok(Object::new_boolean(c.is_lowercase(), true))
+}
+
+pub fn digit_value(mut args: VecDeque<Object>) -> Result<Object> {
+    let c = ensure_char(&args.pop_front().unwrap())?;
+    match c.to_digit(10) {
+        Some(d) => Ok(Object::new_int(d as i64, true)),
+        None => Ok(Object::new_boolean(false, true)),
+    }
+}
+
+pub fn char_upcase(mut args: VecDeque<Object>) -> Result<Object> {
+    let c = ensure_char(&args.pop_front().unwrap())?;
+    Ok(Object::new_char(upcase(c)))
+}
+
+pub fn char_downcase(mut args: VecDeque<Object>) -> Result<Object> {
+    let c = ensure_char(&args.pop_front().unwrap())?;
+    Ok(Object::new_char(downcase(c)))
+}
+
+pub fn char_foldcase(mut args: VecDeque<Object>) -> Result<Object> {
+    let c = ensure_char(&args.pop_front().unwrap())?;
+    Ok(Object::new_char(foldcase(c)))
+}
@@ -10,6 +10,13 @@ pub fn eq(mut args: VecDeque<Object>) -> Result<Object> {
     Ok(Object::new_boolean(Object::scm_eq(&lhs, &rhs), true))
 }
 
+// eqv? is eq?: numbers and characters are compared by value, and the others by identity
+pub fn eqv(mut args: VecDeque<Object>) -> Result<Object> {
+    let lhs = args.pop_front().unwrap();
+    let rhs = args.pop_front().unwrap();
+    Ok(Object::new_boolean(Object::scm_eq(&lhs, &rhs), true))
+}
+
 pub fn neq(mut args: VecDeque<Object>) -> Result<Object> {
     let lhs = args.pop_front().unwrap();
     let rhs = args.pop_front().unwrap();
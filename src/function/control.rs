@@ -0,0 +1,184 @@
+use crate::data::{*, object::*};
+
+use std::collections::VecDeque;
+
+use anyhow::{Result, anyhow, bail};
+
+pub fn ensure_procedure(obj: Object) -> Result<Object> {
+    match obj.kind() {
+        Kind::Procedure(_) => Ok(obj),
+        _ => Err(anyhow!("procedure required, but got {}", obj)),
+    }
+}
+
+pub fn call_cc(mut args: VecDeque<Object>) -> Result<Control> {
+    Ok(Control::CallCC(ensure_procedure(args.pop_front().unwrap())?))
+}
+
+pub fn dynamic_wind(mut args: VecDeque<Object>) -> Result<Control> {
+    let before = ensure_procedure(args.pop_front().unwrap())?;
+    let thunk = ensure_procedure(args.pop_front().unwrap())?;
+    let after = ensure_procedure(args.pop_front().unwrap())?;
+    Ok(Control::DynamicWind(before, thunk, after))
+}
+
+// a list of the objects
+pub fn to_list(objs: impl DoubleEndedIterator<Item = Object>) -> Object {
+    objs.rev().fold(Object::new_empty(), |list, obj| Object::new_pair(obj, list, true))
+}
+
+pub fn reverse(list: &Object) -> Object {
+    list.into_iter().fold(Object::new_empty(), |rev, obj| Object::new_pair(obj, rev, true))
+}
+
+// the cars of the lists, advancing the lists to their cdrs. None if one of them has ended.
+pub fn uncons(lists: &mut [Object]) -> Result<Option<VecDeque<Object>>> {
+    let mut cars = VecDeque::new();
+    for list in lists.iter() {
+        match list.kind() {
+            Kind::Pair(pair) => cars.push_back(pair.car()),
+            Kind::Empty => return Ok(None),
+            _ => bail!("proper list required, but got {}", list),
+        }
+    }
+    for list in lists.iter_mut() {
+        let cdr = match list.kind() {
+            Kind::Pair(pair) => pair.cdr(),
+            _ => unreachable!(),
+        };
+        *list = cdr;
+    }
+    Ok(Some(cars))
+}
+
+pub fn values(args: VecDeque<Object>) -> Result<Object> {
+    Ok(Object::new_values(args.into()))
+}
+
+pub fn call_with_values(mut args: VecDeque<Object>) -> Result<Control> {
+    // the state is [consumer]
+    fn next(val: Object, state: Vec<Object>) -> Result<Control> {
+        let args = match val.kind() {
+            Kind::Values(values) => values.to_vec().into(),
+            _ => VecDeque::from([val]),
+        };
+        Ok(Control::Apply(state[0].clone(), args))
+    }
+
+    let producer = ensure_procedure(args.pop_front().unwrap())?;
+    let consumer = ensure_procedure(args.pop_front().unwrap())?;
+    Ok(Control::Call(producer, VecDeque::new(), next, vec![consumer]))
+}
+
+// (apply proc arg1 ... args)
+pub fn apply(mut args: VecDeque<Object>) -> Result<Control> {
+    let proc = ensure_procedure(args.pop_front().unwrap())?;
+    let last = args.pop_back().unwrap();
+    if !last.is_list() {
+        bail!("proper list required, but got {}", last);
+    }
+    args.extend(&last);
+    Ok(Control::Apply(proc, args))
+}
+
+// The state of map and its relatives is [proc, results (the latest first), list1, list2, ...].
+fn map_step(mut state: Vec<Object>, next: NativeFn, finish: fn(Object) -> Result<Object>) -> Result<Control> {
+    match uncons(&mut state[2..])? {
+        Some(args) => Ok(Control::Call(state[0].clone(), args, next, state)),
+        None => Ok(Control::Return(finish(reverse(&state[1]))?)),
+    }
+}
+
+fn map_state(mut args: VecDeque<Object>, to_list: fn(&Object) -> Result<Object>) -> Result<Vec<Object>> {
+    let proc = ensure_procedure(args.pop_front().unwrap())?;
+    let mut state = vec![proc, Object::new_empty()];
+    for obj in &args {
+        state.push(to_list(obj)?);
+    }
+    Ok(state)
+}
+
+fn list_arg(obj: &Object) -> Result<Object> {
+    match obj.kind() {
+        Kind::Pair(_) | Kind::Empty => Ok(obj.clone()),
+        _ => Err(anyhow!("list required, but got {}", obj)),
+    }
+}
+
+fn vector_arg(obj: &Object) -> Result<Object> {
+    match obj.kind() {
+        Kind::Vector(vector) => Ok(to_list(vector.to_vec().into_iter())),
+        _ => Err(anyhow!("vector required, but got {}", obj)),
+    }
+}
+
+fn string_arg(obj: &Object) -> Result<Object> {
+    match obj.kind() {
+        Kind::String(s) => Ok(to_list(s.chars().map(Object::new_char).collect::<Vec<_>>().into_iter())),
+        _ => Err(anyhow!("string required, but got {}", obj)),
+    }
+}
+
+fn push_result(val: Object, mut state: Vec<Object>) -> Vec<Object> {
+    state[1] = Object::new_pair(val, state[1].clone(), true);
+    state
+}
+
+fn ignore(_: Object) -> Result<Object> {
+    Ok(Object::new_undefined())
+}
+
+pub fn map(args: VecDeque<Object>) -> Result<Control> {
+    fn next(val: Object, state: Vec<Object>) -> Result<Control> {
+        map_step(push_result(val, state), next, Ok)
+    }
+    map_step(map_state(args, list_arg)?, next, Ok)
+}
+
+pub fn for_each(args: VecDeque<Object>) -> Result<Control> {
+    fn next(_: Object, state: Vec<Object>) -> Result<Control> {
+        map_step(state, next, ignore)
+    }
+    map_step(map_state(args, list_arg)?, next, ignore)
+}
+
+pub fn vector_map(args: VecDeque<Object>) -> Result<Control> {
+    fn finish(results: Object) -> Result<Object> {
+        Ok(Object::new_vector(results.into_iter().collect(), true))
+    }
+    fn next(val: Object, state: Vec<Object>) -> Result<Control> {
+        map_step(push_result(val, state), next, finish)
+    }
+    map_step(map_state(args, vector_arg)?, next, finish)
+}
+
+pub fn vector_for_each(args: VecDeque<Object>) -> Result<Control> {
+    fn next(_: Object, state: Vec<Object>) -> Result<Control> {
+        map_step(state, next, ignore)
+    }
+    map_step(map_state(args, vector_arg)?, next, ignore)
+}
+
+pub fn string_map(args: VecDeque<Object>) -> Result<Control> {
+    fn finish(results: Object) -> Result<Object> {
+        let s = results
+            .into_iter()
+            .map(|obj| match obj.kind() {
+                Kind::Char(c) => Ok(*c),
+                _ => Err(anyhow!("char required, but got {}", obj)),
+            })
+            .collect::<Result<String>>()?;
+        Ok(Object::new_string(s, true))
+    }
+    fn next(val: Object, state: Vec<Object>) -> Result<Control> {
+        map_step(push_result(val, state), next, finish)
+    }
+    map_step(map_state(args, string_arg)?, next, finish)
+}
+
+pub fn string_for_each(args: VecDeque<Object>) -> Result<Control> {
+    fn next(_: Object, state: Vec<Object>) -> Result<Control> {
+        map_step(state, next, ignore)
+    }
+    map_step(map_state(args, string_arg)?, next, ignore)
+}
@@ -1,10 +1,30 @@
 use crate::data::*;
+use super::port::write_to;
 
 use std::collections::VecDeque;
 
 use anyhow::Result;
 
+// (display obj [port])
 pub fn display(mut args: VecDeque<Object>) -> Result<Object> {
-    println!("{}", args.pop_front().unwrap());
-    Ok(Object::new_undefined())
+    let s = args.pop_front().unwrap().display_string();
+    write_to(&mut args, &s)
+}
+
+// (write obj [port])
+pub fn write(mut args: VecDeque<Object>) -> Result<Object> {
+    let s = args.pop_front().unwrap().to_string();
+    write_to(&mut args, &s)
+}
+
+// (write-shared obj [port])
+pub fn write_shared(mut args: VecDeque<Object>) -> Result<Object> {
+    let s = args.pop_front().unwrap().write_shared_string();
+    write_to(&mut args, &s)
+}
+
+// (write-simple obj [port])
+pub fn write_simple(mut args: VecDeque<Object>) -> Result<Object> {
+    let s = args.pop_front().unwrap().write_simple_string();
+    write_to(&mut args, &s)
 }
@@ -0,0 +1,55 @@
+use crate::data::{*, object::*};
+use super::list;
+use super::control::ensure_procedure;
+
+use std::collections::VecDeque;
+
+use anyhow::{Result, anyhow};
+
+pub fn with_exception_handler(mut args: VecDeque<Object>) -> Result<Control> {
+    let handler = ensure_procedure(args.pop_front().unwrap())?;
+    let thunk = ensure_procedure(args.pop_front().unwrap())?;
+    Ok(Control::WithHandler(handler, thunk))
+}
+
+pub fn raise(mut args: VecDeque<Object>) -> Result<Control> {
+    Ok(Control::Raise(args.pop_front().unwrap(), false))
+}
+
+pub fn raise_continuable(mut args: VecDeque<Object>) -> Result<Control> {
+    Ok(Control::Raise(args.pop_front().unwrap(), true))
+}
+
+// (error message irritant ...)
+pub fn error(mut args: VecDeque<Object>) -> Result<Control> {
+    let message = args.pop_front().unwrap();
+    let message = match message.kind() {
+        Kind::String(s) => s.clone(),
+        _ => return Err(anyhow!("string required, but got {}", message)),
+    };
+    let irritants = list::list(args)?;
+    Ok(Control::Raise(Object::new_error(message, irritants), false))
+}
+
+pub fn is_error_object(mut args: VecDeque<Object>) -> Result<Object> {
+    match args.pop_front().unwrap().kind() {
+        Kind::Error(_) => Ok(Object::new_boolean(true, true)),
+        _              => Ok(Object::new_boolean(false, true)),
+    }
+}
+
+pub fn error_object_message(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    match obj.kind() {
+        Kind::Error(err) => Ok(Object::new_string(err.message.clone(), true)),
+        _ => Err(anyhow!("error object required, but got {}", obj)),
+    }
+}
+
+pub fn error_object_irritants(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    match obj.kind() {
+        Kind::Error(err) => Ok(err.irritants()),
+        _ => Err(anyhow!("error object required, but got {}", obj)),
+    }
+}
@@ -0,0 +1,226 @@
+use crate::data::{*, object::*, hashtable::{HashTable, Comparator}};
+use super::control::{ensure_procedure, to_list};
+use super::{cmp, string};
+
+use std::collections::VecDeque;
+
+use anyhow::{Result, anyhow, bail};
+
+fn ensure_hash_table(obj: &Object) -> Result<&HashTable> {
+    match obj.kind() {
+        Kind::HashTable(table) => Ok(table),
+        _ => Err(anyhow!("hash table required, but got {}", obj)),
+    }
+}
+
+// the comparator of the equality predicate
+fn comparator(proc: &Object) -> Result<Comparator> {
+    let fun = match proc.kind() {
+        Kind::Procedure(Procedure::Subr(subr)) => subr.fun,
+        _ => bail!("eq?, eqv?, equal? or string=? required, but got {}", proc),
+    };
+    if std::ptr::fn_addr_eq(fun, cmp::eq as SubrFn) {
+        Ok(Comparator::Eq)
+    } else if std::ptr::fn_addr_eq(fun, cmp::eqv as SubrFn) {
+        Ok(Comparator::Eqv)
+    } else if std::ptr::fn_addr_eq(fun, cmp::equal as SubrFn) {
+        Ok(Comparator::Equal)
+    } else if std::ptr::fn_addr_eq(fun, string::string_eq as SubrFn) {
+        Ok(Comparator::String)
+    } else {
+        bail!("eq?, eqv?, equal? or string=? required, but got {}", proc)
+    }
+}
+
+// (make-hash-table [equal? [hash]]): the hash function is checked, but the table hashes the keys by itself
+pub fn make_hash_table(mut args: VecDeque<Object>) -> Result<Object> {
+    let comparator = match args.pop_front() {
+        Some(proc) => comparator(&proc)?,
+        None => Comparator::Equal,
+    };
+    if let Some(hash) = args.pop_front() {
+        ensure_procedure(hash)?;
+    }
+    if let Some(obj) = args.pop_front() {
+        bail!("too many arguments: {}", obj);
+    }
+    Ok(Object::new_hash_table(comparator))
+}
+
+pub fn is_hash_table(mut args: VecDeque<Object>) -> Result<Object> {
+    let res = matches!(args.pop_front().unwrap().kind(), Kind::HashTable(_));
+    Ok(Object::new_boolean(res, true))
+}
+
+// (hash-table-ref table key [failure [success]]): (success value), or (failure) if "key" is not found
+pub fn hash_table_ref(mut args: VecDeque<Object>) -> Result<Control> {
+    let table = args.pop_front().unwrap();
+    let key = args.pop_front().unwrap();
+    let failure = args.pop_front().map(ensure_procedure).transpose()?;
+    let success = args.pop_front().map(ensure_procedure).transpose()?;
+    if let Some(obj) = args.pop_front() {
+        bail!("too many arguments: {}", obj);
+    }
+    match (ensure_hash_table(&table)?.get(&key)?, failure, success) {
+        (Some(value), _, Some(success)) => Ok(Control::Apply(success, VecDeque::from([value]))),
+        (Some(value), _, None) => Ok(Control::Return(value)),
+        (None, Some(failure), _) => Ok(Control::Apply(failure, VecDeque::new())),
+        (None, None, _) => bail!("key not found: {}", key),
+    }
+}
+
+// (hash-table-ref/default table key default)
+pub fn hash_table_ref_default(mut args: VecDeque<Object>) -> Result<Object> {
+    let table = args.pop_front().unwrap();
+    let key = args.pop_front().unwrap();
+    let default = args.pop_front().unwrap();
+    Ok(ensure_hash_table(&table)?.get(&key)?.unwrap_or(default))
+}
+
+// (hash-table-set! table key value)
+pub fn hash_table_set(mut args: VecDeque<Object>) -> Result<Object> {
+    let table = args.pop_front().unwrap();
+    ensure_hash_table(&table)?;
+    let key = args.pop_front().unwrap();
+    let value = args.pop_front().unwrap();
+    table.hash_table_set(&key, &value)?;
+    Ok(Object::new_undefined())
+}
+
+// (hash-table-delete! table key)
+pub fn hash_table_delete(mut args: VecDeque<Object>) -> Result<Object> {
+    let table = args.pop_front().unwrap();
+    ensure_hash_table(&table)?;
+    table.hash_table_delete(&args.pop_front().unwrap())?;
+    Ok(Object::new_undefined())
+}
+
+pub fn hash_table_contains(mut args: VecDeque<Object>) -> Result<Object> {
+    let table = args.pop_front().unwrap();
+    let key = args.pop_front().unwrap();
+    let res = ensure_hash_table(&table)?.get(&key)?.is_some();
+    Ok(Object::new_boolean(res, true))
+}
+
+// The state of hash-table-update! is [table, key, updater].
+fn update_step(val: Object, state: Vec<Object>) -> Result<Control> {
+    fn next(val: Object, state: Vec<Object>) -> Result<Control> {
+        state[0].hash_table_set(&state[1], &val)?;
+        Ok(Control::Return(Object::new_undefined()))
+    }
+
+    Ok(Control::Call(state[2].clone(), VecDeque::from([val]), next, state))
+}
+
+// (hash-table-update! table key updater [failure [success]]):
+// sets (updater (hash-table-ref table key failure success)) to "key"
+pub fn hash_table_update(mut args: VecDeque<Object>) -> Result<Control> {
+    let table = args.pop_front().unwrap();
+    let key = args.pop_front().unwrap();
+    let updater = ensure_procedure(args.pop_front().unwrap())?;
+    let failure = args.pop_front().map(ensure_procedure).transpose()?;
+    let success = args.pop_front().map(ensure_procedure).transpose()?;
+    if let Some(obj) = args.pop_front() {
+        bail!("too many arguments: {}", obj);
+    }
+    let value = ensure_hash_table(&table)?.get(&key)?;
+    let state = vec![table, key, updater];
+    match (value, failure, success) {
+        (Some(value), _, Some(success)) => Ok(Control::Call(success, VecDeque::from([value]), update_step, state)),
+        (Some(value), _, None) => update_step(value, state),
+        (None, Some(failure), _) => Ok(Control::Call(failure, VecDeque::new(), update_step, state)),
+        (None, None, _) => bail!("key not found: {}", state[1]),
+    }
+}
+
+// (hash-table-update!/default table key updater default)
+pub fn hash_table_update_default(mut args: VecDeque<Object>) -> Result<Control> {
+    let table = args.pop_front().unwrap();
+    let key = args.pop_front().unwrap();
+    let updater = ensure_procedure(args.pop_front().unwrap())?;
+    let default = args.pop_front().unwrap();
+    let value = ensure_hash_table(&table)?.get(&key)?.unwrap_or(default);
+    update_step(value, vec![table, key, updater])
+}
+
+pub fn hash_table_keys(mut args: VecDeque<Object>) -> Result<Object> {
+    let table = args.pop_front().unwrap();
+    Ok(to_list(ensure_hash_table(&table)?.keys().into_iter()))
+}
+
+// ((key . value) ...)
+pub fn hash_table_to_alist(mut args: VecDeque<Object>) -> Result<Object> {
+    let table = args.pop_front().unwrap();
+    let entries = ensure_hash_table(&table)?.to_vec().into_iter()
+        .map(|(key, value)| Object::new_pair(key, value, true));
+    Ok(to_list(entries.collect::<Vec<_>>().into_iter()))
+}
+
+// (hash-table-walk table proc): (proc key value) for each entry
+pub fn hash_table_walk(mut args: VecDeque<Object>) -> Result<Control> {
+    // the state is [proc, the entries left as value, key, ... (the next one last)]
+    fn step(mut state: Vec<Object>) -> Result<Control> {
+        if state.len() == 1 {
+            return Ok(Control::Return(Object::new_undefined()))
+        }
+        let key = state.pop().unwrap();
+        let value = state.pop().unwrap();
+        Ok(Control::Call(state[0].clone(), VecDeque::from([key, value]), next, state))
+    }
+    fn next(_: Object, state: Vec<Object>) -> Result<Control> {
+        step(state)
+    }
+
+    let table = args.pop_front().unwrap();
+    let proc = ensure_procedure(args.pop_front().unwrap())?;
+    let mut state = vec![proc];
+    for (key, value) in ensure_hash_table(&table)?.to_vec().into_iter().rev() {
+        state.push(value);
+        state.push(key);
+    }
+    step(state)
+}
+
+// (hash-table-count table): the number of the entries,
+// or (hash-table-count pred table): the number of the entries for which (pred key value) is true
+pub fn hash_table_count(mut args: VecDeque<Object>) -> Result<Control> {
+    // the state is [pred, count, the entries left as value, key, ... (the next one last)]
+    fn step(mut state: Vec<Object>) -> Result<Control> {
+        if state.len() == 2 {
+            return Ok(Control::Return(state.pop().unwrap()))
+        }
+        let key = state.pop().unwrap();
+        let value = state.pop().unwrap();
+        Ok(Control::Call(state[0].clone(), VecDeque::from([key, value]), next, state))
+    }
+    fn next(val: Object, mut state: Vec<Object>) -> Result<Control> {
+        if !val.is_falsy() {
+            let count = match state[1].kind() {
+                Kind::Number(Number::Int(count)) => *count,
+                _ => unreachable!(),
+            };
+            state[1] = Object::new_int(count + 1, true);
+        }
+        step(state)
+    }
+
+    let table = args.pop_back().unwrap();
+    let entries = ensure_hash_table(&table)?.to_vec();
+    let Some(pred) = args.pop_back() else {
+        return Ok(Control::Return(Object::new_int(entries.len() as i64, true)))
+    };
+    if let Some(obj) = args.pop_front() {
+        bail!("too many arguments: {}", obj);
+    }
+    let mut state = vec![ensure_procedure(pred)?, Object::new_int(0, true)];
+    for (key, value) in entries.into_iter().rev() {
+        state.push(value);
+        state.push(key);
+    }
+    step(state)
+}
+
+pub fn hash_table_size(mut args: VecDeque<Object>) -> Result<Object> {
+    let table = args.pop_front().unwrap();
+    Ok(Object::new_int(ensure_hash_table(&table)?.len() as i64, true))
+}
@@ -2,7 +2,9 @@ use crate::data::{*, object::*};
 
 use std::collections::VecDeque;
 
-use anyhow::{Result, anyhow, Context};
+use anyhow::{Result, anyhow, bail, Context};
+
+use super::control::{ensure_procedure, to_list, reverse, uncons};
 
 pub fn is_null(mut args: VecDeque<Object>) -> Result<Object> {
     match args.pop_front().unwrap().kind() {
@@ -120,3 +122,151 @@ pub fn set_cdr(mut args: VecDeque<Object>) -> Result<Object> {
     pair.set_cdr(obj.clone())?;
     Ok(Object::new_undefined())
 }
+
+fn ensure_list(obj: Object) -> Result<Object> {
+    match obj.is_list() {
+        true => Ok(obj),
+        false => Err(anyhow!("proper list required, but got {}", obj)),
+    }
+}
+
+fn split(list: &Object) -> (Object, Object) {
+    match list.kind() {
+        Kind::Pair(pair) => (pair.car(), pair.cdr()),
+        _ => unreachable!(),
+    }
+}
+
+// (filter pred list)
+pub fn filter(mut args: VecDeque<Object>) -> Result<Control> {
+    // the state is [pred, the elements left, the elements kept (the latest first)]
+    fn step(state: Vec<Object>) -> Result<Control> {
+        match state[1].kind() {
+            Kind::Pair(pair) => Ok(Control::Call(state[0].clone(), VecDeque::from([pair.car()]), next, state)),
+            _ => Ok(Control::Return(reverse(&state[2]))),
+        }
+    }
+    fn next(val: Object, mut state: Vec<Object>) -> Result<Control> {
+        let (obj, rest) = split(&state[1]);
+        if !val.is_falsy() {
+            state[2] = Object::new_pair(obj, state[2].clone(), true);
+        }
+        state[1] = rest;
+        step(state)
+    }
+
+    let pred = ensure_procedure(args.pop_front().unwrap())?;
+    let list = ensure_list(args.pop_front().unwrap())?;
+    step(vec![pred, list, Object::new_empty()])
+}
+
+// (fold kons knil list1 list2 ...): (kons elem1 elem2 ... acc) from the left
+pub fn fold(mut args: VecDeque<Object>) -> Result<Control> {
+    // the state is [kons, acc, list1, list2, ...]
+    fn step(mut state: Vec<Object>) -> Result<Control> {
+        match uncons(&mut state[2..])? {
+            Some(mut args) => {
+                args.push_back(state[1].clone());
+                Ok(Control::Call(state[0].clone(), args, next, state))
+            }
+            None => Ok(Control::Return(state[1].clone())),
+        }
+    }
+    fn next(val: Object, mut state: Vec<Object>) -> Result<Control> {
+        state[1] = val;
+        step(state)
+    }
+
+    let kons = ensure_procedure(args.pop_front().unwrap())?;
+    let mut state = vec![kons];
+    state.extend(args);
+    step(state)
+}
+
+// (sort seq less?): a stable merge sort of a list or a vector
+pub fn sort(mut args: VecDeque<Object>) -> Result<Control> {
+    // The state is [less?, runs to merge, merged runs (the latest first), run a, run b,
+    // the merged part of a and b (the latest first), whether seq is a vector].
+    // Runs are merged in pairs until only one is left.
+    const LESS: usize = 0;
+    const QUEUE: usize = 1;
+    const OUT: usize = 2;
+    const A: usize = 3;
+    const B: usize = 4;
+    const ACC: usize = 5;
+    const VECTOR: usize = 6;
+
+    fn step(mut state: Vec<Object>) -> Result<Control> {
+        if let (Kind::Pair(a), Kind::Pair(b)) = (state[A].kind(), state[B].kind()) {
+            let args = VecDeque::from([b.car(), a.car()]);
+            return Ok(Control::Call(state[LESS].clone(), args, next, state));
+        }
+        // a or b has run out, and the rest of the other follows the merged part
+        let mut merged = match state[A].kind() {
+            Kind::Empty => state[B].clone(),
+            _ => state[A].clone(),
+        };
+        for obj in &state[ACC] {
+            merged = Object::new_pair(obj, merged, true);
+        }
+        state[OUT] = Object::new_pair(merged, state[OUT].clone(), true);
+        state[ACC] = Object::new_empty();
+        state[A] = Object::new_empty();
+        state[B] = Object::new_empty();
+        pass(state)
+    }
+
+    fn next(val: Object, mut state: Vec<Object>) -> Result<Control> {
+        let taken = if val.is_falsy() { A } else { B };
+        let (obj, rest) = split(&state[taken]);
+        state[ACC] = Object::new_pair(obj, state[ACC].clone(), true);
+        state[taken] = rest;
+        step(state)
+    }
+
+    // take the next two runs to merge
+    fn pass(mut state: Vec<Object>) -> Result<Control> {
+        if let Kind::Pair(_) = state[QUEUE].kind() {
+            let (a, rest) = split(&state[QUEUE]);
+            if let Kind::Pair(_) = rest.kind() {
+                let (b, rest) = split(&rest);
+                state[A] = a;
+                state[B] = b;
+                state[QUEUE] = rest;
+                return step(state);
+            }
+            // the last run of an odd number is left to the next round
+            state[OUT] = Object::new_pair(a, state[OUT].clone(), true);
+            state[QUEUE] = rest;
+        }
+        // the end of a round
+        let merged = reverse(&state[OUT]);
+        match merged.length() {
+            Some(0) => finish(Object::new_empty(), &state),
+            Some(1) => finish(split(&merged).0, &state),
+            _ => {
+                state[QUEUE] = merged;
+                state[OUT] = Object::new_empty();
+                pass(state)
+            }
+        }
+    }
+
+    fn finish(sorted: Object, state: &[Object]) -> Result<Control> {
+        match state[VECTOR].is_falsy() {
+            true => Ok(Control::Return(sorted)),
+            false => Ok(Control::Return(Object::new_vector(sorted.into_iter().collect(), true))),
+        }
+    }
+
+    let seq = args.pop_front().unwrap();
+    let less = ensure_procedure(args.pop_front().unwrap())?;
+    let (elems, is_vector) = match seq.kind() {
+        Kind::Vector(vector) => (vector.to_vec(), true),
+        _ if seq.is_list() => (seq.into_iter().collect(), false),
+        _ => bail!("list or vector required, but got {}", seq),
+    };
+    let runs = to_list(elems.into_iter().map(|obj| Object::new_pair(obj, Object::new_empty(), true)).collect::<Vec<_>>().into_iter());
+    let empty = Object::new_empty;
+    pass(vec![less, runs, empty(), empty(), empty(), empty(), Object::new_boolean(is_vector, true)])
+}
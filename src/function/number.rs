@@ -1,8 +1,19 @@
 use crate::data::{*, object::*};
 
+use std::cmp::Ordering;
 use std::collections::VecDeque;
 
-use anyhow::{Result, bail, anyhow};
+use anyhow::{Result, anyhow, bail, Context};
+use num_bigint::BigInt;
+use num_integer::Integer;
+use num_traits::{Zero, Signed, ToPrimitive};
+
+fn ensure_number(obj: &Object) -> Result<Number> {
+    match obj.kind() {
+        Kind::Number(num) => Ok(num.clone()),
+        _ => Err(anyhow!("number required, but got {}", obj)),
+    }
+}
 
 pub fn is_number(mut args: VecDeque<Object>) -> Result<Object> {
     match args.pop_front().unwrap().kind() {
@@ -11,474 +22,408 @@ pub fn is_number(mut args: VecDeque<Object>) -> Result<Object> {
     }
 }
 
-pub fn add(mut args: VecDeque<Object>) -> Result<Object> {
-    let mut acc = 0;
-    while let Some(obj) = args.pop_front() {
-        match obj.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(i) => acc += i,
-                &Number::Float(f) => return add_float(args, (acc as f64) + f)
-            }
-            _ => bail!("number required, but got {}", obj)
-        }
+pub fn is_exact_integer(mut args: VecDeque<Object>) -> Result<Object> {
+    match args.pop_front().unwrap().kind() {
+        Kind::Number(num) => Ok(Object::new_boolean(num.is_exact_integer(), true)),
+        _ => Ok(Object::new_boolean(false, true)),
     }
-    Ok(Object::new_int(acc, true))
 }
 
-fn add_float(mut args: VecDeque<Object>, mut acc: f64) -> Result<Object> {
-    while let Some(obj) = args.pop_front() {
-        match obj.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(i) => acc += i as f64,
-                &Number::Float(f) => acc += f,
-            }
-            _ => bail!("number required, but got {}", obj)
-        }
+pub fn add(args: VecDeque<Object>) -> Result<Object> {
+    let mut acc = Number::Int(0);
+    for obj in &args {
+        acc = acc.add(&ensure_number(obj)?);
     }
-    Ok(Object::new_float(acc, true))
+    Ok(Object::new_number(acc, true))
 }
 
 pub fn minus(mut args: VecDeque<Object>) -> Result<Object> {
-    let mut acc;
-
-    let first = args.pop_front().unwrap();
-    if let Kind::Number(num) = first.kind() {
-        match num {
-            &Number::Int(i) => acc = i,
-            &Number::Float(f) => return minus_float(args, f),
-        };
-    } else {
-        bail!("number required, but got {}", first)
+    let first = ensure_number(&args.pop_front().unwrap())?;
+    if args.is_empty() {
+        return Ok(Object::new_number(Number::Int(0).sub(&first), true));
     }
-
-    if args.len() == 0 {
-        return Ok(Object::new_int(-acc, true));
+    let mut acc = first;
+    for obj in &args {
+        acc = acc.sub(&ensure_number(obj)?);
     }
+    Ok(Object::new_number(acc, true))
+}
 
-    while let Some(obj) = args.pop_front() {
-        match obj.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(i) => acc -= i,
-                &Number::Float(f) => return minus_float(args, (acc as f64) - f),
-            }
-            _ => bail!("number required, but got {}", obj)
-        }
+pub fn mul(args: VecDeque<Object>) -> Result<Object> {
+    let mut acc = Number::Int(1);
+    for obj in &args {
+        acc = acc.mul(&ensure_number(obj)?);
     }
-    
-    Ok(Object::new_int(acc, true))
+    Ok(Object::new_number(acc, true))
 }
 
-fn minus_float(mut args: VecDeque<Object>, mut acc: f64) -> Result<Object> {
-    if args.len() == 0{
-        return Ok(Object::new_float(-acc, true));
+pub fn div(mut args: VecDeque<Object>) -> Result<Object> {
+    let first = ensure_number(&args.pop_front().unwrap())?;
+    if args.is_empty() {
+        return Ok(Object::new_number(Number::Int(1).div(&first)?, true));
     }
-
-    while let Some(obj) = args.pop_front() {
-        match obj.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(i) => acc -= i as f64,
-                &Number::Float(f) => acc -= f,
-            }
-            _ => bail!("number required, but got {}", obj)
-        }
+    let mut acc = first;
+    for obj in &args {
+        acc = acc.div(&ensure_number(obj)?)?;
     }
+    Ok(Object::new_number(acc, true))
+}
 
-    Ok(Object::new_float(acc, true))
-} 
+// Each adjacent pair must satisfy "ok". NaN is not ordered, so it makes them false.
+fn compare(args: VecDeque<Object>, ok: fn(Ordering) -> bool) -> Result<Object> {
+    let nums = args.iter().map(ensure_number).collect::<Result<Vec<Number>>>()?;
+    let res = nums.windows(2).all(|w| w[0].partial_cmp(&w[1]).is_some_and(ok));
+    Ok(Object::new_boolean(res, true))
+}
 
-pub fn mul(mut args: VecDeque<Object>) -> Result<Object> {
-    let mut acc = 1;
-    while let Some(obj) = args.pop_front() {
-        match obj.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(i) => acc *= i,
-                &Number::Float(f) => return mul_float(args, (acc as f64) * f)
-            }
-            _ => bail!("number required, but got {}", obj)
-        }
-    }
-    Ok(Object::new_int(acc, true))
+pub fn eq(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, Ordering::is_eq)
 }
 
-fn mul_float(mut args: VecDeque<Object>, mut acc: f64) -> Result<Object> {
-    while let Some(obj) = args.pop_front() {
-        match obj.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(i) => acc *= i as f64,
-                &Number::Float(f) => acc *= f,
-            }
-            _ => bail!("number required, but got {}", obj)
-        }
-    }
-    Ok(Object::new_float(acc, true))
+pub fn lt(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, Ordering::is_lt)
 }
 
-pub fn div(mut args: VecDeque<Object>) -> Result<Object> {
-    let mut acc ;
-    let first = args.pop_front().unwrap();
-    if let Kind::Number(num) = first.kind() {
-        match num {
-            &Number::Int(i) => acc = i as f64,
-            &Number::Float(f) => acc = f,
-        };
-    } else {
-        bail!("number required, but got {}", first)
-    }
+pub fn le(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, Ordering::is_le)
+}
 
-    if args.len() == 0 {
-        // unary div
-        if acc == 0f64 {
-            bail!("zero division error");
-        } else {
-            return Ok(Object::new_float((1 as f64) / acc, true));
-        }
-    }
+pub fn gt(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, Ordering::is_gt)
+}
 
-    while let Some(obj) = args.pop_front() {
-        match obj.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(i) => {
-                    if i == 0 {
-                        bail!("zero division error");
-                    }
-                    acc /= i as f64;
-                }
-                &Number::Float(f) => {
-                    if f == 0.0 {
-                        bail!("zero division error");
-                    }
-                    acc /= f;
-                }
-            }
-            
-            _ => bail!("number required, but got {}", obj)
-        }
-    }
-    Ok(Object::new_float(acc, true))
+pub fn ge(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, Ordering::is_ge)
 }
 
-pub fn eq(mut args: VecDeque<Object>) -> Result<Object> {
-    // binary op "=" 
-    let first = args.pop_front().unwrap();
-    if let Kind::Number(num) = first.kind() {
-        match num {
-            &Number::Int(i) => eq_int(args, i),
-            &Number::Float(f) => eq_float(args, f),
-        }
-    } else {
-        Err(anyhow!("number required, but got {}", first))
-    }
+pub fn exact(mut args: VecDeque<Object>) -> Result<Object> {
+    let num = ensure_number(&args.pop_front().unwrap())?;
+    Ok(Object::new_number(num.exact()?, true))
 }
 
-fn eq_int(mut args: VecDeque<Object>, op1: i64) -> Result<Object> {
-    if let Some(op2) = args.pop_front() {
-        match op2.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(op2) => {
-                    if op1 != op2 {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        eq_int(args, op2)
-                    }
-                }
-                &Number::Float(op2) => {
-                    if (op1 as f64) != op2 {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        eq_float(args, op2)
-                    }
-                }
-            }
-            _ => Err(anyhow!("number required, but got {}", op2))
-        }
-    } else {
-        Ok(Object::new_boolean(true, true))
-    }
+pub fn inexact(mut args: VecDeque<Object>) -> Result<Object> {
+    let num = ensure_number(&args.pop_front().unwrap())?;
+    Ok(Object::new_number(num.inexact(), true))
 }
 
-fn eq_float(mut args: VecDeque<Object>, op1: f64) -> Result<Object> {
-    if let Some(op2) = args.pop_front() {
-        match op2.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(op2) => {
-                    if op1 != (op2 as f64) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        eq_int(args, op2)
-                    }
-                }
-                &Number::Float(op2) => {
-                    if op1 != op2 {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        eq_float(args, op2)
-                    }
-                }
-            }
-            _ => Err(anyhow!("number required, but got {}", op2))
-        }
-    } else {
-        Ok(Object::new_boolean(true, true))
-    }
+pub fn numerator(mut args: VecDeque<Object>) -> Result<Object> {
+    let num = ensure_number(&args.pop_front().unwrap())?;
+    Ok(Object::new_number(num.numerator()?, true))
 }
 
-pub fn lt(mut args: VecDeque<Object>) -> Result<Object> {
-    // binary op "=" 
-    let first = args.pop_front().unwrap();
-    if let Kind::Number(num) = first.kind() {
-        match num {
-            &Number::Int(i) => lt_int(args, i),
-            &Number::Float(f) => lt_float(args, f),
-        }
-    } else {
-        Err(anyhow!("number required, but got {}", first))
-    }
+pub fn denominator(mut args: VecDeque<Object>) -> Result<Object> {
+    let num = ensure_number(&args.pop_front().unwrap())?;
+    Ok(Object::new_number(num.denominator()?, true))
 }
 
-fn lt_int(mut args: VecDeque<Object>, op1: i64) -> Result<Object> {
-    if let Some(op2) = args.pop_front() {
-        match op2.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(op2) => {
-                    if !(op1 < op2) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        lt_int(args, op2)
-                    }
-                }
-                &Number::Float(op2) => {
-                    if !((op1 as f64) < op2) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        lt_float(args, op2)
-                    }
-                }
-            }
-            _ => Err(anyhow!("number required, but got {}", op2))
+fn ensure_integer(obj: &Object) -> Result<(BigInt, bool)> {
+    match obj.kind() {
+        Kind::Number(num) => match num.integer_value() {
+            Some(i) => Ok((i, num.is_exact())),
+            None => Err(anyhow!("integer required, but got {}", obj)),
         }
-    } else {
-        Ok(Object::new_boolean(true, true))
+        _ => Err(anyhow!("integer required, but got {}", obj)),
     }
 }
 
-fn lt_float(mut args: VecDeque<Object>, op1: f64) -> Result<Object> {
-    if let Some(op2) = args.pop_front() {
-        match op2.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(op2) => {
-                    if !(op1 < (op2 as f64)) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        lt_int(args, op2)
-                    }
-                }
-                &Number::Float(op2) => {
-                    if !(op1 < op2) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        lt_float(args, op2)
-                    }
-                }
-            }
-            _ => Err(anyhow!("number required, but got {}", op2))
-        }
-    } else {
-        Ok(Object::new_boolean(true, true))
+// an integer result, which is inexact if any of the operands is
+fn integer(i: BigInt, is_exact: bool) -> Object {
+    match is_exact {
+        true => Object::new_number(Number::from_bigint(i), true),
+        false => Object::new_number(Number::from_bigint(i).inexact(), true),
     }
 }
 
-pub fn le(mut args: VecDeque<Object>) -> Result<Object> {
-    // binary op "=" 
-    let first = args.pop_front().unwrap();
-    if let Kind::Number(num) = first.kind() {
-        match num {
-            &Number::Int(i) => le_int(args, i),
-            &Number::Float(f) => le_float(args, f),
-        }
-    } else {
-        Err(anyhow!("number required, but got {}", first))
+fn divide(mut args: VecDeque<Object>, op: fn(&BigInt, &BigInt) -> (BigInt, BigInt)) -> Result<(Object, Object)> {
+    let (n1, exact1) = ensure_integer(&args.pop_front().unwrap())?;
+    let (n2, exact2) = ensure_integer(&args.pop_front().unwrap())?;
+    if n2.is_zero() {
+        bail!("zero division error");
     }
+    let (q, r) = op(&n1, &n2);
+    Ok((integer(q, exact1 && exact2), integer(r, exact1 && exact2)))
 }
 
-fn le_int(mut args: VecDeque<Object>, op1: i64) -> Result<Object> {
-    if let Some(op2) = args.pop_front() {
-        match op2.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(op2) => {
-                    if !(op1 <= op2) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        le_int(args, op2)
-                    }
-                }
-                &Number::Float(op2) => {
-                    if !((op1 as f64) <= op2) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        le_float(args, op2)
-                    }
-                }
-            }
-            _ => Err(anyhow!("number required, but got {}", op2))
-        }
-    } else {
-        Ok(Object::new_boolean(true, true))
-    }
+fn floor_div(n1: &BigInt, n2: &BigInt) -> (BigInt, BigInt) {
+    n1.div_mod_floor(n2)
 }
 
-fn le_float(mut args: VecDeque<Object>, op1: f64) -> Result<Object> {
-    if let Some(op2) = args.pop_front() {
-        match op2.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(op2) => {
-                    if !(op1 <= (op2 as f64)) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        le_int(args, op2)
-                    }
-                }
-                &Number::Float(op2) => {
-                    if !(op1 <= op2) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        le_float(args, op2)
-                    }
-                }
-            }
-            _ => Err(anyhow!("number required, but got {}", op2))
-        }
-    } else {
-        Ok(Object::new_boolean(true, true))
+fn truncate_div(n1: &BigInt, n2: &BigInt) -> (BigInt, BigInt) {
+    n1.div_rem(n2)
+}
+
+pub fn floor_divide(args: VecDeque<Object>) -> Result<Object> {
+    let (q, r) = divide(args, floor_div)?;
+    Ok(Object::new_values(vec![q, r]))
+}
+
+pub fn floor_quotient(args: VecDeque<Object>) -> Result<Object> {
+    Ok(divide(args, floor_div)?.0)
+}
+
+pub fn floor_remainder(args: VecDeque<Object>) -> Result<Object> {
+    Ok(divide(args, floor_div)?.1)
+}
+
+pub fn truncate_divide(args: VecDeque<Object>) -> Result<Object> {
+    let (q, r) = divide(args, truncate_div)?;
+    Ok(Object::new_values(vec![q, r]))
+}
+
+pub fn truncate_quotient(args: VecDeque<Object>) -> Result<Object> {
+    Ok(divide(args, truncate_div)?.0)
+}
+
+pub fn truncate_remainder(args: VecDeque<Object>) -> Result<Object> {
+    Ok(divide(args, truncate_div)?.1)
+}
+
+fn fold_integers(args: VecDeque<Object>, init: i64, op: fn(&BigInt, &BigInt) -> BigInt) -> Result<Object> {
+    let mut acc = BigInt::from(init);
+    let mut is_exact = true;
+    for obj in &args {
+        let (i, exact) = ensure_integer(obj)?;
+        acc = op(&acc, &i);
+        is_exact &= exact;
     }
+    Ok(integer(acc, is_exact))
+}
+
+pub fn gcd(args: VecDeque<Object>) -> Result<Object> {
+    fold_integers(args, 0, Integer::gcd)
 }
 
-pub fn gt(mut args: VecDeque<Object>) -> Result<Object> {
-    // binary op "=" 
-    let first = args.pop_front().unwrap();
-    if let Kind::Number(num) = first.kind() {
-        match num {
-            &Number::Int(i) => gt_int(args, i),
-            &Number::Float(f) => gt_float(args, f),
+pub fn lcm(args: VecDeque<Object>) -> Result<Object> {
+    fold_integers(args, 1, Integer::lcm)
+}
+
+fn map_number(mut args: VecDeque<Object>, f: fn(&Number) -> Number) -> Result<Object> {
+    let num = ensure_number(&args.pop_front().unwrap())?;
+    Ok(Object::new_number(f(&num), true))
+}
+
+pub fn abs(args: VecDeque<Object>) -> Result<Object> {
+    map_number(args, Number::abs)
+}
+
+pub fn floor(args: VecDeque<Object>) -> Result<Object> {
+    map_number(args, Number::floor)
+}
+
+pub fn ceiling(args: VecDeque<Object>) -> Result<Object> {
+    map_number(args, Number::ceiling)
+}
+
+pub fn round(args: VecDeque<Object>) -> Result<Object> {
+    map_number(args, Number::round)
+}
+
+pub fn truncate(args: VecDeque<Object>) -> Result<Object> {
+    map_number(args, Number::truncate)
+}
+
+pub fn square(args: VecDeque<Object>) -> Result<Object> {
+    map_number(args, |num| num.mul(num))
+}
+
+// the result is inexact if any of the arguments is
+fn select(args: VecDeque<Object>, prefer: Ordering) -> Result<Object> {
+    let nums = args.iter().map(ensure_number).collect::<Result<Vec<Number>>>()?;
+    let mut res = nums[0].clone();
+    for num in &nums[1..] {
+        if num.partial_cmp(&res) == Some(prefer) || num.to_f64().is_nan() {
+            res = num.clone();
         }
-    } else {
-        Err(anyhow!("number required, but got {}", first))
+    }
+    match nums.iter().all(Number::is_exact) {
+        true => Ok(Object::new_number(res, true)),
+        false => Ok(Object::new_number(res.inexact(), true)),
     }
 }
 
-fn gt_int(mut args: VecDeque<Object>, op1: i64) -> Result<Object> {
-    if let Some(op2) = args.pop_front() {
-        match op2.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(op2) => {
-                    if !(op1 > op2) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        gt_int(args, op2)
-                    }
-                }
-                &Number::Float(op2) => {
-                    if !((op1 as f64) > op2) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        gt_float(args, op2)
-                    }
-                }
-            }
-            _ => Err(anyhow!("number required, but got {}", op2))
+pub fn min(args: VecDeque<Object>) -> Result<Object> {
+    select(args, Ordering::Less)
+}
+
+pub fn max(args: VecDeque<Object>) -> Result<Object> {
+    select(args, Ordering::Greater)
+}
+
+pub fn sqrt(mut args: VecDeque<Object>) -> Result<Object> {
+    let num = ensure_number(&args.pop_front().unwrap())?;
+    Ok(Object::new_number(num.sqrt()?, true))
+}
+
+pub fn exact_integer_sqrt(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    match obj.kind() {
+        Kind::Number(num) if num.is_exact_integer() && !num.is_negative() => {
+            let n = num.integer_value().unwrap();
+            let s = n.sqrt();
+            let r = &n - &s * &s;
+            Ok(Object::new_values(vec![integer(s, true), integer(r, true)]))
         }
-    } else {
-        Ok(Object::new_boolean(true, true))
+        _ => Err(anyhow!("exact nonnegative integer required, but got {}", obj)),
     }
 }
 
-fn gt_float(mut args: VecDeque<Object>, op1: f64) -> Result<Object> {
-    if let Some(op2) = args.pop_front() {
-        match op2.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(op2) => {
-                    if !(op1 > (op2 as f64)) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        gt_int(args, op2)
-                    }
-                }
-                &Number::Float(op2) => {
-                    if !(op1 > op2) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        gt_float(args, op2)
-                    }
+pub fn expt(mut args: VecDeque<Object>) -> Result<Object> {
+    let base = ensure_number(&args.pop_front().unwrap())?;
+    let power = ensure_number(&args.pop_front().unwrap())?;
+    match (base.is_exact(), &power) {
+        // an exact number to an exact integer is exact
+        (true, Number::Int(_) | Number::BigInt(_)) => {
+            let n = power.integer_value().unwrap();
+            let e = n.magnitude().to_u32().context("exponent too large")?;
+            let mut res = Number::Int(1);
+            let mut sq = base.clone();
+            for i in 0..u32::BITS - e.leading_zeros() {
+                if e >> i & 1 == 1 {
+                    res = res.mul(&sq);
                 }
+                sq = sq.mul(&sq);
             }
-            _ => Err(anyhow!("number required, but got {}", op2))
+            if n.is_negative() {
+                res = Number::Int(1).div(&res)?;
+            }
+            Ok(Object::new_number(res, true))
         }
-    } else {
-        Ok(Object::new_boolean(true, true))
+        _ if base.is_negative() && !power.is_integer() => bail!("complex numbers are not supported: (expt {} {})", base, power),
+        _ => Ok(Object::new_float(base.to_f64().powf(power.to_f64()), true)),
     }
 }
 
-pub fn ge(mut args: VecDeque<Object>) -> Result<Object> {
-    // binary op "=" 
-    let first = args.pop_front().unwrap();
-    if let Kind::Number(num) = first.kind() {
-        match num {
-            &Number::Int(i) => ge_int(args, i),
-            &Number::Float(f) => ge_float(args, f),
-        }
-    } else {
-        Err(anyhow!("number required, but got {}", first))
+fn map_float(mut args: VecDeque<Object>, f: fn(f64) -> f64) -> Result<Object> {
+    let num = ensure_number(&args.pop_front().unwrap())?;
+    Ok(Object::new_float(f(num.to_f64()), true))
+}
+
+pub fn exp(args: VecDeque<Object>) -> Result<Object> {
+    map_float(args, f64::exp)
+}
+
+// (log z [base])
+pub fn log(mut args: VecDeque<Object>) -> Result<Object> {
+    let z = ensure_number(&args.pop_front().unwrap())?.to_f64();
+    match args.pop_front() {
+        Some(base) => Ok(Object::new_float(z.ln() / ensure_number(&base)?.to_f64().ln(), true)),
+        None => Ok(Object::new_float(z.ln(), true)),
     }
 }
 
-fn ge_int(mut args: VecDeque<Object>, op1: i64) -> Result<Object> {
-    if let Some(op2) = args.pop_front() {
-        match op2.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(op2) => {
-                    if !(op1 >= op2) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        ge_int(args, op2)
-                    }
-                }
-                &Number::Float(op2) => {
-                    if !((op1 as f64) >= op2) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        ge_float(args, op2)
-                    }
-                }
-            }
-            _ => Err(anyhow!("number required, but got {}", op2))
-        }
-    } else {
-        Ok(Object::new_boolean(true, true))
+pub fn sin(args: VecDeque<Object>) -> Result<Object> {
+    map_float(args, f64::sin)
+}
+
+pub fn cos(args: VecDeque<Object>) -> Result<Object> {
+    map_float(args, f64::cos)
+}
+
+pub fn tan(args: VecDeque<Object>) -> Result<Object> {
+    map_float(args, f64::tan)
+}
+
+pub fn asin(args: VecDeque<Object>) -> Result<Object> {
+    map_float(args, f64::asin)
+}
+
+pub fn acos(args: VecDeque<Object>) -> Result<Object> {
+    map_float(args, f64::acos)
+}
+
+// (atan y [x])
+pub fn atan(mut args: VecDeque<Object>) -> Result<Object> {
+    let y = ensure_number(&args.pop_front().unwrap())?.to_f64();
+    match args.pop_front() {
+        Some(x) => Ok(Object::new_float(y.atan2(ensure_number(&x)?.to_f64()), true)),
+        None => Ok(Object::new_float(y.atan(), true)),
     }
 }
 
-fn ge_float(mut args: VecDeque<Object>, op1: f64) -> Result<Object> {
-    if let Some(op2) = args.pop_front() {
-        match op2.kind() {
-            Kind::Number(num) => match num {
-                &Number::Int(op2) => {
-                    if !(op1 >= (op2 as f64)) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        ge_int(args, op2)
-                    }
-                }
-                &Number::Float(op2) => {
-                    if !(op1 >= op2) {
-                        Ok(Object::new_boolean(false, true))
-                    } else {
-                        ge_float(args, op2)
-                    }
-                }
-            }
-            _ => Err(anyhow!("number required, but got {}", op2))
-        }
-    } else {
-        Ok(Object::new_boolean(true, true))
+fn test(mut args: VecDeque<Object>, pred: fn(&Number) -> bool) -> Result<Object> {
+    let num = ensure_number(&args.pop_front().unwrap())?;
+    Ok(Object::new_boolean(pred(&num), true))
+}
+
+pub fn is_zero(args: VecDeque<Object>) -> Result<Object> {
+    test(args, Number::is_zero)
+}
+
+pub fn is_positive(args: VecDeque<Object>) -> Result<Object> {
+    test(args, |num| Number::Int(0) < *num)
+}
+
+pub fn is_negative(args: VecDeque<Object>) -> Result<Object> {
+    test(args, |num| *num < Number::Int(0))
+}
+
+pub fn is_odd(mut args: VecDeque<Object>) -> Result<Object> {
+    let (i, _) = ensure_integer(&args.pop_front().unwrap())?;
+    Ok(Object::new_boolean(i.is_odd(), true))
+}
+
+pub fn is_even(mut args: VecDeque<Object>) -> Result<Object> {
+    let (i, _) = ensure_integer(&args.pop_front().unwrap())?;
+    Ok(Object::new_boolean(i.is_even(), true))
+}
+
+pub fn is_exact(args: VecDeque<Object>) -> Result<Object> {
+    test(args, Number::is_exact)
+}
+
+pub fn is_inexact(args: VecDeque<Object>) -> Result<Object> {
+    test(args, |num| !num.is_exact())
+}
+
+pub fn is_nan(args: VecDeque<Object>) -> Result<Object> {
+    test(args, |num| num.to_f64().is_nan())
+}
+
+pub fn is_infinite(args: VecDeque<Object>) -> Result<Object> {
+    test(args, |num| !num.is_exact() && num.to_f64().is_infinite())
+}
+
+pub fn is_finite(args: VecDeque<Object>) -> Result<Object> {
+    test(args, |num| num.is_exact() || num.to_f64().is_finite())
+}
+
+// integer?, rational? and real? are also true of other objects than numbers
+fn is_kind_of(mut args: VecDeque<Object>, pred: fn(&Number) -> bool) -> Result<Object> {
+    match args.pop_front().unwrap().kind() {
+        Kind::Number(num) => Ok(Object::new_boolean(pred(num), true)),
+        _ => Ok(Object::new_boolean(false, true)),
     }
 }
 
+pub fn is_integer(args: VecDeque<Object>) -> Result<Object> {
+    is_kind_of(args, Number::is_integer)
+}
+
+pub fn is_rational(args: VecDeque<Object>) -> Result<Object> {
+    is_kind_of(args, |num| num.is_exact() || num.to_f64().is_finite())
+}
+
+pub fn is_real(args: VecDeque<Object>) -> Result<Object> {
+    is_kind_of(args, |_| true)
+}
+
+// (number->string z [radix]), where only exact numbers can be written in other radixes than 10
+pub fn number_to_string(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let num = ensure_number(&obj)?;
+    let radix = match args.pop_front() {
+        Some(obj) => match obj.kind() {
+            Kind::Number(Number::Int(r)) if [2, 8, 10, 16].contains(r) => *r as u32,
+            _ => bail!("radix must be 2, 8, 10 or 16, but got {}", obj),
+        }
+        None => 10,
+    };
+    let s = match &num {
+        _ if radix == 10 => num.to_string(),
+        Number::Int(i) => BigInt::from(*i).to_str_radix(radix),
+        Number::BigInt(i) => i.to_str_radix(radix),
+        Number::Rational(r) => format!("{}/{}", r.numer().to_str_radix(radix), r.denom().to_str_radix(radix)),
+        Number::Float(_) => bail!("inexact numbers can be written only in radix 10, but got {}", obj),
+    };
+    Ok(Object::new_string(s, true))
+}
@@ -0,0 +1,330 @@
+use crate::data::{*, object::*, port::Port};
+use super::vector::{index, range};
+use super::control::ensure_procedure;
+use crate::parse::Parser;
+use crate::eval::eval_quote;
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use anyhow::{Result, anyhow, bail};
+
+thread_local! {
+    // the current input, output and error ports, made at the first use
+    static CURRENT_PORTS: RefCell<Option<[Object; 3]>> = const { RefCell::new(None) };
+}
+
+const INPUT: usize = 0;
+const OUTPUT: usize = 1;
+const ERROR: usize = 2;
+
+fn current(which: usize) -> Object {
+    CURRENT_PORTS.with(|ports| {
+        ports.borrow_mut()
+            .get_or_insert_with(|| [
+                Object::new_port(Port::stdin()),
+                Object::new_port(Port::stdout()),
+                Object::new_port(Port::stderr()),
+            ])[which]
+            .clone()
+    })
+}
+
+// replace the current port, and return the previous one
+fn set_current(which: usize, port: Object) -> Object {
+    let prev = current(which);
+    CURRENT_PORTS.with(|ports| ports.borrow_mut().as_mut().unwrap()[which] = port);
+    prev
+}
+
+// go back to the standard ports, e.g. after an error escaped from with-output-to-file
+pub fn reset_current_ports() {
+    CURRENT_PORTS.with(|ports| *ports.borrow_mut() = None);
+}
+
+fn ensure_port(obj: &Object) -> Result<&Port> {
+    match obj.kind() {
+        Kind::Port(port) => Ok(port),
+        _ => Err(anyhow!("port required, but got {}", obj)),
+    }
+}
+
+fn ensure_string(obj: &Object) -> Result<&String> {
+    match obj.kind() {
+        Kind::String(s) => Ok(s),
+        _ => Err(anyhow!("string required, but got {}", obj)),
+    }
+}
+
+// the optional port argument, or the current port
+fn port_arg(args: &mut VecDeque<Object>, which: usize) -> Result<Object> {
+    let port = args.pop_front().unwrap_or_else(|| current(which));
+    ensure_port(&port)?;
+    if let Some(obj) = args.pop_front() {
+        bail!("too many arguments: {}", obj);
+    }
+    Ok(port)
+}
+
+// write to the optional port argument, or to the current output port
+pub fn write_to(args: &mut VecDeque<Object>, s: &str) -> Result<Object> {
+    let port = port_arg(args, OUTPUT)?;
+    ensure_port(&port)?.write_str(s)?;
+    Ok(Object::new_undefined())
+}
+
+fn char_or_eof(c: Option<char>) -> Object {
+    match c {
+        Some(c) => Object::new_char(c),
+        None => Object::new_eof(),
+    }
+}
+
+fn string_or_eof(s: Option<String>) -> Object {
+    match s {
+        Some(s) => Object::new_string(s, true),
+        None => Object::new_eof(),
+    }
+}
+
+pub fn current_input_port(_args: VecDeque<Object>) -> Result<Object> {
+    Ok(current(INPUT))
+}
+
+pub fn current_output_port(_args: VecDeque<Object>) -> Result<Object> {
+    Ok(current(OUTPUT))
+}
+
+pub fn current_error_port(_args: VecDeque<Object>) -> Result<Object> {
+    Ok(current(ERROR))
+}
+
+pub fn is_port(mut args: VecDeque<Object>) -> Result<Object> {
+    let res = matches!(args.pop_front().unwrap().kind(), Kind::Port(_));
+    Ok(Object::new_boolean(res, true))
+}
+
+pub fn is_input_port(mut args: VecDeque<Object>) -> Result<Object> {
+    let res = matches!(args.pop_front().unwrap().kind(), Kind::Port(port) if port.is_input);
+    Ok(Object::new_boolean(res, true))
+}
+
+pub fn is_output_port(mut args: VecDeque<Object>) -> Result<Object> {
+    let res = matches!(args.pop_front().unwrap().kind(), Kind::Port(port) if !port.is_input);
+    Ok(Object::new_boolean(res, true))
+}
+
+pub fn is_input_port_open(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let port = ensure_port(&obj)?;
+    Ok(Object::new_boolean(port.is_input && port.is_open(), true))
+}
+
+pub fn is_output_port_open(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let port = ensure_port(&obj)?;
+    Ok(Object::new_boolean(!port.is_input && port.is_open(), true))
+}
+
+pub fn eof_object(_args: VecDeque<Object>) -> Result<Object> {
+    Ok(Object::new_eof())
+}
+
+pub fn is_eof_object(mut args: VecDeque<Object>) -> Result<Object> {
+    let res = matches!(args.pop_front().unwrap().kind(), Kind::Eof);
+    Ok(Object::new_boolean(res, true))
+}
+
+pub fn open_input_file(mut args: VecDeque<Object>) -> Result<Object> {
+    let path = args.pop_front().unwrap();
+    Ok(Object::new_port(Port::input_file(ensure_string(&path)?)?))
+}
+
+pub fn open_output_file(mut args: VecDeque<Object>) -> Result<Object> {
+    let path = args.pop_front().unwrap();
+    Ok(Object::new_port(Port::output_file(ensure_string(&path)?)?))
+}
+
+pub fn open_input_string(mut args: VecDeque<Object>) -> Result<Object> {
+    let s = args.pop_front().unwrap();
+    Ok(Object::new_port(Port::input_string(ensure_string(&s)?.clone())))
+}
+
+pub fn open_output_string(_args: VecDeque<Object>) -> Result<Object> {
+    Ok(Object::new_port(Port::output_string()))
+}
+
+pub fn get_output_string(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    match ensure_port(&obj)?.get_output_string() {
+        Some(s) => Ok(Object::new_string(s, true)),
+        None => Err(anyhow!("string output port required, but got {}", obj)),
+    }
+}
+
+pub fn close_port(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    ensure_port(&obj)?.close();
+    Ok(Object::new_undefined())
+}
+
+pub fn close_input_port(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    match ensure_port(&obj)? {
+        port if port.is_input => port.close(),
+        _ => bail!("input port required, but got {}", obj),
+    }
+    Ok(Object::new_undefined())
+}
+
+pub fn close_output_port(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    match ensure_port(&obj)? {
+        port if !port.is_input => port.close(),
+        _ => bail!("output port required, but got {}", obj),
+    }
+    Ok(Object::new_undefined())
+}
+
+// (read [port]): the next datum, read as quoted
+pub fn read(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = port_arg(&mut args, INPUT)?;
+    let port = ensure_port(&obj)?;
+    // read lines until they have a whole datum
+    let mut text = String::new();
+    loop {
+        let line = port.read_raw_line()?;
+        let at_end = line.is_none();
+        text.push_str(&line.unwrap_or_default());
+        let incomplete = |e: &anyhow::Error| !at_end && e.chain().any(|e| e.to_string().contains("unterminated"));
+        match Parser::with_name(text.clone(), &port.name).first_token() {
+            None if at_end => return Ok(Object::new_eof()),
+            None => {}
+            Some((Err(e), _)) if incomplete(&e) => {}
+            Some((Err(e), _)) => return Err(e),
+            Some((Ok(token), len)) => {
+                port.unread(&text[len..]);
+                return eval_quote(&token);
+            }
+        }
+    }
+}
+
+pub fn read_char(mut args: VecDeque<Object>) -> Result<Object> {
+    let port = port_arg(&mut args, INPUT)?;
+    Ok(char_or_eof(ensure_port(&port)?.read_char()?))
+}
+
+pub fn peek_char(mut args: VecDeque<Object>) -> Result<Object> {
+    let port = port_arg(&mut args, INPUT)?;
+    Ok(char_or_eof(ensure_port(&port)?.peek_char()?))
+}
+
+pub fn read_line(mut args: VecDeque<Object>) -> Result<Object> {
+    let port = port_arg(&mut args, INPUT)?;
+    Ok(string_or_eof(ensure_port(&port)?.read_line()?))
+}
+
+// (read-string k [port])
+pub fn read_string(mut args: VecDeque<Object>) -> Result<Object> {
+    let k = index(&args.pop_front().unwrap())?;
+    let port = port_arg(&mut args, INPUT)?;
+    Ok(string_or_eof(ensure_port(&port)?.read_string(k)?))
+}
+
+pub fn write_char(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    match obj.kind() {
+        Kind::Char(c) => write_to(&mut args, &c.to_string()),
+        _ => Err(anyhow!("char required, but got {}", obj)),
+    }
+}
+
+// (write-string string [port [start [end]]])
+pub fn write_string(mut args: VecDeque<Object>) -> Result<Object> {
+    let chars: Vec<char> = ensure_string(&args.pop_front().unwrap())?.chars().collect();
+    let mut port = VecDeque::new();
+    port.extend(args.pop_front());
+    let (start, end) = range(args, chars.len())?;
+    write_to(&mut port, &chars[start..end].iter().collect::<String>())
+}
+
+pub fn newline(mut args: VecDeque<Object>) -> Result<Object> {
+    write_to(&mut args, "\n")
+}
+
+pub fn flush_output_port(mut args: VecDeque<Object>) -> Result<Object> {
+    let port = port_arg(&mut args, OUTPUT)?;
+    ensure_port(&port)?.flush()?;
+    Ok(Object::new_undefined())
+}
+
+// the state is [port]
+fn close_and_return(val: Object, state: Vec<Object>) -> Result<Control> {
+    ensure_port(&state[0])?.close();
+    Ok(Control::Return(val))
+}
+
+// (call-with-port port proc): the port is closed when proc returns
+pub fn call_with_port(mut args: VecDeque<Object>) -> Result<Control> {
+    let port = args.pop_front().unwrap();
+    ensure_port(&port)?;
+    let proc = ensure_procedure(args.pop_front().unwrap())?;
+    Ok(Control::Call(proc, VecDeque::from([port.clone()]), close_and_return, vec![port]))
+}
+
+pub fn call_with_input_file(mut args: VecDeque<Object>) -> Result<Control> {
+    let port = open_input_file(VecDeque::from([args.pop_front().unwrap()]))?;
+    args.push_front(port);
+    call_with_port(args)
+}
+
+pub fn call_with_output_file(mut args: VecDeque<Object>) -> Result<Control> {
+    let port = open_output_file(VecDeque::from([args.pop_front().unwrap()]))?;
+    args.push_front(port);
+    call_with_port(args)
+}
+
+// (call-with-output-string proc): the string which proc writes to the port given
+pub fn call_with_output_string(mut args: VecDeque<Object>) -> Result<Control> {
+    // the state is [port]
+    fn next(_val: Object, state: Vec<Object>) -> Result<Control> {
+        let s = ensure_port(&state[0])?.get_output_string().unwrap();
+        Ok(Control::Return(Object::new_string(s, true)))
+    }
+
+    let proc = ensure_procedure(args.pop_front().unwrap())?;
+    let port = Object::new_port(Port::output_string());
+    Ok(Control::Call(proc, VecDeque::from([port.clone()]), next, vec![port]))
+}
+
+// The thunk is called with the file as the current port, which is restored and closed
+// when the thunk returns. If the thunk escapes, the port stays until an error reaches the top level.
+fn with_file(path: Object, thunk: Object, which: usize) -> Result<Control> {
+    // the state is [the previous port, the file]
+    fn restore_input(val: Object, state: Vec<Object>) -> Result<Control> {
+        set_current(INPUT, state[0].clone());
+        close_and_return(val, vec![state[1].clone()])
+    }
+    fn restore_output(val: Object, state: Vec<Object>) -> Result<Control> {
+        set_current(OUTPUT, state[0].clone());
+        close_and_return(val, vec![state[1].clone()])
+    }
+
+    let thunk = ensure_procedure(thunk)?;
+    let path = ensure_string(&path)?;
+    let (file, restore): (_, NativeFn) = match which {
+        INPUT => (Object::new_port(Port::input_file(path)?), restore_input),
+        _ => (Object::new_port(Port::output_file(path)?), restore_output),
+    };
+    let prev = set_current(which, file.clone());
+    Ok(Control::Call(thunk, VecDeque::new(), restore, vec![prev, file]))
+}
+
+pub fn with_input_from_file(mut args: VecDeque<Object>) -> Result<Control> {
+    with_file(args.pop_front().unwrap(), args.pop_front().unwrap(), INPUT)
+}
+
+pub fn with_output_to_file(mut args: VecDeque<Object>) -> Result<Control> {
+    with_file(args.pop_front().unwrap(), args.pop_front().unwrap(), OUTPUT)
+}
@@ -1,8 +1,10 @@
 use crate::data::{*, object::*};
+use crate::vm;
+use super::port::write_to;
 
 use std::collections::VecDeque;
 
-use anyhow::Result;
+use anyhow::{Result, bail};
 
 pub fn is_procedure(mut args: VecDeque<Object>) -> Result<Object> {
     match args.pop_front().unwrap().kind() {
@@ -10,3 +12,13 @@ pub fn is_procedure(mut args: VecDeque<Object>) -> Result<Object> {
         _                  => Ok(Object::new_boolean(false, true)),
     }
 }
+
+// (disassemble proc [port]): write the instructions of the VM for the body of "proc"
+pub fn disassemble(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let listing = match obj.kind() {
+        Kind::Procedure(Procedure::Proc(proc)) => vm::disassemble(&vm::chunk(&proc.lambda.body)),
+        _ => bail!("compound procedure required, but got {}", obj),
+    };
+    write_to(&mut args, &listing)
+}
@@ -0,0 +1,96 @@
+use crate::data::{*, object::*};
+use crate::symbol::Symbol;
+use super::symbol::ensure_symbol;
+use super::vector::index;
+
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use anyhow::{Result, anyhow, bail};
+use once_cell::sync::Lazy;
+
+// The procedures which define-record-type defines are made of these.
+// They are bound to uninterned symbols, which programs can not write,
+// so they can be neither redefined nor used to forge or change records of other types.
+pub static PRIMITIVES: Lazy<Vec<(Symbol, bool, usize, SubrFn)>> = Lazy::new(|| vec![
+    (Symbol::uninterned("make-record-type"), false, 2, make_record_type),
+    (Symbol::uninterned("make-record"), true, 1, make_record),
+    (Symbol::uninterned("record?"), false, 2, is_record),
+    (Symbol::uninterned("record-ref"), false, 3, record_ref),
+    (Symbol::uninterned("record-set!"), false, 4, record_set),
+]);
+
+// the symbol which the primitive "name" is bound to
+pub fn primitive(name: &str) -> Symbol {
+    PRIMITIVES.iter().find(|(id, ..)| id.as_str() == name).unwrap().0.clone()
+}
+
+fn ensure_record_type(obj: &Object) -> Result<Rc<RecordType>> {
+    match obj.kind() {
+        Kind::RecordType(rtd) => Ok(rtd.clone()),
+        _ => Err(anyhow!("record type required, but got {}", obj)),
+    }
+}
+
+// the field "k" of "obj", which must be a record of "rtd"
+fn ensure_field(obj: &Object, rtd: &Rc<RecordType>, k: usize) -> Result<()> {
+    match obj.kind() {
+        Kind::Record(record) if Rc::ptr_eq(&record.rtd, rtd) => {
+            if k >= rtd.fields.len() {
+                bail!("index out of range: {}", k);
+            }
+            Ok(())
+        }
+        _ => Err(anyhow!("record {} required, but got {}", rtd.name, obj)),
+    }
+}
+
+// (make-record-type name (field ...))
+fn make_record_type(mut args: VecDeque<Object>) -> Result<Object> {
+    let name = ensure_symbol(&args.pop_front().unwrap())?;
+    let fields = args.pop_front().unwrap();
+    if !fields.is_list() {
+        bail!("proper list required, but got {}", fields);
+    }
+    let fields = fields.into_iter().map(|obj| ensure_symbol(&obj)).collect::<Result<Vec<Symbol>>>()?;
+    Ok(Object::new_record_type(name, fields))
+}
+
+// (make-record type obj ...): a record with "obj"s in its fields in order
+fn make_record(mut args: VecDeque<Object>) -> Result<Object> {
+    let rtd = ensure_record_type(&args.pop_front().unwrap())?;
+    if args.len() != rtd.fields.len() {
+        bail!("wrong number of fields of {} (required {}, got {})", rtd.name, rtd.fields.len(), args.len());
+    }
+    Ok(Object::new_record(rtd, Vec::from(args)))
+}
+
+// (record? obj type)
+fn is_record(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let rtd = ensure_record_type(&args.pop_front().unwrap())?;
+    let res = matches!(obj.kind(), Kind::Record(record) if Rc::ptr_eq(&record.rtd, &rtd));
+    Ok(Object::new_boolean(res, true))
+}
+
+// (record-ref type record k)
+fn record_ref(mut args: VecDeque<Object>) -> Result<Object> {
+    let rtd = ensure_record_type(&args.pop_front().unwrap())?;
+    let obj = args.pop_front().unwrap();
+    let k = index(&args.pop_front().unwrap())?;
+    ensure_field(&obj, &rtd, k)?;
+    match obj.kind() {
+        Kind::Record(record) => Ok(record.get(k)),
+        _ => unreachable!(),
+    }
+}
+
+// (record-set! type record k obj)
+fn record_set(mut args: VecDeque<Object>) -> Result<Object> {
+    let rtd = ensure_record_type(&args.pop_front().unwrap())?;
+    let obj = args.pop_front().unwrap();
+    let k = index(&args.pop_front().unwrap())?;
+    ensure_field(&obj, &rtd, k)?;
+    obj.set_record_field(k, args.pop_front().unwrap())?;
+    Ok(Object::new_undefined())
+}
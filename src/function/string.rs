@@ -1,8 +1,42 @@
 use crate::data::{*, object::*};
+use crate::symbol::Symbol;
+use super::vector::{index, range, filled};
+use super::control::to_list;
 
 use std::collections::VecDeque;
 
-use anyhow::{Result, anyhow};
+use anyhow::{Result, anyhow, bail};
+
+// strings are handled as sequences of Unicode scalar values
+fn ensure_chars(obj: &Object) -> Result<Vec<char>> {
+    match obj.kind() {
+        Kind::String(s) => Ok(s.chars().collect()),
+        _ => Err(anyhow!("string required, but got {}", obj)),
+    }
+}
+
+fn ensure_char(obj: &Object) -> Result<char> {
+    match obj.kind() {
+        Kind::Char(c) => Ok(*c),
+        _ => Err(anyhow!("char required, but got {}", obj)),
+    }
+}
+
+fn foldcase(s: &str) -> String {
+    s.to_uppercase().to_lowercase()
+}
+
+fn compare(args: VecDeque<Object>, fold: fn(&str) -> String, cmp: fn(&str, &str) -> bool) -> Result<Object> {
+    let strings = args
+        .iter()
+        .map(|obj| match obj.kind() {
+            Kind::String(s) => Ok(fold(s)),
+            _ => Err(anyhow!("string required, but got {}", obj)),
+        })
+        .collect::<Result<Vec<String>>>()?;
+    let res = strings.windows(2).all(|w| cmp(&w[0], &w[1]));
+    Ok(Object::new_boolean(res, true))
+}
 
 pub fn is_string(mut args: VecDeque<Object>) -> Result<Object> {
     match args.pop_front().unwrap().kind() {
@@ -11,57 +45,234 @@ pub fn is_string(mut args: VecDeque<Object>) -> Result<Object> {
     }
 }
 
-pub fn string_append(mut args: VecDeque<Object>) -> Result<Object> {
-    let obj1 = args.pop_front().unwrap();
-    let obj2 = args.pop_front().unwrap();
-    match (obj1.kind(), obj2.kind()) {
-        (Kind::String(s1), Kind::String(s2)) => {
-            let s = s1.clone() + s2;
-            Ok(Object::new_string(s, true))
-        }
-        (Kind::String(_), _) => Err(anyhow!("string required, but got {}", obj2)),
-        (_, _) => Err(anyhow!("string required, but got {}", obj1)),
+pub fn make_string(mut args: VecDeque<Object>) -> Result<Object> {
+    let k = index(&args.pop_front().unwrap())?;
+    let fill = match args.pop_front() {
+        Some(obj) => ensure_char(&obj)?,
+        None => ' ',
+    };
+    if let Some(obj) = args.pop_front() {
+        bail!("too many arguments: {}", obj);
     }
+    Ok(Object::new_string(filled(fill, k)?.into_iter().collect(), true))
 }
 
-pub fn symbol_to_string(mut args: VecDeque<Object>) -> Result<Object> {
+pub fn string(args: VecDeque<Object>) -> Result<Object> {
+    let s = args.iter().map(ensure_char).collect::<Result<String>>()?;
+    Ok(Object::new_string(s, true))
+}
+
+pub fn string_length(mut args: VecDeque<Object>) -> Result<Object> {
     let obj = args.pop_front().unwrap();
     match obj.kind() {
-        Kind::Symbol(s) => Ok(Object::new_string(s.clone(), true)),
-        _ => Err(anyhow!("symbol required, but got {}", obj))
+        Kind::String(s) => Ok(Object::new_int(s.chars().count() as i64, true)),
+        _ => Err(anyhow!("string required, but got {}", obj)),
     }
 }
 
-pub fn string_to_symbol(mut args: VecDeque<Object>) -> Result<Object> {
+pub fn string_ref(mut args: VecDeque<Object>) -> Result<Object> {
     let obj = args.pop_front().unwrap();
+    let k = index(&args.pop_front().unwrap())?;
     match obj.kind() {
-        Kind::String(s) => Ok(Object::new_symbol(s.clone(), true)),
+        Kind::String(s) => s.chars().nth(k).map(Object::new_char).ok_or_else(|| anyhow!("index out of range: {}", k)),
         _ => Err(anyhow!("string required, but got {}", obj)),
     }
 }
 
-pub fn string_to_number(mut args: VecDeque<Object>) -> Result<Object> {
+pub fn string_set(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let k = index(&args.pop_front().unwrap())?;
+    let c = ensure_char(&args.pop_front().unwrap())?;
+    obj.string_splice(k, &[c])?;
+    Ok(Object::new_undefined())
+}
+
+pub fn substring(mut args: VecDeque<Object>) -> Result<Object> {
+    let chars = ensure_chars(&args.pop_front().unwrap())?;
+    let (start, end) = range(args, chars.len())?;
+    Ok(Object::new_string(chars[start..end].iter().collect(), true))
+}
+
+pub fn string_append(args: VecDeque<Object>) -> Result<Object> {
+    let mut s = String::new();
+    for obj in &args {
+        match obj.kind() {
+            Kind::String(t) => s.push_str(t),
+            _ => bail!("string required, but got {}", obj),
+        }
+    }
+    Ok(Object::new_string(s, true))
+}
+
+pub fn string_copy(args: VecDeque<Object>) -> Result<Object> {
+    substring(args)
+}
+
+// (string-copy! to at from [start [end]])
+pub fn string_copy_to(mut args: VecDeque<Object>) -> Result<Object> {
+    let to = args.pop_front().unwrap();
+    let at = index(&args.pop_front().unwrap())?;
+    let chars = ensure_chars(&args.pop_front().unwrap())?;
+    let (start, end) = range(args, chars.len())?;
+    to.string_splice(at, &chars[start..end])?;
+    Ok(Object::new_undefined())
+}
+
+pub fn string_fill(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let len = ensure_chars(&obj)?.len();
+    let fill = ensure_char(&args.pop_front().unwrap())?;
+    let (start, end) = range(args, len)?;
+    obj.string_splice(start, &vec![fill; end - start])?;
+    Ok(Object::new_undefined())
+}
+
+pub fn string_to_list(mut args: VecDeque<Object>) -> Result<Object> {
+    let chars = ensure_chars(&args.pop_front().unwrap())?;
+    let (start, end) = range(args, chars.len())?;
+    let mut list = Object::new_empty();
+    for c in chars[start..end].iter().rev() {
+        list = Object::new_pair(Object::new_char(*c), list, true);
+    }
+    Ok(list)
+}
+
+pub fn list_to_string(mut args: VecDeque<Object>) -> Result<Object> {
+    let list = args.pop_front().unwrap();
+    if !list.is_list() {
+        bail!("proper list required, but got {}", list);
+    }
+    let s = list.into_iter().map(|obj| ensure_char(&obj)).collect::<Result<String>>()?;
+    Ok(Object::new_string(s, true))
+}
+
+pub fn string_eq(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, str::to_string, |a, b| a == b)
+}
+
+pub fn string_lt(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, str::to_string, |a, b| a < b)
+}
+
+pub fn string_gt(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, str::to_string, |a, b| a > b)
+}
+
+pub fn string_le(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, str::to_string, |a, b| a <= b)
+}
+
+pub fn string_ge(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, str::to_string, |a, b| a >= b)
+}
+
+pub fn string_ci_eq(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, foldcase, |a, b| a == b)
+}
+
+pub fn string_ci_lt(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, foldcase, |a, b| a < b)
+}
+
+pub fn string_ci_gt(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, foldcase, |a, b| a > b)
+}
+
+pub fn string_ci_le(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, foldcase, |a, b| a <= b)
+}
+
+pub fn string_ci_ge(args: VecDeque<Object>) -> Result<Object> {
+    compare(args, foldcase, |a, b| a >= b)
+}
+
+fn map_case(mut args: VecDeque<Object>, f: fn(&str) -> String) -> Result<Object> {
     let obj = args.pop_front().unwrap();
     match obj.kind() {
-        Kind::String(s) => {
-            if let Ok(i) = s.parse::<i64>() {
-                Ok(Object::new_int(i, true))
-            } else if let Ok(f) = s.parse::<f64>() {
-                Ok(Object::new_float(f, true))
-            } else {
-                Ok(Object::new_boolean(false, true))
-            }
+        Kind::String(s) => Ok(Object::new_string(f(s), true)),
+        _ => Err(anyhow!("string required, but got {}", obj)),
+    }
+}
+
+pub fn string_upcase(args: VecDeque<Object>) -> Result<Object> {
+    map_case(args, str::to_uppercase)
+}
+
+pub fn string_downcase(args: VecDeque<Object>) -> Result<Object> {
+    map_case(args, str::to_lowercase)
+}
+
+pub fn string_foldcase(args: VecDeque<Object>) -> Result<Object> {
+    map_case(args, foldcase)
+}
+
+// (string-index s pred [start [end]]): the index of the first character which
+// satisfies "pred" (a char or a predicate), or #f
+pub fn string_index(mut args: VecDeque<Object>) -> Result<Control> {
+    // the state is [pred, the characters left, the index of the first of them]
+    fn step(state: Vec<Object>) -> Result<Control> {
+        match state[1].kind() {
+            Kind::Pair(pair) => Ok(Control::Call(state[0].clone(), VecDeque::from([pair.car()]), next, state)),
+            _ => Ok(Control::Return(Object::new_boolean(false, true))),
         }
-        _ => Err(anyhow!("string required, but got {}", obj))
+    }
+    fn next(val: Object, mut state: Vec<Object>) -> Result<Control> {
+        if !val.is_falsy() {
+            return Ok(Control::Return(state[2].clone()));
+        }
+        let (Kind::Pair(pair), Kind::Number(Number::Int(i))) = (state[1].kind(), state[2].kind()) else { unreachable!() };
+        let (rest, i) = (pair.cdr(), *i);
+        state[1] = rest;
+        state[2] = Object::new_int(i + 1, true);
+        step(state)
+    }
+
+    let chars = ensure_chars(&args.pop_front().unwrap())?;
+    let pred = args.pop_front().unwrap();
+    let (start, end) = range(args, chars.len())?;
+    match pred.kind() {
+        Kind::Char(c) => match chars[start..end].iter().position(|d| d == c) {
+            Some(i) => Ok(Control::Return(Object::new_int((start + i) as i64, true))),
+            None => Ok(Control::Return(Object::new_boolean(false, true))),
+        }
+        Kind::Procedure(_) => {
+            let rest = to_list(chars[start..end].iter().map(|c| Object::new_char(*c)));
+            step(vec![pred, rest, Object::new_int(start as i64, true)])
+        }
+        _ => Err(anyhow!("char or procedure required, but got {}", pred)),
+    }
+}
+
+pub fn symbol_to_string(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    match obj.kind() {
+        Kind::Symbol(s) => Ok(Object::new_string(s.to_string(), true)),
+        _ => Err(anyhow!("symbol required, but got {}", obj))
+    }
+}
+
+pub fn string_to_symbol(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    match obj.kind() {
+        Kind::String(s) => Ok(Object::new_symbol(Symbol::intern(s))),
+        _ => Err(anyhow!("string required, but got {}", obj)),
     }
 }
 
-pub fn number_to_string(mut args: VecDeque<Object>) -> Result<Object> {
+// (string->number string [radix])
+pub fn string_to_number(mut args: VecDeque<Object>) -> Result<Object> {
     let obj = args.pop_front().unwrap();
+    let radix = match args.pop_front() {
+        Some(obj) => match obj.kind() {
+            Kind::Number(Number::Int(r)) if [2, 8, 10, 16].contains(r) => *r as u32,
+            _ => bail!("radix must be 2, 8, 10 or 16, but got {}", obj),
+        }
+        None => 10,
+    };
     match obj.kind() {
-        Kind::Number(num) => match num {
-            &Number::Int(i) => Ok(Object::new_int(i, true)),
-            &Number::Float(f) => Ok(Object::new_float(f, true)),
+        Kind::String(s) => match Number::parse(s, radix) {
+            Some(num) => Ok(Object::new_number(num, true)),
+            None => Ok(Object::new_boolean(false, true)),
         }
         _ => Err(anyhow!("string required, but got {}", obj))
     }
@@ -1,8 +1,15 @@
 use crate::data::{*, object::*};
+use crate::symbol::Symbol;
 
+use std::cell::Cell;
 use std::collections::VecDeque;
 
-use anyhow::Result;
+use anyhow::{Result, anyhow};
+
+thread_local! {
+    // the number of symbols made by gensym
+    static GENSYM_COUNT: Cell<usize> = const { Cell::new(0) };
+}
 
 pub fn is_symbol(mut args: VecDeque<Object>) -> Result<Object> {
     match args.pop_front().unwrap().kind() {
@@ -10,3 +17,41 @@ pub fn is_symbol(mut args: VecDeque<Object>) -> Result<Object> {
         _               => Ok(Object::new_boolean(false, true)), 
     }
 }
+
+pub fn ensure_symbol(obj: &Object) -> Result<Symbol> {
+    match obj.kind() {
+        Kind::Symbol(s) => Ok(s.clone()),
+        _ => Err(anyhow!("symbol required, but got {}", obj)),
+    }
+}
+
+pub fn symbol_eq(args: VecDeque<Object>) -> Result<Object> {
+    let symbols = args.iter().map(ensure_symbol).collect::<Result<Vec<Symbol>>>()?;
+    let res = symbols.windows(2).all(|pair| pair[0] == pair[1]);
+    Ok(Object::new_boolean(res, true))
+}
+
+pub fn string_to_uninterned_symbol(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    match obj.kind() {
+        Kind::String(s) => Ok(Object::new_symbol(Symbol::uninterned(s))),
+        _ => Err(anyhow!("string required, but got {}", obj)),
+    }
+}
+
+// (gensym [prefix]): a new uninterned symbol, named prefix (default "g") followed by a number
+pub fn gensym(mut args: VecDeque<Object>) -> Result<Object> {
+    let prefix = match args.pop_front() {
+        Some(obj) => match obj.kind() {
+            Kind::String(s) => s.clone(),
+            Kind::Symbol(s) => s.to_string(),
+            _ => return Err(anyhow!("string or symbol required, but got {}", obj)),
+        }
+        None => "g".to_string(),
+    };
+    let n = GENSYM_COUNT.with(|n| {
+        n.set(n.get() + 1);
+        n.get()
+    });
+    Ok(Object::new_symbol(Symbol::uninterned(&format!("{}{}", prefix, n))))
+}
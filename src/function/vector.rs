@@ -0,0 +1,165 @@
+use crate::data::{*, object::*};
+
+use std::collections::VecDeque;
+
+use anyhow::{Result, anyhow, bail};
+
+pub fn index(obj: &Object) -> Result<usize> {
+    match obj.kind() {
+        Kind::Number(Number::Int(i)) if *i >= 0 => Ok(*i as usize),
+        _ => Err(anyhow!("exact nonnegative integer required, but got {}", obj)),
+    }
+}
+
+// "k" copies of "elem" for make-vector and its relatives, which fails instead of aborting
+// when a huge length can not be allocated
+pub fn filled<T: Clone>(elem: T, k: usize) -> Result<Vec<T>> {
+    let mut elems = Vec::new();
+    elems.try_reserve_exact(k).map_err(|_| anyhow!("out of memory: can not allocate {} elements", k))?;
+    elems.resize(k, elem);
+    Ok(elems)
+}
+
+// optional start and end of a sequence of "len" elements: (... [start [end]])
+pub fn range(mut args: VecDeque<Object>, len: usize) -> Result<(usize, usize)> {
+    let start = match args.pop_front() {
+        Some(obj) => index(&obj)?,
+        None => 0,
+    };
+    let end = match args.pop_front() {
+        Some(obj) => index(&obj)?,
+        None => len,
+    };
+    if let Some(obj) = args.pop_front() {
+        bail!("too many arguments: {}", obj);
+    }
+    if start > end || end > len {
+        bail!("index out of range: {} to {} (length {})", start, end, len);
+    }
+    Ok((start, end))
+}
+
+fn ensure_vector(obj: &Object) -> Result<Vec<Object>> {
+    match obj.kind() {
+        Kind::Vector(vector) => Ok(vector.to_vec()),
+        _ => Err(anyhow!("vector required, but got {}", obj)),
+    }
+}
+
+pub fn is_vector(mut args: VecDeque<Object>) -> Result<Object> {
+    match args.pop_front().unwrap().kind() {
+        Kind::Vector(_) => Ok(Object::new_boolean(true, true)),
+        _               => Ok(Object::new_boolean(false, true)),
+    }
+}
+
+pub fn make_vector(mut args: VecDeque<Object>) -> Result<Object> {
+    let k = index(&args.pop_front().unwrap())?;
+    let fill = args.pop_front().unwrap_or_else(Object::new_undefined);
+    if let Some(obj) = args.pop_front() {
+        bail!("too many arguments: {}", obj);
+    }
+    Ok(Object::new_vector(filled(fill, k)?, true))
+}
+
+pub fn vector(args: VecDeque<Object>) -> Result<Object> {
+    Ok(Object::new_vector(args.into(), true))
+}
+
+pub fn vector_length(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    match obj.kind() {
+        Kind::Vector(vector) => Ok(Object::new_int(vector.len() as i64, true)),
+        _ => Err(anyhow!("vector required, but got {}", obj)),
+    }
+}
+
+pub fn vector_ref(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let k = index(&args.pop_front().unwrap())?;
+    match obj.kind() {
+        Kind::Vector(vector) => vector.get(k).ok_or_else(|| anyhow!("index out of range: {}", k)),
+        _ => Err(anyhow!("vector required, but got {}", obj)),
+    }
+}
+
+pub fn vector_set(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let k = index(&args.pop_front().unwrap())?;
+    obj.vector_splice(k, vec![args.pop_front().unwrap()])?;
+    Ok(Object::new_undefined())
+}
+
+pub fn vector_to_list(mut args: VecDeque<Object>) -> Result<Object> {
+    let elems = ensure_vector(&args.pop_front().unwrap())?;
+    let (start, end) = range(args, elems.len())?;
+    let mut list = Object::new_empty();
+    for obj in elems[start..end].iter().rev() {
+        list = Object::new_pair(obj.clone(), list, true);
+    }
+    Ok(list)
+}
+
+pub fn list_to_vector(mut args: VecDeque<Object>) -> Result<Object> {
+    let list = args.pop_front().unwrap();
+    if !list.is_list() {
+        bail!("proper list required, but got {}", list);
+    }
+    Ok(Object::new_vector(list.into_iter().collect(), true))
+}
+
+pub fn vector_to_string(mut args: VecDeque<Object>) -> Result<Object> {
+    let elems = ensure_vector(&args.pop_front().unwrap())?;
+    let (start, end) = range(args, elems.len())?;
+    let s = elems[start..end]
+        .iter()
+        .map(|obj| match obj.kind() {
+            Kind::Char(c) => Ok(*c),
+            _ => Err(anyhow!("char required, but got {}", obj)),
+        })
+        .collect::<Result<String>>()?;
+    Ok(Object::new_string(s, true))
+}
+
+pub fn string_to_vector(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let chars: Vec<char> = match obj.kind() {
+        Kind::String(s) => s.chars().collect(),
+        _ => bail!("string required, but got {}", obj),
+    };
+    let (start, end) = range(args, chars.len())?;
+    Ok(Object::new_vector(chars[start..end].iter().map(|c| Object::new_char(*c)).collect(), true))
+}
+
+pub fn vector_copy(mut args: VecDeque<Object>) -> Result<Object> {
+    let elems = ensure_vector(&args.pop_front().unwrap())?;
+    let (start, end) = range(args, elems.len())?;
+    Ok(Object::new_vector(elems[start..end].to_vec(), true))
+}
+
+// (vector-copy! to at from [start [end]])
+pub fn vector_copy_to(mut args: VecDeque<Object>) -> Result<Object> {
+    let to = args.pop_front().unwrap();
+    let at = index(&args.pop_front().unwrap())?;
+    let elems = ensure_vector(&args.pop_front().unwrap())?;
+    let (start, end) = range(args, elems.len())?;
+    to.vector_splice(at, elems[start..end].to_vec())?;
+    Ok(Object::new_undefined())
+}
+
+pub fn vector_append(args: VecDeque<Object>) -> Result<Object> {
+    let mut elems = Vec::new();
+    for obj in &args {
+        elems.extend(ensure_vector(obj)?);
+    }
+    Ok(Object::new_vector(elems, true))
+}
+
+pub fn vector_fill(mut args: VecDeque<Object>) -> Result<Object> {
+    let obj = args.pop_front().unwrap();
+    let len = ensure_vector(&obj)?.len();
+    let fill = args.pop_front().unwrap();
+    let (start, end) = range(args, len)?;
+    obj.vector_splice(start, vec![fill; end - start])?;
+    Ok(Object::new_undefined())
+}
@@ -1,9 +1,15 @@
 mod parse;
 mod token;
+mod symbol;
 mod data;
 mod eval;
 mod function;
 mod opt;
+mod error;
+mod trace;
+mod expand;
+mod compile;
+mod vm;
 
 use std::io::{self, Write};
 use std::fs::File;
@@ -20,7 +26,10 @@ fn read_stdin() -> io::Result<String> {
     print!(">>> ");
     io::stdout().flush()?;
     loop {
-        io::stdin().read_line(&mut buf)?;
+        if io::stdin().read_line(&mut buf)? == 0 {
+            // EOF
+            break;
+        }
 
         let has_read = !at_unterminated_paren_or_string(&buf);
         if has_read {
@@ -34,71 +43,99 @@ fn read_stdin() -> io::Result<String> {
 
 fn at_unterminated_paren_or_string(buf: &str) -> bool {
     let mut nest = 0;           // the number of layers of nesting ()
-    let mut in_str = false;    // between " " ?
+    let mut quote = None;       // Some(b'"') between " ", Some(b'|') between | |
+    let mut in_line_comment = false;   // after ; ?
+    let mut block_nest = 0;     // the number of layers of nesting #| |#
 
-    let mut prev_c = b' ';
-    for c in buf.as_bytes() {
-        if !in_str {
+    let bytes = buf.as_bytes();
+    let is_delimiter = |c: u8| c.is_ascii_whitespace() || b"()\";|".contains(&c);
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if in_line_comment {
+            if c == b'\n' {
+                in_line_comment = false;
+            }
+        } else if block_nest > 0 {
+            if bytes[i..].starts_with(b"#|") {
+                block_nest += 1;
+                i += 1;
+            } else if bytes[i..].starts_with(b"|#") {
+                block_nest -= 1;
+                i += 1;
+            }
+        } else if let Some(q) = quote {
+            if c == b'\\' {
+                // the escaped character
+                i += 1;
+            } else if c == q {
+                quote = None;
+            }
+        } else {
             match c {
                 b'(' => nest += 1,
                 b')' => nest = std::cmp::max(0, nest - 1),
-                b'"' if prev_c != b'\\' => {
-                    in_str = true;
+                b'"' | b'|' => quote = Some(c),
+                b';' => in_line_comment = true,
+                b'#' if bytes[i..].starts_with(b"#|") => {
+                    block_nest += 1;
+                    i += 1;
+                }
+                // a character, which may be a delimiter itself: #\( #\; #\space
+                b'#' if bytes[i..].starts_with(b"#\\") => {
+                    i += 2;
+                    while i + 1 < bytes.len() && !is_delimiter(bytes[i + 1]) {
+                        i += 1;
+                    }
                 }
                 _ => {}
             }
-        } else {
-            if *c == b'"' && prev_c != b'\\' {
-               in_str = false;
-            }
         }
-        prev_c = *c;
+        i += 1;
     }
-    nest != 0 || in_str
+    nest != 0 || quote.is_some() || block_nest > 0
 }
 
-fn load_file(env: Environment) {
-    let opt = opt::Opt::parse();
-    match opt.files {
-        Some(files) => {
-            for file_name in files {
-                let path = Path::new(&file_name);
-                let mut buf = String::new();
-                let mut file = match File::open(&path) {
-                    Ok(file) => file,
-                    Err(reason) => {
-                        println!("could't open {}: {}", file_name, reason);
-                        continue;
-                    }
-                };
-                if let Err(reason) = file.read_to_string(&mut buf) {
-                    println!("could't read {}: {}", file_name, reason)
+fn load_file(files: Option<Vec<String>>, env: Environment) {
+    if let Some(files) = files {
+        for file_name in files {
+            let path = Path::new(&file_name);
+            let mut buf = String::new();
+            let mut file = match File::open(path) {
+                Ok(file) => file,
+                Err(reason) => {
+                    println!("could't open {}: {}", file_name, reason);
+                    continue;
                 }
-                let parser = Parser::new(buf);
-                let tokens = parser.build_tokens();
-                for token in tokens {
-                    match token {
-                        Ok(token) => match eval(token, env.clone()) {
-                            Ok(_) => {},
-                            Err(reason) => println!("[ERROR] {}", reason),
-                        }
-                        Err(reason) => println!("[ERROR] {}", reason),
+            };
+            if let Err(reason) = file.read_to_string(&mut buf) {
+                println!("could't read {}: {}", file_name, reason)
+            }
+            let parser = Parser::with_name(buf, &file_name);
+            let tokens = parser.build_tokens();
+            for token in tokens {
+                match token {
+                    Ok(token) => match eval(token, env.clone()) {
+                        Ok(_) => {},
+                        Err(reason) => error::report(&reason),
                     }
-                    
+                    Err(reason) => error::report(&reason),
                 }
+                
             }
         }
-        None => {}
     }
 }
 
 fn main() {
-    Memory::init(2048);
-    let global_env = Environment::new_global(function::make_lib());
-    load_file(global_env.clone());
+    let opt = opt::Opt::parse();
+    Memory::init(opt.heap_size, opt.max_heap_size, opt.heap_growth);
+    vm::set_enabled(opt.vm);
+    let global_env = Environment::new_global(function::make_lib(), function::make_ctrl_lib(), function::make_primitives());
+    load_file(opt.files, global_env.clone());
     loop {
         let input = read_stdin().unwrap();
-        if input.len() == 0 {
+        if input.is_empty() {
             break;
         }
         let lex = Parser::new(input);
@@ -109,12 +146,40 @@ fn main() {
                     let res = eval(token, global_env.clone());
                     match res {
                         Ok(obj) => println!("{}", obj),
-                        Err(err) => println!("[ERROR] {}", err),
+                        Err(err) => error::report(&err),
                     }
                 }
-                Err(err) => println!("[ERROR] {}", err),
+                Err(err) => error::report(&err),
             }
         }
         Memory::gc();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unterminated_input() {
+        assert!(!at_unterminated_paren_or_string("(+ 1 2)\n"));
+        assert!(at_unterminated_paren_or_string("(+ 1\n"));
+        assert!(at_unterminated_paren_or_string("(a ; )\n"));
+        assert!(!at_unterminated_paren_or_string("#| ( |# (a)\n"));
+        assert!(at_unterminated_paren_or_string("#| #| |# (a)\n"));
+        // characters which are delimiters
+        assert!(!at_unterminated_paren_or_string("(char->integer #\\()\n"));
+        assert!(!at_unterminated_paren_or_string("(list #\\) #\\\" #\\|)\n"));
+        assert!(!at_unterminated_paren_or_string("(display #\\;)\n"));
+        assert!(!at_unterminated_paren_or_string("(list #\\space #\\x41 #\\λ)\n"));
+        assert!(at_unterminated_paren_or_string("(list #\\space #\\a\n"));
+        assert!(!at_unterminated_paren_or_string("#\\("));
+        // strings and identifiers in bars
+        assert!(!at_unterminated_paren_or_string("(display \"a ( ; b\")\n"));
+        assert!(!at_unterminated_paren_or_string("\"a\\\\\"\n"));
+        assert!(at_unterminated_paren_or_string("\"a\\\"\n"));
+        assert!(!at_unterminated_paren_or_string("'|a ) ; b|\n"));
+        assert!(!at_unterminated_paren_or_string("'|a\\|b|\n"));
+        assert!(at_unterminated_paren_or_string("'(|a b\n"));
+    }
+}
@@ -9,5 +9,25 @@ use clap::Parser;
 )]
 pub struct Opt {
     #[clap(short, long, name="FILE")]
-    pub files: Option<Vec<String>>
+    pub files: Option<Vec<String>>,
+    /// Initial number of objects which fit in the heap
+    #[clap(long, name="SIZE", default_value_t = 2048)]
+    pub heap_size: usize,
+    /// Maximum number of objects which fit in the heap
+    #[clap(long, name="MAX", default_value_t = 1 << 22)]
+    pub max_heap_size: usize,
+    /// Factor by which the heap grows or shrinks
+    #[clap(long, name="FACTOR", default_value_t = 2.0, parse(try_from_str = parse_growth))]
+    pub heap_growth: f64,
+    /// Run programs on the bytecode VM instead of the tree-walking evaluator
+    #[clap(long)]
+    pub vm: bool,
+}
+
+fn parse_growth(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(factor) if factor > 1.0 => Ok(factor),
+        Ok(_) => Err("must be greater than 1".to_string()),
+        Err(err) => Err(err.to_string()),
+    }
 }
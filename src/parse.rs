@@ -2,28 +2,62 @@ use regex::Regex;
 use once_cell::sync::Lazy;
 use anyhow::{Context, Result, anyhow, bail};
 
-use super::token::Token;
+use std::sync::Arc;
+
+use super::token::{Token, Source, Pos, CHAR_NAMES};
+use super::symbol::Symbol;
+use super::error;
+use super::data::object::Number;
 
 
 pub struct Parser {
-    input: String
+    src: Arc<Source>,
+    line_starts: Vec<usize>,
 }
 
-static RE_PERIOD: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\.([\(\)'\s]|$)").unwrap());
+static RE_PERIOD: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\.([\(\)';\s]|$)").unwrap());
+static RE_LABEL: Lazy<Regex> = Lazy::new(|| Regex::new(r"^#(\d+)([=#])").unwrap());
 
 impl Parser {
     pub fn new(input: String) -> Self {
-        Parser{input: input}
+        Parser::with_name(input, "<stdin>")
+    }
+
+    pub fn with_name(input: String, name: &str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(input.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Parser{
+            src: Arc::new(Source{name: name.to_string(), text: input}),
+            line_starts,
+        }
+    }
+
+    fn pos(&self, cursor: usize) -> Pos {
+        let line = self.line_starts.partition_point(|&start| start <= cursor);
+        let line_start = self.line_starts[line - 1];
+        let col = self.src.text[line_start..cursor].chars().count() + 1;
+        Pos{src: self.src.clone(), line, col}
     }
 
     pub fn build_tokens(&self) -> Vec<Result<Token>> {
         let mut cursor = 0;
         let mut res = Vec::new();
 
-        while let Some(()) = self.skip_whitespace(&mut cursor) {
-            if self.input.as_bytes()[cursor] == b')' {
+        loop {
+            match self.skip_whitespace(&mut cursor) {
+                Ok(Some(())) => {}
+                Ok(None) => break,
+                Err(e) => {
+                    // the rest is read after the error, as after an error of a datum
+                    res.push(Err(e));
+                    continue;
+                }
+            }
+            if self.src.text.as_bytes()[cursor] == b')' {
+                let pos = self.pos(cursor);
                 cursor += 1;
-                res.push(Err(anyhow!("read error: extra close parenthesis")))
+                res.push(Err(error::locate(anyhow!("read error: extra close parenthesis"), Some(&pos))))
             } else {
                 res.push(self.token(&mut cursor));
             }
@@ -32,60 +66,126 @@ impl Parser {
         res
     }
 
+    // the first datum and the length of the text up to its end, for read.
+    // None if there are only whitespace and comments.
+    pub fn first_token(&self) -> Option<(Result<Token>, usize)> {
+        let mut cursor = 0;
+        if let Err(e) = self.skip_whitespace(&mut cursor).transpose()? {
+            return Some((Err(e), self.src.text.len()));
+        }
+        let res = self.token(&mut cursor);
+        Some((res, cursor))
+    }
+
     fn token(&self, cursor: &mut usize) -> Result<Token> {
-        if !(*cursor < self.input.len()) {
+        if *cursor >= self.src.text.len()  {
             bail!("lexical analyzer error: index out of bounds");
         }
 
-        match self.input.as_bytes()[*cursor] {
+        let pos = self.pos(*cursor);
+        self.token_at(cursor, pos.clone())
+            .map_err(|e| error::locate(e, Some(&pos)))
+    }
+
+    fn token_at(&self, cursor: &mut usize, pos: Pos) -> Result<Token> {
+        match self.src.text.as_bytes()[*cursor] {
             b'('  => {
                 *cursor += 1;
-                self.skip_whitespace(cursor).context("read error: unterminated parenthesis")?;
-                self.token_pair(cursor)
+                self.skip_whitespace(cursor)?.context("read error: unterminated parenthesis")?;
+                self.token_pair(cursor, pos)
             },
             b')'  => Err(anyhow!("read error: extra close parenthesis")),
-            b'\'' => {
-                *cursor += 1;
-                self.skip_whitespace(cursor).context("read error: unterminated quote")?;
-                self.token(cursor)
-                    .map(|t| Token::Symbol(Box::new(t)))
+            b'\'' | b'`' | b',' => {
+                // 'x -> (quote x), `x -> (quasiquote x), ,x -> (unquote x), ,@x -> (unquote-splicing x)
+                let name = match self.src.text.as_bytes()[*cursor..] {
+                    [b'\'', ..] => "quote",
+                    [b'`', ..] => "quasiquote",
+                    [b',', b'@', ..] => "unquote-splicing",
+                    _ => "unquote",
+                };
+                *cursor += if name == "unquote-splicing" { 2 } else { 1 };
+                self.skip_whitespace(cursor)?.with_context(|| format!("read error: unterminated {}", name))?;
+                let datum = self.token(cursor)?;
+                Ok(Token::Pair{
+                    car: Arc::new(Token::Id(Symbol::intern(name), Some(pos.clone()))),
+                    cdr: Arc::new(Token::Pair{car: Arc::new(datum), cdr: Arc::new(Token::Empty(None)), pos: None}),
+                    pos: Some(pos),
+                })
             },
             b'"'  => {
                 *cursor += 1;
-                self.token_str(cursor)
+                self.token_str(cursor, pos)
             },
-            _ if RE_PERIOD.is_match(self.input.split_at(*cursor).1) => {
+            b'|'  => {
+                // |hello world|: an identifier with any characters
+                *cursor += 1;
+                let name = self.escaped(cursor, '|', "identifier")?;
+                Ok(Token::Id(Symbol::intern(&name), Some(pos)))
+            },
+            b'#' if self.src.text.as_bytes()[*cursor..].starts_with(b"#\\") => {
+                *cursor += 2;
+                self.token_char(cursor, pos)
+            }
+            b'#' if RE_LABEL.is_match(self.src.text.split_at(*cursor).1) => {
+                // #n= or #n#
+                let caps = RE_LABEL.captures(self.src.text.split_at(*cursor).1).unwrap();
+                let n = caps[1].parse().context("read error: datum label is too large")?;
+                *cursor += caps[0].len();
+                if &caps[2] == "#" {
+                    return Ok(Token::LabelRef(n, Some(pos)));
+                }
+                self.skip_whitespace(cursor)?.context("read error: unterminated datum label")?;
+                self.token(cursor)
+                    .map(|t| Token::Label(n, Arc::new(t), Some(pos)))
+            }
+            b'#' if self.src.text.as_bytes()[*cursor..].starts_with(b"#(") => {
+                *cursor += 2;
+                Ok(Token::Vector(self.token_vector(cursor)?, Some(pos)))
+            }
+            b'#' if self.src.text.as_bytes()[*cursor..].starts_with(b"#u8(") => {
+                *cursor += 4;
+                let bytes = self.token_vector(cursor)?
+                    .into_iter()
+                    .map(|t| match t {
+                        Token::Int(i, _) if (0..256).contains(&i) => Ok(i as u8),
+                        _ => Err(error::locate(anyhow!("read error: byte required, but got {}", t), t.pos())),
+                    })
+                    .collect::<Result<Vec<u8>>>()?;
+                Ok(Token::Bytevector(bytes, Some(pos)))
+            }
+            _ if RE_PERIOD.is_match(self.src.text.split_at(*cursor).1) => {
                 *cursor += 1;
                 Err(anyhow!("read error: dot in wrong context"))
             }
-            _     => self.token_id_or_literal(cursor)
+            _     => self.token_id_or_literal(cursor, pos)
         }
     }
     
-    fn token_pair(&self, cursor: &mut usize) -> Result<Token> {
-        if self.input.as_bytes()[*cursor] == b')' {
+    fn token_pair(&self, cursor: &mut usize, pos: Pos) -> Result<Token> {
+        // "pos" is where the list starts, i.e. "(" or the first element of the rest
+        if self.src.text.as_bytes()[*cursor] == b')' {
             // ()
             *cursor += 1;
-            return Ok(Token::Empty);
+            return Ok(Token::Empty(Some(pos)));
         }
 
         let car = self.token(cursor)?;
         
-        self.skip_whitespace(cursor).context("read error: unterminated parenthesis")?;
-        if self.input.split_at(*cursor).1.starts_with(".)") {
+        self.skip_whitespace(cursor)?.context("read error: unterminated parenthesis")?;
+        if self.src.text.split_at(*cursor).1.starts_with(".)") {
             // (a .)
             *cursor += 2;
             Err(anyhow!("read error: dot in wrong context"))
 
-        } else if RE_PERIOD.is_match(self.input.split_at(*cursor).1) {
+        } else if RE_PERIOD.is_match(self.src.text.split_at(*cursor).1) {
             // (a . b)
             *cursor += 1;
-            self.skip_whitespace(cursor).context("read error: unterminated parenthesis")?;
+            self.skip_whitespace(cursor)?.context("read error: unterminated parenthesis")?;
             let cdr = self.token(cursor)?;
-            self.skip_whitespace(cursor).context("read error: unterminated parenthesis")?;
-            if self.input.as_bytes()[*cursor] == b')' {
+            self.skip_whitespace(cursor)?.context("read error: unterminated parenthesis")?;
+            if self.src.text.as_bytes()[*cursor] == b')' {
                 *cursor += 1;
-                Ok(Token::Pair{car: Box::new(car), cdr: Box::new(cdr)})
+                Ok(Token::Pair{car: Arc::new(car), cdr: Arc::new(cdr), pos: Some(pos)})
             } else {
                 // (a . b c)
                 *cursor += 1;
@@ -94,94 +194,203 @@ impl Parser {
 
         } else {
             // (a b)
-            let cdr = self.token_pair(cursor)?;
-            Ok(Token::Pair{car: Box::new(car), cdr: Box::new(cdr)})
+            let cdr = self.token_pair(cursor, self.pos(*cursor))?;
+            Ok(Token::Pair{car: Arc::new(car), cdr: Arc::new(cdr), pos: Some(pos)})
         }
     }
 
-    fn token_str(&self, cursor: &mut usize) -> Result<Token> {
-        static RE_STRING: Lazy<Regex> = Lazy::new(|| Regex::new(r#"^(\\"|[^"])*"#).unwrap());
-        let mat = RE_STRING.find(self.input.split_at(*cursor).1).unwrap();
-        let start = *cursor;
-        let end = *cursor + mat.end();
-        
-        if end == self.input.len() {
-            Err(anyhow!("read error: unterminated string"))
+    // elements of #( ... ) or #u8( ... )
+    fn token_vector(&self, cursor: &mut usize) -> Result<Vec<Token>> {
+        let mut elems = Vec::new();
+        loop {
+            self.skip_whitespace(cursor)?.context("read error: unterminated vector")?;
+            if self.src.text.as_bytes()[*cursor] == b')' {
+                *cursor += 1;
+                return Ok(elems);
+            }
+            elems.push(self.token(cursor)?);
+        }
+    }
+
+    fn token_str(&self, cursor: &mut usize, pos: Pos) -> Result<Token> {
+        Ok(Token::String(self.escaped(cursor, '"', "string")?, Some(pos)))
+    }
+
+    // the text up to "close" with its escapes decoded, i.e. the contents of "a\nb" or |a b|
+    fn escaped(&self, cursor: &mut usize, close: char, what: &str) -> Result<String> {
+        let rest = self.src.text.split_at(*cursor).1;
+        let mut chars = rest.char_indices().peekable();
+        let mut s = String::new();
+        loop {
+            match chars.next() {
+                None => bail!("read error: unterminated {}", what),
+                Some((i, c)) if c == close => {
+                    *cursor += i + 1;
+                    return Ok(s);
+                }
+                Some((_, '\\')) => match chars.next() {
+                    None => bail!("read error: unterminated {}", what),
+                    Some((_, 'a')) => s.push('\x07'),
+                    Some((_, 'b')) => s.push('\x08'),
+                    Some((_, 't')) => s.push('\t'),
+                    Some((_, 'n')) => s.push('\n'),
+                    Some((_, 'r')) => s.push('\r'),
+                    Some((_, c @ ('"' | '\\' | '|'))) => s.push(c),
+                    Some((i, 'x')) => {
+                        // \x41;
+                        let hex = &rest[i + 1..];
+                        let end = hex.find(';').with_context(|| format!("read error: unterminated hex escape in {}", what))?;
+                        let c = u32::from_str_radix(&hex[..end], 16)
+                            .ok()
+                            .and_then(char::from_u32)
+                            .with_context(|| format!("read error: invalid hex escape in {}: \\x{};", what, &hex[..end]))?;
+                        s.push(c);
+                        while chars.next_if(|&(j, _)| j <= i + 1 + end).is_some() {}
+                    }
+                    Some((_, c)) if c.is_whitespace() => {
+                        // line continuation: \<intraline whitespace>*<line ending><intraline whitespace>*
+                        let mut c = c;
+                        while c != '\n' {
+                            match chars.next_if(|&(_, c)| c.is_whitespace()) {
+                                Some((_, next)) => c = next,
+                                None => bail!("read error: invalid line continuation in {}", what),
+                            }
+                        }
+                        while chars.next_if(|&(_, c)| c == ' ' || c == '\t').is_some() {}
+                    }
+                    Some((_, c)) => bail!("read error: unknown escape in {}: \\{}", what, c),
+                }
+                Some((_, c)) => s.push(c),
+            }
+        }
+    }
+
+    fn token_char(&self, cursor: &mut usize, pos: Pos) -> Result<Token> {
+        // #\a, #\space, #\x41
+        let rest = self.src.text.split_at(*cursor).1;
+        let first = rest.chars().next().context("read error: unterminated character")?;
+        // the first character can be a delimiter, e.g. #\( or #\ (space)
+        let len = first.len_utf8() + rest[first.len_utf8()..]
+            .find(|c: char| c.is_whitespace() || "()'\";".contains(c))
+            .unwrap_or(rest.len() - first.len_utf8());
+        let name = &rest[..len];
+        *cursor += len;
+
+        if name.chars().count() == 1 {
+            Ok(Token::Char(first, Some(pos)))
+        } else if let Some((_, c)) = CHAR_NAMES.iter().find(|(n, _)| *n == name) {
+            Ok(Token::Char(*c, Some(pos)))
+        } else if let Some(c) = name.strip_prefix('x').and_then(|hex| u32::from_str_radix(hex, 16).ok()) {
+            let c = char::from_u32(c).with_context(|| format!("read error: invalid character: #\\{}", name))?;
+            Ok(Token::Char(c, Some(pos)))
         } else {
-            *cursor = end + 1;
-            Ok(Token::String(self.input.get(start..end).unwrap().to_string()))
+            Err(anyhow!("read error: unknown character name: #\\{}", name))
         }
     }
 
-    fn token_id_or_literal(&self, cursor: &mut usize) -> Result<Token> {
-        static RE_FLOAT: Lazy<Regex> = Lazy::new(|| Regex::new(r#"^[\+-]?(\d*\.\d*([Ee][\+-]?\d+)?|\d+e[\+-]?\d+)([\(\)'"\s]|$)"#).unwrap());
-        static RE_FLOAT_NOINT: Lazy<Regex> = Lazy::new(|| Regex::new(r"^(?P<sign>[\+-])\.").unwrap()); // .123 (must be changed to 0.123)
-        static RE_INT: Lazy<Regex> = Lazy::new(|| Regex::new(r#"^[\+-]?\d+([\(\)'"\s]|$)"#).unwrap());
-        static RE_BOOL: Lazy<Regex> = Lazy::new(|| Regex::new(r##"^#[ft]([\(\)'"#\s]|$)"##).unwrap());
-        static RE_ID: Lazy<Regex> = Lazy::new(|| Regex::new(r#"^[[:alnum:]!\$%&\*\+-\./<=>\?@\^_]+([\(\)'"\s]|$)"#).unwrap());
-        static RE_DELIMITER: Lazy<Regex> = Lazy::new(|| Regex::new(r#"[\(\)\s'"]"#).unwrap());
+    fn token_id_or_literal(&self, cursor: &mut usize, pos: Pos) -> Result<Token> {
+        static RE_BOOL: Lazy<Regex> = Lazy::new(|| Regex::new(r##"^#[ft]([\(\)'"#;\s]|$)"##).unwrap());
+        static RE_ID: Lazy<Regex> = Lazy::new(|| Regex::new(r#"^[[:alnum:]!\$%&\*\+-\./<=>\?@\^_]+([\(\)'";\s]|$)"#).unwrap());
+        static RE_DELIMITER: Lazy<Regex> = Lazy::new(|| Regex::new(r#"[\(\)\s'";]"#).unwrap());
+        static RE_RATIONAL: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[\+-]?\d+/\d+$").unwrap());
 
-        let input_from_cursor = self.input.split_at(*cursor).1;
+        let input_from_cursor = self.src.text.split_at(*cursor).1;
+        let word = &input_from_cursor[..RE_DELIMITER.find(input_from_cursor).map_or(input_from_cursor.len(), |m| m.start())];
         if RE_PERIOD.is_match(input_from_cursor) {
             *cursor += 1;
             Err(anyhow!("read error: dot in wrong context"))
         
-        } else if let Some(mat) = RE_FLOAT.find(input_from_cursor) {
-            let (start, mut end) = (*cursor, *cursor + mat.end() - 1);
-            if !RE_DELIMITER.is_match_at(&self.input, end) {
-                // match `$` (end)
-                end += 1;
-            }
-            *cursor = end;
-            Ok(Token::Float(RE_FLOAT_NOINT
-                .replace(self.input.get(start..end).unwrap(), "${sign}0.")
-                .parse()
-                .unwrap()))
+        } else if let Some(num) = Number::parse(word, 10) {
+            *cursor += word.len();
+            Ok(number_token(num, pos))
 
-        } else if let Some(mat) = RE_INT.find(input_from_cursor) {
-            let (start, mut end) = (*cursor, *cursor + mat.end() - 1);
-            if !RE_DELIMITER.is_match_at(&self.input, end) {
-                end += 1;
-            }
-            *cursor = end;
-            Ok(Token::Int(self.input
-                .get(start..end)
-                .unwrap()
-                .parse()
-                .context("overflow")?))
+        } else if RE_RATIONAL.is_match(word) {
+            // a rational which is not a number, whose denominator is 0
+            *cursor += word.len();
+            Err(anyhow!("read error: division by zero: {}", word))
 
         } else if RE_BOOL.is_match(input_from_cursor) {
             *cursor += 2;
             if input_from_cursor.as_bytes()[1] == b'f' {
-                Ok(Token::Boolean(false))
+                Ok(Token::Boolean(false, Some(pos)))
             } else {
-                Ok(Token::Boolean(true))
+                Ok(Token::Boolean(true, Some(pos)))
             }
 
         } else if let Some(mat) = RE_ID.find(input_from_cursor) {
             let (start, mut end) = (*cursor, *cursor + mat.end() - 1);
-            if !RE_DELIMITER.is_match_at(&self.input, end) {
+            if !RE_DELIMITER.is_match_at(&self.src.text, end) {
                 end += 1;
             }
             *cursor = end;
-            Ok(Token::Id(self.input.get(start..end).unwrap().to_string()))
+            Ok(Token::Id(Symbol::intern(self.src.text.get(start..end).unwrap()), Some(pos)))
 
         } else {
-            *cursor = RE_DELIMITER.find(input_from_cursor).map_or(self.input.len(), |m| *cursor + m.end());
+            *cursor = RE_DELIMITER.find(input_from_cursor).map_or(self.src.text.len(), |m| *cursor + m.end());
             Err(anyhow!("read error: invalid symbol name"))
         }
     }
 
-    fn skip_whitespace(&self, cursor: &mut usize) -> Option<()> {
+    // None at the end of the input
+    fn skip_whitespace(&self, cursor: &mut usize) -> Result<Option<()>> {
+        // skip whitespace and comments (; line, #| block |#, #; datum)
+        let bytes = self.src.text.as_bytes();
         loop {
-            if *cursor >= self.input.len() {
-                return None;
-            } else if self.input.as_bytes()[*cursor].is_ascii_whitespace() {
+            if *cursor >= self.src.text.len() {
+                return Ok(None);
+            } else if bytes[*cursor].is_ascii_whitespace() {
                 *cursor += 1;
+            } else if bytes[*cursor] == b';' {
+                while *cursor < bytes.len() && bytes[*cursor] != b'\n' {
+                    *cursor += 1;
+                }
+            } else if bytes[*cursor..].starts_with(b"#|") {
+                self.skip_block_comment(cursor)?;
+            } else if bytes[*cursor..].starts_with(b"#;") {
+                *cursor += 2;
+                if self.skip_whitespace(cursor)?.is_none() {
+                    return Ok(None);
+                }
+                if bytes[*cursor] == b')' {
+                    // (a #;) : nothing to comment out
+                    return Ok(Some(()));
+                }
+                self.token(cursor)?;
+            } else {
+                return Ok(Some(()));
+            }
+        }
+    }
+
+    fn skip_block_comment(&self, cursor: &mut usize) -> Result<()> {
+        // block comments can be nested: #| #| |# |#
+        let bytes = self.src.text.as_bytes();
+        let start = *cursor;
+        let mut nest = 0;
+        while *cursor < bytes.len() {
+            if bytes[*cursor..].starts_with(b"#|") {
+                nest += 1;
+                *cursor += 2;
+            } else if bytes[*cursor..].starts_with(b"|#") {
+                nest -= 1;
+                *cursor += 2;
+                if nest == 0 {
+                    return Ok(());
+                }
             } else {
-                return Some(());
+                *cursor += 1;
             }
         }
+        Err(error::locate(anyhow!("read error: unterminated block comment"), Some(&self.pos(start))))
+    }
+}
+
+fn number_token(num: Number, pos: Pos) -> Token {
+    match num {
+        Number::Int(i) => Token::Int(i, Some(pos)),
+        Number::BigInt(i) => Token::BigInt(i, Some(pos)),
+        Number::Rational(r) => Token::Rational(r, Some(pos)),
+        Number::Float(f) => Token::Float(f, Some(pos)),
     }
 }
 
@@ -193,44 +402,85 @@ mod tests {
     fn lex_float() {
         let lex = Parser::new(r"23.4 -.0098e3 .".to_string());
         let tokens = lex.build_tokens();
-        assert_eq!(format!("{:?}", tokens.get(0).unwrap().as_ref().unwrap()), "23.4[float]");
+        assert_eq!(format!("{:?}", tokens.first().unwrap().as_ref().unwrap()), "23.4[float]");
         assert_eq!(format!("{:?}", tokens.get(1).unwrap().as_ref().unwrap()), "-9.8[float]");
         //assert_eq!(format!("{:?}", tokens.get(2).unwrap().as_ref().err().unwrap()), ("read error: dot in wrong context"));
     }
 
     #[test]
     fn lex_int() {
-        let lex = Parser::new(r"234 0098".to_string());
+        let lex = Parser::new(r"234 0098 -123456789012345678901234567890".to_string());
         let tokens = lex.build_tokens();
-        assert_eq!(format!("{:?}", tokens.get(0).unwrap().as_ref().unwrap()), "234[int]");
+        assert_eq!(format!("{:?}", tokens.first().unwrap().as_ref().unwrap()), "234[int]");
         assert_eq!(format!("{:?}", tokens.get(1).unwrap().as_ref().unwrap()), "98[int]");
+        assert_eq!(format!("{:?}", tokens.get(2).unwrap().as_ref().unwrap()), "-123456789012345678901234567890[int]");
+    }
+
+    #[test]
+    fn lex_number_prefix() {
+        let lex = Parser::new(r"#xff #b-101 #o17 #e1.25 #i1/4 #x#e1/A 6/4 -1/3 +inf.0 -inf.0 +nan.0 #x1.5 1/0 +i".to_string());
+        let tokens = lex.build_tokens();
+        let expected = [
+            "255[int]", "-5[int]", "15[int]", "5/4[rational]", "0.25[float]", "1/10[rational]",
+            "3/2[rational]", "-1/3[rational]", "inf[float]", "-inf[float]", "NaN[float]",
+        ];
+        for (token, expected) in tokens.iter().zip(expected) {
+            assert_eq!(format!("{:?}", token.as_ref().unwrap()), expected);
+        }
+        assert!(tokens.get(11).unwrap().is_err());
+        assert_eq!(format!("{}", tokens.get(12).unwrap().as_ref().err().unwrap()), "read error: division by zero: 1/0");
+        assert_eq!(format!("{:?}", tokens.get(13).unwrap().as_ref().unwrap()), "+i[id]");
     }
 
     #[test]
     fn lex_string() {
         let lex = Parser::new(r#""hoge" "fああueo84()79" "hogefuga"#.to_string());
         let tokens = lex.build_tokens();
-        assert_eq!(format!("{:?}", tokens.get(0).unwrap().as_ref().unwrap()), r#""hoge"[string]"#);
+        assert_eq!(format!("{:?}", tokens.first().unwrap().as_ref().unwrap()), r#""hoge"[string]"#);
         assert_eq!(format!("{:?}", tokens.get(1).unwrap().as_ref().unwrap()), r#""fああueo84()79"[string]"#);
         //assert_eq!(format!("{:?}", tokens.get(2).unwrap().as_ref().err().unwrap()), "read error: unterminated string");
     }
 
+    #[test]
+    fn lex_string_escape() {
+        let lex = Parser::new("\"a\\nb\\t\\\"\\\\\" \"\\x41;\\x3bb;\" \"a \\  \n   b\" \"\\q\"".to_string());
+        let tokens = lex.build_tokens();
+        assert_eq!(format!("{:?}", tokens.first().unwrap().as_ref().unwrap()), r#""a\nb\t\"\\"[string]"#);
+        assert_eq!(format!("{:?}", tokens.get(1).unwrap().as_ref().unwrap()), r#""Aλ"[string]"#);
+        assert_eq!(format!("{:?}", tokens.get(2).unwrap().as_ref().unwrap()), r#""a b"[string]"#);
+        assert_eq!(format!("{}", tokens.get(3).unwrap().as_ref().err().unwrap()), r"read error: unknown escape in string: \q");
+    }
+
     #[test]
     fn lex_bool() {
         let lex = Parser::new(r"#t #f #t42 #f#t".to_string());
         let tokens = lex.build_tokens();
-        assert_eq!(format!("{:?}", tokens.get(0).unwrap().as_ref().unwrap()), "#t[boolean]");
+        assert_eq!(format!("{:?}", tokens.first().unwrap().as_ref().unwrap()), "#t[boolean]");
         assert_eq!(format!("{:?}", tokens.get(1).unwrap().as_ref().unwrap()), "#f[boolean]");
         //assert_eq!(format!("{:?}", tokens.get(2).unwrap().as_ref().err().unwrap()), "read error: invalid symbol name");
         assert_eq!(format!("{:?}", tokens.get(3).unwrap().as_ref().unwrap()), "#f[boolean]");
         assert_eq!(format!("{:?}", tokens.get(4).unwrap().as_ref().unwrap()), "#t[boolean]");
     }
 
+    #[test]
+    fn lex_char() {
+        let lex = Parser::new(r"#\a #\space #\x41 #\( #\λ #\x #\newline)".to_string());
+        let tokens = lex.build_tokens();
+        assert_eq!(format!("{:?}", tokens[0].as_ref().unwrap()), r"#\a[char]");
+        assert_eq!(format!("{:?}", tokens[1].as_ref().unwrap()), r"#\space[char]");
+        assert_eq!(format!("{:?}", tokens[2].as_ref().unwrap()), r"#\A[char]");
+        assert_eq!(format!("{:?}", tokens[3].as_ref().unwrap()), r"#\([char]");
+        assert_eq!(format!("{:?}", tokens[4].as_ref().unwrap()), r"#\λ[char]");
+        assert_eq!(format!("{:?}", tokens[5].as_ref().unwrap()), r"#\x[char]");
+        assert_eq!(format!("{:?}", tokens[6].as_ref().unwrap()), r"#\newline[char]");
+        assert!(tokens[7].is_err());
+    }
+
     #[test]
     fn lex_id() {
         let lex = Parser::new(r"hoge fuga あ #123 piyo".to_string());
         let tokens = lex.build_tokens();
-        assert_eq!(format!("{:?}", tokens.get(0).unwrap().as_ref().unwrap()), "hoge[id]");
+        assert_eq!(format!("{:?}", tokens.first().unwrap().as_ref().unwrap()), "hoge[id]");
         assert_eq!(format!("{:?}", tokens.get(1).unwrap().as_ref().unwrap()), "fuga[id]");
         //assert_eq!(format!("{:?}", tokens.get(2).unwrap().as_ref().err().unwrap()), "read error: invalid symbol name");
         //assert_eq!(format!("{:?}", tokens.get(3).unwrap().as_ref().err().unwrap()), "read error: invalid symbol name");
@@ -239,27 +489,29 @@ mod tests {
 
     #[test]
     fn lex_symble() {
-        let lex = Parser::new(r#"'123 '1.23e-3 '"foo" '#t '''bar"#.to_string());
+        let lex = Parser::new(r#"'123 '1.23e-3 '"foo" '#t '''bar `(a ,b ,@ c)"#.to_string());
         let tokens = lex.build_tokens();
-        assert_eq!(format!("{:?}", tokens.get(0).unwrap().as_ref().unwrap()), "'123[int]");
-        assert_eq!(format!("{:?}", tokens.get(1).unwrap().as_ref().unwrap()), "'0.00123[float]");
-        assert_eq!(format!("{:?}", tokens.get(2).unwrap().as_ref().unwrap()), "'\"foo\"[string]");
-        assert_eq!(format!("{:?}", tokens.get(3).unwrap().as_ref().unwrap()), "'#t[boolean]");
-        assert_eq!(format!("{:?}", tokens.get(4).unwrap().as_ref().unwrap()), "'''bar[id]");
+        assert_eq!(format!("{:?}", tokens.first().unwrap().as_ref().unwrap()), "(quote[id] 123[int])");
+        assert_eq!(format!("{:?}", tokens.get(1).unwrap().as_ref().unwrap()), "(quote[id] 0.00123[float])");
+        assert_eq!(format!("{:?}", tokens.get(2).unwrap().as_ref().unwrap()), "(quote[id] \"foo\"[string])");
+        assert_eq!(format!("{:?}", tokens.get(3).unwrap().as_ref().unwrap()), "(quote[id] #t[boolean])");
+        assert_eq!(format!("{:?}", tokens.get(4).unwrap().as_ref().unwrap()), "(quote[id] (quote[id] (quote[id] bar[id])))");
+        assert_eq!(format!("{:?}", tokens.get(5).unwrap().as_ref().unwrap()),
+            "(quasiquote[id] (a[id] (unquote[id] b[id]) (unquote-splicing[id] c[id])))");
     }
 
     #[test]
     fn lex_pair() {
         let lex1 = Parser::new(r#"() (() ()) (() . (() . ())))"#.to_string());
         let tokens1 = lex1.build_tokens();
-        assert_eq!(format!("{:?}", tokens1.get(0).unwrap().as_ref().unwrap()), "()");
+        assert_eq!(format!("{:?}", tokens1.first().unwrap().as_ref().unwrap()), "()");
         assert_eq!(format!("{:?}", tokens1.get(1).unwrap().as_ref().unwrap()), "(() ())");
         assert_eq!(format!("{:?}", tokens1.get(2).unwrap().as_ref().unwrap()), "(() ())");
         //assert_eq!(format!("{:?}", tokens1.get(3).unwrap().as_ref().err().unwrap()), "read error: extra close parenthesis");
 
         let lex2 = Parser::new(r#"(define ls '(1 2 3 4))"#.to_string());
         let tokens2 = lex2.build_tokens();
-        assert_eq!(format!("{:?}", tokens2.get(0).unwrap().as_ref().unwrap()), "(define[id] ls[id] '(1[int] 2[int] 3[int] 4[int]))");
+        assert_eq!(format!("{:?}", tokens2.first().unwrap().as_ref().unwrap()), "(define[id] ls[id] (quote[id] (1[int] 2[int] 3[int] 4[int])))");
 
         let lex3 = Parser::new(
         r#"
@@ -271,7 +523,92 @@ mod tests {
         )
         "#.to_string());
         let tokens3 = lex3.build_tokens();
-        assert_eq!(format!("{:?}", tokens3.get(0).unwrap().as_ref().unwrap()),
+        assert_eq!(format!("{:?}", tokens3.first().unwrap().as_ref().unwrap()),
         "(define[id] (fact[id] n[id]) (if[id] (eq?[id] n[id] 0[int]) 1[int] (*[id] n[id] (fact[id] (-[id] n[id] 1[int])))))");
     }
+
+    #[test]
+    fn lex_vector() {
+        let lex = Parser::new(r"#(1 #\a (b c) #()) #u8(0 255) #u8(256) #(1 . 2)".to_string());
+        let tokens = lex.build_tokens();
+        assert_eq!(format!("{:?}", tokens[0].as_ref().unwrap()), r"#(1[int] #\a[char] (b[id] c[id]) #())");
+        assert_eq!(format!("{:?}", tokens[1].as_ref().unwrap()), "#u8(0 255)[bytevector]");
+        assert!(tokens[2].is_err());
+        assert!(tokens[3].is_err());
+    }
+
+    #[test]
+    fn lex_comment() {
+        let lex = Parser::new(r#"
+        ; line comment (
+        foo ; trailing comment
+        #| block #| nested |# comment ) |# bar
+        #;(baz (qux)) quux
+        (1 #;2 3 #| 4 |#)
+        (a . #;b c)
+        "#.to_string());
+        let tokens = lex.build_tokens();
+        assert_eq!(tokens.len(), 5);
+        assert_eq!(format!("{:?}", tokens[0].as_ref().unwrap()), "foo[id]");
+        assert_eq!(format!("{:?}", tokens[1].as_ref().unwrap()), "bar[id]");
+        assert_eq!(format!("{:?}", tokens[2].as_ref().unwrap()), "quux[id]");
+        assert_eq!(format!("{:?}", tokens[3].as_ref().unwrap()), "(1[int] 3[int])");
+        assert_eq!(format!("{:?}", tokens[4].as_ref().unwrap()), "(a[id] . c[id])");
+
+        let lex = Parser::new(r"hoge;comment".to_string());
+        let tokens = lex.build_tokens();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(format!("{:?}", tokens[0].as_ref().unwrap()), "hoge[id]");
+
+        let lex = Parser::new("foo #| #| |#\nbar".to_string());
+        let tokens = lex.build_tokens();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(format!("{}", tokens[1].as_ref().err().unwrap()), "read error: unterminated block comment");
+
+        let lex = Parser::new("#;(a . b c) foo".to_string());
+        let tokens = lex.build_tokens();
+        assert_eq!(format!("{}", tokens[0].as_ref().err().unwrap()), "read error: bad dot syntax");
+    }
+
+    #[test]
+    fn lex_bar_id() {
+        let lex = Parser::new(r"|hello world| |a\|b| || |x".to_string());
+        let tokens = lex.build_tokens();
+        assert_eq!(format!("{:?}", tokens[0].as_ref().unwrap()), "hello world[id]");
+        assert_eq!(format!("{:?}", tokens[1].as_ref().unwrap()), "a|b[id]");
+        assert_eq!(format!("{:?}", tokens[2].as_ref().unwrap()), "[id]");
+        assert_eq!(format!("{}", tokens[3].as_ref().err().unwrap()), "read error: unterminated identifier");
+    }
+
+    #[test]
+    fn lex_pos() {
+        let lex = Parser::with_name("(foo\n  \"bär\" 'baz)\n)".to_string(), "test.scm");
+        let tokens = lex.build_tokens();
+        let list = tokens[0].as_ref().unwrap();
+        assert_eq!(format!("{}", list.pos().unwrap()), "test.scm:1:1");
+        assert_eq!(format!("{}", list.nth(0).unwrap().pos().unwrap()), "test.scm:1:2");
+        assert_eq!(format!("{}", list.nth(1).unwrap().pos().unwrap()), "test.scm:2:3");
+        assert_eq!(format!("{}", list.nth(2).unwrap().pos().unwrap()), "test.scm:2:9");
+        let err = tokens[1].as_ref().err().unwrap();
+        assert_eq!(format!("{}", err.downcast_ref::<error::SchemeError>().unwrap().pos.as_ref().unwrap()), "test.scm:3:1");
+    }
+
+    #[test]
+    fn first_token() {
+        let (token, len) = Parser::new(" ; comment\n(a b) c".to_string()).first_token().unwrap();
+        assert_eq!(format!("{:?}", token.unwrap()), "(a[id] b[id])");
+        assert_eq!(len, 16);
+        assert!(Parser::new("#| only a comment |# ".to_string()).first_token().is_none());
+        let (token, _) = Parser::new("(a\n".to_string()).first_token().unwrap();
+        assert!(format!("{:#}", token.unwrap_err()).contains("unterminated"));
+    }
+
+    #[test]
+    fn lex_label() {
+        let lex = Parser::new("#0=(1 . #0#) #12= #(a #12#) #3".to_string());
+        let tokens = lex.build_tokens();
+        assert_eq!(format!("{:?}", tokens[0].as_ref().unwrap()), "#0=(1[int] . #0#[label])");
+        assert_eq!(format!("{:?}", tokens[1].as_ref().unwrap()), "#12=#(a[id] #12#[label])");
+        assert!(tokens[2].is_err());
+    }
 }
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, Weak};
+
+use once_cell::sync::Lazy;
+
+// name -> the interned symbol with the name, which is removed when the symbol is dropped
+static SYMBOLS: Lazy<Mutex<HashMap<String, Weak<Name>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/**
+ * Symbol: a name of a symbol or an identifier.
+ * Every symbol read or made by string->symbol is interned, i.e. there is only one symbol for each name,
+ * so symbols are compared and hashed by address.
+ * Uninterned symbols are made by string->uninterned-symbol and gensym, and are different from any other.
+ * A symbol which is not used any more is dropped, and made again if its name is interned later.
+ */
+#[derive(Clone)]
+pub struct Symbol(Arc<Name>);
+
+struct Name {
+    text: String,
+    interned: bool,
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        let mut symbols = SYMBOLS.lock().unwrap();
+        if let Some(name) = symbols.get(name).and_then(Weak::upgrade) {
+            return Symbol(name);
+        }
+        let symbol = Symbol(Arc::new(Name{text: name.to_string(), interned: true}));
+        symbols.insert(name.to_string(), Arc::downgrade(&symbol.0));
+        symbol
+    }
+
+    pub fn uninterned(name: &str) -> Symbol {
+        Symbol(Arc::new(Name{text: name.to_string(), interned: false}))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0.text
+    }
+
+    pub fn is_interned(&self) -> bool {
+        self.0.interned
+    }
+}
+
+impl Drop for Name {
+    fn drop(&mut self) {
+        if !self.interned {
+            return
+        }
+        // the name may have been interned again since the last symbol was dropped
+        let mut symbols = SYMBOLS.lock().unwrap();
+        if symbols.get(&self.text).is_some_and(|name| name.strong_count() == 0) {
+            symbols.remove(&self.text);
+        }
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.0).hash(state)
+    }
+}
+
+// compares the name, e.g. id == "define"
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl Deref for Symbol {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_interned(name: &str) -> bool {
+        SYMBOLS.lock().unwrap().contains_key(name)
+    }
+
+    #[test]
+    fn intern_test() {
+        let a = Symbol::intern("intern-test");
+        assert!(a == Symbol::intern("intern-test"));
+        assert!(a != Symbol::uninterned("intern-test"));
+        assert!(is_interned("intern-test"));
+        // the name is forgotten with the last symbol, and interned again
+        drop(a);
+        assert!(!is_interned("intern-test"));
+        let b = Symbol::intern("intern-test");
+        assert!(b.is_interned() && b == Symbol::intern("intern-test"));
+        assert!(is_interned("intern-test"));
+        drop(Symbol::uninterned("intern-test"));
+        assert!(is_interned("intern-test"));
+    }
+}
@@ -1,18 +1,143 @@
 use std::fmt;
+use std::sync::Arc;
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+
+use crate::symbol::Symbol;
+use crate::parse::Parser;
 
 #[derive(Clone)]
 pub enum Token {
-    Int(i64),
-    Float(f64),
-    Boolean(bool),
-    Id(String),
-    String(String),
-    Pair{car: Box<Token>, cdr: Box<Token>},
-    Empty,
-    Symbol(Box<Token>),
+    Int(i64, Option<Pos>),
+    BigInt(BigInt, Option<Pos>),    // an integer out of the range of i64
+    Rational(BigRational, Option<Pos>),
+    Float(f64, Option<Pos>),
+    Boolean(bool, Option<Pos>),
+    Char(char, Option<Pos>),
+    Id(Symbol, Option<Pos>),
+    String(String, Option<Pos>),
+    Pair{car: Arc<Token>, cdr: Arc<Token>, pos: Option<Pos>},
+    Empty(Option<Pos>),
+    Vector(Vec<Token>, Option<Pos>),
+    Bytevector(Vec<u8>, Option<Pos>),
+    Label(usize, Arc<Token>, Option<Pos>),  // #n=datum
+    LabelRef(usize, Option<Pos>),           // #n#
+}
+
+/**
+ * Source: the text which tokens are read from.
+ * It is shared by the tokens and used to show an excerpt in error messages.
+ */
+pub struct Source {
+    pub name: String,
+    pub text: String,
+}
+
+/**
+ * Pos: where a token starts in its source (line and column count from 1)
+ */
+#[derive(Clone)]
+pub struct Pos {
+    pub src: Arc<Source>,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Source {
+    pub fn line(&self, line: usize) -> Option<&str> {
+        self.text.lines().nth(line - 1)
+    }
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.src.name, self.line, self.col)
+    }
+}
+
+// names of characters written as #\name
+pub const CHAR_NAMES: [(&str, char); 9] = [
+    ("alarm", '\x07'), ("backspace", '\x08'), ("delete", '\x7f'), ("escape", '\x1b'),
+    ("newline", '\n'), ("null", '\0'), ("return", '\r'), ("space", ' '), ("tab", '\t'),
+];
+
+// the external representation of a character: #\a, #\space, #\x3bb
+pub fn write_char(c: char) -> String {
+    if let Some((name, _)) = CHAR_NAMES.iter().find(|(_, ch)| *ch == c) {
+        format!("#\\{}", name)
+    } else if c.is_control() {
+        format!("#\\x{:x}", c as u32)
+    } else {
+        format!("#\\{}", c)
+    }
+}
+
+// the external representation of a string, with escapes: "a\nb"
+pub fn write_string(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\x07' => res.push_str("\\a"),
+            '\x08' => res.push_str("\\b"),
+            '\t' => res.push_str("\\t"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            c if c.is_control() => res.push_str(&format!("\\x{:x};", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+// the external representation of a symbol, in bars if it is not read back as it is: |hello world|
+pub fn write_symbol(name: &str) -> String {
+    if let [Ok(Token::Id(id, _))] = Parser::new(name.to_string()).build_tokens().as_slice() {
+        if id.as_str() == name {
+            return name.to_string();
+        }
+    }
+    let mut res = String::from("|");
+    for c in name.chars() {
+        match c {
+            '|' => res.push_str("\\|"),
+            '\\' => res.push_str("\\\\"),
+            c if c.is_control() => res.push_str(&format!("\\x{:x};", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('|');
+    res
+}
+
+// The expander renames identifiers to "name#N", which cannot be read.
+// This is the name as the user wrote it.
+pub fn written_name(id: &str) -> &str {
+    id.split('#').next().unwrap()
+}
+
+// the symbol of written_name(id); only renamed identifiers are uninterned
+pub fn written_symbol(id: &Symbol) -> Symbol {
+    if id.is_interned() {
+        id.clone()
+    } else {
+        Symbol::intern(written_name(id))
+    }
 }
 
 impl Token {
+    pub fn pos(&self) -> Option<&Pos> {
+        match self {
+            Token::Int(_, pos) | Token::BigInt(_, pos) | Token::Rational(_, pos) | Token::Float(_, pos) | Token::Boolean(_, pos) | Token::Char(_, pos) |
+            Token::Id(_, pos) | Token::String(_, pos) | Token::Pair{pos, ..} |
+            Token::Empty(pos) | Token::Vector(_, pos) |
+            Token::Bytevector(_, pos) | Token::Label(_, _, pos) | Token::LabelRef(_, pos) => pos.as_ref(),
+        }
+    }
+
     pub fn car(&self) -> Option<&Self> {
         match self {
             Token::Pair{car, ..} => Some(&**car),
@@ -22,7 +147,7 @@ impl Token {
 
     pub fn cdr(&self) -> Option<&Self> {
         match self {
-            Token::Pair{car: _, cdr} => Some(cdr),
+            Token::Pair{car: _, cdr, ..} => Some(cdr),
             _ => None,
         }
     }
@@ -33,9 +158,9 @@ impl Token {
 
     // (a ..) -> ..
     pub fn next(&self) -> Option<&Self> {
-        if let Token::Pair{car: _, cdr} = self {
+        if let Token::Pair{car: _, cdr, ..} = self {
             match &**cdr {
-                Token::Pair{..} | Token::Empty => Some(cdr),
+                Token::Pair{..} | Token::Empty(_) => Some(cdr),
                 _ => None
             } 
         } else {
@@ -46,7 +171,7 @@ impl Token {
     pub fn nth(&self, n: usize) -> Option<&Self> {
         let mut t = self;
         for _ in 0..n {
-            if let Token::Pair{car: _, cdr} = t {
+            if let Token::Pair{car: _, cdr, ..} = t {
                 t = &**cdr;
             } else {
                 return None;
@@ -56,22 +181,18 @@ impl Token {
     }
 
     pub fn is_empty(&self) -> bool {
-        if let Token::Empty = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Token::Empty(_))
     }
 
     pub fn is_list(&self) -> bool {
         let mut t = self;
         loop {
             match t {
-                Token::Pair{car: _, cdr} => {
+                Token::Pair{car: _, cdr, ..} => {
                     t = &**cdr;
                     continue;
                 }
-                Token::Empty => {
+                Token::Empty(_) => {
                     return true;
                 }
                 _ => {
@@ -85,22 +206,34 @@ impl Token {
 impl fmt::Display for Token {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Token::Int(i) => write!(f, "{}", i),
-            Token::Float(fl) => write!(f, "{}", fl),
-            Token::Boolean(b) if *b => write!(f, "#t"),
-            Token::Boolean(_) => write!(f, "#f"), 
-            Token::Id(id) => write!(f, "{}", id),
-            Token::String(s) => write!(f, "\"{}\"", s),
-            Token::Pair{car, cdr} => match **cdr {
+            Token::Int(i, _) => write!(f, "{}", i),
+            Token::BigInt(i, _) => write!(f, "{}", i),
+            Token::Rational(r, _) => write!(f, "{}", r),
+            Token::Float(fl, _) => write!(f, "{}", fl),
+            Token::Boolean(b, _) if *b => write!(f, "#t"),
+            Token::Boolean(_, _) => write!(f, "#f"), 
+            Token::Char(c, _) => write!(f, "{}", write_char(*c)),
+            Token::Id(id, _) => write!(f, "{}", written_name(id)),
+            Token::String(s, _) => write!(f, "{}", write_string(s)),
+            Token::Pair{car, cdr, ..} => match **cdr {
                 Token::Pair{..} => {
                     let cdr = format!("{}", cdr);
                     write!(f, "({} {}", car, cdr.split_at(1).1)
                 },
-                Token::Empty => write!(f, "({})", car),
+                Token::Empty(_) => write!(f, "({})", car),
                 _ => write!(f, "({} . {})", car, cdr),
             },
-            Token::Empty => write!(f, "()"),
-            Token::Symbol(s) => write!(f, "'{}", s),
+            Token::Empty(_) => write!(f, "()"),
+            Token::Vector(elems, _) => {
+                let elems: Vec<String> = elems.iter().map(|t| format!("{}", t)).collect();
+                write!(f, "#({})", elems.join(" "))
+            }
+            Token::Bytevector(bytes, _) => {
+                let bytes: Vec<String> = bytes.iter().map(|b| b.to_string()).collect();
+                write!(f, "#u8({})", bytes.join(" "))
+            }
+            Token::Label(n, datum, _) => write!(f, "#{}={}", n, datum),
+            Token::LabelRef(n, _) => write!(f, "#{}#", n),
         }
     }
 }
@@ -108,24 +241,36 @@ impl fmt::Display for Token {
 impl fmt::Debug for Token {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Token::Int(i) => write!(f, "{}[int]", i),
-            Token::Float(fl) => write!(f, "{}[float]", fl),
-            Token::Boolean(b) if *b => write!(f, "#t[boolean]"),
-            Token::Boolean(_) => write!(f, "#f[boolean]"),
-            Token::Id(id) => write!(f, "{}[id]", id),
-            Token::String(s) => write!(f, "\"{}\"[string]", s),
-            Token::Pair{car, cdr} => match **cdr {
+            Token::Int(i, _) => write!(f, "{}[int]", i),
+            Token::BigInt(i, _) => write!(f, "{}[int]", i),
+            Token::Rational(r, _) => write!(f, "{}[rational]", r),
+            Token::Float(fl, _) => write!(f, "{}[float]", fl),
+            Token::Boolean(b, _) if *b => write!(f, "#t[boolean]"),
+            Token::Boolean(_, _) => write!(f, "#f[boolean]"),
+            Token::Char(c, _) => write!(f, "{}[char]", write_char(*c)),
+            Token::Id(id, _) => write!(f, "{}[id]", id),
+            Token::String(s, _) => write!(f, "{}[string]", write_string(s)),
+            Token::Pair{car, cdr, ..} => match **cdr {
                 Token::Pair{..} => {
                     let cdr = format!("{:?}", cdr);
                     write!(f, "({:?} {}", car, cdr.split_at(1).1) // (car . (...)) -> (car ...) 
                 },
-                Token::Empty => write!(f, "({:?})", car),
+                Token::Empty(_) => write!(f, "({:?})", car),
                 _ => {
                     write!(f, "({:?} . {:?})", car, cdr)
                 }
             }
-            Token::Empty => write!(f, "()"),
-            Token::Symbol(s) => write!(f, "'{:?}", s)
+            Token::Empty(_) => write!(f, "()"),
+            Token::Vector(elems, _) => {
+                let elems: Vec<String> = elems.iter().map(|t| format!("{:?}", t)).collect();
+                write!(f, "#({})", elems.join(" "))
+            }
+            Token::Bytevector(bytes, _) => {
+                let bytes: Vec<String> = bytes.iter().map(|b| b.to_string()).collect();
+                write!(f, "#u8({})[bytevector]", bytes.join(" "))
+            }
+            Token::Label(n, datum, _) => write!(f, "#{}={:?}", n, datum),
+            Token::LabelRef(n, _) => write!(f, "#{}#[label]", n),
         }
     }
 }
@@ -146,7 +291,7 @@ pub struct TokenIter<'a> {
 impl<'a> Iterator for TokenIter<'a> {
     type Item = &'a Token;
     fn next(&mut self) -> Option<&'a Token> {
-        if let Token::Pair{car, cdr} = self.token {
+        if let Token::Pair{car, cdr, ..} = self.token {
             self.token = &**cdr;
             Some(&**car)
         } else {
@@ -161,11 +306,13 @@ mod tests {
     #[test]
     fn nth_test1() {
         let t = Token::Pair{
-            car: Box::new(Token::Int(0)),
-            cdr: Box::new(Token::Pair { 
-                car: Box::new(Token::Boolean(false)), 
-                cdr: Box::new(Token::Empty)
-            })
+            car: Arc::new(Token::Int(0, None)),
+            cdr: Arc::new(Token::Pair { 
+                car: Arc::new(Token::Boolean(false, None)), 
+                cdr: Arc::new(Token::Empty(None)),
+                pos: None,
+            }),
+            pos: None,
         };
         assert_eq!(format!("{}", t.nth(0).unwrap()), "0");
         assert_eq!(format!("{}", t.nth(1).unwrap()), "#f");
@@ -174,12 +321,37 @@ mod tests {
     #[should_panic]
     fn nth_test2() {
         let t = Token::Pair{
-            car: Box::new(Token::Int(0)),
-            cdr: Box::new(Token::Pair { 
-                car: Box::new(Token::Boolean(false)), 
-                cdr: Box::new(Token::Empty)
-            })
+            car: Arc::new(Token::Int(0, None)),
+            cdr: Arc::new(Token::Pair { 
+                car: Arc::new(Token::Boolean(false, None)), 
+                cdr: Arc::new(Token::Empty(None)),
+                pos: None,
+            }),
+            pos: None,
         };
         t.nth(2).unwrap();
     }
+    #[test]
+    fn write_symbol_test() {
+        assert_eq!(write_symbol("abc"), "abc");
+        assert_eq!(write_symbol("->x"), "->x");
+        assert_eq!(write_symbol("hello world"), "|hello world|");
+        assert_eq!(write_symbol(r"a|b\c"), r"|a\|b\\c|");
+        assert_eq!(write_symbol("123"), "|123|");
+        assert_eq!(write_symbol(""), "||");
+    }
+    #[test]
+    fn written_name_test() {
+        assert_eq!(written_name("tmp#12"), "tmp");
+        assert_eq!(written_name("tmp"), "tmp");
+        assert_eq!(format!("{}", Token::Id(Symbol::uninterned("x#3"), None)), "x");
+    }
+
+    #[test]
+    fn written_symbol_test() {
+        let renamed = Symbol::uninterned("tmp#12");
+        assert!(written_symbol(&renamed) == Symbol::intern("tmp"));
+        assert!(written_symbol(&renamed) != renamed);
+        assert!(Symbol::uninterned("tmp") != Symbol::intern("tmp"));
+    }
 }
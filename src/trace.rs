@@ -0,0 +1,52 @@
+use crate::token::Pos;
+use crate::data::{Cont, cont::Frame};
+
+use std::fmt;
+use std::sync::Arc;
+
+/**
+ * Call: a call of a scheme procedure and where it was made.
+ */
+#[derive(Clone)]
+pub struct Call {
+    pub name: Option<Arc<str>>,
+    pub site: Option<Pos>,
+}
+
+/**
+ * CallFrame: a procedure call which has not returned yet, kept as a frame of the continuation.
+ * Tail calls replace the frame of their caller; the frame keeps the first call,
+ * the last tail call and the number of tail calls collapsed into it.
+ */
+#[derive(Clone)]
+pub struct CallFrame {
+    pub call: Call,
+    pub tail: Option<Call>,
+    pub tail_calls: usize,
+}
+
+// the call stack in continuation "k", the most recent call first
+pub fn backtrace(k: &Cont) -> Vec<CallFrame> {
+    let mut trace = Vec::new();
+    let mut k = Some(k.clone());
+    while let Some(cont) = k {
+        if let Frame::Call(frame) = cont.frame() {
+            trace.push(frame.clone());
+        }
+        k = cont.parent();
+    }
+    trace
+}
+
+impl fmt::Display for Call {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{}", name)?,
+            None => write!(f, "#<procedure>")?,
+        }
+        if let Some(pos) = &self.site {
+            write!(f, " ({})", pos)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,376 @@
+use std::cell::Cell;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::data::Object;
+use crate::token::{Token, Pos, written_name};
+use crate::symbol::Symbol;
+use crate::compile::{Code, Op, Lambda, Let, Clause, DoLoop, Guard, Quasi};
+
+thread_local! {
+    // whether compiled code is run on the VM instead of by the tree-walking evaluator
+    static ENABLED: Cell<bool> = const { Cell::new(false) };
+}
+
+pub fn set_enabled(on: bool) {
+    ENABLED.with(|enabled| enabled.set(on));
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.with(|enabled| enabled.get())
+}
+
+/**
+ * Chunk: code compiled to instructions of the stack machine in eval.rs.
+ * The instructions push values to the operand stack and pop their operands from it.
+ * pos[i] is where an error of the instruction i is reported.
+ */
+pub struct Chunk {
+    pub insts: Vec<Inst>,
+    pub pos: Vec<Option<Pos>>,
+}
+
+pub enum Inst {
+    Const(Object),
+    Local(usize, usize, Symbol),
+    Global(Symbol),
+    // pop a value and assign it; push undefined
+    SetLocal(usize, usize),
+    SetGlobal(Symbol),
+    // pop a value and define it in a slot of the current frame, or at toplevel; push the name
+    Define(Option<usize>, Symbol),
+    // pop a value and bind it to a slot of the current frame (letrec)
+    Bind(usize, Symbol),
+    Closure(Rc<Lambda>),
+    // push the procedure of a named let
+    NamedLet(Rc<Let>),
+    Pop,
+    Jump(usize),
+    // pop a value and jump if it is #f
+    JumpIfFalse(usize),
+    // jump if the value on the top is #f (and) or not #f (or); pop it otherwise
+    And(usize),
+    Or(usize),
+    // pop n values and bind them in a new frame of the size
+    Enter(usize, usize),
+    Leave,
+    // pop n values and bind them in a new frame which replaces the current one (do)
+    Rebind(usize),
+    // pop the unquoted values and fill the template with them
+    Quasi(Rc<Quasi>),
+    // run the body of guard in a new frame, and push its value
+    Guard(Rc<Guard>),
+    // pop n arguments and the operator, and apply it.
+    // A tail call returns what the operator returns.
+    Call(usize, Token),
+    TailCall(usize, Token),
+    // pop a value and return it
+    Return,
+    Error(String),
+}
+
+// the chunk of "code", compiled when it is first run
+pub fn chunk(code: &Code) -> Rc<Chunk> {
+    code.chunk.get_or_init(|| {
+        let mut chunk = Chunk{insts: Vec::new(), pos: Vec::new()};
+        chunk.exp(code, true);
+        Rc::new(chunk)
+    }).clone()
+}
+
+impl Chunk {
+    fn emit(&mut self, inst: Inst, pos: Option<&Pos>) -> usize {
+        self.insts.push(inst);
+        self.pos.push(pos.cloned());
+        self.insts.len() - 1
+    }
+
+    // make the jump at "at" go to the next instruction
+    fn patch(&mut self, at: usize) {
+        let next = self.insts.len();
+        match &mut self.insts[at] {
+            Inst::Jump(to) | Inst::JumpIfFalse(to) | Inst::And(to) | Inst::Or(to) => *to = next,
+            _ => unreachable!(),
+        }
+    }
+
+    // the instructions which push the value of "code".
+    // Code in tail position returns its value instead.
+    fn exp(&mut self, code: &Code, tail: bool) {
+        let pos = code.pos.as_ref();
+        match &code.op {
+            Op::Const(obj) => {
+                self.emit(Inst::Const(obj.clone()), pos);
+            }
+            Op::Local(depth, index, id) => {
+                self.emit(Inst::Local(*depth, *index, id.clone()), pos);
+            }
+            Op::Global(id) => {
+                self.emit(Inst::Global(id.clone()), pos);
+            }
+            Op::Set(addr, id, exp) => {
+                self.exp(exp, false);
+                let inst = match addr {
+                    Some((depth, index)) => Inst::SetLocal(*depth, *index),
+                    None => Inst::SetGlobal(id.clone()),
+                };
+                self.emit(inst, exp.pos.as_ref());
+            }
+            Op::Define(index, id, exp) => {
+                match exp {
+                    Some(exp) => self.exp(exp, false),
+                    None => {
+                        self.emit(Inst::Const(Object::new_undefined()), pos);
+                    }
+                }
+                self.emit(Inst::Define(*index, id.clone()), pos);
+            }
+            Op::Lambda(lambda) => {
+                self.emit(Inst::Closure(lambda.clone()), pos);
+            }
+            Op::If(test, then, els) => {
+                self.exp(test, false);
+                let to_els = self.emit(Inst::JumpIfFalse(0), pos);
+                self.exp(then, tail);
+                let to_end = (!tail).then(|| self.emit(Inst::Jump(0), pos));
+                self.patch(to_els);
+                match els {
+                    Some(els) => self.exp(els, tail),
+                    None => self.undefined(pos, tail),
+                }
+                if let Some(to_end) = to_end {
+                    self.patch(to_end);
+                }
+                return;
+            }
+            Op::Seq(codes) => {
+                let (last, codes) = codes.split_last().unwrap();
+                for code in codes {
+                    self.exp(code, false);
+                    self.emit(Inst::Pop, pos);
+                }
+                return self.exp(last, tail);
+            }
+            Op::App(app) => {
+                self.exp(&app.exps[0], false);
+                // an unbound operator is reported at the application
+                if matches!(app.exps[0].op, Op::Local(..) | Op::Global(_)) {
+                    *self.pos.last_mut().unwrap() = app.call.pos().cloned();
+                }
+                for exp in &app.exps[1..] {
+                    self.exp(exp, false);
+                }
+                return self.call(app.exps.len() - 1, &app.call, tail);
+            }
+            Op::Let(lt) => match &lt.name {
+                Some(_) => {
+                    self.emit(Inst::NamedLet(lt.clone()), pos);
+                    for init in &lt.inits {
+                        self.exp(init, false);
+                    }
+                    return self.call(lt.inits.len(), &lt.call, tail);
+                }
+                None => {
+                    for init in &lt.inits {
+                        self.exp(init, false);
+                    }
+                    self.emit(Inst::Enter(lt.inits.len(), lt.body.size), pos);
+                    return self.frame_body(&lt.body.body, pos, tail);
+                }
+            }
+            Op::Letrec(lt) => {
+                self.emit(Inst::Enter(0, lt.body.size), pos);
+                for (index, init) in lt.inits.iter().enumerate() {
+                    self.exp(init, false);
+                    self.emit(Inst::Bind(index, lt.ids[index].clone()), pos);
+                }
+                return self.frame_body(&lt.body.body, pos, tail);
+            }
+            Op::Cond(clauses) => return self.clauses(clauses, pos, tail),
+            Op::And(tests) => return self.junction(tests, true, pos, tail),
+            Op::Or(tests) => return self.junction(tests, false, pos, tail),
+            Op::Do(lp) => return self.do_loop(lp, pos, tail),
+            Op::Guard(guard) => {
+                self.emit(Inst::Guard(guard.clone()), pos);
+            }
+            Op::Quasi(quasi) => {
+                for exp in &quasi.exps {
+                    self.exp(exp, false);
+                }
+                self.emit(Inst::Quasi(quasi.clone()), pos);
+            }
+            Op::Error(message) => {
+                self.emit(Inst::Error(message.clone()), pos);
+            }
+        }
+        if tail {
+            self.emit(Inst::Return, pos);
+        }
+    }
+
+    fn undefined(&mut self, pos: Option<&Pos>, tail: bool) {
+        self.emit(Inst::Const(Object::new_undefined()), pos);
+        if tail {
+            self.emit(Inst::Return, pos);
+        }
+    }
+
+    fn call(&mut self, argc: usize, call: &Token, tail: bool) {
+        let inst = if tail { Inst::TailCall(argc, call.clone()) } else { Inst::Call(argc, call.clone()) };
+        self.emit(inst, call.pos());
+    }
+
+    // the body of a frame entered just before; the frame is left after the body unless it returns
+    fn frame_body(&mut self, body: &Code, pos: Option<&Pos>, tail: bool) {
+        self.exp(body, tail);
+        if !tail {
+            self.emit(Inst::Leave, pos);
+        }
+    }
+
+    fn clauses(&mut self, clauses: &[Clause], pos: Option<&Pos>, tail: bool) {
+        let mut to_end = Vec::new();
+        for clause in clauses {
+            match &clause.test {
+                None => {
+                    self.exp(&clause.body, tail);
+                    to_end.iter().for_each(|&at| self.patch(at));
+                    return;
+                }
+                Some(test) => {
+                    self.exp(test, false);
+                    let to_next = self.emit(Inst::JumpIfFalse(0), pos);
+                    self.exp(&clause.body, tail);
+                    if !tail {
+                        to_end.push(self.emit(Inst::Jump(0), pos));
+                    }
+                    self.patch(to_next);
+                }
+            }
+        }
+        self.undefined(pos, tail);
+        to_end.iter().for_each(|&at| self.patch(at));
+    }
+
+    // and, or: a test which decides the value leaves with it
+    fn junction(&mut self, tests: &[Rc<Code>], is_and: bool, pos: Option<&Pos>, tail: bool) {
+        let (last, tests) = tests.split_last().unwrap();
+        let mut to_end = Vec::new();
+        for test in tests {
+            self.exp(test, false);
+            let jump = if is_and { Inst::And(0) } else { Inst::Or(0) };
+            to_end.push(self.emit(jump, pos));
+        }
+        self.exp(last, tail);
+        to_end.iter().for_each(|&at| self.patch(at));
+        if tail && !to_end.is_empty() {
+            self.emit(Inst::Return, pos);
+        }
+    }
+
+    // each iteration of do has its own frame, where the test, commands and steps are run
+    fn do_loop(&mut self, lp: &DoLoop, pos: Option<&Pos>, tail: bool) {
+        for init in &lp.inits {
+            self.exp(init, false);
+        }
+        self.emit(Inst::Enter(lp.inits.len(), lp.inits.len()), pos);
+        let test = self.insts.len();
+        self.exp(&lp.test, false);
+        let to_body = self.emit(Inst::JumpIfFalse(0), pos);
+        match &lp.exps {
+            Some(exps) => self.exp(exps, tail),
+            None => self.undefined(pos, tail),
+        }
+        let to_end = (!tail).then(|| self.emit(Inst::Jump(0), pos));
+        self.patch(to_body);
+        if let Some(cmds) = &lp.cmds {
+            self.exp(cmds, false);
+            self.emit(Inst::Pop, pos);
+        }
+        for step in &lp.steps {
+            self.exp(step, false);
+        }
+        self.emit(Inst::Rebind(lp.steps.len()), pos);
+        self.emit(Inst::Jump(test), pos);
+        if let Some(to_end) = to_end {
+            self.patch(to_end);
+            self.emit(Inst::Leave, pos);
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inst::Const(obj) => write!(f, "const {}", obj),
+            Inst::Local(depth, index, id) => write!(f, "local {} {} ; {}", depth, index, written_name(id)),
+            Inst::Global(id) => write!(f, "global {}", written_name(id)),
+            Inst::SetLocal(depth, index) => write!(f, "set-local {} {}", depth, index),
+            Inst::SetGlobal(id) => write!(f, "set-global {}", written_name(id)),
+            Inst::Define(Some(index), id) => write!(f, "define {} ; {}", index, written_name(id)),
+            Inst::Define(None, id) => write!(f, "define {}", written_name(id)),
+            Inst::Bind(index, id) => write!(f, "bind {} ; {}", index, written_name(id)),
+            Inst::Closure(_) => write!(f, "closure"),
+            Inst::NamedLet(lt) => write!(f, "named-let {}", written_name(lt.name.as_ref().unwrap())),
+            Inst::Pop => write!(f, "pop"),
+            Inst::Jump(to) => write!(f, "jump {}", to),
+            Inst::JumpIfFalse(to) => write!(f, "jump-if-false {}", to),
+            Inst::And(to) => write!(f, "and {}", to),
+            Inst::Or(to) => write!(f, "or {}", to),
+            Inst::Enter(n, size) => write!(f, "enter {} {}", n, size),
+            Inst::Leave => write!(f, "leave"),
+            Inst::Rebind(n) => write!(f, "rebind {}", n),
+            Inst::Quasi(quasi) => write!(f, "quasi {}", quasi.exps.len()),
+            Inst::Guard(_) => write!(f, "guard"),
+            Inst::Call(argc, _) => write!(f, "call {}", argc),
+            Inst::TailCall(argc, _) => write!(f, "tail-call {}", argc),
+            Inst::Return => write!(f, "return"),
+            Inst::Error(message) => write!(f, "error {:?}", message),
+        }
+    }
+}
+
+// a listing of "chunk", followed by those of the procedures and guards made in it.
+// closure, named-let and guard refer to them by number.
+pub fn disassemble(chunk: &Chunk) -> String {
+    let mut listing = String::new();
+    let mut nested: Vec<(String, Rc<Code>)> = Vec::new();
+    list(chunk, &mut listing, &mut nested);
+    let mut next = 0;
+    while next < nested.len() {
+        let (title, code) = nested[next].clone();
+        next += 1;
+        listing += &format!("\n[{}] {}", next, title);
+        if let Some(pos) = &code.pos {
+            listing += &format!(" at {}", pos);
+        }
+        listing += ":\n";
+        list(&self::chunk(&code), &mut listing, &mut nested);
+    }
+    listing
+}
+
+fn list(chunk: &Chunk, listing: &mut String, nested: &mut Vec<(String, Rc<Code>)>) {
+    for (i, inst) in chunk.insts.iter().enumerate() {
+        let refer = |nested: &mut Vec<(String, Rc<Code>)>, title: String, code: &Rc<Code>| {
+            nested.push((title, code.clone()));
+            nested.len()
+        };
+        let note = match inst {
+            Inst::Closure(lambda) => format!(" [{}]", refer(nested, "lambda".to_string(), &lambda.body)),
+            Inst::NamedLet(lt) => format!(" [{}]", refer(nested, "named let".to_string(), &lt.body.body)),
+            Inst::Guard(guard) => {
+                let body = refer(nested, "guard".to_string(), &guard.body.body);
+                for clause in guard.clauses.iter() {
+                    if let Some(test) = &clause.test {
+                        refer(nested, format!("test of guard [{}]", body), test);
+                    }
+                    refer(nested, format!("clause of guard [{}]", body), &clause.body);
+                }
+                format!(" [{}]", body)
+            }
+            _ => String::new(),
+        };
+        *listing += &format!("{:4}  {}{}\n", i, inst, note);
+    }
+}
@@ -0,0 +1,413 @@
+// Runs Scheme programs with the interpreter and checks what they print.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// the output of "program" run from a file with the options "args"
+fn run(args: &[&str], program: &str) -> String {
+    let n = COUNT.fetch_add(1, Ordering::SeqCst);
+    let path = std::env::temp_dir().join(format!("mini-scheme-test-{}-{}.scm", std::process::id(), n));
+    std::fs::write(&path, program).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_mini-scheme"))
+        .args(args)
+        .arg("-f")
+        .arg(&path)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    // without the prompt of the REPL, which ends at once, and with the file written as FILE in errors
+    let stdout = stdout.strip_suffix(">>> ").unwrap_or(&stdout).trim_end();
+    stdout.replace(path.to_str().unwrap(), "FILE")
+}
+
+// the output of "input" typed into the REPL, which runs a major GC after each input
+fn repl(input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mini-scheme"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn call_stack() {
+    // the stack of an error shows the callers, and is empty again after it
+    let program = r#"
+        (define (f x) (g x))
+        (define (g x) (+ x 'a))
+        (define (h x) (* 2 (f x)))
+        (h 1)
+        (h 2)
+    "#;
+    let error = |line, col| format!(concat!(
+        "[ERROR] number required, but got a\n",
+        " --> FILE:3:23\n",
+        "  |\n",
+        "3 |         (define (g x) (+ x 'a))\n",
+        "  |                       ^\n",
+        "call stack (most recent call first):\n",
+        "    at g (FILE:2:23)\n",
+        "      [1 tail call collapsed]\n",
+        "    at f (FILE:4:28)\n",
+        "    at h (FILE:{}:{})"), line, col);
+    assert_eq!(run(&[], program), format!("{}\n{}", error(5, 9), error(6, 9)));
+}
+
+#[test]
+fn continuations() {
+    let program = r#"
+        (define trace '())
+        (define (note x) (set! trace (cons x trace)))
+        (define (count n) (if (= n 0) 'done (count (- n 1))))
+        (define (deep n) (if (= n 0) 0 (+ 1 (deep (- n 1)))))
+        (display (list
+          (+ 100 (call/cc (lambda (k) 1)))
+          (call-with-current-continuation (lambda (k) (+ 1 (k 42))))
+          ; re-entered three times
+          (let ((k #f) (n 0))
+            (let ((v (call/cc (lambda (c) (set! k c) 0))))
+              (set! n (+ n 1))
+              (if (< v 3) (k (+ v 1)) (list v n))))
+          (call/cc (lambda (out)
+            (dynamic-wind
+              (lambda () (note 'before))
+              (lambda () (note 'during) (out 'escaped) (note 'not-here))
+              (lambda () (note 'after)))))
+          trace
+          (count 100000)
+          (deep 500)))
+    "#;
+    assert_eq!(run(&[], program), "(101 42 (3 4) escaped (after during before) done 500)");
+}
+
+#[test]
+fn exceptions() {
+    let program = r#"
+        (display (list
+          (guard (e ((symbol? e) (list 'symbol e)) ((number? e) (list 'number e))) (raise 42))
+          (guard (e ((error-object? e) (cons (string->symbol (error-object-message e)) (error-object-irritants e))))
+            (error "bad" 1 2))
+          ; an exception which no clause takes is raised again
+          (guard (e ((symbol? e) (list 'outer e))) (guard (e2 ((number? e2) 'inner)) (raise 'sym)))
+          (with-exception-handler (lambda (e) (* e 10)) (lambda () (+ 1 (raise-continuable 5))))
+          (guard (e (#t (error-object? e))) (car 1))
+          (call/cc (lambda (k) (with-exception-handler (lambda (e) (k (list 'handled e))) (lambda () (raise 'boom)))))
+          (guard (e ((string? e) e) (else 'else)) (raise 'x))))
+    "#;
+    assert_eq!(run(&[], program), "((number 42) (bad 1 2) (outer sym) 51 #t (handled boom) else)");
+}
+
+#[test]
+fn strings() {
+    // strings are indexed by Unicode scalar values
+    let program = r#"
+        (define s (make-string 3 #\a))
+        (string-set! s 1 #\λ)
+        (define t (string-copy "hello world"))
+        (string-copy! t 0 "HE")
+        (string-fill! t #\z 6 8)
+        (define (sym s) (string->symbol s))
+        (display (list
+          (sym s)
+          (sym t)
+          (sym (substring "hello" 1 3))
+          (sym (string-upcase "straße"))
+          (sym (string-downcase "ÀB"))
+          (sym (list->string (string->list "abc" 1)))
+          (sym (string #\1 #\2))
+          (sym (string-copy "abcdef" 2 4))
+          (string-length "λμν")
+          (char->integer (string-ref "aλb" 1))
+          (string=? "abc" "abc" "abc")
+          (string<? "abc" "abd")
+          (string-ci=? "AbC" "aBc")
+          (string-index "hello" #\l)
+          (string->utf8 "λ")))
+    "#;
+    assert_eq!(run(&[], program), "(aλa HEllo zzrld el STRASSE àb bc 12 cd 3 955 #t #t #t 2 #u8(206 187))");
+}
+
+#[test]
+fn ports() {
+    let path = std::env::temp_dir().join(format!("mini-scheme-test-{}-port.txt", std::process::id()));
+    let program = format!(r#"
+        (define path "{}")
+        (call-with-output-file path (lambda (port) (write-string "first line" port) (newline port) (write-char #\x port)))
+        (define in (open-input-file path))
+        (define line (read-line in))
+        (define c1 (peek-char in))
+        (define c2 (read-char in))
+        (define end (read-char in))
+        (close-port in)
+        (define out (open-output-string))
+        (write-string "abc" out)
+        (write-char #\d out)
+        (define sin (open-input-string "hello world"))
+        (display (list
+          (string->symbol line)
+          (char->integer c1)
+          (char->integer c2)
+          (eof-object? end)
+          (input-port-open? in)
+          (string->symbol (get-output-string out))
+          (string->symbol (read-string 5 sin))
+          (char->integer (read-char sin))
+          (string->symbol (read-line sin))
+          (eof-object? (read-line sin))
+          (string->symbol (call-with-output-string (lambda (port) (write-string "xyz" port))))
+          (string->symbol (with-input-from-file path read-line))
+          (input-port? sin)
+          (output-port? sin)))
+    "#, path.display());
+    let output = run(&[], &program);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output, "(first line 120 120 #t #f abcd hello 32 world #t xyz first line #t #f)");
+}
+
+#[test]
+fn quasiquote() {
+    let program = r#"
+        (define x 5)
+        (define l '(a b))
+        (display (list
+          `(x ,x)
+          `(1 ,@l 2 ,@'() 3)
+          `(1 . ,x)
+          `#(1 ,x ,@l)
+          ; only the innermost unquote is evaluated
+          `(nested `(a ,(b ,x)))
+          `,x
+          (quasiquote (1 (unquote (+ 1 1)) (unquote-splicing (list 3 4))))
+          ''x
+          (car ''x)
+          `(,@l . tail)))
+    "#;
+    let expected = "((x 5) (1 a b 2 3) (1 . 5) #(1 5 a b) (nested (quasiquote (a (unquote (b 5))))) 5 (1 2 3 4) (quote x) quote (a b . tail))";
+    assert_eq!(run(&[], program), expected);
+}
+
+#[test]
+fn lexical_addresses() {
+    // variables of nested let, lambda and internal define are resolved at each depth,
+    // past shadowed ones, and captured ones are shared by the closures
+    let program = r#"
+        (define x 'global)
+        (define (f a)
+          (let ((b (+ a 1)))
+            (define (g c)
+              (define d (* c 10))
+              (let ((a (+ a 100)) (x 'local))
+                (lambda (e) (list a b c d e x))))
+            (g (+ b 1))))
+        (write ((f 1) 5))
+        (write x)
+        (define (counter)
+          (let ((n 0))
+            (lambda () (set! n (+ n 1)) n)))
+        (define c1 (counter))
+        (define c2 (counter))
+        (c1) (c1)
+        (write (list (c1) (c2)))
+        (define (shadow x)
+          (let ((x (* x 2)))
+            (let ((f (lambda () x)))
+              (set! x (+ x 1))
+              (let ((x 'inner)) (list x (f))))))
+        (write (shadow 5))
+        (define (even-odd n)
+          (define (ev? n) (if (= n 0) #t (od? (- n 1))))
+          (define (od? n) (if (= n 0) #f (ev? (- n 1))))
+          (list (ev? n) (od? n)))
+        (write (even-odd 7))
+        (write (map (lambda (p) (p)) (let loop ((i 2) (acc (quote ()))) (if (< i 0) acc (loop (- i 1) (cons (lambda () i) acc))))))
+        (write (let* ((a 1) (b (+ a 1)) (a (* b 10))) (list a b)))
+        (write (letrec ((fact (lambda (n) (if (= n 0) 1 (* n (fact (- n 1))))))) (fact 20)))
+        (write (do ((i 0 (+ i 1)) (s 0 (+ s i))) ((= i 5) s)))
+        (define (late) (later 1))
+        (define (later y) (list y x))
+        (write (late))
+    "#;
+    let expected = "(101 2 3 30 5 local)global(3 1)(inner 11)(#f #t)(0 1 2)(20 2)243290200817664000010(1 global)";
+    assert_eq!(run(&[], program), expected);
+}
+
+#[test]
+fn vm() {
+    // the VM and the tree-walking evaluator give the same results
+    let calls = r#"
+        (define (loop n acc) (if (= n 0) acc (loop (- n 1) (+ acc 1))))
+        (write (loop 100000 0))
+        (define (tak x y z) (if (not (< y x)) z (tak (tak (- x 1) y z) (tak (- y 1) z x) (tak (- z 1) x y))))
+        (write (tak 12 8 4))
+        (write (let fact ((n 25)) (if (= n 0) 1 (* n (fact (- n 1))))))
+        (write (apply + 1 2 '(3 4)))
+        (write (call-with-values (lambda () (values 1 2 3)) list))
+        (write `(1 ,(+ 1 1) ,@(list 3 4)))
+        (write (cond ((> 1 2) 'a) ((car (list 2)) 'b) (else 'none)))
+        (write (and 1 2 #f 3))
+        (write (or #f '() 3))
+    "#;
+    let control = r#"
+        (write (let ((k #f) (n 0))
+                 (let ((v (call/cc (lambda (c) (set! k c) 0))))
+                   (set! n (+ n 1))
+                   (if (< v 3) (k (+ v 1)) (list v n)))))
+        (define trace '())
+        (define (note x) (set! trace (cons x trace)))
+        (write (call/cc (lambda (out) (dynamic-wind (lambda () (note 'in)) (lambda () (out 'escaped)) (lambda () (note 'out))))))
+        (write trace)
+        (write (guard (e ((symbol? e) (list 'caught e)) ((string? e) 'string)) (raise 'oops)))
+        (write (guard (e ((error-object? e) (error-object-message e))) (error "bad thing" 1 2)))
+        (write (with-exception-handler (lambda (e) 10) (lambda () (+ 1 (raise-continuable 'c)))))
+        (write (guard (e (#t 'car-failed)) (car 1)))
+    "#;
+    let programs = [
+        (calls, "10000051551121004333098598400000010(1 2 3)(1 2 3 4)b#f()"),
+        (control, "(3 4)escaped(out in)(caught oops)\"bad thing\"11car-failed"),
+    ];
+    for (program, expected) in programs {
+        assert_eq!(run(&[], program), expected);
+        assert_eq!(run(&["--vm"], program), expected);
+    }
+}
+
+#[test]
+fn sort() {
+    let program = r#"
+        (write (sort '(3 1 2 5 4) <))
+        (write (sort (vector 3 1 2) >))
+        (write (sort '() <))
+        ; elements which are not less than each other stay in their order
+        (write (sort '((1 . a) (0 . b) (1 . c) (0 . d) (1 . e)) (lambda (x y) (< (car x) (car y)))))
+        (define (numbers n) (let loop ((i n) (acc '())) (if (= i 0) acc (loop (- i 1) (cons (modulo (* i 7919) 10007) acc)))))
+        (define sorted (sort (numbers 20000) <))
+        (write (let loop ((l sorted)) (or (null? (cdr l)) (and (<= (car l) (car (cdr l))) (loop (cdr l))))))
+        (write (length sorted))
+    "#;
+    assert_eq!(run(&[], program), "(1 2 3 4 5)#(3 2 1)()((0 . b) (0 . d) (1 . a) (1 . c) (1 . e))#t20000");
+}
+
+#[test]
+fn small_heap() {
+    // GC runs many times, also while the global environment is made
+    let program = r#"
+        (write (list 1 2))
+        (write (vector-map + #(1 2) #(10 20)))
+        (define h (make-hash-table))
+        (hash-table-set! h 'a 1)
+        (write (hash-table-ref h 'a))
+        (define (f n) (if (= n 0) '() (cons n (f (- n 1)))))
+        (write (apply + (f 100)))
+        (define (g n acc) (if (= n 0) (vector-ref acc 0) (g (- n 1) (make-vector 3 n))))
+        (write (g 5000 #f))
+        (write (let loop ((i 0) (s "")) (if (= i 50) (string-length s) (loop (+ i 1) (string-append s "ab")))))
+    "#;
+    let expected = "(1 2)#(11 22)150501100";
+    assert_eq!(run(&[], program), expected);
+    assert_eq!(run(&["--heap-size", "16"], program), expected);
+    assert_eq!(run(&["--heap-size", "16", "--max-heap-size", "16"], program), expected);
+}
+
+#[test]
+fn record_primitives() {
+    // the procedures of a record type do not depend on globals which programs can redefine
+    let program = r#"
+        (define-record-type point (make-point x y) point? (x point-x set-point-x!) (y point-y))
+        (define record-ref (lambda args 'hijacked))
+        (define (record? . args) #t)
+        (define p (make-point 1 2))
+        (set-point-x! p 10)
+        (write (list (point-x p) (point-y p) (point? p) (point? 'p)))
+        (write (guard (e (#t 'unbound)) (make-record point 1 2)))
+    "#;
+    assert_eq!(run(&[], program), "(10 2 #t #f)unbound");
+}
+
+#[test]
+fn eqv_inexact() {
+    let program = r#"
+        (write (list (eqv? 0.0 -0.0) (eqv? +nan.0 +nan.0) (eqv? 1.5 1.5) (eqv? 1 1.0) (eqv? 1/2 1/2) (= 0.0 -0.0)))
+        (write (list (eq? -0.0 0.0) (equal? '(0.0) '(-0.0)) (equal? (vector +nan.0) (vector +nan.0))))
+    "#;
+    assert_eq!(run(&[], program), "(#f #t #t #f #t #t)(#f #f #t)");
+}
+
+#[test]
+fn numeric_library() {
+    // integer division, rounding and exactness follow R7RS
+    let program = r#"
+        (write (list (quotient 17 -5) (remainder 17 -5) (modulo 17 -5) (modulo -7 2.0)))
+        (write (call-with-values (lambda () (floor/ -7 2)) list))
+        (write (call-with-values (lambda () (truncate/ -7 2)) list))
+        (write (list (gcd 32 -36) (gcd) (lcm 4 6) (lcm 4.0 6) (abs -7/2) (min 1 2.0) (max 1/2 1/3)))
+        (write (list (floor -3.5) (ceiling 7/2) (round 5/2) (round 7/2) (round -2.5) (truncate -7/2)))
+        (write (list (sqrt 16) (sqrt 1/4) (sqrt 2) (expt 2 100) (expt 2 -2) (expt 4 1/2) (expt 2.0 3)))
+        (write (call-with-values (lambda () (exact-integer-sqrt 17)) list))
+        (write (list (exp 0) (log 1) (atan 1 1) (number->string 255 16) (number->string -10 2) (number->string 1/3 2)))
+        (write (list (zero? 0.0) (positive? -1/2) (odd? 7) (even? 0) (integer? 2.0) (integer? 1/2) (nan? +nan.0) (exact-integer? 2.0)))
+    "#;
+    let expected = concat!(
+        "(-3 2 -3 1.0)",
+        "(-4 1)",
+        "(-3 -1)",
+        "(4 0 12 12.0 7/2 1.0 1/2)",
+        "(-4.0 4 2 4 -2.0 -3)",
+        "(4 1/2 1.4142135623730951 1267650600228229401496703205376 1/4 2.0 8.0)",
+        "(4 1)",
+        "(1.0 0.0 0.7853981633974483 \"ff\" \"-1010\" \"1/11\")",
+        "(#t #f #t #t #t #f #t #f)",
+    );
+    assert_eq!(run(&[], program), expected);
+}
+
+#[test]
+fn deep_structures() {
+    // GC marks without recursion, so structures deeper than the Rust stack survive it
+    let input = concat!(
+        "(define (nest n acc) (if (= n 0) acc (nest (- n 1) (cons acc '()))))\n",
+        "(define deep (nest 100000 '()))\n",
+        "(define (vnest n acc) (if (= n 0) acc (vnest (- n 1) (vector acc))))\n",
+        "(define vdeep (vnest 100000 #()))\n",
+        "(define long (let loop ((i 0) (l '())) (if (= i 300000) l (loop (+ i 1) (cons i l)))))\n",
+        "(let loop ((l deep) (n 0)) (if (null? l) n (loop (car l) (+ n 1))))\n",
+        "(let loop ((v vdeep) (n 0)) (if (= (vector-length v) 0) n (loop (vector-ref v 0) (+ n 1))))\n",
+        "(length long)\n",
+    );
+    let expected = ">>> nest\n>>> deep\n>>> vnest\n>>> vdeep\n>>> long\n>>> 100000\n>>> 100000\n>>> 300000\n>>> ";
+    assert_eq!(repl(input), expected);
+}
+
+#[test]
+fn long_lists() {
+    // lists are written along the cdrs without recursion
+    let program = r#"
+        (define l (let loop ((i 0) (acc '())) (if (= i 300000) acc (loop (+ i 1) (cons (- 299999 i) acc)))))
+        (define (written write l) (let ((port (open-output-string))) (write l port) (string-length (get-output-string port))))
+        (write (list (written write l) (written write-shared l) (written write-simple l) (written display l)))
+        (define c (list 1 2))
+        (set-cdr! (cdr c) c)
+        (write (cons 0 c))
+        (display (cons 1 "(x"))
+    "#;
+    assert_eq!(run(&[], program), "(1988891 1988891 1988891 1988891)(0 . #0=(1 2 . #0#))(1 . (x)");
+}
+
+#[test]
+fn symbols_collected() {
+    // symbols which are not used any more do not stay in the heap
+    let program = r#"
+        (define kept (string->symbol "kept"))
+        (define (make n) (if (= n 0) 'done (begin (string->symbol (number->string n)) (make (- n 1)))))
+        (write (make 100000))
+        (write (list (eq? kept (string->symbol "kept")) (eq? (string->symbol "abc") 'abc) (symbol=? kept 'kept)))
+    "#;
+    assert_eq!(run(&["--max-heap-size", "10000"], program), "done(#t #t #t)");
+}